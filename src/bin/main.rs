@@ -1,23 +1,36 @@
 extern crate env_logger;
+extern crate hex;
 extern crate kaliko;
 #[macro_use] extern crate log;
 extern crate serde;
 #[macro_use] extern crate serde_derive;
 extern crate toml;
 
+use hex::FromHex;
 use kaliko::KalikoControlMessage;
 use kaliko::bitcoin;
-use kaliko::network::{Command, Message};
+use kaliko::bitcoin::ChainParams;
+use kaliko::mempool::{EstimateMode, Mempool, MempoolManager};
+use kaliko::network::{Command, Decodable, Message};
+use kaliko::network::transaction::Transaction;
+use kaliko::network::version;
 use kaliko::peer;
-use kaliko::peer::PeerConnection;
+use kaliko::peer::{PeerConnection, PeerInfo};
+use kaliko::peer::broadcast::id_to_hex;
 use kaliko::storage::BlockHeaderStorage;
+use kaliko::util::AdjustedTime;
 use std::fmt::Display;
 use std::fs::File;
-use std::io::Read;
-use std::net::{SocketAddr, SocketAddrV6, TcpStream, ToSocketAddrs};
+use std::io;
+use std::io::{BufRead, Read};
+use std::env;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::process;
 use std::sync::{Arc, mpsc, Mutex};
 use std::sync::mpsc::Sender;
 use std::thread;
+use std::time;
 
 fn byte_slice_as_hex(slice: &[u8]) -> String {
     let mut result = String::new();
@@ -27,51 +40,145 @@ fn byte_slice_as_hex(slice: &[u8]) -> String {
     result
 }
 
+fn default_network() -> String {
+    String::from("testnet3")
+}
+
 #[derive(Deserialize)]
 struct Config {
+    // One of mainnet, testnet3, testnet4, signet, regtest, etc.
+    #[serde(default = "default_network")]
+    network: String,
+    // Hex encoded challenge of a custom signet. Only used with `network = "signet"`.
+    signet_challenge: Option<String>,
+    // Each network keeps its data in its own directory inside this one.
     storage_location: String,
     peer_seed_list: String,
     max_active_peers: usize,
+    // Extra comments to put in our BIP14 user agent.
+    #[serde(default)]
+    user_agent_comments: Vec<String>,
+    // Whether peers should announce transactions to us.
+    #[serde(default)]
+    relay_transactions: bool,
+    // In satoshis per 1000 bytes.
+    min_relay_fee: Option<u64>,
+    // Asmap file in Bitcoin Core's binary format (as made by its contrib/asmap tool), used to spread outbound peers over different ASes.
+    asmap: Option<String>,
+    // How long, in seconds, misbehaving peers are banned for.
+    ban_time: Option<u64>,
+    // How long, in seconds, to wait for a connection to a peer to open.
+    connect_timeout: Option<u64>,
+    // DNS server (e.g. "1.1.1.1:53") to look up DNS seeds with. The system resolver is used when not set.
+    dns_server: Option<String>,
+    // Whether to accept connections from other peers, on the network's default port unless `listen_port` is set.
+    #[serde(default)]
+    listen: bool,
+    listen_port: Option<u16>,
+    max_inbound_peers: Option<usize>,
+    // In megabytes.
+    max_mempool_size: Option<usize>,
 }
 
 pub struct Kaliko {
     config: Config,
+    params: ChainParams,
     main_control_sender: mpsc::Sender<KalikoControlMessage>,
     main_control_receiver: mpsc::Receiver<KalikoControlMessage>,
     storage_channel: mpsc::Sender<KalikoControlMessage>,
     peer_manager_channel: mpsc::Sender<KalikoControlMessage>,
+    mempool_channel: mpsc::Sender<KalikoControlMessage>,
 }
 
 impl Kaliko {
-    pub fn new() -> Kaliko {
+    pub fn new(config_location: &str) -> Result<Kaliko, String> {
         // Config file parsing.
-        let mut config_file = File::open("kaliko.toml").unwrap();
+        let mut config_file = File::open(config_location).unwrap();
         let mut contents = String::new();
         config_file.read_to_string(&mut contents).unwrap();
         let config: Config = toml::from_str(&contents).unwrap();
+        let network = config.network.parse::<bitcoin::Network>().unwrap();
+        let params = match config.signet_challenge {
+            Some(ref challenge) if network == bitcoin::Network::Signet => ChainParams::custom_signet(Vec::from_hex(challenge).unwrap()),
+            Some(_) => panic!("signet_challenge can only be set when running on signet"),
+            None => network.params(),
+        };
+        info!("Running on {}", network);
         trace!("Finish config file parsing");
 
         let (main_control_sender, main_control_receiver) = mpsc::channel();
 
         // Storage communication set up.
-        let storage = BlockHeaderStorage::new(&config.storage_location, main_control_sender.clone());
+        // Older versions kept every header in a single file at `storage_location`.
+        let storage_location = Path::new(&config.storage_location);
+        if storage_location.is_file() {
+            return Err(format!("storage_location {} is a file left by an older version of Kaliko. It's now a directory with one subdirectory per network, so move or delete the file (its headers will be downloaded again)", storage_location.display()));
+        }
+
+        let data_dir = storage_location.join(network.name());
+        let adjusted_time = AdjustedTime::new();
+        let storage = BlockHeaderStorage::new(&params, &data_dir, adjusted_time.clone(), main_control_sender.clone())?;
         let storage_channel = storage.incoming_sender();
         storage.start();
         trace!("Finish storage communication set up");
 
+        // Mempool communication set up.
+        let mut mempool = Mempool::new();
+        if let Some(min_relay_fee) = config.min_relay_fee {
+            mempool.set_min_relay_fee(min_relay_fee);
+        }
+        if let Some(max_mempool_size) = config.max_mempool_size {
+            mempool.set_max_size(max_mempool_size * 1000 * 1000);
+        }
+        let mempool_manager = MempoolManager::new(mempool, &data_dir, main_control_sender.clone());
+        let mempool_channel = mempool_manager.incoming_sender();
+        mempool_manager.start();
+        trace!("Finish mempool communication set up");
+
         // Peer manager communication set up.
-        let peer_manager = peer::PeerManager::new(bitcoin::Network::Testnet3, config.max_active_peers, config.max_active_peers, main_control_sender.clone());
+        let mut peer_settings = peer::PeerSettings::new();
+        peer_settings.user_agent = version::format_user_agent("Kaliko", env!("CARGO_PKG_VERSION"), &config.user_agent_comments);
+        peer_settings.relay = config.relay_transactions;
+        if let Some(min_relay_fee) = config.min_relay_fee {
+            peer_settings.min_relay_fee = min_relay_fee;
+        }
+        peer_settings.adjusted_time = adjusted_time;
+
+        let mut peer_manager = peer::PeerManager::new(params.clone(), peer_settings, &data_dir, config.max_active_peers, main_control_sender.clone());
+        if let Some(ref asmap_location) = config.asmap {
+            let asmap = peer::Asmap::load(Path::new(asmap_location)).map_err(|e| format!("Couldn't load asmap {}: {}", asmap_location, e))?;
+            info!("Loaded asmap of {} bytes", asmap.len());
+            peer_manager.set_asmap(asmap);
+        }
+        if let Some(ban_time) = config.ban_time {
+            peer_manager.set_ban_duration(ban_time);
+        }
+        if let Some(connect_timeout) = config.connect_timeout {
+            peer_manager.set_connect_timeout(time::Duration::from_secs(connect_timeout));
+        }
+        if let Some(ref dns_server) = config.dns_server {
+            let dns_server = dns_server.parse().map_err(|_| format!("dns_server {} should be an IP address and a port, like 1.1.1.1:53", dns_server))?;
+            peer_manager.set_dns_server(dns_server);
+        }
+        if config.listen {
+            peer_manager.set_listen_port(config.listen_port.unwrap_or(params.default_port));
+        }
+        if let Some(max_inbound_peers) = config.max_inbound_peers {
+            peer_manager.set_max_inbound_peers(max_inbound_peers);
+        }
         let peer_manager_channel = peer_manager.control_sender();
         peer_manager.start();
         trace!("Finish peer manager communication set up");
 
-        Kaliko {
+        Ok(Kaliko {
             config,
+            params,
             main_control_sender,
             main_control_receiver,
             storage_channel,
             peer_manager_channel,
-        }
+            mempool_channel,
+        })
     }
 
     pub fn process_message(&self, msg: Message) {
@@ -91,6 +198,33 @@ impl Kaliko {
             KalikoControlMessage::NewHeadersAvailable(peer, headers) => {
                 self.storage_channel.send(KalikoControlMessage::NewHeadersAvailable(peer, headers)).unwrap();
             },
+            KalikoControlMessage::NewBlockAvailable(peer, block) => {
+                self.storage_channel.send(KalikoControlMessage::NewBlockAvailable(peer, block)).unwrap();
+            },
+            KalikoControlMessage::BlocksAnnounced(peer, hashes) => {
+                self.storage_channel.send(KalikoControlMessage::BlocksAnnounced(peer, hashes)).unwrap();
+            },
+            KalikoControlMessage::RequestBlocksFromPeer(peer, hashes) => {
+                self.peer_manager_channel.send(KalikoControlMessage::RequestBlocksFromPeer(peer, hashes)).unwrap();
+            },
+            KalikoControlMessage::ChainHeightUpdated(height) => {
+                self.peer_manager_channel.send(KalikoControlMessage::ChainHeightUpdated(height)).unwrap();
+                self.mempool_channel.send(KalikoControlMessage::ChainHeightUpdated(height)).unwrap();
+            },
+            msg @ KalikoControlMessage::NewTransactionAvailable(..) | msg @ KalikoControlMessage::SubmitTransaction(..) | msg @ KalikoControlMessage::TransactionsRequested(..) | msg @ KalikoControlMessage::EstimateSmartFee(..) => {
+                self.mempool_channel.send(msg).unwrap();
+            },
+            KalikoControlMessage::BlockConnected(height, block) => {
+                self.peer_manager_channel.send(KalikoControlMessage::BlockConnected(height, block.clone())).unwrap();
+                self.mempool_channel.send(KalikoControlMessage::BlockConnected(height, block)).unwrap();
+            },
+            KalikoControlMessage::Shutdown => {
+                self.peer_manager_channel.send(KalikoControlMessage::Shutdown).unwrap();
+                self.mempool_channel.send(KalikoControlMessage::Shutdown).unwrap();
+            },
+            msg @ KalikoControlMessage::Misbehaving(..) | msg @ KalikoControlMessage::Ban(..) | msg @ KalikoControlMessage::Unban(_) | msg @ KalikoControlMessage::ListBanned(_) | msg @ KalikoControlMessage::PeerBestHeight(..) | msg @ KalikoControlMessage::GetPeerInfo(_) | msg @ KalikoControlMessage::MinFeeUpdated(_) | msg @ KalikoControlMessage::AnnounceTransaction(..) | msg @ KalikoControlMessage::AnnounceHeaders(..) | msg @ KalikoControlMessage::BroadcastTransaction(_) | msg @ KalikoControlMessage::TransactionsRemoved(_) | msg @ KalikoControlMessage::ServeTransactions(..) => {
+                self.peer_manager_channel.send(msg).unwrap();
+            },
             KalikoControlMessage::RequestHeadersFromPeer(peer, latest_hash) => {
                 // TODO: find a way to just route the message?
                 self.peer_manager_channel.send(KalikoControlMessage::RequestHeadersFromPeer(peer, latest_hash)).unwrap();
@@ -100,31 +234,180 @@ impl Kaliko {
     }
 }
 
+fn print_peer_info(info: &PeerInfo) {
+    let sent = info.sent.total();
+    let received = info.received.total();
+
+    println!("{} ({})", info.addr, if info.inbound { "inbound" } else { "outbound" });
+    println!("  services: {}, user agent: {}, version: {}", info.services, info.user_agent, info.version);
+    println!("  starting height: {}, current height: {}", info.starting_height, info.current_height);
+    println!("  connected for {}s, ping: {:?}, min ping: {:?}, fee filter: {}, wtxid relay: {}", info.connected_time.as_secs(), info.ping, info.min_ping, info.fee_filter, info.wtxid_relay);
+    println!("  sent {} messages ({} bytes), received {} messages ({} bytes)", sent.count, sent.bytes, received.count, received.bytes);
+
+    for (command, stats) in info.sent.commands() {
+        println!("    sent {}: {} ({} bytes)", command, stats.count, stats.bytes);
+    }
+    for (command, stats) in info.received.commands() {
+        println!("    received {}: {} ({} bytes)", command, stats.count, stats.bytes);
+    }
+}
+
+// Reads commands from stdin, one per line.
+fn run_console(main_control_sender: Sender<KalikoControlMessage>) {
+    thread::spawn(move || {
+        let stdin = io::stdin();
+
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+
+            let mut words = line.split_whitespace();
+            let command = match words.next() {
+                Some(command) => command,
+                None => continue,
+            };
+            let args = words.collect::<Vec<&str>>();
+
+            match (command, &args[..]) {
+                ("stop", []) => {
+                    main_control_sender.send(KalikoControlMessage::Shutdown).unwrap();
+                    break;
+                },
+                ("ban", [ip]) | ("ban", [ip, _]) => {
+                    let duration = match args.get(1).map(|d| d.parse::<u64>()) {
+                        None => Ok(0),
+                        Some(result) => result,
+                    };
+
+                    match (ip.parse::<IpAddr>(), duration) {
+                        (Ok(ip), Ok(duration)) => main_control_sender.send(KalikoControlMessage::Ban(ip, duration)).unwrap(),
+                        _ => println!("Usage: ban <ip> [seconds]"),
+                    }
+                },
+                ("unban", [ip]) => {
+                    match ip.parse::<IpAddr>() {
+                        Ok(ip) => main_control_sender.send(KalikoControlMessage::Unban(ip)).unwrap(),
+                        Err(_) => println!("Usage: unban <ip>"),
+                    }
+                },
+                ("listbanned", []) => {
+                    let (reply_sender, reply_receiver) = mpsc::channel();
+                    main_control_sender.send(KalikoControlMessage::ListBanned(reply_sender)).unwrap();
+
+                    if let Ok(banned) = reply_receiver.recv() {
+                        for (ip, until) in banned {
+                            println!("{} banned until {}", ip, until);
+                        }
+                    }
+                },
+                ("getpeerinfo", []) => {
+                    let (reply_sender, reply_receiver) = mpsc::channel();
+                    main_control_sender.send(KalikoControlMessage::GetPeerInfo(reply_sender)).unwrap();
+
+                    if let Ok(peers) = reply_receiver.recv() {
+                        for info in peers {
+                            print_peer_info(&info);
+                        }
+                    }
+                },
+                ("sendrawtransaction", [raw]) => {
+                    // Trailing bytes mean it wasn't a transaction after all.
+                    let tx = Vec::from_hex(raw).ok().and_then(|bytes| {
+                        let mut reader = &bytes[..];
+                        Transaction::decode(&mut reader).ok().filter(|_| reader.is_empty())
+                    });
+
+                    match tx {
+                        Some(tx) => {
+                            let txid = tx.txid();
+                            let (reply_sender, reply_receiver) = mpsc::channel();
+                            main_control_sender.send(KalikoControlMessage::SubmitTransaction(tx, reply_sender)).unwrap();
+
+                            match reply_receiver.recv() {
+                                Ok(Ok(())) => println!("{}", id_to_hex(&txid)),
+                                Ok(Err(e)) => println!("Transaction rejected by the mempool: {:?}", e),
+                                Err(_) => (),
+                            }
+                        },
+                        None => println!("Usage: sendrawtransaction <hex encoded transaction>"),
+                    }
+                },
+                ("estimatesmartfee", [target]) | ("estimatesmartfee", [target, _]) => {
+                    let mode = match args.get(1) {
+                        None | Some(&"conservative") => Some(EstimateMode::Conservative),
+                        Some(&"economical") => Some(EstimateMode::Economical),
+                        Some(_) => None,
+                    };
+
+                    match (target.parse::<u32>(), mode) {
+                        (Ok(target), Some(mode)) => {
+                            let (reply_sender, reply_receiver) = mpsc::channel();
+                            main_control_sender.send(KalikoControlMessage::EstimateSmartFee(target, mode, reply_sender)).unwrap();
+
+                            match reply_receiver.recv() {
+                                Ok(Some((fee_rate, target))) => println!("{} sat/kvB to confirm within {} blocks", fee_rate, target),
+                                _ => println!("Not enough data to estimate fees yet"),
+                            }
+                        },
+                        _ => println!("Usage: estimatesmartfee <blocks> [conservative|economical]"),
+                    }
+                },
+                _ => println!("Unknown command: {}", line.trim()),
+            }
+        }
+    });
+}
+
 fn main() {
     env_logger::init();
     info!("Starting Kaliko");
 
-    let kaliko = Kaliko::new();
+    // The config file can be given as the only argument, which allows running instances for different networks side by side.
+    let config_location = env::args().nth(1).unwrap_or(String::from("kaliko.toml"));
+    let kaliko = match Kaliko::new(&config_location) {
+        Ok(kaliko) => kaliko,
+        Err(e) => {
+            eprintln!("Couldn't start Kaliko: {}", e);
+            process::exit(1);
+        },
+    };
 
     debug!("Reading peer seed list");
     let mut initial_peers = peer::read_peer_list(&kaliko.config.peer_seed_list);
+    let mut seeds = vec![];
     while let Some(addr) = initial_peers.pop() {
-        let addrs = match addr.to_socket_addrs() {
+        // Peers listed without a port use the network's default one.
+        let addrs = match addr.to_socket_addrs().or_else(|_| (addr.as_str(), kaliko.params.default_port).to_socket_addrs()) {
             Ok(result) => result.collect::<Vec<SocketAddr>>(),
             Err(_) => continue,
         };
 
-        // TODO: improve this to prioritize e.g. IPv6 or something like that.
-        for addr in addrs {
-            trace!("Sending message to connect to {}", addr);
-            kaliko.peer_manager_channel.send(KalikoControlMessage::StartPeerConnection(addr)).unwrap();
-        }
+        seeds.extend(addrs);
     }
 
+    // Seeds the address manager already knows about are left as they are, so what it learned in earlier runs is kept.
+    kaliko.peer_manager_channel.send(KalikoControlMessage::SeedAddresses(seeds)).unwrap();
+
+    run_console(kaliko.main_control_sender.clone());
+
+    // Both the peer manager and the mempool have state to save before we stop.
+    let mut running_components = 2;
     loop {
         // if let Ok(msg) = kaliko.main_control_receiver.try_recv() {
         if let Ok(msg) = kaliko.main_control_receiver.recv() {
             trace!("Got control message: {:?}", msg);
+
+            if let KalikoControlMessage::ShutdownComplete = msg {
+                running_components -= 1;
+                if running_components == 0 {
+                    info!("Stopping Kaliko");
+                    break;
+                }
+                continue;
+            }
+
             kaliko.process_control_message(msg);
         }
     }
@@ -18,11 +18,7 @@ trait BIP32Value {
 
 impl BIP32Value for Network {
     fn bip32_value(&self) -> [u8; 4] {
-        match *self {
-            Network::Mainnet => [0x04, 0x88, 0xAD, 0xE4],
-            Network::Testnet | Network::Testnet3 => [0x04, 0x35, 0x83, 0x94],
-            Network::Namecoin => panic!("I don't know a BIP32 value for Namecoin!"),
-        }
+        self.params().bip32_private_version
     }
 
     fn from_slice(slice: &[u8]) -> Result<Network, BIP32Error> {
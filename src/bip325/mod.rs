@@ -0,0 +1,126 @@
+use bitcoin::ChainParams;
+use byteorder::{ByteOrder, LittleEndian};
+use network::Decodable;
+use network::block::{merkle_root, Block};
+use network::encode::serialize;
+use network::transaction::{OutPoint, Transaction, TxIn, TxOut};
+use script::{instructions, push_data, verify_script, Instruction, ScriptError, SignatureChecker, VERIFY_DERSIG, VERIFY_NULLDUMMY, VERIFY_P2SH, VERIFY_WITNESS};
+use script::opcodes::{OP_0, OP_RETURN};
+use util::sha256d;
+
+#[cfg(test)]
+mod tests;
+
+// Marks the push in the witness commitment that carries the block's signet solution.
+pub const SIGNET_HEADER: [u8; 4] = [0xec, 0xc7, 0xda, 0xa2];
+
+// OP_RETURN, a 36 byte push, and the BIP141 commitment header.
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+const BLOCK_SCRIPT_VERIFY_FLAGS: u32 = VERIFY_P2SH | VERIFY_WITNESS | VERIFY_DERSIG | VERIFY_NULLDUMMY;
+
+#[derive(Debug, PartialEq)]
+pub enum SignetError {
+    NoCoinbase,
+    NoWitnessCommitment,
+    MalformedSolution,
+    InvalidSolution(ScriptError),
+}
+
+// A signet's network magic is taken from the hash of its challenge.
+pub fn message_start(challenge: &[u8]) -> u32 {
+    let hash = sha256d(&serialize(&challenge.to_vec()));
+    LittleEndian::read_u32(&hash[..4])
+}
+
+// BIP141: the commitment is the last coinbase output starting with the commitment header.
+fn witness_commitment_index(coinbase: &Transaction) -> Option<usize> {
+    coinbase.outputs.iter().rposition(|output| output.script_pubkey.len() >= 38 && output.script_pubkey.starts_with(&WITNESS_COMMITMENT_HEADER))
+}
+
+// Takes the solution out of the witness commitment script, leaving only the signet header in its push. Returns the new script and the solution, if there was one.
+fn take_solution(commitment: &[u8]) -> (Vec<u8>, Option<Vec<u8>>) {
+    let mut replacement = vec![];
+    let mut solution = None;
+
+    for instruction in instructions(commitment) {
+        match instruction {
+            Ok(Instruction::Push([])) => replacement.push(OP_0),
+            Ok(Instruction::Push(data)) => {
+                // The push only counts if it has something after the header.
+                if solution.is_none() && data.len() > SIGNET_HEADER.len() && data.starts_with(&SIGNET_HEADER) {
+                    solution = Some(data[SIGNET_HEADER.len()..].to_vec());
+                    push_data(&mut replacement, &SIGNET_HEADER);
+                } else {
+                    push_data(&mut replacement, data);
+                }
+            },
+            Ok(Instruction::Op(op)) => replacement.push(op),
+            Err(_) => break,
+        }
+    }
+
+    (replacement, solution)
+}
+
+// Builds the virtual transactions of BIP325: `to_spend` commits to the block and pays to the challenge, and `to_sign` spends it using the block's solution.
+pub fn signet_transactions(block: &Block, challenge: &[u8]) -> Result<(Transaction, Transaction), SignetError> {
+    let coinbase = block.transactions.first().ok_or(SignetError::NoCoinbase)?;
+    let commitment_index = witness_commitment_index(coinbase).ok_or(SignetError::NoWitnessCommitment)?;
+
+    let mut modified_coinbase = coinbase.clone();
+    let mut to_sign_input = TxIn::new(OutPoint::null(), vec![], 0);
+
+    let (commitment, solution) = take_solution(&coinbase.outputs[commitment_index].script_pubkey);
+    if let Some(solution) = solution {
+        modified_coinbase.outputs[commitment_index].script_pubkey = commitment;
+
+        let mut reader = &solution[..];
+        to_sign_input.script_sig = Vec::<u8>::decode(&mut reader).map_err(|_| SignetError::MalformedSolution)?;
+        to_sign_input.witness = Vec::<Vec<u8>>::decode(&mut reader).map_err(|_| SignetError::MalformedSolution)?;
+
+        if !reader.is_empty() {
+            return Err(SignetError::MalformedSolution);
+        }
+    }
+
+    // The signature can't sign itself, so the block is committed to with the solution taken out of the coinbase.
+    let mut hashes = block.transactions.iter().map(|tx| tx.txid()).collect::<Vec<[u8; 32]>>();
+    hashes[0] = modified_coinbase.txid();
+    let (modified_merkle_root, _) = merkle_root(&hashes);
+
+    // Everything in the header except the bits and nonce.
+    let mut block_data = serialize(&block.header.version());
+    block_data.extend_from_slice(&block.header.prev_block);
+    block_data.extend_from_slice(&modified_merkle_root);
+    block_data.extend_from_slice(&serialize(&block.header.timestamp()));
+
+    let mut to_spend_script_sig = vec![OP_0];
+    push_data(&mut to_spend_script_sig, &block_data);
+
+    let to_spend = Transaction::new(0, vec![TxIn::new(OutPoint::null(), to_spend_script_sig, 0)], vec![TxOut::new(0, challenge.to_vec())], 0);
+
+    to_sign_input.previous_output = OutPoint::new(to_spend.txid(), 0);
+    let to_sign = Transaction::new(0, vec![to_sign_input], vec![TxOut::new(0, vec![OP_RETURN])], 0);
+
+    Ok((to_spend, to_sign))
+}
+
+// Checks that the block is signed according to the signet challenge in `params`. Networks without a challenge accept any block.
+pub fn check_block_solution(block: &Block, params: &ChainParams) -> Result<(), SignetError> {
+    let challenge = match params.signet_challenge {
+        Some(ref challenge) => challenge,
+        None => return Ok(()),
+    };
+
+    if block.hash() == params.genesis.hash() {
+        return Ok(());
+    }
+
+    let (to_spend, to_sign) = signet_transactions(block, challenge)?;
+    let input = &to_sign.inputs[0];
+    let checker = SignatureChecker::new(&to_sign, 0, to_spend.outputs[0].value);
+
+    verify_script(&input.script_sig, &to_spend.outputs[0].script_pubkey, &input.witness, BLOCK_SCRIPT_VERIFY_FLAGS, &checker)
+        .map_err(SignetError::InvalidSolution)
+}
\ No newline at end of file
@@ -0,0 +1,148 @@
+use bitcoin::{ChainParams, Network};
+use network::block::{merkle_root, Block};
+use network::encode::serialize;
+use network::headers::BlockHeader;
+use network::transaction::{OutPoint, Transaction, TxIn, TxOut};
+use rand::thread_rng;
+use script::{legacy_sighash, push_data, ScriptError, SIGHASH_ALL};
+use script::opcodes::{OP_1, OP_CHECKMULTISIG, OP_RETURN};
+use secp256k1::{Message, Secp256k1, SecretKey};
+use super::*;
+
+fn commitment_script(signet_push: &[u8]) -> Vec<u8> {
+    let mut script = vec![OP_RETURN];
+    let mut commitment = vec![0xaa, 0x21, 0xa9, 0xed];
+    commitment.extend_from_slice(&[0x42; 32]);
+    push_data(&mut script, &commitment);
+    push_data(&mut script, signet_push);
+    script
+}
+
+fn block_with_commitment(params: &ChainParams, commitment: Vec<u8>) -> Block {
+    let coinbase_input = TxIn::new(OutPoint::null(), vec![0x51, 0x51], 0xFFFFFFFF);
+    let coinbase = Transaction::new(2, vec![coinbase_input], vec![TxOut::new(5000000000, vec![0x51]), TxOut::new(0, commitment)], 0);
+    let other = Transaction::new(2, vec![TxIn::new(OutPoint::new([3; 32], 1), vec![], 0xFFFFFFFE)], vec![TxOut::new(1000, vec![0x51])], 0);
+
+    let mut prev_block = [0u8; 32];
+    prev_block.copy_from_slice(&params.genesis.hash());
+    let (root, _) = merkle_root(&[coinbase.txid(), other.txid()]);
+    let header = BlockHeader::new(0x20000000, prev_block, root, 1598918500, params.pow_limit_bits, 0);
+
+    Block::new(header, vec![coinbase, other])
+}
+
+// Signs the block like a signet miner would: first committing to the block with only the signet header in the commitment, then filling in the solution.
+fn signed_block(params: &ChainParams, key: &SecretKey) -> Block {
+    let secp = Secp256k1::new();
+    let challenge = params.signet_challenge.clone().unwrap();
+    let mut block = block_with_commitment(params, commitment_script(&SIGNET_HEADER));
+
+    let (_, to_sign) = signet_transactions(&block, &challenge).unwrap();
+    let hash = legacy_sighash(&to_sign, 0, &challenge, SIGHASH_ALL);
+    let mut signature = secp.sign(&Message::from_slice(&hash).unwrap(), key).unwrap().serialize_der(&secp);
+    signature.push(SIGHASH_ALL as u8);
+
+    // Bare multisig, so the solution is only a scriptSig with the dummy element and the signature.
+    let mut script_sig = vec![0x00];
+    push_data(&mut script_sig, &signature);
+    let mut solution = SIGNET_HEADER.to_vec();
+    solution.extend_from_slice(&serialize(&script_sig));
+    solution.extend_from_slice(&serialize(&Vec::<Vec<u8>>::new()));
+
+    block.transactions[0].outputs[1].script_pubkey = commitment_script(&solution);
+    let (root, _) = block.compute_merkle_root();
+    block.header = BlockHeader::new(block.header.version(), block.header.prev_block, root, block.header.timestamp(), params.pow_limit_bits, 0);
+
+    block
+}
+
+fn one_of_one_params() -> (ChainParams, SecretKey) {
+    let secp = Secp256k1::new();
+    let (secret_key, public_key) = secp.generate_keypair(&mut thread_rng()).unwrap();
+
+    let mut challenge = vec![OP_1];
+    push_data(&mut challenge, &public_key.serialize());
+    challenge.push(OP_1);
+    challenge.push(OP_CHECKMULTISIG);
+
+    (ChainParams::custom_signet(challenge), secret_key)
+}
+
+#[test]
+fn signed_block_is_accepted() {
+    let (params, key) = one_of_one_params();
+    let block = signed_block(&params, &key);
+
+    assert!(block.check_merkle_root());
+    assert_eq!(check_block_solution(&block, &params), Ok(()));
+
+    // Other networks don't care about the solution.
+    assert_eq!(check_block_solution(&block, &Network::Testnet3.params()), Ok(()));
+}
+
+#[test]
+fn block_signed_by_someone_else_is_rejected() {
+    let (params, _) = one_of_one_params();
+    let (_, other_key) = one_of_one_params();
+    let block = signed_block(&params, &other_key);
+
+    assert_eq!(check_block_solution(&block, &params), Err(SignetError::InvalidSolution(ScriptError::EvalFalse)));
+}
+
+#[test]
+fn changing_signed_block_invalidates_solution() {
+    let (params, key) = one_of_one_params();
+    let mut block = signed_block(&params, &key);
+
+    block.transactions[1].outputs[0].value = 2000;
+    assert_eq!(check_block_solution(&block, &params), Err(SignetError::InvalidSolution(ScriptError::EvalFalse)));
+}
+
+#[test]
+fn unsigned_blocks_are_rejected() {
+    let (params, _) = one_of_one_params();
+
+    let block = block_with_commitment(&params, commitment_script(&SIGNET_HEADER));
+    assert_eq!(check_block_solution(&block, &params), Err(SignetError::InvalidSolution(ScriptError::InvalidStackOperation)));
+
+    let block = block_with_commitment(&params, vec![OP_RETURN]);
+    assert_eq!(check_block_solution(&block, &params), Err(SignetError::NoWitnessCommitment));
+}
+
+// Core's signet_parse_tests, with an OP_TRUE challenge so only the parsing of the solution matters.
+#[test]
+fn core_parse_vectors() {
+    let params = ChainParams::custom_signet(vec![OP_1]);
+
+    let block_with = |script_pubkey: Vec<u8>| {
+        let coinbase = Transaction::new(1, vec![], vec![TxOut::new(0, script_pubkey)], 0);
+        // A second transaction so the merkle root covers more than the coinbase.
+        Block::new(BlockHeader::new(0, [0; 32], [0; 32], 0, 0, 0), vec![coinbase.clone(), coinbase])
+    };
+    // The BIP141 commitment, followed by the signet push if there's one.
+    let with_signet_push = |push: Option<&[u8]>| {
+        let mut commitment = vec![0xaa, 0x21, 0xa9, 0xed];
+        commitment.extend_from_slice(&[0xff; 32]);
+        let mut script = vec![OP_RETURN];
+        push_data(&mut script, &commitment);
+        if let Some(data) = push {
+            let mut signet_push = SIGNET_HEADER.to_vec();
+            signet_push.extend_from_slice(data);
+            push_data(&mut script, &signet_push);
+        }
+        block_with(script)
+    };
+
+    let empty = Block::new(BlockHeader::new(0, [0; 32], [0; 32], 0, 0, 0), vec![]);
+    assert_eq!(check_block_solution(&empty, &params), Err(SignetError::NoCoinbase));
+    assert_eq!(check_block_solution(&block_with(vec![]), &params), Err(SignetError::NoWitnessCommitment));
+
+    // Without a solution, or with only the header, the scriptSig and witness are empty, which OP_TRUE accepts.
+    assert_eq!(check_block_solution(&with_signet_push(None), &params), Ok(()));
+    assert_eq!(check_block_solution(&with_signet_push(Some(&[])), &params), Ok(()));
+
+    // A scriptSig of OP_TRUE, missing the witness, then with an empty witness, then with a byte too many.
+    assert_eq!(check_block_solution(&with_signet_push(Some(&[0x01, 0x51])), &params), Err(SignetError::MalformedSolution));
+    assert_eq!(check_block_solution(&with_signet_push(Some(&[0x01, 0x51, 0x00])), &params), Ok(()));
+    assert_eq!(check_block_solution(&with_signet_push(Some(&[0x01, 0x51, 0x00, 0x00])), &params), Err(SignetError::MalformedSolution));
+}
\ No newline at end of file
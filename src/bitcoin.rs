@@ -1,7 +1,390 @@
+use bip325;
+use hex::FromHex;
+use network::headers::BlockHeader;
+use std::fmt;
+use std::str::FromStr;
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Network {
     Mainnet,
     Testnet,
     Testnet3,
+    Testnet4,
+    Regtest,
+    Signet,
     Namecoin,
+}
+
+// Everything that differs from one network to another.
+#[derive(Clone, Debug)]
+pub struct ChainParams {
+    pub network: Network,
+    pub magic: u32,
+    pub genesis: BlockHeader,
+    pub default_port: u16,
+    pub dns_seeds: &'static [&'static str],
+
+    // Difficulty rules.
+    pub pow_limit_bits: u32,
+    pub pow_target_timespan: u32,
+    pub pow_target_spacing: u32,
+    // Blocks may use the minimum difficulty if they come more than twice the target spacing after the previous block.
+    pub allow_min_difficulty_blocks: bool,
+    pub no_retargeting: bool,
+    // BIP94 rules (timewarp fix and no minimum difficulty carry over on retargets).
+    pub enforce_bip94: bool,
+
+    // Pairs of (height, block hash). Hashes are in the usual display (reversed) order.
+    pub checkpoints: &'static [(u32, &'static str)],
+
+    // Address prefixes.
+    pub pubkey_address_prefix: u8,
+    pub script_address_prefix: u8,
+    pub secret_key_prefix: u8,
+    pub bech32_hrp: &'static str,
+    pub bip32_public_version: [u8; 4],
+    pub bip32_private_version: [u8; 4],
+
+    // BIP325: script every block (other than the genesis) must be signed against. Only signets have one.
+    pub signet_challenge: Option<Vec<u8>>,
+}
+
+const TWO_WEEKS: u32 = 14 * 24 * 60 * 60;
+const TEN_MINUTES: u32 = 10 * 60;
+
+const MAINNET_BIP32_PUBLIC: [u8; 4] = [0x04, 0x88, 0xB2, 0x1E];
+const MAINNET_BIP32_PRIVATE: [u8; 4] = [0x04, 0x88, 0xAD, 0xE4];
+const TESTNET_BIP32_PUBLIC: [u8; 4] = [0x04, 0x35, 0x87, 0xCF];
+const TESTNET_BIP32_PRIVATE: [u8; 4] = [0x04, 0x35, 0x83, 0x94];
+
+// The merkle root of every network's genesis block except testnet4 and namecoin.
+const SATOSHI_GENESIS_MERKLE_ROOT: &'static str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+
+const MAINNET_CHECKPOINTS: &[(u32, &'static str)] = &[
+    (11111, "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d"),
+    (33333, "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6"),
+    (74000, "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20"),
+    (105000, "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97"),
+    (134444, "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe"),
+    (168000, "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763"),
+    (193000, "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317"),
+    (210000, "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e"),
+    (216116, "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e"),
+    (225430, "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932"),
+    (250000, "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214"),
+    (279000, "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40"),
+    (295000, "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983"),
+];
+
+const TESTNET3_CHECKPOINTS: &[(u32, &'static str)] = &[
+    (546, "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70"),
+];
+
+const MAINNET_DNS_SEEDS: &[&'static str] = &[
+    "seed.bitcoin.sipa.be",
+    "dnsseed.bluematt.me",
+    "seed.bitcoinstats.com",
+    "seed.bitcoin.jonasschnelli.ch",
+    "seed.btc.petertodd.net",
+    "seed.bitcoin.sprovoost.nl",
+    "dnsseed.emzy.de",
+    "seed.bitcoin.wiz.biz",
+];
+
+const TESTNET3_DNS_SEEDS: &[&'static str] = &[
+    "testnet-seed.bitcoin.jonasschnelli.ch",
+    "seed.tbtc.petertodd.net",
+    "seed.testnet.bitcoin.sprovoost.nl",
+    "testnet-seed.bluematt.me",
+];
+
+const TESTNET4_DNS_SEEDS: &[&'static str] = &[
+    "seed.testnet4.bitcoin.sprovoost.nl",
+    "seed.testnet4.wiz.biz",
+];
+
+const SIGNET_DNS_SEEDS: &[&'static str] = &[
+    "seed.signet.bitcoin.sprovoost.nl",
+    "seed.signet.achownodes.xyz",
+];
+
+// 1-of-2 multisig used by the default signet.
+pub const DEFAULT_SIGNET_CHALLENGE: &str = "512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430210359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae";
+
+const NAMECOIN_DNS_SEEDS: &[&'static str] = &[
+    "nmc.seed.quisquis.de",
+    "seed.nmc.markasoftware.com",
+];
+
+// Takes a hash in display order and returns it in the internal byte order.
+pub fn hash_from_hex(hex: &str) -> [u8; 32] {
+    let mut result = [0u8; 32];
+    result.copy_from_slice(&Vec::from_hex(hex).unwrap().iter().cloned().rev().collect::<Vec<u8>>());
+    result
+}
+
+fn genesis(merkle_root: &str, timestamp: u32, bits: u32, nonce: u32) -> BlockHeader {
+    BlockHeader::new(1, [0; 32], hash_from_hex(merkle_root), timestamp, bits, nonce)
+}
+
+impl Network {
+    // Name used in the config file and for the network's data directory.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Testnet3 => "testnet3",
+            Network::Testnet4 => "testnet4",
+            Network::Regtest => "regtest",
+            Network::Signet => "signet",
+            Network::Namecoin => "namecoin",
+        }
+    }
+
+    pub fn params(&self) -> ChainParams {
+        match *self {
+            Network::Mainnet => ChainParams {
+                network: *self,
+                magic: 0xD9B4BEF9,
+                genesis: genesis(SATOSHI_GENESIS_MERKLE_ROOT, 1231006505, 0x1d00ffff, 2083236893),
+                default_port: 8333,
+                dns_seeds: MAINNET_DNS_SEEDS,
+                pow_limit_bits: 0x1d00ffff,
+                pow_target_timespan: TWO_WEEKS,
+                pow_target_spacing: TEN_MINUTES,
+                allow_min_difficulty_blocks: false,
+                no_retargeting: false,
+                enforce_bip94: false,
+                checkpoints: MAINNET_CHECKPOINTS,
+                pubkey_address_prefix: 0,
+                script_address_prefix: 5,
+                secret_key_prefix: 128,
+                bech32_hrp: "bc",
+                bip32_public_version: MAINNET_BIP32_PUBLIC,
+                bip32_private_version: MAINNET_BIP32_PRIVATE,
+                signet_challenge: None,
+            },
+            // The testnet that came before testnet3.
+            Network::Testnet => ChainParams {
+                network: *self,
+                magic: 0xDAB5BFFA,
+                genesis: genesis(SATOSHI_GENESIS_MERKLE_ROOT, 1296688602, 0x1d07fff8, 384568319),
+                default_port: 18333,
+                dns_seeds: &[],
+                pow_limit_bits: 0x1d07fff8,
+                pow_target_timespan: TWO_WEEKS,
+                pow_target_spacing: TEN_MINUTES,
+                allow_min_difficulty_blocks: true,
+                no_retargeting: false,
+                enforce_bip94: false,
+                checkpoints: &[],
+                pubkey_address_prefix: 111,
+                script_address_prefix: 196,
+                secret_key_prefix: 239,
+                bech32_hrp: "tb",
+                bip32_public_version: TESTNET_BIP32_PUBLIC,
+                bip32_private_version: TESTNET_BIP32_PRIVATE,
+                signet_challenge: None,
+            },
+            Network::Testnet3 => ChainParams {
+                network: *self,
+                magic: 0x0709110B,
+                genesis: genesis(SATOSHI_GENESIS_MERKLE_ROOT, 1296688602, 0x1d00ffff, 414098458),
+                default_port: 18333,
+                dns_seeds: TESTNET3_DNS_SEEDS,
+                pow_limit_bits: 0x1d00ffff,
+                pow_target_timespan: TWO_WEEKS,
+                pow_target_spacing: TEN_MINUTES,
+                allow_min_difficulty_blocks: true,
+                no_retargeting: false,
+                enforce_bip94: false,
+                checkpoints: TESTNET3_CHECKPOINTS,
+                pubkey_address_prefix: 111,
+                script_address_prefix: 196,
+                secret_key_prefix: 239,
+                bech32_hrp: "tb",
+                bip32_public_version: TESTNET_BIP32_PUBLIC,
+                bip32_private_version: TESTNET_BIP32_PRIVATE,
+                signet_challenge: None,
+            },
+            Network::Testnet4 => ChainParams {
+                network: *self,
+                magic: 0x283F161C,
+                genesis: genesis("7aa0a7ae1e223414cb807e40cd57e667b718e42aaf9306db9102fe28912b7b4e", 1714777860, 0x1d00ffff, 393743547),
+                default_port: 48333,
+                dns_seeds: TESTNET4_DNS_SEEDS,
+                pow_limit_bits: 0x1d00ffff,
+                pow_target_timespan: TWO_WEEKS,
+                pow_target_spacing: TEN_MINUTES,
+                allow_min_difficulty_blocks: true,
+                no_retargeting: false,
+                enforce_bip94: true,
+                checkpoints: &[],
+                pubkey_address_prefix: 111,
+                script_address_prefix: 196,
+                secret_key_prefix: 239,
+                bech32_hrp: "tb",
+                bip32_public_version: TESTNET_BIP32_PUBLIC,
+                bip32_private_version: TESTNET_BIP32_PRIVATE,
+                signet_challenge: None,
+            },
+            // Regtest shares its magic with the old testnet.
+            Network::Regtest => ChainParams {
+                network: *self,
+                magic: 0xDAB5BFFA,
+                genesis: genesis(SATOSHI_GENESIS_MERKLE_ROOT, 1296688602, 0x207fffff, 2),
+                default_port: 18444,
+                dns_seeds: &[],
+                pow_limit_bits: 0x207fffff,
+                pow_target_timespan: TWO_WEEKS,
+                pow_target_spacing: TEN_MINUTES,
+                allow_min_difficulty_blocks: true,
+                no_retargeting: true,
+                enforce_bip94: false,
+                checkpoints: &[],
+                pubkey_address_prefix: 111,
+                script_address_prefix: 196,
+                secret_key_prefix: 239,
+                bech32_hrp: "bcrt",
+                bip32_public_version: TESTNET_BIP32_PUBLIC,
+                bip32_private_version: TESTNET_BIP32_PRIVATE,
+                signet_challenge: None,
+            },
+            // The default (global) signet.
+            Network::Signet => ChainParams {
+                network: *self,
+                magic: 0x40CF030A,
+                genesis: genesis(SATOSHI_GENESIS_MERKLE_ROOT, 1598918400, 0x1e0377ae, 52613770),
+                default_port: 38333,
+                dns_seeds: SIGNET_DNS_SEEDS,
+                pow_limit_bits: 0x1e0377ae,
+                pow_target_timespan: TWO_WEEKS,
+                pow_target_spacing: TEN_MINUTES,
+                allow_min_difficulty_blocks: false,
+                no_retargeting: false,
+                enforce_bip94: false,
+                checkpoints: &[],
+                pubkey_address_prefix: 111,
+                script_address_prefix: 196,
+                secret_key_prefix: 239,
+                bech32_hrp: "tb",
+                bip32_public_version: TESTNET_BIP32_PUBLIC,
+                bip32_private_version: TESTNET_BIP32_PRIVATE,
+                signet_challenge: Some(Vec::from_hex(DEFAULT_SIGNET_CHALLENGE).unwrap()),
+            },
+            // Namecoin has no BIP32 versions of its own, so it uses bitcoin's.
+            Network::Namecoin => ChainParams {
+                network: *self,
+                magic: 0xFEB4BEF9,
+                genesis: genesis("41c62dbd9068c89a449525e3cd5ac61b20ece28c3c38b3f35b2161f0e6d3cb0d", 1303000001, 0x1c007fff, 0xa21ea192),
+                default_port: 8334,
+                dns_seeds: NAMECOIN_DNS_SEEDS,
+                pow_limit_bits: 0x1d00ffff,
+                pow_target_timespan: TWO_WEEKS,
+                pow_target_spacing: TEN_MINUTES,
+                allow_min_difficulty_blocks: false,
+                no_retargeting: false,
+                enforce_bip94: false,
+                checkpoints: &[],
+                pubkey_address_prefix: 52,
+                script_address_prefix: 13,
+                secret_key_prefix: 180,
+                bech32_hrp: "nc",
+                bip32_public_version: MAINNET_BIP32_PUBLIC,
+                bip32_private_version: MAINNET_BIP32_PRIVATE,
+                signet_challenge: None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Network, String> {
+        let networks = [Network::Mainnet, Network::Testnet, Network::Testnet3, Network::Testnet4, Network::Regtest, Network::Signet, Network::Namecoin];
+
+        networks.iter()
+            .find(|network| network.name() == s)
+            .cloned()
+            .ok_or_else(|| format!("Unknown network \"{}\"", s))
+    }
+}
+
+impl ChainParams {
+    // Parameters of a signet using its own challenge instead of the default one. It's a different network, so it has its own magic and no seeds.
+    pub fn custom_signet(challenge: Vec<u8>) -> ChainParams {
+        let mut params = Network::Signet.params();
+
+        params.magic = bip325::message_start(&challenge);
+        params.dns_seeds = &[];
+        params.signet_challenge = Some(challenge);
+
+        params
+    }
+
+    // Hash (in internal byte order) the block at `height` must have, if there's a checkpoint at that height.
+    pub fn checkpoint(&self, height: u32) -> Option<[u8; 32]> {
+        self.checkpoints.iter()
+            .find(|&&(checkpoint_height, _)| checkpoint_height == height)
+            .map(|&(_, hash)| hash_from_hex(hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn genesis_hashes() {
+        let expected = [
+            (Network::Mainnet, "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"),
+            (Network::Testnet, "00000007199508e34a9ff81e6ec0c477a4cccff2a4767a8eee39c11db367b008"),
+            (Network::Testnet3, "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943"),
+            (Network::Testnet4, "00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043"),
+            (Network::Regtest, "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206"),
+            (Network::Signet, "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6"),
+            (Network::Namecoin, "000000000062b72c5e2ceb45fbc8587e807c155b0da735e6483dfba2f0a9c770"),
+        ];
+
+        for &(network, hash) in expected.iter() {
+            assert_eq!(network.params().genesis.hash(), hash_from_hex(hash).to_vec(), "Wrong genesis for {:?}", network);
+        }
+    }
+
+    #[test]
+    fn checkpoint_lookup() {
+        let params = Network::Testnet3.params();
+
+        assert_eq!(params.checkpoint(546), Some(hash_from_hex("000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70")));
+        assert_eq!(params.checkpoint(547), None);
+    }
+
+    #[test]
+    fn signet_magic_comes_from_challenge() {
+        let default_challenge = Vec::from_hex(DEFAULT_SIGNET_CHALLENGE).unwrap();
+        assert_eq!(ChainParams::custom_signet(default_challenge).magic, Network::Signet.params().magic);
+
+        // OP_TRUE.
+        assert_ne!(ChainParams::custom_signet(vec![0x51]).magic, Network::Signet.params().magic);
+
+        // Only the default signet has seeds.
+        assert!(!Network::Signet.params().dns_seeds.is_empty());
+        assert!(ChainParams::custom_signet(vec![0x51]).dns_seeds.is_empty());
+    }
+
+    #[test]
+    fn network_names_roundtrip() {
+        for network in [Network::Mainnet, Network::Testnet3, Network::Regtest, Network::Signet].iter() {
+            assert_eq!(network.name().parse::<Network>(), Ok(*network));
+        }
+
+        assert!("bitcoin".parse::<Network>().is_err());
+    }
 }
\ No newline at end of file
@@ -7,22 +7,34 @@ extern crate rand;
 extern crate ring;
 extern crate ripemd160;
 extern crate secp256k1;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
 extern crate sha2;
+extern crate toml;
 
 pub mod base58;
 pub mod bip32;
+pub mod bip325;
 pub mod bip39;
 pub mod bip44;
 pub mod bitcoin;
+pub mod mempool;
 pub mod network;
 pub mod peer;
+pub mod script;
 pub mod storage;
 pub mod util;
 
-use network::Message;
+use mempool::{EstimateMode, MempoolError};
+use network::{Message, NetworkAddress};
+use network::block::Block;
 use network::headers::BlockHeader;
-use std::net::SocketAddr;
+use network::inv::InventoryVector;
+use network::transaction::Transaction;
+use peer::PeerInfo;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::mpsc::Sender;
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub enum KalikoControlMessage {
@@ -36,4 +48,68 @@ pub enum KalikoControlMessage {
     RequestHeadersFromPeer(SocketAddr, Vec<Vec<u8>>),
     RequestHeaders(Vec<Vec<u8>>),
     NewHeadersAvailable(SocketAddr, Vec<BlockHeader>),
+    RequestBlocksFromPeer(SocketAddr, Vec<Vec<u8>>),
+    RequestBlocks(Vec<Vec<u8>>),
+    NewBlockAvailable(SocketAddr, Block),
+    // A block in our chain, with its height, which passed the checks we can do.
+    BlockConnected(i32, Block),
+    NewTransactionAvailable(SocketAddr, Transaction),
+    // Hashes of blocks a peer announced with inv.
+    BlocksAnnounced(SocketAddr, Vec<Vec<u8>>),
+    ChainHeightUpdated(i32),
+    // Addresses to bootstrap the address manager with.
+    SeedAddresses(Vec<SocketAddr>),
+    // Addresses a DNS seed answered with.
+    DnsSeedAddresses(Vec<SocketAddr>),
+    // Asks a peer connection to close.
+    Disconnect,
+    // Someone connected to us. The stream waits in the peer manager until it's accepted.
+    InboundConnection(SocketAddr),
+    // The address a peer sees us connecting from.
+    ExternalAddressSeen(SocketAddr, SocketAddr),
+    // How many seconds the peer's clock is ahead of ours, according to its version message.
+    PeerTimeOffset(SocketAddr, i64),
+    RequestAddresses,
+    SendAddresses(Vec<NetworkAddress>),
+    // Round trip time of a ping to the peer.
+    PeerPingTime(SocketAddr, Duration),
+    // Height of the best header from the peer that made it into our chain.
+    PeerBestHeight(SocketAddr, i32),
+    // Sent to a peer connection, which answers with what it knows about the peer.
+    RequestPeerInfo(Sender<PeerInfo>),
+    GetPeerInfo(Sender<Vec<PeerInfo>>),
+    // The minimum fee rate, in satoshis per 1000 bytes, of transactions we accept changed.
+    MinFeeUpdated(u64),
+    // Tells peers about a transaction (by txid and wtxid) paying the given fee rate.
+    AnnounceTransaction([u8; 32], [u8; 32], u64),
+    // Asks for the fee rate needed to confirm within some number of blocks, answered with the fee rate and the target it's actually for.
+    EstimateSmartFee(u32, EstimateMode, Sender<Option<(u64, u32)>>),
+    // One of our own transactions for the mempool, answered with whether it was accepted. Accepted ones get broadcast.
+    SubmitTransaction(Transaction, Sender<Result<(), MempoolError>>),
+    // One of our own transactions to send out and keep announcing until it confirms.
+    BroadcastTransaction(Transaction),
+    // Transactions the mempool dropped without them confirming, because of conflicts, eviction or expiry.
+    TransactionsRemoved(Vec<[u8; 32]>),
+    // Tells a peer about one of our own transactions (by txid and wtxid), even if it might have heard about it already.
+    AnnounceOwnTransaction([u8; 32], [u8; 32]),
+    // Sends a transaction a peer asked us for.
+    SendTransaction(Transaction),
+    // Transactions a peer asked us for that aren't our own, for the mempool to look up.
+    TransactionsRequested(SocketAddr, Vec<InventoryVector>),
+    // The mempool's answer to TransactionsRequested: what it has, and what it doesn't.
+    ServeTransactions(SocketAddr, Vec<Transaction>, Vec<InventoryVector>),
+    // Tells a peer we don't have what it asked for.
+    SendNotFound(Vec<InventoryVector>),
+    // Tells peers other than the given one about our new tip, with the headers leading to it.
+    AnnounceHeaders(SocketAddr, Vec<BlockHeader>),
+    // A peer did something it shouldn't have, adding the given score towards getting banned.
+    Misbehaving(SocketAddr, u32, String),
+    // Bans an IP for the given number of seconds, or for the default time when it's 0.
+    Ban(IpAddr, u64),
+    Unban(IpAddr),
+    // Replies with the banned IPs and when their bans end.
+    ListBanned(Sender<Vec<(IpAddr, u64)>>),
+    // Asks the peer manager to save what it needs for the next start and stop.
+    Shutdown,
+    ShutdownComplete,
 }
\ No newline at end of file
@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use toml;
+use util::write_file_atomically;
+
+// Fee rates are tracked in buckets, each one this much higher than the one before, from the minimum up to the maximum.
+const MIN_BUCKET_FEE_RATE: f64 = 1000.0;
+const MAX_BUCKET_FEE_RATE: f64 = 1e7;
+const FEE_SPACING: f64 = 1.1;
+// Confirmation times are tracked over three horizons. Old data points fade by the decay every block, and are counted in periods of `scale` blocks.
+const SHORT_DECAY: f64 = 0.962;
+const SHORT_SCALE: u32 = 1;
+const SHORT_PERIODS: u32 = 12;
+const MEDIUM_DECAY: f64 = 0.9952;
+const MEDIUM_SCALE: u32 = 2;
+const MEDIUM_PERIODS: u32 = 24;
+const LONG_DECAY: f64 = 0.99931;
+const LONG_SCALE: u32 = 24;
+const LONG_PERIODS: u32 = 42;
+// Share of transactions which must have confirmed within the target for a fee rate to pass, depending on how the target is checked.
+const HALF_SUCCESS_PCT: f64 = 0.6;
+const SUCCESS_PCT: f64 = 0.85;
+const DOUBLE_SUCCESS_PCT: f64 = 0.95;
+// A range of buckets needs this many data points per block, on average, to be trusted.
+const SUFFICIENT_FEE_TXS: f64 = 0.1;
+
+#[derive(Deserialize, Serialize)]
+struct SavedConfirmStats {
+    confirmed: Vec<Vec<f64>>,
+    failed: Vec<Vec<f64>>,
+    tx_count: Vec<f64>,
+    fee_sum: Vec<f64>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SavedFeeEstimator {
+    best_height: i32,
+    short: SavedConfirmStats,
+    medium: SavedConfirmStats,
+    long: SavedConfirmStats,
+}
+
+// How economical (more likely to take longer) or conservative (more likely to overpay) an estimate is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EstimateMode {
+    Economical,
+    Conservative,
+}
+
+// Upper bounds of the fee rate buckets. The last bucket takes everything above the maximum.
+fn bucket_bounds() -> Vec<f64> {
+    let mut bounds = vec![];
+    let mut bound = MIN_BUCKET_FEE_RATE;
+
+    while bound <= MAX_BUCKET_FEE_RATE {
+        bounds.push(bound);
+        bound *= FEE_SPACING;
+    }
+
+    bounds.push(f64::INFINITY);
+    bounds
+}
+
+// Confirmation times of the transactions in each bucket, over one horizon.
+struct ConfirmStats {
+    decay: f64,
+    scale: u32,
+    // Transactions which confirmed within each number of periods, and the ones which left the mempool unconfirmed after waiting at least that long.
+    confirmed: Vec<Vec<f64>>,
+    failed: Vec<Vec<f64>>,
+    // Transactions which confirmed at all, and the sum of their fee rates.
+    tx_count: Vec<f64>,
+    fee_sum: Vec<f64>,
+}
+
+impl ConfirmStats {
+    fn new(decay: f64, scale: u32, periods: u32, buckets: usize) -> ConfirmStats {
+        ConfirmStats {
+            decay,
+            scale,
+            confirmed: vec![vec![0.0; buckets]; periods as usize],
+            failed: vec![vec![0.0; buckets]; periods as usize],
+            tx_count: vec![0.0; buckets],
+            fee_sum: vec![0.0; buckets],
+        }
+    }
+
+    fn max_target(&self) -> u32 {
+        self.confirmed.len() as u32 * self.scale
+    }
+
+    fn decay(&mut self) {
+        let decay = self.decay;
+
+        for value in self.confirmed.iter_mut().chain(self.failed.iter_mut()).flat_map(|period| period.iter_mut()) {
+            *value *= decay;
+        }
+        for value in self.tx_count.iter_mut().chain(self.fee_sum.iter_mut()) {
+            *value *= decay;
+        }
+    }
+
+    fn record_confirmation(&mut self, blocks: u32, bucket: usize, fee_rate: u64) {
+        let scale = self.scale;
+
+        for (period, confirmed) in self.confirmed.iter_mut().enumerate() {
+            if blocks <= (period as u32 + 1) * scale {
+                confirmed[bucket] += 1.0;
+            }
+        }
+
+        self.tx_count[bucket] += 1.0;
+        self.fee_sum[bucket] += fee_rate as f64;
+    }
+
+    fn record_failure(&mut self, blocks: u32, bucket: usize) {
+        let scale = self.scale;
+
+        for (period, failed) in self.failed.iter_mut().enumerate() {
+            if blocks >= (period as u32 + 1) * scale {
+                failed[bucket] += 1.0;
+            }
+        }
+    }
+
+    // Lowest fee rate at which at least `success_pct` of the transactions confirmed within `target` blocks. `unconfirmed` has how many transactions of each bucket are still waiting after `target` blocks, which count as failures too.
+    fn estimate(&self, target: u32, success_pct: f64, unconfirmed: &[f64]) -> Option<u64> {
+        if target == 0 || target > self.max_target() {
+            return None;
+        }
+
+        let period = (target.div_ceil(self.scale) - 1) as usize;
+        let sufficient = SUFFICIENT_FEE_TXS / (1.0 - self.decay);
+        let (mut confirmed, mut total, mut failed, mut fee_sum) = (0.0, 0.0, 0.0, 0.0);
+        let mut passing = None;
+
+        // Starting from the highest fee rates, buckets are grouped until there's enough data to judge them, and we stop at the first group that confirmed too slowly.
+        for bucket in (0..self.tx_count.len()).rev() {
+            confirmed += self.confirmed[period][bucket];
+            total += self.tx_count[bucket];
+            failed += self.failed[period][bucket] + unconfirmed[bucket];
+            fee_sum += self.fee_sum[bucket];
+
+            if total < sufficient {
+                continue;
+            }
+
+            if confirmed / (total + failed) < success_pct {
+                break;
+            }
+
+            passing = Some((fee_sum / total) as u64);
+            confirmed = 0.0;
+            total = 0.0;
+            failed = 0.0;
+            fee_sum = 0.0;
+        }
+
+        passing
+    }
+
+    fn saved(&self) -> SavedConfirmStats {
+        SavedConfirmStats {
+            confirmed: self.confirmed.clone(),
+            failed: self.failed.clone(),
+            tx_count: self.tx_count.clone(),
+            fee_sum: self.fee_sum.clone(),
+        }
+    }
+
+    fn restore(&mut self, saved: SavedConfirmStats) -> Result<(), String> {
+        let buckets = self.tx_count.len();
+        let fits = |values: &Vec<Vec<f64>>| values.len() == self.confirmed.len() && values.iter().all(|period| period.len() == buckets);
+
+        if !fits(&saved.confirmed) || !fits(&saved.failed) || saved.tx_count.len() != buckets || saved.fee_sum.len() != buckets {
+            return Err(String::from("Saved fee estimates don't match the buckets in use"));
+        }
+
+        self.confirmed = saved.confirmed;
+        self.failed = saved.failed;
+        self.tx_count = saved.tx_count;
+        self.fee_sum = saved.fee_sum;
+        Ok(())
+    }
+}
+
+// Estimates the fee rate needed for a transaction to confirm within some number of blocks, based on how long mempool transactions took to confirm.
+pub struct FeeEstimator {
+    bounds: Vec<f64>,
+    best_height: i32,
+    short: ConfirmStats,
+    medium: ConfirmStats,
+    long: ConfirmStats,
+    // Mempool transactions we're waiting to see confirmed, with the height they arrived at and their bucket.
+    tracked: HashMap<[u8; 32], (i32, usize)>,
+}
+
+impl FeeEstimator {
+    pub fn new() -> FeeEstimator {
+        let bounds = bucket_bounds();
+        let buckets = bounds.len();
+
+        FeeEstimator {
+            bounds,
+            best_height: 0,
+            short: ConfirmStats::new(SHORT_DECAY, SHORT_SCALE, SHORT_PERIODS, buckets),
+            medium: ConfirmStats::new(MEDIUM_DECAY, MEDIUM_SCALE, MEDIUM_PERIODS, buckets),
+            long: ConfirmStats::new(LONG_DECAY, LONG_SCALE, LONG_PERIODS, buckets),
+            tracked: HashMap::new(),
+        }
+    }
+
+    pub fn max_target(&self) -> u32 {
+        self.long.max_target()
+    }
+
+    fn bucket(&self, fee_rate: u64) -> usize {
+        self.bounds.iter().position(|bound| fee_rate as f64 <= *bound).unwrap_or(self.bounds.len() - 1)
+    }
+
+    fn stats_mut(&mut self) -> [&mut ConfirmStats; 3] {
+        [&mut self.short, &mut self.medium, &mut self.long]
+    }
+
+    // Starts tracking a transaction which just entered the mempool, while our best height was `height`.
+    pub fn process_transaction(&mut self, txid: [u8; 32], fee_rate: u64, height: i32) {
+        // Transactions seen while catching up with the chain would look like they took longer to confirm than they did.
+        if height < self.best_height {
+            return;
+        }
+
+        let bucket = self.bucket(fee_rate);
+        self.tracked.insert(txid, (height, bucket));
+    }
+
+    // A transaction left the mempool without confirming, e.g. because it was replaced or evicted.
+    pub fn remove_transaction(&mut self, txid: &[u8; 32]) {
+        if let Some((height, bucket)) = self.tracked.remove(txid) {
+            let blocks = (self.best_height - height).max(0) as u32;
+
+            for stats in self.stats_mut().iter_mut() {
+                stats.record_failure(blocks, bucket);
+            }
+        }
+    }
+
+    // Records the confirmation times of the tracked transactions a new block at `height` confirmed, with their fee rates.
+    pub fn process_block(&mut self, height: i32, confirmed: &[([u8; 32], u64)]) {
+        // Blocks we've seen already, e.g. after a reorg, would count some transactions twice.
+        if height <= self.best_height {
+            return;
+        }
+
+        self.best_height = height;
+
+        for stats in self.stats_mut().iter_mut() {
+            stats.decay();
+        }
+
+        let mut count = 0;
+        for (txid, fee_rate) in confirmed {
+            let (entry_height, bucket) = match self.tracked.remove(txid) {
+                Some(tracked) => tracked,
+                None => continue,
+            };
+
+            let blocks = height - entry_height;
+            if blocks <= 0 {
+                continue;
+            }
+
+            count += 1;
+            for stats in self.stats_mut().iter_mut() {
+                stats.record_confirmation(blocks as u32, bucket, *fee_rate);
+            }
+        }
+
+        debug!("Fee estimator saw {} of the {} transactions confirmed at height {}", count, confirmed.len(), height);
+    }
+
+    // How many tracked transactions of each bucket have been waiting for at least `target` blocks.
+    fn unconfirmed(&self, target: u32) -> Vec<f64> {
+        let mut result = vec![0.0; self.bounds.len()];
+
+        for &(height, bucket) in self.tracked.values() {
+            if self.best_height - height >= target as i32 {
+                result[bucket] += 1.0;
+            }
+        }
+
+        result
+    }
+
+    // Estimate from the shortest horizon tracking `target`.
+    fn estimate_combined(&self, target: u32, success_pct: f64) -> Option<u64> {
+        let unconfirmed = self.unconfirmed(target);
+
+        [&self.short, &self.medium, &self.long].iter()
+            .find(|stats| target <= stats.max_target())
+            .and_then(|stats| stats.estimate(target, success_pct, &unconfirmed))
+    }
+
+    // Highest estimate with a higher success rate from the longer horizons, which react slower to fee rates going down.
+    fn estimate_conservative(&self, target: u32) -> Option<u64> {
+        let unconfirmed = self.unconfirmed(target);
+
+        [&self.medium, &self.long].iter()
+            .filter(|stats| target <= stats.max_target())
+            .filter_map(|stats| stats.estimate(target, DOUBLE_SUCCESS_PCT, &unconfirmed))
+            .max()
+    }
+
+    // Fee rate for confirming within `target` blocks, and the target the estimate is actually for, which can differ when the requested one is out of range.
+    pub fn estimate_smart_fee(&self, target: u32, mode: EstimateMode) -> Option<(u64, u32)> {
+        // Every transaction confirms in one block at best, so a target of one can't be told apart from a target of two.
+        let target = target.clamp(2, self.max_target());
+
+        // Confirming within half the target is checked too, but with a lower success rate.
+        let mut estimate = [self.estimate_combined(target / 2, HALF_SUCCESS_PCT), self.estimate_combined(target, SUCCESS_PCT)].iter().filter_map(|fee_rate| *fee_rate).max();
+
+        if mode == EstimateMode::Conservative || estimate.is_none() {
+            estimate = estimate.into_iter().chain(self.estimate_conservative(2 * target)).max();
+        }
+
+        estimate.map(|fee_rate| (fee_rate, target))
+    }
+
+    pub fn load(path: &Path) -> Result<FeeEstimator, String> {
+        let mut contents = String::new();
+        File::open(path).and_then(|mut file| file.read_to_string(&mut contents)).map_err(|e| e.to_string())?;
+
+        let saved: SavedFeeEstimator = toml::from_str(&contents).map_err(|e| e.to_string())?;
+        let mut result = FeeEstimator::new();
+
+        result.best_height = saved.best_height;
+        result.short.restore(saved.short)?;
+        result.medium.restore(saved.medium)?;
+        result.long.restore(saved.long)?;
+
+        Ok(result)
+    }
+
+    // Transactions we're still waiting on aren't saved, since they'll have to be seen again anyway.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let saved = SavedFeeEstimator {
+            best_height: self.best_height,
+            short: self.short.saved(),
+            medium: self.medium.saved(),
+            long: self.long.saved(),
+        };
+
+        let contents = toml::to_string(&saved).map_err(|e| e.to_string())?;
+        write_file_atomically(path, contents.as_bytes())
+    }
+}
+
+impl Default for FeeEstimator {
+    fn default() -> FeeEstimator {
+        FeeEstimator::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::save_then_load;
+
+    // Every block confirms a batch of expensive transactions right away, and a batch of cheap ones after `cheap_delay` blocks.
+    fn simulate(estimator: &mut FeeEstimator, blocks: i32, cheap_delay: i32) {
+        let mut id = 0u32;
+        let mut next_id = || {
+            id += 1;
+            let mut txid = [0u8; 32];
+            txid[..4].copy_from_slice(&id.to_le_bytes());
+            txid
+        };
+
+        let mut pending: Vec<(i32, [u8; 32], u64)> = vec![];
+        for height in 1..=blocks {
+            for _ in 0..5 {
+                let expensive = next_id();
+                let cheap = next_id();
+                estimator.process_transaction(expensive, 50_000, height - 1);
+                estimator.process_transaction(cheap, 2000, height - 1);
+                pending.push((height, expensive, 50_000));
+                pending.push((height - 1 + cheap_delay, cheap, 2000));
+            }
+
+            let confirmed = pending.iter().filter(|&&(at, _, _)| at == height).map(|&(_, txid, fee_rate)| (txid, fee_rate)).collect::<Vec<([u8; 32], u64)>>();
+            pending.retain(|&(at, _, _)| at != height);
+            estimator.process_block(height, &confirmed);
+        }
+    }
+
+    #[test]
+    fn estimates_follow_confirmation_times() {
+        let mut estimator = FeeEstimator::new();
+        assert_eq!(estimator.estimate_smart_fee(2, EstimateMode::Economical), None);
+
+        simulate(&mut estimator, 200, 10);
+
+        // Only the expensive transactions confirm quickly, but waiting long enough the cheap ones do too.
+        let (fast, target) = estimator.estimate_smart_fee(1, EstimateMode::Economical).unwrap();
+        assert_eq!(target, 2);
+        assert!(fast > 40_000);
+        let (slow, _) = estimator.estimate_smart_fee(24, EstimateMode::Economical).unwrap();
+        assert!(slow < 3000);
+
+        // Being conservative means checking twice the target with a higher success rate too.
+        let (conservative, _) = estimator.estimate_smart_fee(12, EstimateMode::Conservative).unwrap();
+        let (economical, _) = estimator.estimate_smart_fee(12, EstimateMode::Economical).unwrap();
+        assert!(conservative >= economical);
+
+        assert_eq!(estimator.estimate_smart_fee(5000, EstimateMode::Economical).unwrap().1, estimator.max_target());
+    }
+
+    #[test]
+    fn evicted_transactions_count_as_failures() {
+        let mut estimator = FeeEstimator::new();
+        simulate(&mut estimator, 100, 10);
+        let (before, _) = estimator.estimate_smart_fee(24, EstimateMode::Economical).unwrap();
+
+        // Lots of transactions paying slightly more than the cheap ones never made it.
+        let txids = (0..2000u32).map(|i| {
+            let mut txid = [0xFFu8; 32];
+            txid[..4].copy_from_slice(&i.to_le_bytes());
+            txid
+        }).collect::<Vec<[u8; 32]>>();
+
+        for txid in txids.iter() {
+            estimator.process_transaction(*txid, 3000, 100);
+        }
+        for height in 101..=130 {
+            estimator.process_block(height, &[]);
+        }
+        for txid in txids.iter() {
+            estimator.remove_transaction(txid);
+        }
+
+        let (after, _) = estimator.estimate_smart_fee(24, EstimateMode::Economical).unwrap();
+        assert!(after > before);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut estimator = FeeEstimator::new();
+        simulate(&mut estimator, 50, 3);
+
+        let loaded = save_then_load("fee_estimates", |path| estimator.save(path), FeeEstimator::load);
+
+        assert_eq!(loaded.best_height, 50);
+        for target in 2..10 {
+            assert_eq!(loaded.estimate_smart_fee(target, EstimateMode::Conservative), estimator.estimate_smart_fee(target, EstimateMode::Conservative));
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,290 @@
+use ::KalikoControlMessage;
+use mempool::{EstimateMode, FeeEstimator, Mempool, MempoolError};
+use network::block::Block;
+use network::inv::{InventoryType, InventoryVector};
+use network::transaction::Transaction;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// How often we look for expired transactions and changes in our minimum fee.
+const MAINTENANCE_INTERVAL: u64 = 60;
+// How often the fee estimator is written to disk.
+const FEE_ESTIMATES_SAVE_INTERVAL: u64 = 60 * 60;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+// Runs the mempool in its own thread, feeding it transactions from peers and blocks from storage.
+pub struct MempoolManager {
+    mempool: Mempool,
+    fee_estimator: FeeEstimator,
+    fee_estimates_location: PathBuf,
+    last_fee_estimates_save: Instant,
+    best_height: i32,
+    // Minimum fee rate we last told peers about.
+    announced_min_fee: u64,
+    incoming_control_sender: Sender<KalikoControlMessage>,
+    incoming_control_receiver: Receiver<KalikoControlMessage>,
+    outgoing_control_sender: Sender<KalikoControlMessage>,
+}
+
+impl MempoolManager {
+    pub fn new(mempool: Mempool, data_dir: &Path, outgoing_control_sender: Sender<KalikoControlMessage>) -> MempoolManager {
+        let (incoming_control_sender, incoming_control_receiver) = channel();
+        let announced_min_fee = mempool.min_fee(now());
+
+        let fee_estimates_location = data_dir.join("fee_estimates.toml");
+        let fee_estimator = match FeeEstimator::load(&fee_estimates_location) {
+            Ok(fee_estimator) => fee_estimator,
+            Err(e) => {
+                info!("Starting with no fee estimates: {}", e);
+                FeeEstimator::new()
+            },
+        };
+
+        MempoolManager {
+            mempool,
+            fee_estimator,
+            fee_estimates_location,
+            last_fee_estimates_save: Instant::now(),
+            best_height: 0,
+            announced_min_fee,
+            incoming_control_sender,
+            incoming_control_receiver,
+            outgoing_control_sender,
+        }
+    }
+
+    pub fn incoming_sender(&self) -> Sender<KalikoControlMessage> {
+        self.incoming_control_sender.clone()
+    }
+
+    // Returns the fee rate of the accepted transaction, keeping the fee estimator and broadcaster up to date with what was removed.
+    fn accept_transaction(&mut self, tx: Transaction) -> Result<u64, MempoolError> {
+        let txid = tx.txid();
+
+        let result = match self.mempool.accept(tx, self.best_height, now()) {
+            Ok(removed) => {
+                let fee_rate = self.mempool.get(&txid).unwrap().fee_rate();
+                self.fee_estimator.process_transaction(txid, fee_rate, self.best_height);
+                self.transactions_removed(removed);
+                Ok(fee_rate)
+            },
+            Err(MempoolError::MempoolFull(removed)) => {
+                self.transactions_removed(removed.clone());
+                Err(MempoolError::MempoolFull(removed))
+            },
+            Err(e) => Err(e),
+        };
+
+        self.announce_min_fee_if_changed();
+        result
+    }
+
+    fn transactions_removed(&mut self, txids: Vec<[u8; 32]>) {
+        if txids.is_empty() {
+            return;
+        }
+
+        for txid in txids.iter() {
+            self.fee_estimator.remove_transaction(txid);
+        }
+
+        self.outgoing_control_sender.send(KalikoControlMessage::TransactionsRemoved(txids)).unwrap();
+    }
+
+    fn add_transaction(&mut self, peer: SocketAddr, tx: Transaction) {
+        let txid = tx.txid();
+        let wtxid = tx.wtxid();
+
+        match self.accept_transaction(tx) {
+            Ok(fee_rate) => {
+                debug!("[{}] Accepted transaction {} with fee rate {}", peer, ::hex::encode(txid), fee_rate);
+                self.outgoing_control_sender.send(KalikoControlMessage::AnnounceTransaction(txid, wtxid, fee_rate)).unwrap();
+            },
+            // Not something peers can be blamed for, since we can't validate transactions fully.
+            Err(MempoolError::AlreadyKnown) => (),
+            Err(MempoolError::MempoolFull(removed)) => debug!("[{}] Transaction {} was evicted right away, along with {} others", peer, ::hex::encode(txid), removed.len()),
+            Err(e) => debug!("[{}] Rejected transaction {}: {:?}", peer, ::hex::encode(txid), e),
+        }
+    }
+
+    // Our own transactions are only broadcast once the mempool takes them, and then announced until they confirm instead of just once. Without a UTXO set, most of them spend outputs we don't know about, so those are broadcast unchecked.
+    fn submit_transaction(&mut self, tx: Transaction, reply: Sender<Result<(), MempoolError>>) {
+        let result = match self.accept_transaction(tx.clone()) {
+            Ok(_) | Err(MempoolError::AlreadyKnown) | Err(MempoolError::MissingInputs) => {
+                self.outgoing_control_sender.send(KalikoControlMessage::BroadcastTransaction(tx)).unwrap();
+                Ok(())
+            },
+            Err(e) => Err(e),
+        };
+
+        let _ = reply.send(result);
+    }
+
+    // Answers a peer asking for transactions, including the ones we don't have so it doesn't wait for them.
+    fn serve_transactions(&self, peer: SocketAddr, inventory: Vec<InventoryVector>) {
+        let mut found = vec![];
+        let mut missing = vec![];
+
+        for inv in inventory {
+            let entry = match inv.object_type() {
+                InventoryType::Wtx => self.mempool.get_by_wtxid(&inv.hash()),
+                _ => self.mempool.get(&inv.hash()),
+            };
+
+            match entry {
+                Some(entry) => found.push(entry.tx.clone()),
+                None => missing.push(inv),
+            }
+        }
+
+        self.outgoing_control_sender.send(KalikoControlMessage::ServeTransactions(peer, found, missing)).unwrap();
+    }
+
+    fn connect_block(&mut self, height: i32, block: Block) {
+        let (confirmed, conflicts) = self.mempool.remove_for_block(&block, height);
+        debug!("Block at height {} confirmed {} mempool transactions and conflicted with {}", height, confirmed.len(), conflicts.len());
+
+        let confirmed = confirmed.iter().map(|entry| (entry.txid, entry.fee_rate())).collect::<Vec<([u8; 32], u64)>>();
+        self.fee_estimator.process_block(height, &confirmed);
+        self.transactions_removed(conflicts);
+
+        self.best_height = self.best_height.max(height);
+    }
+
+    fn announce_min_fee_if_changed(&mut self) {
+        let min_fee = self.mempool.min_fee(now());
+
+        if min_fee != self.announced_min_fee {
+            self.announced_min_fee = min_fee;
+            self.outgoing_control_sender.send(KalikoControlMessage::MinFeeUpdated(min_fee)).unwrap();
+        }
+    }
+
+    fn maintain(&mut self) {
+        let expired = self.mempool.expire(now());
+        if !expired.is_empty() {
+            debug!("Expired {} mempool transactions", expired.len());
+        }
+        self.transactions_removed(expired);
+
+        self.announce_min_fee_if_changed();
+
+        if self.last_fee_estimates_save.elapsed() >= Duration::from_secs(FEE_ESTIMATES_SAVE_INTERVAL) {
+            self.save_fee_estimates();
+        }
+    }
+
+    fn save_fee_estimates(&mut self) {
+        self.last_fee_estimates_save = Instant::now();
+
+        if let Err(e) = self.fee_estimator.save(&self.fee_estimates_location) {
+            warn!("Couldn't save fee estimates: {}", e);
+        }
+    }
+
+    // The estimate is never below what the mempool would accept right now.
+    fn estimate_smart_fee(&self, target: u32, mode: EstimateMode) -> Option<(u64, u32)> {
+        let min_fee = self.mempool.min_fee(now());
+        self.fee_estimator.estimate_smart_fee(target, mode).map(|(fee_rate, target)| (fee_rate.max(min_fee), target))
+    }
+
+    pub fn start(mut self) {
+        thread::spawn(move || {
+            let mut next_maintenance = Instant::now() + Duration::from_secs(MAINTENANCE_INTERVAL);
+
+            loop {
+                match self.incoming_control_receiver.recv_timeout(Duration::from_secs(1)) {
+                    Ok(KalikoControlMessage::NewTransactionAvailable(peer, tx)) => self.add_transaction(peer, tx),
+                    Ok(KalikoControlMessage::SubmitTransaction(tx, reply)) => self.submit_transaction(tx, reply),
+                    Ok(KalikoControlMessage::TransactionsRequested(peer, inventory)) => self.serve_transactions(peer, inventory),
+                    Ok(KalikoControlMessage::BlockConnected(height, block)) => self.connect_block(height, block),
+                    Ok(KalikoControlMessage::ChainHeightUpdated(height)) => self.best_height = height,
+                    Ok(KalikoControlMessage::EstimateSmartFee(target, mode, reply)) => {
+                        let _ = reply.send(self.estimate_smart_fee(target, mode));
+                    },
+                    Ok(KalikoControlMessage::Shutdown) => {
+                        info!("Shutting down the mempool");
+                        self.save_fee_estimates();
+                        self.outgoing_control_sender.send(KalikoControlMessage::ShutdownComplete).unwrap();
+                        break;
+                    },
+                    Ok(_) | Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+
+                if Instant::now() >= next_maintenance {
+                    next_maintenance = Instant::now() + Duration::from_secs(MAINTENANCE_INTERVAL);
+                    self.maintain();
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use network::transaction::{OutPoint, TxIn, TxOut};
+    use util::temp_path;
+
+    fn manager() -> (MempoolManager, Receiver<KalikoControlMessage>) {
+        let (sender, receiver) = channel();
+        (MempoolManager::new(Mempool::new(), &temp_path("mempool"), sender), receiver)
+    }
+
+    #[test]
+    fn own_transactions_spending_unknown_outputs_are_broadcast() {
+        let (mut manager, outgoing) = manager();
+        let input = TxIn::new(OutPoint::new([1; 32], 0), vec![], 0xFFFFFFFF);
+        let tx = Transaction::new(2, vec![input], vec![TxOut::new(1000, vec![0x51])], 0);
+
+        let (reply_sender, reply) = channel();
+        manager.submit_transaction(tx.clone(), reply_sender);
+
+        assert_eq!(reply.recv().unwrap(), Ok(()));
+        match outgoing.try_recv() {
+            Ok(KalikoControlMessage::BroadcastTransaction(broadcast)) => assert_eq!(broadcast, tx),
+            other => panic!("Unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn requested_transactions_are_served_or_not_found() {
+        let (mut manager, outgoing) = manager();
+        let outpoint = OutPoint::new([1; 32], 0);
+        manager.mempool.add_coin(outpoint, TxOut::new(100_000, vec![0x51]));
+        let tx = Transaction::new(2, vec![TxIn::new(outpoint, vec![0x51], 0xFFFFFFFF)], vec![TxOut::new(90_000, vec![0x51])], 0);
+        manager.mempool.accept(tx.clone(), 1, 0).unwrap();
+
+        let peer = "1.2.3.4:8333".parse().unwrap();
+        let unknown = InventoryVector::new(InventoryType::Wtx, [2; 32]);
+        manager.serve_transactions(peer, vec![InventoryVector::new(InventoryType::Tx, tx.txid()), InventoryVector::new(InventoryType::Wtx, tx.wtxid()), unknown]);
+
+        match outgoing.try_recv() {
+            Ok(KalikoControlMessage::ServeTransactions(to, found, missing)) => {
+                assert_eq!(to, peer);
+                assert_eq!(found, vec![tx.clone(), tx]);
+                assert_eq!(missing.iter().map(|inv| inv.hash()).collect::<Vec<[u8; 32]>>(), vec![[2; 32]]);
+            },
+            other => panic!("Unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_own_transactions_are_rejected() {
+        let (mut manager, outgoing) = manager();
+        let tx = Transaction::new(2, vec![], vec![TxOut::new(1000, vec![0x51])], 0);
+
+        let (reply_sender, reply) = channel();
+        manager.submit_transaction(tx, reply_sender);
+
+        assert_eq!(reply.recv().unwrap(), Err(MempoolError::Invalid));
+        assert!(outgoing.try_recv().is_err());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,577 @@
+use network::block::Block;
+use network::encode::serialize;
+use network::transaction::{money_range, OutPoint, Transaction, TxOut, SEQUENCE_LOCKTIME_DISABLE_FLAG, SEQUENCE_LOCKTIME_MASK};
+use peer::fee_filter::DEFAULT_MIN_RELAY_FEE;
+use script::{verify_script, ScriptError, SignatureChecker, VERIFY_CHECKLOCKTIMEVERIFY, VERIFY_CHECKSEQUENCEVERIFY, VERIFY_DERSIG, VERIFY_DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM, VERIFY_MINIMALDATA, VERIFY_NULLDUMMY, VERIFY_P2SH, VERIFY_WITNESS};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+
+pub mod fee_estimator;
+pub mod mempool_manager;
+
+#[cfg(test)]
+mod tests;
+
+pub use self::fee_estimator::{EstimateMode, FeeEstimator};
+pub use self::mempool_manager::MempoolManager;
+
+// Fee rates are in satoshis per 1000 virtual bytes, like fee filters.
+pub const DEFAULT_MAX_MEMPOOL_SIZE: usize = 300 * 1000 * 1000;
+// Transactions still unconfirmed after this many seconds are dropped.
+pub const MEMPOOL_EXPIRY: u64 = 14 * 24 * 60 * 60;
+// Replacing transactions, and raising the minimum fee after evicting transactions, costs at least this much on top.
+pub const INCREMENTAL_RELAY_FEE: u64 = 1000;
+// Limits of a transaction's in-mempool package, counting the transaction itself.
+const MAX_ANCESTORS: usize = 25;
+const MAX_DESCENDANTS: usize = 25;
+const MAX_PACKAGE_SIZE: usize = 101_000;
+// BIP125: at most this many transactions can be replaced at once.
+const MAX_REPLACED_TRANSACTIONS: usize = 100;
+// Inputs with a lower sequence signal that their transaction may be replaced (BIP125).
+const MAX_REPLACEABLE_SEQUENCE: u32 = 0xFFFFFFFD;
+// Outputs of blocks are only kept for this many blocks (about a day), so they don't add up to the whole UTXO set.
+pub const COIN_CACHE_BLOCKS: i32 = 144;
+// The minimum fee raised by evictions halves this often.
+const ROLLING_MIN_FEE_HALF_LIFE: u64 = 12 * 60 * 60;
+// Inputs are checked with the block rules and some of the policy rules on top. Taproot isn't checked, so spending any witness version but 0 is rejected instead of relayed unchecked.
+const STANDARD_SCRIPT_VERIFY_FLAGS: u32 = VERIFY_P2SH | VERIFY_DERSIG | VERIFY_NULLDUMMY | VERIFY_CHECKLOCKTIMEVERIFY | VERIFY_CHECKSEQUENCEVERIFY | VERIFY_WITNESS | VERIFY_MINIMALDATA | VERIFY_DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM;
+// We don't know the median time past of our chain, which is usually about an hour behind the clock, so time locks are checked against a time safely behind it.
+const MEDIAN_TIME_PAST_LAG: u64 = 2 * 60 * 60;
+
+#[derive(Debug, PartialEq)]
+pub enum MempoolError {
+    AlreadyKnown,
+    Coinbase,
+    // The transaction's lock time hasn't passed yet, or it has relative lock times we can't check.
+    NonFinal,
+    // The transaction has no inputs or outputs, or spends the same output twice.
+    Invalid,
+    // An output, or the sum of the inputs or the outputs, is negative or more than MAX_MONEY.
+    ValueOutOfRange,
+    // The input at the given index doesn't satisfy the script of the output it spends.
+    InvalidScript(usize, ScriptError),
+    // Spends outputs we know nothing about. Without a UTXO set, we only know about outputs of mempool transactions and of blocks we downloaded.
+    MissingInputs,
+    OutputsExceedInputs,
+    // The transaction's fee rate, and the minimum one we accept.
+    FeeTooLow(u64, u64),
+    TooManyAncestors,
+    TooManyDescendants,
+    // Spends the same outputs as a mempool transaction it can't replace.
+    Conflict(String),
+    // Accepted, but evicted right away since the mempool is full. The transactions it replaced, and the ones evicted along with it, are gone anyway, so their txids are still returned.
+    MempoolFull(Vec<[u8; 32]>),
+}
+
+pub fn fee_rate(fee: u64, vsize: usize) -> u64 {
+    fee * 1000 / vsize.max(1) as u64
+}
+
+// BIP141 virtual size, which is the weight divided by 4, rounded up.
+pub fn virtual_size(tx: &Transaction) -> usize {
+    let mut base = vec![];
+    tx.encode_with_witness(&mut base, false).expect("Writing to a Vec never fails");
+    let total = serialize(tx).len();
+
+    (base.len() * 3 + total).div_ceil(4)
+}
+
+// Adds up output values, making sure each of them and the total stay within MAX_MONEY.
+fn sum_values<'a, I: Iterator<Item = &'a TxOut>>(outputs: I) -> Result<i64, MempoolError> {
+    let mut total = 0i64;
+
+    for output in outputs {
+        if !money_range(output.value) {
+            return Err(MempoolError::ValueOutOfRange);
+        }
+
+        total = total.checked_add(output.value).filter(|total| money_range(*total)).ok_or(MempoolError::ValueOutOfRange)?;
+    }
+
+    Ok(total)
+}
+
+// BIP68 relative lock times depend on when the spent outputs confirmed, which we don't know, so only the ones that are always satisfied are allowed.
+fn has_sequence_locks(tx: &Transaction) -> bool {
+    tx.version >= 2 && tx.inputs.iter().any(|input| input.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG == 0 && input.sequence & SEQUENCE_LOCKTIME_MASK != 0)
+}
+
+fn signals_replaceability(tx: &Transaction) -> bool {
+    tx.inputs.iter().any(|input| input.sequence <= MAX_REPLACEABLE_SEQUENCE)
+}
+
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub txid: [u8; 32],
+    pub wtxid: [u8; 32],
+    // In satoshis.
+    pub fee: u64,
+    pub vsize: usize,
+    // When the transaction entered the mempool, and the height of our chain at that time.
+    pub time: u64,
+    pub height: i32,
+    // Mempool transactions this one spends from, and the ones spending from it.
+    parents: HashSet<[u8; 32]>,
+    children: HashSet<[u8; 32]>,
+}
+
+impl MempoolEntry {
+    pub fn fee_rate(&self) -> u64 {
+        fee_rate(self.fee, self.vsize)
+    }
+}
+
+// Unconfirmed transactions we know about, along with how they depend on each other.
+pub struct Mempool {
+    entries: HashMap<[u8; 32], MempoolEntry>,
+    // Txid of each entry by its wtxid, for peers asking for transactions by wtxid.
+    wtxids: HashMap<[u8; 32], [u8; 32]>,
+    // Mempool transaction spending each outpoint.
+    spenders: HashMap<OutPoint, [u8; 32]>,
+    // Confirmed outputs we know about, which transactions can spend.
+    coins: HashMap<OutPoint, TxOut>,
+    // Outputs in `coins` by the height of the block creating them, so old ones can be dropped.
+    coin_heights: BTreeMap<i32, Vec<OutPoint>>,
+    total_size: usize,
+    max_size: usize,
+    min_relay_fee: u64,
+    // Raised when transactions get evicted, so the ones replacing them have to pay more.
+    rolling_min_fee: u64,
+    rolling_min_fee_updated: u64,
+}
+
+impl Mempool {
+    pub fn new() -> Mempool {
+        Mempool {
+            entries: HashMap::new(),
+            wtxids: HashMap::new(),
+            spenders: HashMap::new(),
+            coins: HashMap::new(),
+            coin_heights: BTreeMap::new(),
+            total_size: 0,
+            max_size: DEFAULT_MAX_MEMPOOL_SIZE,
+            min_relay_fee: DEFAULT_MIN_RELAY_FEE,
+            rolling_min_fee: 0,
+            rolling_min_fee_updated: 0,
+        }
+    }
+
+    // In virtual bytes.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    pub fn set_min_relay_fee(&mut self, min_relay_fee: u64) {
+        self.min_relay_fee = min_relay_fee;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Virtual size of all transactions.
+    pub fn size(&self) -> usize {
+        self.total_size
+    }
+
+    pub fn contains(&self, txid: &[u8; 32]) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &[u8; 32]) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    pub fn get_by_wtxid(&self, wtxid: &[u8; 32]) -> Option<&MempoolEntry> {
+        self.wtxids.get(wtxid).and_then(|txid| self.entries.get(txid))
+    }
+
+    pub fn add_coin(&mut self, outpoint: OutPoint, output: TxOut) {
+        self.coins.insert(outpoint, output);
+    }
+
+    // The minimum fee rate of transactions we accept.
+    pub fn min_fee(&self, now: u64) -> u64 {
+        let half_lives = now.saturating_sub(self.rolling_min_fee_updated) as f64 / ROLLING_MIN_FEE_HALF_LIFE as f64;
+        let rolling_min_fee = self.rolling_min_fee as f64 / 2f64.powf(half_lives);
+
+        // Once low enough, it stops mattering.
+        if rolling_min_fee < (INCREMENTAL_RELAY_FEE / 2) as f64 {
+            return self.min_relay_fee;
+        }
+
+        self.min_relay_fee.max(rolling_min_fee as u64)
+    }
+
+    fn traverse<F: Fn(&MempoolEntry) -> &HashSet<[u8; 32]>>(&self, txid: &[u8; 32], next: F) -> HashSet<[u8; 32]> {
+        let mut result = HashSet::new();
+        let mut queue = self.entries.get(txid).map_or(VecDeque::new(), |entry| next(entry).iter().cloned().collect());
+
+        while let Some(current) = queue.pop_front() {
+            if !result.insert(current) {
+                continue;
+            }
+
+            if let Some(entry) = self.entries.get(&current) {
+                queue.extend(next(entry).iter().cloned());
+            }
+        }
+
+        result
+    }
+
+    pub fn ancestors(&self, txid: &[u8; 32]) -> HashSet<[u8; 32]> {
+        self.traverse(txid, |entry| &entry.parents)
+    }
+
+    pub fn descendants(&self, txid: &[u8; 32]) -> HashSet<[u8; 32]> {
+        self.traverse(txid, |entry| &entry.children)
+    }
+
+    // Fee and size of the transaction along with its ancestors, which is what a miner gets for including it.
+    fn ancestor_fee_rate(&self, txid: &[u8; 32]) -> u64 {
+        self.package_fee_rate(txid, self.ancestors(txid))
+    }
+
+    // Fee rate of the transaction along with its descendants, which is what we lose by evicting it.
+    fn descendant_fee_rate(&self, txid: &[u8; 32]) -> u64 {
+        self.package_fee_rate(txid, self.descendants(txid))
+    }
+
+    fn package_fee_rate(&self, txid: &[u8; 32], others: HashSet<[u8; 32]>) -> u64 {
+        let (fee, size) = others.iter().chain(Some(txid)).filter_map(|id| self.entries.get(id)).fold((0, 0), |(fee, size), entry| (fee + entry.fee, size + entry.vsize));
+        fee_rate(fee, size)
+    }
+
+    // Transactions from the most to the least profitable to mine, by ancestor fee rate.
+    pub fn entries_by_fee_rate(&self) -> Vec<&MempoolEntry> {
+        let mut result = self.entries.values().map(|entry| (self.ancestor_fee_rate(&entry.txid), entry)).collect::<Vec<(u64, &MempoolEntry)>>();
+        result.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.txid.cmp(&b.1.txid)));
+        result.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+    fn output(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        match self.entries.get(&outpoint.txid) {
+            Some(entry) => entry.tx.outputs.get(outpoint.vout as usize).cloned(),
+            None => self.coins.get(outpoint).cloned(),
+        }
+    }
+
+    // Adds `tx` to the mempool, returning the txids of the transactions it replaced or that got evicted to make room for it.
+    pub fn accept(&mut self, tx: Transaction, height: i32, now: u64) -> Result<Vec<[u8; 32]>, MempoolError> {
+        if tx.is_coinbase() {
+            return Err(MempoolError::Coinbase);
+        }
+
+        let txid = tx.txid();
+        if self.entries.contains_key(&txid) {
+            return Err(MempoolError::AlreadyKnown);
+        }
+
+        // It has to be valid in the next block.
+        if !tx.is_final((height + 1) as u32, now.saturating_sub(MEDIAN_TIME_PAST_LAG)) || has_sequence_locks(&tx) {
+            return Err(MempoolError::NonFinal);
+        }
+
+        let outpoints = tx.inputs.iter().map(|input| input.previous_output).collect::<HashSet<OutPoint>>();
+        if tx.inputs.is_empty() || tx.outputs.is_empty() || outpoints.len() != tx.inputs.len() {
+            return Err(MempoolError::Invalid);
+        }
+
+        let conflicts = outpoints.iter().filter_map(|outpoint| self.spenders.get(outpoint)).cloned().collect::<HashSet<[u8; 32]>>();
+
+        // In the same order as the inputs, since their scripts are checked against them.
+        let mut spent_outputs = vec![];
+        for input in tx.inputs.iter() {
+            match self.output(&input.previous_output) {
+                Some(output) => spent_outputs.push(output),
+                None => return Err(MempoolError::MissingInputs),
+            }
+        }
+
+        let input_value = sum_values(spent_outputs.iter())?;
+        let output_value = sum_values(tx.outputs.iter())?;
+        if output_value > input_value {
+            return Err(MempoolError::OutputsExceedInputs);
+        }
+
+        let fee = (input_value - output_value) as u64;
+        let vsize = virtual_size(&tx);
+        let min_fee = self.min_fee(now);
+        if fee_rate(fee, vsize) < min_fee {
+            return Err(MempoolError::FeeTooLow(fee_rate(fee, vsize), min_fee));
+        }
+
+        let parents = outpoints.iter().map(|outpoint| outpoint.txid).filter(|parent| self.entries.contains_key(parent)).collect::<HashSet<[u8; 32]>>();
+        let mut ancestors = parents.clone();
+        for parent in parents.iter() {
+            ancestors.extend(self.ancestors(parent));
+        }
+
+        let replaced = self.check_replacement(fee, vsize, &parents, &ancestors, &conflicts)?;
+
+        let ancestors_size = ancestors.iter().map(|ancestor| self.entries[ancestor].vsize).sum::<usize>();
+        if ancestors.len() + 1 > MAX_ANCESTORS || ancestors_size + vsize > MAX_PACKAGE_SIZE {
+            return Err(MempoolError::TooManyAncestors);
+        }
+
+        // Replaced transactions are on their way out, so they don't count.
+        if ancestors.iter().any(|ancestor| self.descendants(ancestor).difference(&replaced).count() + 2 > MAX_DESCENDANTS) {
+            return Err(MempoolError::TooManyDescendants);
+        }
+
+        // The most expensive check goes last.
+        for (index, (input, spent_output)) in tx.inputs.iter().zip(spent_outputs.iter()).enumerate() {
+            let checker = SignatureChecker::new(&tx, index, spent_output.value);
+            verify_script(&input.script_sig, &spent_output.script_pubkey, &input.witness, STANDARD_SCRIPT_VERIFY_FLAGS, &checker)
+                .map_err(|e| MempoolError::InvalidScript(index, e))?;
+        }
+
+        for id in replaced.iter() {
+            self.remove(id);
+        }
+
+        for parent in parents.iter() {
+            self.entries.get_mut(parent).unwrap().children.insert(txid);
+        }
+        for outpoint in outpoints {
+            self.spenders.insert(outpoint, txid);
+        }
+
+        let wtxid = tx.wtxid();
+        self.total_size += vsize;
+        self.wtxids.insert(wtxid, txid);
+        self.entries.insert(txid, MempoolEntry {
+            wtxid,
+            tx,
+            txid,
+            fee,
+            vsize,
+            time: now,
+            height,
+            parents,
+            children: HashSet::new(),
+        });
+
+        let mut removed = replaced.into_iter().collect::<Vec<[u8; 32]>>();
+        removed.extend(self.trim(now));
+
+        if !self.entries.contains_key(&txid) {
+            removed.retain(|id| *id != txid);
+            return Err(MempoolError::MempoolFull(removed));
+        }
+
+        Ok(removed)
+    }
+
+    // Checks whether a transaction paying `fee` can replace the transactions it conflicts with under the BIP125 rules, and returns every transaction it would replace.
+    fn check_replacement(&self, fee: u64, vsize: usize, parents: &HashSet<[u8; 32]>, ancestors: &HashSet<[u8; 32]>, conflicts: &HashSet<[u8; 32]>) -> Result<HashSet<[u8; 32]>, MempoolError> {
+        let mut replaced = conflicts.clone();
+        if conflicts.is_empty() {
+            return Ok(replaced);
+        }
+
+        let new_fee_rate = fee_rate(fee, vsize);
+        let mut conflict_parents = HashSet::new();
+
+        for conflict in conflicts.iter() {
+            let entry = &self.entries[conflict];
+
+            // Rule 1: the replaced transactions, or one of their ancestors, signal that they may be replaced.
+            let replaceable = signals_replaceability(&entry.tx) || self.ancestors(conflict).iter().any(|ancestor| signals_replaceability(&self.entries[ancestor].tx));
+            if !replaceable {
+                return Err(MempoolError::Conflict("not replaceable".to_string()));
+            }
+
+            // Otherwise, miners would lose fees by mining the replacement instead.
+            if new_fee_rate <= entry.fee_rate() {
+                return Err(MempoolError::Conflict("fee rate not higher than the replaced transaction's".to_string()));
+            }
+
+            conflict_parents.extend(entry.parents.iter().cloned());
+            replaced.extend(self.descendants(conflict));
+        }
+
+        // Rule 5.
+        if replaced.len() > MAX_REPLACED_TRANSACTIONS {
+            return Err(MempoolError::Conflict("replaces too many transactions".to_string()));
+        }
+
+        if !ancestors.is_disjoint(&replaced) {
+            return Err(MempoolError::Conflict("spends a transaction it replaces".to_string()));
+        }
+
+        // Rule 2: no unconfirmed inputs other than the ones the replaced transactions had.
+        if !parents.is_subset(&conflict_parents) {
+            return Err(MempoolError::Conflict("adds unconfirmed inputs".to_string()));
+        }
+
+        // Rules 3 and 4: paying for everything replaced, and for its own relay.
+        let replaced_fees = replaced.iter().map(|id| self.entries[id].fee).sum::<u64>();
+        if fee < replaced_fees {
+            return Err(MempoolError::Conflict("pays less fees than the transactions it replaces".to_string()));
+        }
+
+        if fee - replaced_fees < INCREMENTAL_RELAY_FEE * vsize as u64 / 1000 {
+            return Err(MempoolError::Conflict("doesn't pay enough for its own relay".to_string()));
+        }
+
+        Ok(replaced)
+    }
+
+    // Removes a single transaction, leaving its descendants without a parent. Callers deal with descendants first when they have to go too.
+    fn remove_entry(&mut self, txid: &[u8; 32]) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(txid)?;
+        self.total_size -= entry.vsize;
+        self.wtxids.remove(&entry.wtxid);
+
+        for input in entry.tx.inputs.iter() {
+            if self.spenders.get(&input.previous_output) == Some(txid) {
+                self.spenders.remove(&input.previous_output);
+            }
+        }
+        for parent in entry.parents.iter() {
+            if let Some(parent) = self.entries.get_mut(parent) {
+                parent.children.remove(txid);
+            }
+        }
+        for child in entry.children.iter() {
+            if let Some(child) = self.entries.get_mut(child) {
+                child.parents.remove(txid);
+            }
+        }
+
+        Some(entry)
+    }
+
+    // Removes a transaction along with its descendants, returning the txids of everything removed.
+    pub fn remove(&mut self, txid: &[u8; 32]) -> Vec<[u8; 32]> {
+        if !self.entries.contains_key(txid) {
+            return vec![];
+        }
+
+        let mut removed = self.descendants(txid).into_iter().collect::<Vec<[u8; 32]>>();
+        removed.push(*txid);
+
+        for id in removed.iter() {
+            self.remove_entry(id);
+        }
+
+        removed
+    }
+
+    // Evicts the transactions (with their descendants) with the lowest fee rate until the mempool fits its size limit.
+    fn trim(&mut self, now: u64) -> Vec<[u8; 32]> {
+        let mut removed = vec![];
+        if self.total_size <= self.max_size {
+            return removed;
+        }
+
+        // Fee rates are computed once, and then only updated for the ancestors of evicted transactions, which are the only ones changing.
+        let mut fee_rates = self.entries.keys().map(|txid| (*txid, self.descendant_fee_rate(txid))).collect::<HashMap<[u8; 32], u64>>();
+        let mut by_fee_rate = fee_rates.iter().map(|(txid, fee_rate)| (*fee_rate, *txid)).collect::<BTreeSet<(u64, [u8; 32])>>();
+
+        while self.total_size > self.max_size {
+            let (worst_fee_rate, worst) = match by_fee_rate.iter().next() {
+                Some(&worst) => worst,
+                None => break,
+            };
+
+            // Transactions paying less than what got evicted aren't accepted anymore.
+            let new_min_fee = worst_fee_rate + INCREMENTAL_RELAY_FEE;
+            if new_min_fee > self.min_fee(now) {
+                self.rolling_min_fee = new_min_fee;
+                self.rolling_min_fee_updated = now;
+            }
+
+            debug!("Mempool full, evicting transactions with fee rate {}", worst_fee_rate);
+            let ancestors = self.ancestors(&worst);
+            for txid in self.remove(&worst) {
+                if let Some(fee_rate) = fee_rates.remove(&txid) {
+                    by_fee_rate.remove(&(fee_rate, txid));
+                }
+                removed.push(txid);
+            }
+
+            for ancestor in ancestors {
+                let fee_rate = self.descendant_fee_rate(&ancestor);
+                if let Some(old_fee_rate) = fee_rates.insert(ancestor, fee_rate) {
+                    by_fee_rate.remove(&(old_fee_rate, ancestor));
+                }
+                by_fee_rate.insert((fee_rate, ancestor));
+            }
+        }
+
+        removed
+    }
+
+    // Drops transactions that stayed unconfirmed for too long, returning the txids of everything removed.
+    pub fn expire(&mut self, now: u64) -> Vec<[u8; 32]> {
+        let expired = self.entries.values().filter(|entry| entry.time + MEMPOOL_EXPIRY < now).map(|entry| entry.txid).collect::<Vec<[u8; 32]>>();
+        let mut removed = vec![];
+
+        for txid in expired {
+            removed.extend(self.remove(&txid));
+        }
+
+        removed
+    }
+
+    // Removes the transactions confirmed by `block` and the ones conflicting with them, and learns about the outputs it creates. Returns the confirmed entries and the txids of the conflicting transactions.
+    pub fn remove_for_block(&mut self, block: &Block, height: i32) -> (Vec<MempoolEntry>, Vec<[u8; 32]>) {
+        let mut confirmed = vec![];
+        let mut conflicts = vec![];
+        let mut created = vec![];
+
+        for tx in block.transactions.iter() {
+            let txid = tx.txid();
+
+            // Parents come before their children in a block, so a confirmed transaction never has mempool parents left.
+            if let Some(entry) = self.remove_entry(&txid) {
+                confirmed.push(entry);
+            }
+
+            for input in tx.inputs.iter() {
+                if let Some(conflict) = self.spenders.get(&input.previous_output).cloned() {
+                    conflicts.extend(self.remove(&conflict));
+                }
+
+                self.coins.remove(&input.previous_output);
+            }
+
+            // Coinbase outputs can't be spent for a while, so they aren't worth keeping.
+            if tx.is_coinbase() {
+                continue;
+            }
+
+            for (vout, output) in tx.outputs.iter().enumerate() {
+                let outpoint = OutPoint::new(txid, vout as u32);
+                self.coins.insert(outpoint, output.clone());
+                created.push(outpoint);
+            }
+        }
+
+        self.coin_heights.entry(height).or_default().extend(created);
+        self.forget_coins(height - COIN_CACHE_BLOCKS);
+
+        (confirmed, conflicts)
+    }
+
+    // Drops the outputs created at `height` and below.
+    fn forget_coins(&mut self, height: i32) {
+        while let Some(oldest) = self.coin_heights.keys().next().cloned().filter(|oldest| *oldest <= height) {
+            for outpoint in self.coin_heights.remove(&oldest).unwrap() {
+                self.coins.remove(&outpoint);
+            }
+        }
+    }
+}
+
+impl Default for Mempool {
+    fn default() -> Mempool {
+        Mempool::new()
+    }
+}
\ No newline at end of file
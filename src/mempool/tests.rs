@@ -0,0 +1,271 @@
+use network::transaction::{OutPoint, Transaction, TxIn, TxOut, MAX_MONEY};
+use rand::thread_rng;
+use script::{legacy_sighash, push_data, SIGHASH_ALL};
+use script::opcodes::OP_CHECKSIG;
+use secp256k1::{Message, Secp256k1};
+use super::*;
+
+const FINAL_SEQUENCE: u32 = 0xFFFFFFFF;
+
+fn coin(mempool: &mut Mempool, id: u8, value: i64) -> OutPoint {
+    let outpoint = OutPoint::new([id; 32], 0);
+    mempool.add_coin(outpoint, TxOut::new(value, vec![0x51]));
+    outpoint
+}
+
+fn spend(outpoints: &[OutPoint], sequence: u32, output_values: &[i64]) -> Transaction {
+    let inputs = outpoints.iter().map(|outpoint| TxIn::new(*outpoint, vec![0x51], sequence)).collect();
+    let outputs = output_values.iter().map(|value| TxOut::new(*value, vec![0x51])).collect();
+    Transaction::new(2, inputs, outputs, 0)
+}
+
+#[test]
+fn fees_and_missing_inputs() {
+    let mut mempool = Mempool::new();
+    let outpoint = coin(&mut mempool, 1, 100_000);
+
+    let tx = spend(&[outpoint], FINAL_SEQUENCE, &[90_000]);
+    let txid = tx.txid();
+    assert_eq!(mempool.accept(tx.clone(), 1, 0), Ok(vec![]));
+    assert_eq!(mempool.accept(tx, 1, 0), Err(MempoolError::AlreadyKnown));
+    assert_eq!(mempool.get(&txid).unwrap().fee, 10_000);
+
+    assert_eq!(mempool.accept(spend(&[OutPoint::new([2; 32], 0)], FINAL_SEQUENCE, &[1]), 1, 0), Err(MempoolError::MissingInputs));
+
+    let outpoint = coin(&mut mempool, 3, 1000);
+    assert_eq!(mempool.accept(spend(&[outpoint], FINAL_SEQUENCE, &[2000]), 1, 0), Err(MempoolError::OutputsExceedInputs));
+    match mempool.accept(spend(&[outpoint], FINAL_SEQUENCE, &[999]), 1, 0) {
+        Err(MempoolError::FeeTooLow(_, DEFAULT_MIN_RELAY_FEE)) => (),
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn values_out_of_range() {
+    let mut mempool = Mempool::new();
+    let outpoint = coin(&mut mempool, 1, MAX_MONEY);
+
+    assert_eq!(mempool.accept(spend(&[outpoint], FINAL_SEQUENCE, &[-1]), 1, 0), Err(MempoolError::ValueOutOfRange));
+    assert_eq!(mempool.accept(spend(&[outpoint], FINAL_SEQUENCE, &[MAX_MONEY + 1]), 1, 0), Err(MempoolError::ValueOutOfRange));
+    assert_eq!(mempool.accept(spend(&[outpoint], FINAL_SEQUENCE, &[MAX_MONEY, 1]), 1, 0), Err(MempoolError::ValueOutOfRange));
+    // Would overflow without the checks.
+    assert_eq!(mempool.accept(spend(&[outpoint], FINAL_SEQUENCE, &[i64::MAX, i64::MAX]), 1, 0), Err(MempoolError::ValueOutOfRange));
+
+    let other = coin(&mut mempool, 2, MAX_MONEY);
+    assert_eq!(mempool.accept(spend(&[outpoint, other], FINAL_SEQUENCE, &[1]), 1, 0), Err(MempoolError::ValueOutOfRange));
+}
+
+#[test]
+fn input_scripts_are_verified() {
+    let secp = Secp256k1::new();
+    let (secret_key, public_key) = secp.generate_keypair(&mut thread_rng()).unwrap();
+
+    // Pay to public key.
+    let mut script_pubkey = vec![];
+    push_data(&mut script_pubkey, &public_key.serialize());
+    script_pubkey.push(OP_CHECKSIG);
+
+    let mut mempool = Mempool::new();
+    let outpoint = OutPoint::new([1; 32], 0);
+    mempool.add_coin(outpoint, TxOut::new(100_000, script_pubkey.clone()));
+
+    let sign = |tx: &Transaction| {
+        let hash = legacy_sighash(tx, 0, &script_pubkey, SIGHASH_ALL);
+        let mut signature = secp.sign(&Message::from_slice(&hash).unwrap(), &secret_key).unwrap().serialize_der(&secp);
+        signature.push(SIGHASH_ALL as u8);
+
+        let mut script_sig = vec![];
+        push_data(&mut script_sig, &signature);
+        script_sig
+    };
+
+    // A signature for a different transaction.
+    let mut tx = spend(&[outpoint], FINAL_SEQUENCE, &[90_000]);
+    tx.inputs[0].script_sig = sign(&spend(&[outpoint], FINAL_SEQUENCE, &[80_000]));
+    assert_eq!(mempool.accept(tx.clone(), 1, 0), Err(MempoolError::InvalidScript(0, ScriptError::EvalFalse)));
+
+    tx.inputs[0].script_sig = sign(&tx);
+    assert_eq!(mempool.accept(tx, 1, 0), Ok(vec![]));
+}
+
+#[test]
+fn non_final_transactions() {
+    let mut mempool = Mempool::new();
+    let outpoint = coin(&mut mempool, 1, 100_000);
+
+    let mut tx = spend(&[outpoint], 0, &[90_000]);
+    tx.lock_time = 10;
+    assert_eq!(mempool.accept(tx.clone(), 9, 0), Err(MempoolError::NonFinal));
+    assert!(mempool.accept(tx, 10, 0).is_ok());
+
+    // Relative lock times can't be checked, unless they're zero or disabled.
+    let outpoint = coin(&mut mempool, 2, 100_000);
+    assert_eq!(mempool.accept(spend(&[outpoint], 1, &[90_000]), 1, 0), Err(MempoolError::NonFinal));
+    assert!(mempool.accept(spend(&[outpoint], 1 | SEQUENCE_LOCKTIME_DISABLE_FLAG, &[90_000]), 1, 0).is_ok());
+}
+
+#[test]
+fn taproot_spends_are_rejected() {
+    let mut mempool = Mempool::new();
+    let outpoint = OutPoint::new([1; 32], 0);
+    let mut script_pubkey = vec![0x51];
+    push_data(&mut script_pubkey, &[0x11; 32]);
+    mempool.add_coin(outpoint, TxOut::new(100_000, script_pubkey));
+
+    let mut input = TxIn::new(outpoint, vec![], FINAL_SEQUENCE);
+    input.witness = vec![vec![1]];
+    let tx = Transaction::new(2, vec![input], vec![TxOut::new(90_000, vec![0x51])], 0);
+
+    assert_eq!(mempool.accept(tx, 1, 0), Err(MempoolError::InvalidScript(0, ScriptError::DiscourageUpgradableWitnessProgram)));
+}
+
+#[test]
+fn packages_and_ordering() {
+    let mut mempool = Mempool::new();
+    let low = coin(&mut mempool, 1, 100_000);
+    let high = coin(&mut mempool, 2, 100_000);
+
+    let parent = spend(&[low], FINAL_SEQUENCE, &[99_000]);
+    let child = spend(&[OutPoint::new(parent.txid(), 0)], FINAL_SEQUENCE, &[50_000]);
+    let other = spend(&[high], FINAL_SEQUENCE, &[90_000]);
+
+    // Children can't come before their parents.
+    assert_eq!(mempool.accept(child.clone(), 1, 0), Err(MempoolError::MissingInputs));
+    mempool.accept(parent.clone(), 1, 0).unwrap();
+    mempool.accept(child.clone(), 1, 0).unwrap();
+    mempool.accept(other.clone(), 1, 0).unwrap();
+
+    assert_eq!(mempool.ancestors(&child.txid()).into_iter().collect::<Vec<[u8; 32]>>(), vec![parent.txid()]);
+    assert_eq!(mempool.descendants(&parent.txid()).into_iter().collect::<Vec<[u8; 32]>>(), vec![child.txid()]);
+
+    // The child pays enough for its parent to be mined too.
+    let order = mempool.entries_by_fee_rate().iter().map(|entry| entry.txid).collect::<Vec<[u8; 32]>>();
+    assert_eq!(order, vec![child.txid(), other.txid(), parent.txid()]);
+
+    // Removing a transaction removes its descendants.
+    assert_eq!(mempool.remove(&parent.txid()).len(), 2);
+    assert_eq!(mempool.len(), 1);
+}
+
+#[test]
+fn replacement_rules() {
+    let mut mempool = Mempool::new();
+    let outpoint = coin(&mut mempool, 1, 100_000);
+
+    let original = spend(&[outpoint], FINAL_SEQUENCE, &[90_000]);
+    mempool.accept(original.clone(), 1, 0).unwrap();
+
+    // Not signaling replaceability.
+    match mempool.accept(spend(&[outpoint], FINAL_SEQUENCE, &[50_000]), 1, 0) {
+        Err(MempoolError::Conflict(_)) => (),
+        other => panic!("Unexpected result {:?}", other),
+    }
+
+    mempool.remove(&original.txid());
+    let original = spend(&[outpoint], 0, &[90_000]);
+    mempool.accept(original.clone(), 1, 0).unwrap();
+
+    // Paying less fees, or not enough more for its own relay.
+    assert!(mempool.accept(spend(&[outpoint], 0, &[95_000]), 1, 0).is_err());
+    assert!(mempool.accept(spend(&[outpoint], 0, &[89_999]), 1, 0).is_err());
+
+    let replacement = spend(&[outpoint], 0, &[80_000]);
+    assert_eq!(mempool.accept(replacement.clone(), 1, 0), Ok(vec![original.txid()]));
+    assert!(!mempool.contains(&original.txid()));
+    assert!(mempool.contains(&replacement.txid()));
+}
+
+#[test]
+fn eviction_raises_min_fee() {
+    let mut mempool = Mempool::new();
+    let cheap = spend(&[coin(&mut mempool, 1, 100_000)], FINAL_SEQUENCE, &[99_000]);
+    let expensive = spend(&[coin(&mut mempool, 2, 100_000)], FINAL_SEQUENCE, &[90_000]);
+
+    mempool.accept(cheap.clone(), 1, 0).unwrap();
+    mempool.set_max_size(mempool.size() + virtual_size(&expensive) - 1);
+
+    assert_eq!(mempool.accept(expensive.clone(), 1, 0), Ok(vec![cheap.txid()]));
+    assert!(mempool.min_fee(0) > fee_rate(1000, virtual_size(&cheap)));
+
+    // The minimum fee goes back down over time.
+    assert_eq!(mempool.min_fee(30 * 24 * 60 * 60), DEFAULT_MIN_RELAY_FEE);
+}
+
+#[test]
+fn evicted_replacement_still_reports_what_it_replaced() {
+    let mut mempool = Mempool::new();
+    let outpoint = coin(&mut mempool, 1, 100_000);
+
+    let original = spend(&[outpoint], 0, &[90_000]);
+    mempool.accept(original.clone(), 1, 0).unwrap();
+
+    // Too big to fit even in an empty mempool.
+    let replacement = spend(&[outpoint], 0, &[80_000]);
+    mempool.set_max_size(virtual_size(&replacement) - 1);
+
+    assert_eq!(mempool.accept(replacement, 1, 0), Err(MempoolError::MempoolFull(vec![original.txid()])));
+    assert!(mempool.is_empty());
+}
+
+#[test]
+fn eviction_updates_package_fee_rates() {
+    let mut mempool = Mempool::new();
+    let parent = spend(&[coin(&mut mempool, 1, 100_000)], FINAL_SEQUENCE, &[90_000]);
+    let child = spend(&[OutPoint::new(parent.txid(), 0)], FINAL_SEQUENCE, &[89_500]);
+    let middle = spend(&[coin(&mut mempool, 2, 100_000)], FINAL_SEQUENCE, &[94_000]);
+
+    for tx in [&parent, &child, &middle] {
+        mempool.accept(tx.clone(), 1, 0).unwrap();
+    }
+
+    // The cheap child drags its parent down until it's evicted, and then the parent is worth more than the other transaction.
+    let new = spend(&[coin(&mut mempool, 3, 100_000)], FINAL_SEQUENCE, &[80_000]);
+    mempool.set_max_size(mempool.size() - virtual_size(&child));
+
+    assert_eq!(mempool.accept(new.clone(), 1, 0), Ok(vec![child.txid(), middle.txid()]));
+    assert!(mempool.contains(&parent.txid()));
+    assert!(mempool.contains(&new.txid()));
+}
+
+#[test]
+fn blocks_confirm_and_conflict() {
+    let mut mempool = Mempool::new();
+    let outpoint = coin(&mut mempool, 1, 100_000);
+
+    let parent = spend(&[outpoint], FINAL_SEQUENCE, &[90_000]);
+    let child = spend(&[OutPoint::new(parent.txid(), 0)], FINAL_SEQUENCE, &[80_000]);
+    let double_spend = spend(&[coin(&mut mempool, 2, 100_000)], FINAL_SEQUENCE, &[90_000]);
+    mempool.accept(parent.clone(), 1, 0).unwrap();
+    mempool.accept(child.clone(), 1, 0).unwrap();
+    mempool.accept(double_spend.clone(), 1, 0).unwrap();
+
+    let conflicting = spend(&[double_spend.inputs[0].previous_output], FINAL_SEQUENCE, &[1000]);
+    let block = Block::new(::bitcoin::Network::Regtest.params().genesis, vec![parent.clone(), conflicting]);
+    let (confirmed, conflicts) = mempool.remove_for_block(&block, 1);
+
+    assert_eq!(confirmed.iter().map(|entry| entry.txid).collect::<Vec<[u8; 32]>>(), vec![parent.txid()]);
+    assert_eq!(conflicts, vec![double_spend.txid()]);
+    assert!(mempool.ancestors(&child.txid()).is_empty());
+
+    // Outputs of confirmed transactions can be spent.
+    let outpoint = OutPoint::new(parent.txid(), 1);
+    assert_eq!(mempool.accept(spend(&[outpoint], FINAL_SEQUENCE, &[1]), 1, 0), Err(MempoolError::MissingInputs));
+    mempool.remove(&child.txid());
+    assert!(mempool.accept(spend(&[OutPoint::new(parent.txid(), 0)], FINAL_SEQUENCE, &[80_000]), 1, 0).is_ok());
+
+    assert_eq!(mempool.expire(MEMPOOL_EXPIRY + 1).len(), 1);
+}
+
+#[test]
+fn old_block_outputs_are_forgotten() {
+    let mut mempool = Mempool::new();
+    let genesis = ::bitcoin::Network::Regtest.params().genesis;
+    let confirmed = spend(&[OutPoint::new([1; 32], 0)], FINAL_SEQUENCE, &[90_000, 90_000]);
+    mempool.remove_for_block(&Block::new(genesis, vec![confirmed.clone()]), 10);
+
+    mempool.remove_for_block(&Block::new(genesis, vec![]), 10 + COIN_CACHE_BLOCKS - 1);
+    assert!(mempool.accept(spend(&[OutPoint::new(confirmed.txid(), 0)], FINAL_SEQUENCE, &[80_000]), 10 + COIN_CACHE_BLOCKS - 1, 0).is_ok());
+
+    mempool.remove_for_block(&Block::new(genesis, vec![]), 10 + COIN_CACHE_BLOCKS);
+    assert_eq!(mempool.accept(spend(&[OutPoint::new(confirmed.txid(), 1)], FINAL_SEQUENCE, &[80_000]), 10 + COIN_CACHE_BLOCKS, 0), Err(MempoolError::MissingInputs));
+}
\ No newline at end of file
@@ -1,54 +1,34 @@
 use std::io::{Read, Write};
 
-use network::NetworkError;
+use network::{MAX_ADDR_COUNT, NetworkError};
+use network::encode::{decode_vec_with_limit, Decodable, Encodable};
 use network::networkaddress::NetworkAddress;
-use network::varint::VarInt;
 
 #[derive(Clone, Debug)]
 pub struct AddrPayload {
-    count: VarInt,
     pub addr_list: Vec<NetworkAddress>,
 }
 
 impl AddrPayload {
-    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
-        self.count.serialize(writer)?;
-
-        for addr in self.addr_list.iter() {
-            addr.serialize(writer)?;
+    pub fn new(addr_list: Vec<NetworkAddress>) -> AddrPayload {
+        AddrPayload {
+            addr_list,
         }
-
-        Ok(())
     }
+}
 
-    pub fn length(&self) -> usize {
-        self.count.length() + self.addr_list.len() * NetworkAddress::length()
+impl Encodable for AddrPayload {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        self.addr_list.encode(writer)
     }
+}
 
-    pub fn deserialize<R: Read>(reader: &mut R) -> Result<AddrPayload, NetworkError> {
-        let count = VarInt::deserialize(reader)?;
-        let total_addrs = count.value();
-
-        let mut addr_list: Vec<NetworkAddress> = vec![];
-
-        // TODO: do something against huge lengths.
-        for _ in 0..total_addrs {
-            let curr_addr = NetworkAddress::deserialize(reader)?;
-            addr_list.push(curr_addr);
-        }
+impl Decodable for AddrPayload {
+    fn decode<R: Read>(reader: &mut R) -> Result<AddrPayload, NetworkError> {
+        let addr_list = decode_vec_with_limit(reader, MAX_ADDR_COUNT)?;
 
         Ok(AddrPayload {
-            count,
             addr_list,
         })
     }
-
-    pub fn new(addr_list: Vec<NetworkAddress>) -> AddrPayload {
-        let count = VarInt::new(addr_list.len() as u64);
-
-        AddrPayload {
-            count,
-            addr_list,
-        }
-    }
 }
\ No newline at end of file
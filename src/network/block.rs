@@ -0,0 +1,112 @@
+use std::io::{Read, Write};
+
+use network::NetworkError;
+use network::encode::{Decodable, Encodable};
+use network::headers::BlockHeader;
+use network::transaction::Transaction;
+use util::sha256d;
+
+// Computes the merkle root of `hashes`, along with whether the tree has two identical hashes next to each other at some level. Such a tree has the same root as a different list of transactions (CVE-2012-2459), so it can't be trusted.
+pub fn merkle_root(hashes: &[[u8; 32]]) -> ([u8; 32], bool) {
+    if hashes.is_empty() {
+        return ([0; 32], false);
+    }
+
+    let mut level = hashes.to_vec();
+    let mut mutated = false;
+
+    while level.len() > 1 {
+        if level.chunks(2).any(|pair| pair.len() == 2 && pair[0] == pair[1]) {
+            mutated = true;
+        }
+
+        // Odd levels get their last hash paired with itself.
+        if level.len() % 2 == 1 {
+            let last = level[level.len() - 1];
+            level.push(last);
+        }
+
+        level = level.chunks(2).map(|pair| {
+            let mut data = pair[0].to_vec();
+            data.extend_from_slice(&pair[1]);
+            sha256d(&data)
+        }).collect();
+    }
+
+    (level[0], mutated)
+}
+
+#[derive(Clone, Debug)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
+}
+
+impl Block {
+    pub fn new(header: BlockHeader, transactions: Vec<Transaction>) -> Block {
+        Block {
+            header,
+            transactions,
+        }
+    }
+
+    pub fn hash(&self) -> Vec<u8> {
+        self.header.hash()
+    }
+
+    pub fn compute_merkle_root(&self) -> ([u8; 32], bool) {
+        merkle_root(&self.transactions.iter().map(|tx| tx.txid()).collect::<Vec<[u8; 32]>>())
+    }
+
+    // Whether the transactions are really the ones committed to by the header.
+    pub fn check_merkle_root(&self) -> bool {
+        let (root, mutated) = self.compute_merkle_root();
+        !mutated && root == self.header.merkle_root()
+    }
+}
+
+impl Encodable for Block {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        self.header.encode(writer)?;
+        self.transactions.encode(writer)?;
+
+        Ok(())
+    }
+}
+
+impl Decodable for Block {
+    fn decode<R: Read>(reader: &mut R) -> Result<Block, NetworkError> {
+        let header = BlockHeader::decode(reader)?;
+        let transactions = Vec::<Transaction>::decode(reader)?;
+
+        Ok(Block::new(header, transactions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::Network;
+    use hex::FromHex;
+
+    #[test]
+    fn genesis_block_merkle_root() {
+        // The testnet3 genesis block, with its only transaction.
+        let bytes = Vec::from_hex("0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff001d1aa4ae180101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000").unwrap();
+
+        let block = Block::decode(&mut &bytes[..]).unwrap();
+        assert_eq!(block.hash(), Network::Testnet3.params().genesis.hash());
+        assert!(block.transactions[0].is_coinbase());
+        assert!(block.check_merkle_root());
+    }
+
+    #[test]
+    fn duplicated_transactions_are_detected() {
+        let hashes = [[1u8; 32], [2u8; 32], [3u8; 32]];
+        let duplicated = [[1u8; 32], [2u8; 32], [3u8; 32], [3u8; 32]];
+
+        let (root, mutated) = merkle_root(&hashes);
+        assert!(!mutated);
+        assert_eq!(merkle_root(&duplicated), (root, true));
+    }
+}
\ No newline at end of file
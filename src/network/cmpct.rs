@@ -1,7 +1,7 @@
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Read, Write};
 
 use network::NetworkError;
+use network::encode::{Decodable, Encodable};
 
 #[derive(Clone, Debug)]
 pub struct SendCmpctPayload {
@@ -10,31 +10,31 @@ pub struct SendCmpctPayload {
 }
 
 impl SendCmpctPayload {
-    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
-        writer.write_u8(self.announce as u8)?;
-        writer.write_u64::<LittleEndian>(self.version)?;
+    pub fn new() -> SendCmpctPayload {
+        SendCmpctPayload {
+            announce: false,
+            version: 1,
+        }
+    }
+}
+
+impl Encodable for SendCmpctPayload {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        self.announce.encode(writer)?;
+        self.version.encode(writer)?;
 
         Ok(())
     }
+}
 
-    pub fn deserialize<R: Read>(reader: &mut R) -> Result<SendCmpctPayload, NetworkError> {
-        let announce = reader.read_u8()? == 1;
-        let version = reader.read_u64::<LittleEndian>()?;
+impl Decodable for SendCmpctPayload {
+    fn decode<R: Read>(reader: &mut R) -> Result<SendCmpctPayload, NetworkError> {
+        let announce = bool::decode(reader)?;
+        let version = u64::decode(reader)?;
 
         Ok(SendCmpctPayload{
             announce,
             version,
         })
     }
-
-    pub fn length() -> usize {
-        9
-    }
-
-    pub fn new() -> SendCmpctPayload {
-        SendCmpctPayload {
-            announce: false,
-            version: 1,
-        }
-    }
 }
\ No newline at end of file
@@ -1,13 +1,16 @@
-use byteorder::{ByteOrder, BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, BigEndian, LittleEndian, ReadBytesExt};
 use sha2::{Digest, Sha256};
 use std::io::{Read, Write};
 
-use network::NetworkError;
+use network::{MAX_PROTOCOL_MESSAGE_LENGTH, NetworkError};
+use network::encode::{ByteCounter, Decodable, Encodable};
 use network::addr::AddrPayload;
+use network::block::Block;
 use network::blocks::GetBlocksOrHeadersPayload;
 use network::cmpct::SendCmpctPayload;
 use network::headers::HeadersPayload;
 use network::inv::InvPayload;
+use network::transaction::Transaction;
 use network::version::VersionPayload;
 
 #[derive(Clone, Debug)]
@@ -16,26 +19,42 @@ pub enum Command {
     Verack,
     SendHeaders,
     SendCmpct(SendCmpctPayload),
+    // Sent during the version handshake by peers announcing transactions by wtxid (BIP339).
+    WtxidRelay,
     Addr(AddrPayload),
+    GetAddr,
     Feefilter(u64),
     Inv(InvPayload),
+    GetData(InvPayload),
+    // Answers a getdata with the requested objects we can't send.
+    NotFound(InvPayload),
     GetBlocks(GetBlocksOrHeadersPayload),
     GetHeaders(GetBlocksOrHeadersPayload),
     Headers(HeadersPayload),
+    Block(Block),
+    Tx(Transaction),
     Ping(u64),
     Pong(u64),
+    // Any command we don't know about. The raw name and payload are kept so the message can still be handled elsewhere or re-serialized.
+    Unknown { name: [u8; 12], payload: Vec<u8> },
 }
 
 const VERSION_COMMAND: [u8; 12] = [b'v', b'e', b'r', b's', b'i', b'o', b'n', 0, 0, 0, 0, 0];
 const VERACK_COMMAND: [u8; 12] = [b'v', b'e', b'r', b'a', b'c', b'k', 0, 0, 0, 0, 0, 0];
 const SENDHEADERS_COMMAND: [u8; 12] = [b's', b'e', b'n', b'd', b'h', b'e', b'a', b'd', b'e', b'r', b's', 0];
 const SENDCMPCT_COMMAND: [u8; 12] = [b's', b'e', b'n', b'd', b'c', b'm', b'p', b'c', b't', 0, 0, 0];
+const WTXIDRELAY_COMMAND: [u8; 12] = [b'w', b't', b'x', b'i', b'd', b'r', b'e', b'l', b'a', b'y', 0, 0];
 const ADDR_COMMAND: [u8; 12] = [b'a', b'd', b'd', b'r', 0, 0, 0, 0, 0, 0, 0, 0];
+const GETADDR_COMMAND: [u8; 12] = [b'g', b'e', b't', b'a', b'd', b'd', b'r', 0, 0, 0, 0, 0];
 const FEEFILTER_COMMAND: [u8; 12] = [b'f', b'e', b'e', b'f', b'i', b'l', b't', b'e', b'r', 0, 0, 0];
 const INV_COMMAND: [u8; 12] = [b'i', b'n', b'v', 0, 0, 0, 0, 0, 0, 0, 0, 0];
+const GETDATA_COMMAND: [u8; 12] = [b'g', b'e', b't', b'd', b'a', b't', b'a', 0, 0, 0, 0, 0];
+const NOTFOUND_COMMAND: [u8; 12] = [b'n', b'o', b't', b'f', b'o', b'u', b'n', b'd', 0, 0, 0, 0];
 const GETBLOCKS_COMMAND: [u8; 12] = [b'g', b'e', b't', b'b', b'l', b'o', b'c', b'k', b's', 0, 0, 0];
 const GETHEADERS_COMMAND: [u8; 12] = [b'g', b'e', b't', b'h', b'e', b'a', b'd', b'e', b'r', b's', 0, 0];
 const HEADERS_COMMAND: [u8; 12] = [b'h', b'e', b'a', b'd', b'e', b'r', b's', 0, 0, 0, 0, 0];
+const BLOCK_COMMAND: [u8; 12] = [b'b', b'l', b'o', b'c', b'k', 0, 0, 0, 0, 0, 0, 0];
+const TX_COMMAND: [u8; 12] = [b't', b'x', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
 const PING_COMMAND: [u8; 12] = [b'p', b'i', b'n', b'g', 0, 0, 0, 0, 0, 0, 0, 0];
 const PONG_COMMAND: [u8; 12] = [b'p', b'o', b'n', b'g', 0, 0, 0, 0, 0, 0, 0, 0];
 
@@ -46,14 +65,25 @@ impl Command {
             Command::Verack => "verack",
             Command::SendHeaders => "sendheaders",
             Command::SendCmpct(_) => "sendcmpct",
+            Command::WtxidRelay => "wtxidrelay",
             Command::Addr(_) => "addr",
+            Command::GetAddr => "getaddr",
             Command::Feefilter(_) => "feefilter",
             Command::Inv(_) => "inv",
+            Command::GetData(_) => "getdata",
+            Command::NotFound(_) => "notfound",
             Command::GetBlocks(_) => "getblocks",
             Command::GetHeaders(_) => "getheaders",
             Command::Headers(_) => "headers",
+            Command::Block(_) => "block",
+            Command::Tx(_) => "tx",
             Command::Ping(_) => "ping",
             Command::Pong(_) => "pong",
+            Command::Unknown { ref name, .. } => {
+                // Command names are NULL-padded ASCII. Anything that isn't valid UTF-8 can't be shown as a name.
+                let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                ::std::str::from_utf8(&name[..end]).unwrap_or("<invalid>")
+            },
         }
     }
 
@@ -63,47 +93,48 @@ impl Command {
             Command::Verack => VERACK_COMMAND,
             Command::SendHeaders => SENDHEADERS_COMMAND,
             Command::SendCmpct(_) => SENDCMPCT_COMMAND,
+            Command::WtxidRelay => WTXIDRELAY_COMMAND,
             Command::Addr(_) => ADDR_COMMAND,
+            Command::GetAddr => GETADDR_COMMAND,
             Command::Feefilter(_) => FEEFILTER_COMMAND,
             Command::Inv(_) => INV_COMMAND,
+            Command::GetData(_) => GETDATA_COMMAND,
+            Command::NotFound(_) => NOTFOUND_COMMAND,
             Command::GetBlocks(_) => GETBLOCKS_COMMAND,
             Command::GetHeaders(_) => GETHEADERS_COMMAND,
             Command::Headers(_) => HEADERS_COMMAND,
+            Command::Block(_) => BLOCK_COMMAND,
+            Command::Tx(_) => TX_COMMAND,
             Command::Ping(_) => PING_COMMAND,
             Command::Pong(_) => PONG_COMMAND,
+            Command::Unknown { name, .. } => name,
         }
     }
 
+    // Serializes only the payload of the command.
     pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
         match *self {
-            Command::Version(ref p) => p.serialize(writer)?,
-            Command::SendCmpct(ref p) => p.serialize(writer)?,
-            Command::Addr(ref p) => p.serialize(writer)?,
-            Command::Feefilter(p) | Command::Ping(p) | Command::Pong(p) => writer.write_u64::<LittleEndian>(p)?,
-            Command::Inv(ref p) => p.serialize(writer)?,
-            Command::GetBlocks(ref p) | Command::GetHeaders(ref p) => p.serialize(writer)?,
-            Command::Headers(ref p) => p.serialize(writer)?,
-            Command::Verack | Command::SendHeaders => (),
+            Command::Version(ref p) => p.encode(writer)?,
+            Command::SendCmpct(ref p) => p.encode(writer)?,
+            Command::Addr(ref p) => p.encode(writer)?,
+            Command::Feefilter(p) | Command::Ping(p) | Command::Pong(p) => p.encode(writer)?,
+            Command::Inv(ref p) | Command::GetData(ref p) | Command::NotFound(ref p) => p.encode(writer)?,
+            Command::GetBlocks(ref p) | Command::GetHeaders(ref p) => p.encode(writer)?,
+            Command::Headers(ref p) => p.encode(writer)?,
+            Command::Block(ref p) => p.encode(writer)?,
+            Command::Tx(ref p) => p.encode(writer)?,
+            Command::Unknown { ref payload, .. } => writer.write_all(payload)?,
+            Command::Verack | Command::SendHeaders | Command::WtxidRelay | Command::GetAddr => (),
         }
 
         Ok(())
     }
 
+    // Length of the payload, taken straight from its serialization.
     pub fn length(&self) -> usize {
-        match *self {
-            Command::Version(_) => VersionPayload::length(),
-            Command::Verack => 0,
-            Command::SendHeaders => 0,
-            Command::SendCmpct(_) => SendCmpctPayload::length(),
-            Command::Addr(ref p) => p.length(),
-            Command::Feefilter(_) => 8,
-            Command::Inv(ref p) => p.length(),
-            Command::GetBlocks(ref p) => p.length(),
-            Command::GetHeaders(ref p) => p.length(),
-            Command::Headers(ref p) => p.length(),
-            Command::Ping(_) => 8,
-            Command::Pong(_) => 8,
-        }
+        let mut counter = ByteCounter::new();
+        self.serialize(&mut counter).expect("ByteCounter never fails to write");
+        counter.count()
     }
 
     pub fn deserialize<R: Read>(reader: &mut R) -> Result<Command, NetworkError> {
@@ -114,37 +145,44 @@ impl Command {
         let length = reader.read_u32::<LittleEndian>()?;
         let checksum = reader.read_u32::<BigEndian>()?;
 
+        if length as usize > MAX_PROTOCOL_MESSAGE_LENGTH {
+            return Err(NetworkError::OversizedMessage);
+        }
+
         // Constraining reader to read at most `length` bytes.
-        // Should probably do some validation of length here to prevent huge lengths.
         let mut constrained_reader = reader.take(length as u64);
 
         let result = match command_bytes {
-            VERSION_COMMAND => Command::Version(VersionPayload::deserialize(&mut constrained_reader)?),
+            VERSION_COMMAND => Command::Version(VersionPayload::decode(&mut constrained_reader)?),
             VERACK_COMMAND => Command::Verack,
             SENDHEADERS_COMMAND => Command::SendHeaders,
-            SENDCMPCT_COMMAND => Command::SendCmpct(SendCmpctPayload::deserialize(&mut constrained_reader)?),
-            ADDR_COMMAND => Command::Addr(AddrPayload::deserialize(&mut constrained_reader)?),
-            FEEFILTER_COMMAND => {
-                let result = constrained_reader.read_u64::<LittleEndian>()?;
-                Command::Feefilter(result)
-            },
-            INV_COMMAND => Command::Inv(InvPayload::deserialize(&mut constrained_reader)?),
-            GETBLOCKS_COMMAND => Command::GetBlocks(GetBlocksOrHeadersPayload::deserialize(&mut constrained_reader)?),
-            GETHEADERS_COMMAND => Command::GetHeaders(GetBlocksOrHeadersPayload::deserialize(&mut constrained_reader)?),
-            HEADERS_COMMAND => Command::Headers(HeadersPayload::deserialize(&mut constrained_reader)?),
-            PING_COMMAND => {
-                let result = constrained_reader.read_u64::<LittleEndian>()?;
-                Command::Ping(result)
-            },
-            PONG_COMMAND => {
-                let result = constrained_reader.read_u64::<LittleEndian>()?;
-                Command::Pong(result)
-            },
+            SENDCMPCT_COMMAND => Command::SendCmpct(SendCmpctPayload::decode(&mut constrained_reader)?),
+            WTXIDRELAY_COMMAND => Command::WtxidRelay,
+            ADDR_COMMAND => Command::Addr(AddrPayload::decode(&mut constrained_reader)?),
+            GETADDR_COMMAND => Command::GetAddr,
+            FEEFILTER_COMMAND => Command::Feefilter(u64::decode(&mut constrained_reader)?),
+            INV_COMMAND => Command::Inv(InvPayload::decode(&mut constrained_reader)?),
+            GETDATA_COMMAND => Command::GetData(InvPayload::decode(&mut constrained_reader)?),
+            NOTFOUND_COMMAND => Command::NotFound(InvPayload::decode(&mut constrained_reader)?),
+            GETBLOCKS_COMMAND => Command::GetBlocks(GetBlocksOrHeadersPayload::decode(&mut constrained_reader)?),
+            GETHEADERS_COMMAND => Command::GetHeaders(GetBlocksOrHeadersPayload::decode(&mut constrained_reader)?),
+            HEADERS_COMMAND => Command::Headers(HeadersPayload::decode(&mut constrained_reader)?),
+            BLOCK_COMMAND => Command::Block(Block::decode(&mut constrained_reader)?),
+            TX_COMMAND => Command::Tx(Transaction::decode(&mut constrained_reader)?),
+            PING_COMMAND => Command::Ping(u64::decode(&mut constrained_reader)?),
+            PONG_COMMAND => Command::Pong(u64::decode(&mut constrained_reader)?),
             _ => {
-                let mut vec = vec![];
-                vec.extend_from_slice(&command_bytes);
-                let command_name = String::from_utf8(vec).unwrap();
-                return Err(NetworkError::InvalidCommand(command_name))
+                let mut payload = vec![];
+                constrained_reader.read_to_end(&mut payload)?;
+
+                if payload.len() != length as usize {
+                    return Err(NetworkError::NotEnoughData);
+                }
+
+                Command::Unknown {
+                    name: command_bytes,
+                    payload,
+                }
             },
         };
 
@@ -163,4 +201,41 @@ impl Command {
 
         BigEndian::read_u32(&dhash[..4])
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::WriteBytesExt;
+    use network::Message;
+    use super::*;
+
+    #[test]
+    fn unknown_command_roundtrip() {
+        let name = [b's', b'e', b'n', b'd', b't', b'x', b'r', b'c', b'n', b'c', b'l', 0];
+        let command = Command::Unknown { name, payload: vec![0xde, 0xad, 0xbe, 0xef] };
+
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&command.name_as_bytes());
+        bytes.write_u32::<LittleEndian>(command.length() as u32).unwrap();
+        bytes.write_u32::<BigEndian>(command.checksum()).unwrap();
+        command.serialize(&mut bytes).unwrap();
+
+        let result = Command::deserialize(&mut &bytes[..]).unwrap();
+        assert_eq!(result.name(), "sendtxrcncl");
+        assert_eq!(result.name_as_bytes(), name);
+
+        let mut result_bytes = vec![];
+        result.serialize(&mut result_bytes).unwrap();
+        assert_eq!(result_bytes, vec![0xde, 0xad, 0xbe, 0xef]);
+    }
+
+    #[test]
+    fn wtxidrelay_roundtrip() {
+        let mut bytes = vec![];
+        Message::new(0x0709110b, Command::WtxidRelay).serialize(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 24);
+
+        let result = Message::deserialize(&mut &bytes[..]).unwrap();
+        assert_eq!(result.command.name(), "wtxidrelay");
+    }
 }
\ No newline at end of file
@@ -0,0 +1,240 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write};
+
+use network::{MAX_PROTOCOL_MESSAGE_LENGTH, NetworkError};
+use network::varint::VarInt;
+
+// Consensus encoding of anything that goes over the wire.
+pub trait Encodable {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError>;
+
+    // By default the length is computed from the encoding itself, so it can never get out of sync with `encode`.
+    fn encoded_length(&self) -> usize {
+        let mut counter = ByteCounter::new();
+        self.encode(&mut counter).expect("ByteCounter never fails to write");
+        counter.count()
+    }
+}
+
+pub trait Decodable: Sized {
+    fn decode<R: Read>(reader: &mut R) -> Result<Self, NetworkError>;
+}
+
+// A writer that throws away everything written to it, only keeping track of how many bytes it got.
+pub struct ByteCounter(usize);
+
+impl ByteCounter {
+    pub fn new() -> ByteCounter {
+        ByteCounter(0)
+    }
+
+    pub fn count(&self) -> usize {
+        self.0
+    }
+}
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Reads a VarInt-prefixed list, refusing to go any further if the peer claims more than `max_items` items.
+pub fn decode_vec_with_limit<R: Read, T: Decodable>(reader: &mut R, max_items: usize) -> Result<Vec<T>, NetworkError> {
+    let count = VarInt::decode(reader)?.value();
+
+    if count > max_items as u64 {
+        return Err(NetworkError::OversizedMessage);
+    }
+
+    // Not using `with_capacity(count)` on purpose: the count comes from the peer, so we only allocate for items that actually arrive.
+    let mut result = vec![];
+    for _ in 0..count {
+        result.push(T::decode(reader)?);
+    }
+
+    Ok(result)
+}
+
+pub fn serialize<T: Encodable>(value: &T) -> Vec<u8> {
+    let mut result = vec![];
+    value.encode(&mut result).expect("Writing to a Vec never fails");
+    result
+}
+
+macro_rules! impl_int_encodable {
+    ($int_type:ty, $read_fn:ident, $write_fn:ident) => {
+        impl Encodable for $int_type {
+            fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+                writer.$write_fn::<LittleEndian>(*self)?;
+                Ok(())
+            }
+
+            fn encoded_length(&self) -> usize {
+                ::std::mem::size_of::<$int_type>()
+            }
+        }
+
+        impl Decodable for $int_type {
+            fn decode<R: Read>(reader: &mut R) -> Result<$int_type, NetworkError> {
+                Ok(reader.$read_fn::<LittleEndian>()?)
+            }
+        }
+    }
+}
+
+impl_int_encodable!(u16, read_u16, write_u16);
+impl_int_encodable!(u32, read_u32, write_u32);
+impl_int_encodable!(u64, read_u64, write_u64);
+impl_int_encodable!(i32, read_i32, write_i32);
+impl_int_encodable!(i64, read_i64, write_i64);
+
+impl Encodable for u8 {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        writer.write_u8(*self)?;
+        Ok(())
+    }
+
+    fn encoded_length(&self) -> usize {
+        1
+    }
+}
+
+impl Decodable for u8 {
+    fn decode<R: Read>(reader: &mut R) -> Result<u8, NetworkError> {
+        Ok(reader.read_u8()?)
+    }
+}
+
+impl Encodable for bool {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        writer.write_u8(*self as u8)?;
+        Ok(())
+    }
+
+    fn encoded_length(&self) -> usize {
+        1
+    }
+}
+
+impl Decodable for bool {
+    fn decode<R: Read>(reader: &mut R) -> Result<bool, NetworkError> {
+        Ok(reader.read_u8()? != 0)
+    }
+}
+
+macro_rules! impl_array_encodable {
+    ($size:expr) => {
+        impl Encodable for [u8; $size] {
+            fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+                writer.write_all(self)?;
+                Ok(())
+            }
+
+            fn encoded_length(&self) -> usize {
+                $size
+            }
+        }
+
+        impl Decodable for [u8; $size] {
+            fn decode<R: Read>(reader: &mut R) -> Result<[u8; $size], NetworkError> {
+                let mut result = [0u8; $size];
+                reader.read_exact(&mut result)?;
+                Ok(result)
+            }
+        }
+    }
+}
+
+impl_array_encodable!(4);
+impl_array_encodable!(12);
+impl_array_encodable!(16);
+impl_array_encodable!(32);
+
+impl<T: Encodable> Encodable for Vec<T> {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        VarInt::new(self.len() as u64).encode(writer)?;
+
+        for item in self.iter() {
+            item.encode(writer)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: Decodable> Decodable for Vec<T> {
+    fn decode<R: Read>(reader: &mut R) -> Result<Vec<T>, NetworkError> {
+        // Every item takes at least one byte, so a list can never have more items than a message has bytes.
+        decode_vec_with_limit(reader, MAX_PROTOCOL_MESSAGE_LENGTH)
+    }
+}
+
+impl Encodable for String {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        VarInt::new(self.len() as u64).encode(writer)?;
+        writer.write_all(self.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+impl Decodable for String {
+    fn decode<R: Read>(reader: &mut R) -> Result<String, NetworkError> {
+        let length = VarInt::decode(reader)?.value();
+
+        if length > MAX_PROTOCOL_MESSAGE_LENGTH as u64 {
+            return Err(NetworkError::OversizedMessage);
+        }
+
+        // Not allocating `length` bytes upfront, so a peer lying about the length can only make us allocate as much as it actually sent.
+        let mut data = vec![];
+        reader.take(length).read_to_end(&mut data)?;
+
+        if data.len() as u64 != length {
+            return Err(NetworkError::NotEnoughData);
+        }
+
+        Ok(String::from_utf8(data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_lengths_match_encoding() {
+        for value in [0u64, 0xFC, 0xFD, 0xFFFF, 0x1_0000, 0xFFFF_FFFF, 0x1_0000_0000].iter() {
+            let varint = VarInt::new(*value);
+            assert_eq!(varint.encoded_length(), serialize(&varint).len());
+            assert_eq!(VarInt::decode(&mut &serialize(&varint)[..]).unwrap().value(), *value);
+        }
+    }
+
+    #[test]
+    fn string_roundtrip() {
+        let user_agent = String::from("/Satoshi:0.16.0/");
+        let bytes = serialize(&user_agent);
+
+        assert_eq!(bytes[0] as usize, user_agent.len());
+        assert_eq!(user_agent.encoded_length(), bytes.len());
+        assert_eq!(String::decode(&mut &bytes[..]).unwrap(), user_agent);
+    }
+
+    #[test]
+    fn vec_with_limit_rejects_long_lists() {
+        let bytes = serialize(&vec![1u32, 2, 3]);
+
+        assert_eq!(decode_vec_with_limit::<_, u32>(&mut &bytes[..], 3).unwrap(), vec![1, 2, 3]);
+        match decode_vec_with_limit::<_, u32>(&mut &bytes[..], 2) {
+            Err(NetworkError::OversizedMessage) => (),
+            other => panic!("Expected OversizedMessage, got {:?}", other),
+        }
+    }
+}
\ No newline at end of file
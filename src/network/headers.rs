@@ -1,6 +1,5 @@
-use byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt};
-use hex::FromHex;
-use network::NetworkError;
+use network::{MAX_HEADERS_COUNT, NetworkError};
+use network::encode::{serialize, Decodable, Encodable};
 use network::varint::VarInt;
 use sha2::{Digest, Sha256};
 use std::io::{Read, Write};
@@ -13,39 +12,51 @@ pub struct BlockHeader {
     timestamp: u32,
     bits: u32,
     nonce: u32,
-    txn_count: VarInt,
 }
 
-impl PartialEq for BlockHeader {
-    fn eq(&self, other: &BlockHeader) -> bool {
-        self.hash() == other.hash()
+// Expands the compact form of a target (the "bits" of a header) into a big endian 256-bit number. Negative, zero and overflowing targets are invalid.
+pub fn compact_to_target(bits: u32) -> Option<[u8; 32]> {
+    let exponent = (bits >> 24) as usize;
+    let mantissa = bits & 0x007FFFFF;
+
+    let negative = mantissa != 0 && bits & 0x00800000 != 0;
+    let overflow = mantissa != 0 && (exponent > 34 || (mantissa > 0xFF && exponent > 33) || (mantissa > 0xFFFF && exponent > 32));
+    if negative || overflow {
+        return None;
     }
-}
-impl Eq for BlockHeader {}
 
-impl BlockHeader {
-    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
-        self.serialize_no_txn_count(writer)?;
-        self.txn_count.serialize(writer)?;
+    let mut target = [0u8; 32];
+    if exponent <= 3 {
+        let value = mantissa >> (8 * (3 - exponent));
+        target[28..].copy_from_slice(&value.to_be_bytes());
+    } else {
+        // The mantissa's bytes, from the least significant one, end up `exponent - 3` bytes from the end.
+        for i in 0..3 {
+            let position = exponent - 3 + i;
+            if position < 32 {
+                target[31 - position] = (mantissa >> (8 * i)) as u8;
+            }
+        }
+    }
 
-        Ok(())
+    if target == [0u8; 32] {
+        return None;
     }
 
-    pub fn serialize_no_txn_count<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
-        writer.write_i32::<LittleEndian>(self.version)?;
-        writer.write_all(&self.prev_block)?;
-        writer.write_all(&self.merkle_root)?;
-        writer.write_u32::<LittleEndian>(self.timestamp)?;
-        writer.write_u32::<LittleEndian>(self.bits)?;
-        writer.write_u32::<LittleEndian>(self.nonce)?;
+    Some(target)
+}
 
-        Ok(())
+impl PartialEq for BlockHeader {
+    fn eq(&self, other: &BlockHeader) -> bool {
+        self.hash() == other.hash()
     }
+}
+impl Eq for BlockHeader {}
 
+impl BlockHeader {
     // TODO: likely make this a property in the struct so we don't have to calculate it all the time.
     pub fn hash(&self) -> Vec<u8> {
-        let mut header_bytes = vec![];
-        self.serialize_no_txn_count(&mut header_bytes).unwrap();
+        let header_bytes = serialize(self);
 
         let mut result = Vec::new();
         result.extend_from_slice(&Sha256::digest(&Sha256::digest(&header_bytes)));
@@ -53,45 +64,80 @@ impl BlockHeader {
         result
     }
 
-    pub fn length(&self) -> usize {
-        80 + self.txn_count.length()
-    }
-
-    pub fn deserialize<R: Read>(reader: &mut R) -> Result<BlockHeader, NetworkError> {
-        let version = reader.read_i32::<LittleEndian>()?;
-        let mut prev_block = [0; 32];
-        reader.read_exact(&mut prev_block)?;
-        let mut merkle_root = [0; 32];
-        reader.read_exact(&mut merkle_root)?;
-        let timestamp = reader.read_u32::<LittleEndian>()?;
-        let bits = reader.read_u32::<LittleEndian>()?;;
-        let nonce = reader.read_u32::<LittleEndian>()?;
-        let txn_count = VarInt::deserialize(reader)?;
-
-        Ok(BlockHeader {
+    pub fn new(version: i32, prev_block: [u8; 32], merkle_root: [u8; 32], timestamp: u32, bits: u32, nonce: u32) -> BlockHeader {
+        BlockHeader {
             version,
             prev_block,
             merkle_root,
             timestamp,
             bits,
             nonce,
-            txn_count,
-        })
+        }
     }
 
-    pub fn new_genesis() -> BlockHeader {
-        let mut merkle_root = [0u8; 32];
-        merkle_root.copy_from_slice(&Vec::from_hex("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b").unwrap().iter().cloned().rev().collect::<Vec<u8>>());
+    pub fn version(&self) -> i32 {
+        self.version
+    }
 
-        BlockHeader {
-            version: 1,
-            prev_block: [0; 32],
-            merkle_root,
-            timestamp: 1296688602,
-            bits: 0x1d00ffff,
-            nonce: 414098458,
-            txn_count: VarInt::new(1),
+    pub fn merkle_root(&self) -> [u8; 32] {
+        self.merkle_root
+    }
+
+    pub fn timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    // Whether the header's hash meets the target in its bits, and that target is no easier than the network's limit.
+    pub fn check_proof_of_work(&self, pow_limit_bits: u32) -> bool {
+        let (target, pow_limit) = match (compact_to_target(self.bits), compact_to_target(pow_limit_bits)) {
+            (Some(target), Some(pow_limit)) => (target, pow_limit),
+            _ => return false,
+        };
+
+        if target > pow_limit {
+            return false;
+        }
+
+        let mut hash = [0u8; 32];
+        for (byte, hash_byte) in hash.iter_mut().zip(self.hash().iter().rev()) {
+            *byte = *hash_byte;
         }
+
+        hash <= target
+    }
+}
+
+impl Encodable for BlockHeader {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        self.version.encode(writer)?;
+        self.prev_block.encode(writer)?;
+        self.merkle_root.encode(writer)?;
+        self.timestamp.encode(writer)?;
+        self.bits.encode(writer)?;
+        self.nonce.encode(writer)?;
+
+        Ok(())
+    }
+
+    fn encoded_length(&self) -> usize {
+        80
+    }
+}
+
+impl Decodable for BlockHeader {
+    fn decode<R: Read>(reader: &mut R) -> Result<BlockHeader, NetworkError> {
+        Ok(BlockHeader {
+            version: i32::decode(reader)?,
+            prev_block: <[u8; 32]>::decode(reader)?,
+            merkle_root: <[u8; 32]>::decode(reader)?,
+            timestamp: u32::decode(reader)?,
+            bits: u32::decode(reader)?,
+            nonce: u32::decode(reader)?,
+        })
     }
 }
 
@@ -107,14 +153,13 @@ impl ::std::fmt::Debug for BlockHeader {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         let reversed_prev_block = self.prev_block.iter().cloned().rev().collect::<Vec<u8>>();
         let reversed_merkle_root = self.merkle_root.iter().cloned().rev().collect::<Vec<u8>>();
-        write!(f, "BlockHeader {{ version: {}, prev_block: {}, merkle_root: {}, timestamp: {}, bits: {}, nonce: {}, txn_count: {:?} }}",
+        write!(f, "BlockHeader {{ version: {}, prev_block: {}, merkle_root: {}, timestamp: {}, bits: {}, nonce: {} }}",
             self.version,
             byte_slice_as_hex(&reversed_prev_block),
             byte_slice_as_hex(&reversed_merkle_root),
             self.timestamp,
             self.bits,
-            self.nonce,
-            self.txn_count)
+            self.nonce)
     }
 }
 
@@ -129,51 +174,101 @@ impl ::std::fmt::Display for BlockHeader {
 #[derive(Clone, Debug)]
 pub struct HeadersPayload {
     pub headers: Vec<BlockHeader>,
-    count: VarInt,
 }
 
-impl HeadersPayload {
-    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
-        self.count.serialize(writer)?;
+impl Encodable for HeadersPayload {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        VarInt::new(self.headers.len() as u64).encode(writer)?;
 
+        // In a headers message, every header is followed by a transaction count, which is always 0.
         for header in self.headers.iter() {
-            header.serialize(writer)?;
+            header.encode(writer)?;
+            VarInt::new(0).encode(writer)?;
         }
 
         Ok(())
     }
+}
 
-    pub fn length(&self) -> usize {
-        self.count.length() + self.headers.iter().fold(0, |acc, elem| acc + elem.length())
-    }
+impl Decodable for HeadersPayload {
+    fn decode<R: Read>(reader: &mut R) -> Result<HeadersPayload, NetworkError> {
+        let count = VarInt::decode(reader)?.value();
 
-    pub fn deserialize<R: Read>(reader: &mut R) -> Result<HeadersPayload, NetworkError> {
-        let count = VarInt::deserialize(reader)?;
-        let length = count.value();
+        if count > MAX_HEADERS_COUNT as u64 {
+            return Err(NetworkError::OversizedMessage);
+        }
 
         let mut headers = vec![];
 
-        for _ in 0..length {
-            let curr_header = BlockHeader::deserialize(reader)?;
+        for _ in 0..count {
+            let curr_header = BlockHeader::decode(reader)?;
+            // Transaction count, which carries no information in a headers message.
+            VarInt::decode(reader)?;
             headers.push(curr_header);
         }
 
         Ok(HeadersPayload {
             headers,
-            count,
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use bitcoin::Network;
     use hex::FromHex;
     use super::*;
 
     #[test]
     fn genesis_block_hash() {
-        let genesis_block = BlockHeader::new_genesis();
+        let genesis_block = Network::Testnet3.params().genesis;
 
         assert_eq!(genesis_block.hash(), Vec::from_hex("000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943").unwrap().iter().cloned().rev().collect::<Vec<u8>>());
     }
+
+    #[test]
+    fn compact_targets() {
+        // Cases from Core's arith_uint256 tests.
+        let target = |hex: &str| {
+            let mut result = [0u8; 32];
+            let bytes = Vec::from_hex(hex).unwrap();
+            result[32 - bytes.len()..].copy_from_slice(&bytes);
+            Some(result)
+        };
+
+        assert_eq!(compact_to_target(0x01003456), None);
+        assert_eq!(compact_to_target(0x01123456), target("12"));
+        assert_eq!(compact_to_target(0x02123456), target("1234"));
+        assert_eq!(compact_to_target(0x04123456), target("12345600"));
+        assert_eq!(compact_to_target(0x05009234), target("92340000"));
+        assert_eq!(compact_to_target(0x20123456), target("1234560000000000000000000000000000000000000000000000000000000000"));
+        assert_eq!(compact_to_target(0x04923456), None);
+        assert_eq!(compact_to_target(0xff123456), None);
+    }
+
+    #[test]
+    fn proof_of_work() {
+        for network in [Network::Mainnet, Network::Testnet3, Network::Testnet4, Network::Regtest, Network::Signet].iter() {
+            let params = network.params();
+            assert!(params.genesis.check_proof_of_work(params.pow_limit_bits));
+        }
+
+        // Changing the nonce breaks the proof of work, and claiming an easier target than the limit doesn't help.
+        let genesis = Network::Mainnet.params().genesis;
+        let tampered = BlockHeader::new(genesis.version(), genesis.prev_block, genesis.merkle_root(), genesis.timestamp(), genesis.bits(), genesis.nonce + 1);
+        assert!(!tampered.check_proof_of_work(0x1d00ffff));
+        let easy = BlockHeader::new(genesis.version(), genesis.prev_block, genesis.merkle_root(), genesis.timestamp(), 0x207fffff, 0);
+        assert!(!easy.check_proof_of_work(0x1d00ffff));
+    }
+
+    #[test]
+    fn huge_headers_count_is_rejected() {
+        // VarInt claiming 2001 headers, with no actual header data following it.
+        let payload = [0xFD, 0xD1, 0x07];
+
+        match HeadersPayload::decode(&mut &payload[..]) {
+            Err(NetworkError::OversizedMessage) => (),
+            other => panic!("Expected OversizedMessage, got {:?}", other),
+        }
+    }
 }
\ No newline at end of file
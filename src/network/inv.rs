@@ -1,39 +1,46 @@
-use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Read, Write};
 
-use network::NetworkError;
-use network::varint::VarInt;
+use network::{MAX_INV_COUNT, NetworkError};
+use network::encode::{decode_vec_with_limit, Decodable, Encodable};
 
-#[derive(Clone, Debug)]
+// Set on the type of inventory vectors asking for (or announcing) objects with their witness data (BIP144).
+const MSG_WITNESS_FLAG: u32 = 1 << 30;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum InventoryType {
     Error,
-    Msg_Tx,
-    Msg_Block,
-    Msg_Filtered_Block,
-    Msg_Cmpct_Block,
+    Tx,
+    Block,
+    FilteredBlock,
+    CmpctBlock,
+    // Transaction identified by its wtxid (BIP339).
+    Wtx,
+    // Newer types we don't know about are carried through, so they don't make the whole message invalid.
+    Unknown(u32),
 }
 
 impl InventoryType {
     pub fn value(&self) -> u32 {
         match *self {
             InventoryType::Error => 0,
-            InventoryType::Msg_Tx => 1,
-            InventoryType::Msg_Block => 2,
-            InventoryType::Msg_Filtered_Block => 3,
-            InventoryType::Msg_Cmpct_Block => 4,
+            InventoryType::Tx => 1,
+            InventoryType::Block => 2,
+            InventoryType::FilteredBlock => 3,
+            InventoryType::CmpctBlock => 4,
+            InventoryType::Wtx => 5,
+            InventoryType::Unknown(value) => value,
         }
     }
 
-    pub fn deserialize<R: Read>(reader: &mut R) -> Result<InventoryType, NetworkError> {
-        let object_type = reader.read_u32::<LittleEndian>()?;
-
-        match object_type {
-            0 => Ok(InventoryType::Error),
-            1 => Ok(InventoryType::Msg_Tx),
-            2 => Ok(InventoryType::Msg_Block),
-            3 => Ok(InventoryType::Msg_Filtered_Block),
-            4 => Ok(InventoryType::Msg_Cmpct_Block),
-            _ => Err(NetworkError::InvalidValue),
+    pub fn from_value(value: u32) -> InventoryType {
+        match value {
+            0 => InventoryType::Error,
+            1 => InventoryType::Tx,
+            2 => InventoryType::Block,
+            3 => InventoryType::FilteredBlock,
+            4 => InventoryType::CmpctBlock,
+            5 => InventoryType::Wtx,
+            _ => InventoryType::Unknown(value),
         }
     }
 }
@@ -41,28 +48,67 @@ impl InventoryType {
 #[derive(Clone)]
 pub struct InventoryVector {
     object_type: InventoryType,
+    witness: bool,
     hash: [u8; 32],
 }
 
 impl InventoryVector {
-    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
-        writer.write_u32::<LittleEndian>(self.object_type.value())?;
-        writer.write_all(&self.hash)?;
+    pub fn new(object_type: InventoryType, hash: [u8; 32]) -> InventoryVector {
+        InventoryVector {
+            object_type,
+            witness: false,
+            hash,
+        }
+    }
 
-        Ok(())
+    // Same as `new`, but for the object along with its witness data.
+    pub fn with_witness(object_type: InventoryType, hash: [u8; 32]) -> InventoryVector {
+        InventoryVector {
+            object_type,
+            witness: true,
+            hash,
+        }
+    }
+
+    pub fn object_type(&self) -> InventoryType {
+        self.object_type
+    }
+
+    pub fn witness(&self) -> bool {
+        self.witness
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        self.hash
+    }
+
+    // Type as sent over the network, including the witness flag.
+    pub fn type_value(&self) -> u32 {
+        if self.witness {
+            self.object_type.value() | MSG_WITNESS_FLAG
+        } else {
+            self.object_type.value()
+        }
     }
+}
+
+impl Encodable for InventoryVector {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        self.type_value().encode(writer)?;
+        self.hash.encode(writer)?;
 
-    pub fn length() -> usize {
-        36
+        Ok(())
     }
+}
 
-    pub fn deserialize<R: Read>(reader: &mut R) -> Result<InventoryVector, NetworkError> {
-        let object_type = InventoryType::deserialize(reader)?;
-        let mut hash = [0; 32];
-        reader.read_exact(&mut hash)?;
+impl Decodable for InventoryVector {
+    fn decode<R: Read>(reader: &mut R) -> Result<InventoryVector, NetworkError> {
+        let type_value = u32::decode(reader)?;
+        let hash = <[u8; 32]>::decode(reader)?;
 
         Ok(InventoryVector {
-            object_type,
+            object_type: InventoryType::from_value(type_value & !MSG_WITNESS_FLAG),
+            witness: type_value & MSG_WITNESS_FLAG != 0,
             hash,
         })
     }
@@ -79,45 +125,62 @@ fn byte_slice_as_hex(slice: &[u8]) -> String {
 impl ::std::fmt::Debug for InventoryVector {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         let reversed_hash = self.hash.iter().cloned().rev().collect::<Vec<u8>>();
-        write!(f, "InventoryVector {{ {:?}, hash: {} }}", self.object_type, byte_slice_as_hex(&reversed_hash))
+        write!(f, "InventoryVector {{ {:?}, witness: {}, hash: {} }}", self.object_type, self.witness, byte_slice_as_hex(&reversed_hash))
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct InvPayload {
-    count: VarInt,
     inventory: Vec<InventoryVector>,
 }
 
 impl InvPayload {
-    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
-        self.count.serialize(writer)?;
-
-        for object in self.inventory.iter() {
-            object.serialize(writer)?;
+    pub fn new(inventory: Vec<InventoryVector>) -> InvPayload {
+        InvPayload {
+            inventory,
         }
-
-        Ok(())
     }
 
-    pub fn length(&self) -> usize {
-        self.count.length() + self.inventory.len() * InventoryVector::length()
+    pub fn inventory(&self) -> &[InventoryVector] {
+        &self.inventory
     }
+}
 
-    pub fn deserialize<R: Read>(reader: &mut R) -> Result<InvPayload, NetworkError> {
-        let count = VarInt::deserialize(reader)?;
-        let length = count.value();
-
-        let mut inventory = vec![];
+impl Encodable for InvPayload {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        self.inventory.encode(writer)
+    }
+}
 
-        for _ in 0..length {
-            let curr_vector = InventoryVector::deserialize(reader)?;
-            inventory.push(curr_vector);
-        }
+impl Decodable for InvPayload {
+    fn decode<R: Read>(reader: &mut R) -> Result<InvPayload, NetworkError> {
+        let inventory = decode_vec_with_limit(reader, MAX_INV_COUNT)?;
 
         Ok(InvPayload {
-            count,
             inventory,
         })
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn witness_and_unknown_types() {
+        let mut bytes = vec![];
+        // MSG_WITNESS_TX, MSG_WTX and an unknown type.
+        for &type_value in [0x40000001u32, 5, 0x1234].iter() {
+            bytes.extend_from_slice(&type_value.to_le_bytes());
+            bytes.extend_from_slice(&[7u8; 32]);
+        }
+
+        let payload = InvPayload::decode(&mut &[&[3u8][..], &bytes[..]].concat()[..]).unwrap();
+        assert_eq!(payload.inventory().iter().map(|inv| (inv.object_type(), inv.witness())).collect::<Vec<(InventoryType, bool)>>(),
+            vec![(InventoryType::Tx, true), (InventoryType::Wtx, false), (InventoryType::Unknown(0x1234), false)]);
+
+        let mut encoded = vec![];
+        payload.encode(&mut encoded).unwrap();
+        assert_eq!(&encoded[1..], &bytes[..]);
+    }
 }
\ No newline at end of file
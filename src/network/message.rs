@@ -1,29 +1,29 @@
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Read, Write};
 
-use bitcoin::Network;
-use network::{Command, NetworkError, NetworkValue};
+use network::{Command, NetworkError};
 
 #[derive(Clone, Debug)]
 pub struct Message {
-    pub network: Network,
+    // Start string identifying the network the message belongs to.
+    pub magic: u32,
     pub command: Command,
     checksum: u32,
 }
 
 impl Message {
-    pub fn new(network: Network, command: Command) -> Message {
+    pub fn new(magic: u32, command: Command) -> Message {
         let checksum = command.checksum();
 
         Message {
-            network,
+            magic,
             command,
             checksum,
         }
     }
 
     pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
-        writer.write_u32::<LittleEndian>(self.network.network_value())?;
+        writer.write_u32::<LittleEndian>(self.magic)?;
         writer.write_all(&self.command.name_as_bytes())?;
         writer.write_u32::<LittleEndian>(self.command.length() as u32)?;
         // Internal byte order.
@@ -34,9 +34,9 @@ impl Message {
     }
 
     pub fn deserialize<R: Read>(reader: &mut R) -> Result<Message, NetworkError> {
-        let network = Network::from_u32(reader.read_u32::<LittleEndian>()?)?;
+        let magic = reader.read_u32::<LittleEndian>()?;
         let command = Command::deserialize(reader)?;
 
-        Ok(Message::new(network, command))
+        Ok(Message::new(magic, command))
     }
 }
\ No newline at end of file
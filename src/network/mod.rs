@@ -1,29 +1,41 @@
-use bitcoin::Network;
-
-mod addr;
+pub mod addr;
+pub mod block;
 pub mod blocks;
 pub mod cmpct;
 pub mod command;
+pub mod encode;
 pub mod headers;
-mod inv;
+pub mod inv;
 pub mod message;
 mod networkaddress;
+pub mod services;
+pub mod transaction;
 mod varint;
-mod varstring;
 pub mod version;
 
 pub use self::command::Command;
+pub use self::encode::{Decodable, Encodable};
 pub use self::message::Message;
+pub use self::networkaddress::NetworkAddress;
+pub use self::services::ServiceFlags;
+
+// Upper bounds for data received from peers. Anything bigger than these is rejected before we allocate memory for it.
+pub const MAX_PROTOCOL_MESSAGE_LENGTH: usize = 4 * 1000 * 1000;
+pub const MAX_ADDR_COUNT: usize = 1000;
+pub const MAX_INV_COUNT: usize = 50000;
+pub const MAX_HEADERS_COUNT: usize = 2000;
+pub const MAX_LOCATOR_HASHES: usize = 101;
 
 #[derive(Debug)]
 pub enum NetworkError {
     InvalidChecksum,
-    InvalidCommand(String),
     InvalidValue,
     MalformedUTF8String,
     NotEnoughData,
+    ObsoleteProtocolVersion(i32),
+    OversizedMessage,
     PeerClosedConnection,
-    UnknownNetworkIdentifier,
+    UnexpectedMessage(String),
     WrongNetwork,
 }
 
@@ -43,30 +55,4 @@ impl From<::std::string::FromUtf8Error> for NetworkError {
     fn from(_: ::std::string::FromUtf8Error) -> NetworkError {
         NetworkError::MalformedUTF8String
     }
-}
-
-trait NetworkValue {
-    fn network_value(&self) -> u32;
-    fn from_u32(value: u32) -> Result<Network, NetworkError>;
-}
-
-impl NetworkValue for Network {
-    fn network_value(&self) -> u32 {
-        match *self {
-            Network::Mainnet => 0xD9B4BEF9,
-            Network::Testnet => 0xDAB5BFFA,
-            Network::Testnet3 => 0x0709110B,
-            Network::Namecoin => 0xFEB4BEF9,
-        }
-    }
-
-    fn from_u32(value: u32) -> Result<Network, NetworkError> {
-        match value {
-            0xD9B4BEF9 => Ok(Network::Mainnet),
-            0xDAB5BFFA => Ok(Network::Testnet),
-            0x0709110B => Ok(Network::Testnet3),
-            0xFEB4BEF9 => Ok(Network::Namecoin),
-            _ => Err(NetworkError::UnknownNetworkIdentifier)
-        }
-    }
 }
\ No newline at end of file
@@ -1,8 +1,10 @@
-use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Read, Write};
 use std::net::{Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use network::NetworkError;
+use network::encode::{Decodable, Encodable};
+use network::services::ServiceFlags;
 
 fn socket_v6_to_v4(socket: SocketAddrV6) -> Option<SocketAddr> {
     let ip = socket.ip();
@@ -18,7 +20,7 @@ fn socket_v6_to_v4(socket: SocketAddrV6) -> Option<SocketAddr> {
 #[derive(Clone, Debug)]
 pub struct NetworkAddress {
     time: u32,
-    services: u64,
+    services: ServiceFlags,
     socket_addr: SocketAddrV6,
 }
 
@@ -26,11 +28,38 @@ impl NetworkAddress {
     pub fn new() -> NetworkAddress {
         NetworkAddress {
             time: 0,
-            services: 0,
+            services: ServiceFlags::NONE,
             socket_addr: SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 0, 0, 0),
         }
     }
 
+    pub fn from_socket_addr(addr: SocketAddr, services: ServiceFlags) -> NetworkAddress {
+        // The protocol only knows about IPv6 addresses, so IPv4 addresses are sent as IPv4-mapped IPv6 addresses.
+        let socket_addr = match addr {
+            SocketAddr::V4(v4) => SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0),
+            SocketAddr::V6(v6) => v6,
+        };
+
+        NetworkAddress {
+            time: 0,
+            services,
+            socket_addr,
+        }
+    }
+
+    // Last time the address was seen, as claimed by whoever sent it to us.
+    pub fn time(&self) -> u32 {
+        self.time
+    }
+
+    pub fn set_time(&mut self, time: u32) {
+        self.time = time;
+    }
+
+    pub fn services(&self) -> ServiceFlags {
+        self.services
+    }
+
     pub fn socket_addr(&self) -> SocketAddr {
         // If we have an IPv4-mapped IPv6 address, convert it back to IPv4.
         match socket_v6_to_v4(self.socket_addr) {
@@ -39,44 +68,44 @@ impl NetworkAddress {
         }
     }
 
-    pub fn length() -> usize {
-        30
+    pub fn encode_without_time<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        self.services.encode(writer)?;
+        self.socket_addr.ip().octets().encode(writer)?;
+        // Port is the only thing in the protocol using network byte order.
+        writer.write_u16::<BigEndian>(self.socket_addr.port())?;
+
+        Ok(())
     }
 
-    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
-        writer.write_u32::<LittleEndian>(self.time)?;
-        self.serialize_no_time(writer)?;
+    pub fn decode_without_time<R: Read>(reader: &mut R) -> Result<NetworkAddress, NetworkError> {
+        let services = ServiceFlags::decode(reader)?;
+        let ip = <[u8; 16]>::decode(reader)?;
+        let port = reader.read_u16::<BigEndian>()?;
 
-        Ok(())
+        Ok(NetworkAddress {
+            time: 0,
+            services,
+            socket_addr: SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0),
+        })
     }
+}
 
-    pub fn serialize_no_time<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
-        writer.write_u64::<LittleEndian>(self.services)?;
-        writer.write_all(&self.socket_addr.ip().octets())?;
-        writer.write_u16::<BigEndian>(self.socket_addr.port())?;
+impl Encodable for NetworkAddress {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        self.time.encode(writer)?;
+        self.encode_without_time(writer)?;
 
         Ok(())
     }
+}
 
-    pub fn deserialize<R: Read>(reader: &mut R) -> Result<NetworkAddress, NetworkError> {
-        let time = reader.read_u32::<LittleEndian>()?;
+impl Decodable for NetworkAddress {
+    fn decode<R: Read>(reader: &mut R) -> Result<NetworkAddress, NetworkError> {
+        let time = u32::decode(reader)?;
 
-        let mut addr_no_time = NetworkAddress::deserialize_no_time(reader)?;
+        let mut addr_no_time = NetworkAddress::decode_without_time(reader)?;
         addr_no_time.time = time;
 
         Ok(addr_no_time)
     }
-
-    pub fn deserialize_no_time<R: Read>(reader: &mut R) -> Result<NetworkAddress, NetworkError> {
-        let services = reader.read_u64::<LittleEndian>()?;
-        let mut ip = [0; 16];
-        reader.read_exact(&mut ip)?;
-        let port = reader.read_u16::<BigEndian>()?;
-
-        Ok(NetworkAddress {
-            time: 0,
-            services,
-            socket_addr: SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0),
-        })
-    }
 }
\ No newline at end of file
@@ -0,0 +1,135 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::ops::{BitAnd, BitOr, BitOrAssign};
+
+use network::NetworkError;
+use network::encode::{Decodable, Encodable};
+
+// Bit field of services a node advertises in its version message and in addr messages.
+#[derive(Clone, Copy, Default, Eq, Hash, PartialEq)]
+pub struct ServiceFlags(u64);
+
+impl ServiceFlags {
+    pub const NONE: ServiceFlags = ServiceFlags(0);
+    // Serves the full block chain.
+    pub const NETWORK: ServiceFlags = ServiceFlags(1 << 0);
+    // BIP111.
+    pub const BLOOM: ServiceFlags = ServiceFlags(1 << 2);
+    // BIP144.
+    pub const WITNESS: ServiceFlags = ServiceFlags(1 << 3);
+    // BIP157.
+    pub const COMPACT_FILTERS: ServiceFlags = ServiceFlags(1 << 6);
+    // BIP159: serves only the last 288 blocks.
+    pub const NETWORK_LIMITED: ServiceFlags = ServiceFlags(1 << 10);
+    // BIP324.
+    pub const P2P_V2: ServiceFlags = ServiceFlags(1 << 11);
+
+    pub fn from_bits(bits: u64) -> ServiceFlags {
+        ServiceFlags(bits)
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(&self, other: ServiceFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: ServiceFlags) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: ServiceFlags) {
+        self.0 &= !other.0;
+    }
+}
+
+impl BitOr for ServiceFlags {
+    type Output = ServiceFlags;
+
+    fn bitor(self, other: ServiceFlags) -> ServiceFlags {
+        ServiceFlags(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for ServiceFlags {
+    fn bitor_assign(&mut self, other: ServiceFlags) {
+        self.insert(other);
+    }
+}
+
+impl BitAnd for ServiceFlags {
+    type Output = ServiceFlags;
+
+    fn bitand(self, other: ServiceFlags) -> ServiceFlags {
+        ServiceFlags(self.0 & other.0)
+    }
+}
+
+const FLAG_NAMES: &[(ServiceFlags, &'static str)] = &[
+    (ServiceFlags::NETWORK, "NETWORK"),
+    (ServiceFlags::BLOOM, "BLOOM"),
+    (ServiceFlags::WITNESS, "WITNESS"),
+    (ServiceFlags::COMPACT_FILTERS, "COMPACT_FILTERS"),
+    (ServiceFlags::NETWORK_LIMITED, "NETWORK_LIMITED"),
+    (ServiceFlags::P2P_V2, "P2P_V2"),
+];
+
+impl fmt::Display for ServiceFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "NONE");
+        }
+
+        let mut remaining = *self;
+        let mut names = vec![];
+
+        for &(flag, name) in FLAG_NAMES.iter() {
+            if remaining.contains(flag) {
+                names.push(name.to_string());
+                remaining.remove(flag);
+            }
+        }
+
+        // Bits we don't know a name for are shown as they are.
+        if remaining.0 != 0 {
+            names.push(format!("0x{:x}", remaining.0));
+        }
+
+        write!(f, "{}", names.join("|"))
+    }
+}
+
+impl fmt::Debug for ServiceFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ServiceFlags({})", self)
+    }
+}
+
+impl Encodable for ServiceFlags {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        self.0.encode(writer)
+    }
+}
+
+impl Decodable for ServiceFlags {
+    fn decode<R: Read>(reader: &mut R) -> Result<ServiceFlags, NetworkError> {
+        Ok(ServiceFlags(u64::decode(reader)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_shows_known_and_unknown_bits() {
+        let flags = ServiceFlags::NETWORK | ServiceFlags::WITNESS | ServiceFlags::from_bits(1 << 24);
+
+        assert!(flags.contains(ServiceFlags::NETWORK | ServiceFlags::WITNESS));
+        assert!(!flags.contains(ServiceFlags::BLOOM));
+        assert_eq!(flags.to_string(), "NETWORK|WITNESS|0x1000000");
+        assert_eq!(ServiceFlags::NONE.to_string(), "NONE");
+    }
+}
\ No newline at end of file
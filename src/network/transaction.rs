@@ -0,0 +1,290 @@
+use std::io::{Read, Write};
+
+use network::{MAX_PROTOCOL_MESSAGE_LENGTH, NetworkError};
+use network::encode::{serialize, Decodable, Encodable};
+use network::varint::VarInt;
+use util::sha256d;
+
+// No amount of satoshis can be bigger than every bitcoin that will ever exist.
+pub const MAX_MONEY: i64 = 21_000_000 * 100_000_000;
+
+pub fn money_range(value: i64) -> bool {
+    (0..=MAX_MONEY).contains(&value)
+}
+
+// Lock times below this are block heights, and the ones from it onwards are timestamps.
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+// Inputs with this sequence don't enforce the transaction's lock time.
+pub const SEQUENCE_FINAL: u32 = 0xFFFFFFFF;
+// BIP68: relative lock times are disabled on inputs with this sequence bit set.
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+// BIP68: with this bit set, the relative lock time is in units of 512 seconds instead of blocks.
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000FFFF;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct OutPoint {
+    pub txid: [u8; 32],
+    pub vout: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: [u8; 32], vout: u32) -> OutPoint {
+        OutPoint {
+            txid,
+            vout,
+        }
+    }
+
+    // The outpoint used by coinbase inputs, which don't spend anything.
+    pub fn null() -> OutPoint {
+        OutPoint::new([0; 32], 0xFFFFFFFF)
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == OutPoint::null()
+    }
+}
+
+impl Encodable for OutPoint {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        self.txid.encode(writer)?;
+        self.vout.encode(writer)?;
+
+        Ok(())
+    }
+
+    fn encoded_length(&self) -> usize {
+        36
+    }
+}
+
+impl Decodable for OutPoint {
+    fn decode<R: Read>(reader: &mut R) -> Result<OutPoint, NetworkError> {
+        let txid = <[u8; 32]>::decode(reader)?;
+        let vout = u32::decode(reader)?;
+
+        Ok(OutPoint::new(txid, vout))
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TxIn {
+    pub previous_output: OutPoint,
+    pub script_sig: Vec<u8>,
+    pub sequence: u32,
+    // Not part of the input's own encoding: it goes after all outputs when the transaction is serialized with witness data.
+    pub witness: Vec<Vec<u8>>,
+}
+
+impl TxIn {
+    pub fn new(previous_output: OutPoint, script_sig: Vec<u8>, sequence: u32) -> TxIn {
+        TxIn {
+            previous_output,
+            script_sig,
+            sequence,
+            witness: vec![],
+        }
+    }
+}
+
+impl Encodable for TxIn {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        self.previous_output.encode(writer)?;
+        self.script_sig.encode(writer)?;
+        self.sequence.encode(writer)?;
+
+        Ok(())
+    }
+}
+
+impl Decodable for TxIn {
+    fn decode<R: Read>(reader: &mut R) -> Result<TxIn, NetworkError> {
+        let previous_output = OutPoint::decode(reader)?;
+        let script_sig = Vec::<u8>::decode(reader)?;
+        let sequence = u32::decode(reader)?;
+
+        Ok(TxIn::new(previous_output, script_sig, sequence))
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TxOut {
+    pub value: i64,
+    pub script_pubkey: Vec<u8>,
+}
+
+impl TxOut {
+    pub fn new(value: i64, script_pubkey: Vec<u8>) -> TxOut {
+        TxOut {
+            value,
+            script_pubkey,
+        }
+    }
+}
+
+impl Encodable for TxOut {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        self.value.encode(writer)?;
+        self.script_pubkey.encode(writer)?;
+
+        Ok(())
+    }
+}
+
+impl Decodable for TxOut {
+    fn decode<R: Read>(reader: &mut R) -> Result<TxOut, NetworkError> {
+        let value = i64::decode(reader)?;
+        let script_pubkey = Vec::<u8>::decode(reader)?;
+
+        Ok(TxOut::new(value, script_pubkey))
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Transaction {
+    pub version: i32,
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<TxOut>,
+    pub lock_time: u32,
+}
+
+impl Transaction {
+    pub fn new(version: i32, inputs: Vec<TxIn>, outputs: Vec<TxOut>, lock_time: u32) -> Transaction {
+        Transaction {
+            version,
+            inputs,
+            outputs,
+            lock_time,
+        }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].previous_output.is_null()
+    }
+
+    // Whether the transaction's lock time allows it into a block at `height` with a median time past of `time`.
+    pub fn is_final(&self, height: u32, time: u64) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+
+        let lock_time_passed = if self.lock_time < LOCKTIME_THRESHOLD {
+            self.lock_time < height
+        } else {
+            (self.lock_time as u64) < time
+        };
+
+        lock_time_passed || self.inputs.iter().all(|input| input.sequence == SEQUENCE_FINAL)
+    }
+
+    pub fn has_witness(&self) -> bool {
+        self.inputs.iter().any(|input| !input.witness.is_empty())
+    }
+
+    // Hash of the serialization without witness data.
+    pub fn txid(&self) -> [u8; 32] {
+        let mut bytes = vec![];
+        self.encode_with_witness(&mut bytes, false).expect("Writing to a Vec never fails");
+        sha256d(&bytes)
+    }
+
+    // BIP141: hash of the serialization including witness data. Same as the txid for transactions without witnesses.
+    pub fn wtxid(&self) -> [u8; 32] {
+        sha256d(&serialize(self))
+    }
+
+    pub fn encode_with_witness<W: Write>(&self, writer: &mut W, with_witness: bool) -> Result<(), NetworkError> {
+        self.version.encode(writer)?;
+
+        // BIP144: the marker and flag bytes can't be mistaken for an input count, since a transaction always has inputs.
+        if with_witness {
+            0u8.encode(writer)?;
+            1u8.encode(writer)?;
+        }
+
+        self.inputs.encode(writer)?;
+        self.outputs.encode(writer)?;
+
+        if with_witness {
+            for input in self.inputs.iter() {
+                input.witness.encode(writer)?;
+            }
+        }
+
+        self.lock_time.encode(writer)?;
+
+        Ok(())
+    }
+}
+
+impl Encodable for Transaction {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        self.encode_with_witness(writer, self.has_witness())
+    }
+}
+
+impl Decodable for Transaction {
+    fn decode<R: Read>(reader: &mut R) -> Result<Transaction, NetworkError> {
+        let version = i32::decode(reader)?;
+
+        let mut input_count = VarInt::decode(reader)?.value();
+        let mut with_witness = false;
+
+        if input_count == 0 {
+            // This is the BIP144 marker, so what follows is the flag and then the real input count.
+            if u8::decode(reader)? != 1 {
+                return Err(NetworkError::InvalidValue);
+            }
+
+            with_witness = true;
+            input_count = VarInt::decode(reader)?.value();
+        }
+
+        if input_count > MAX_PROTOCOL_MESSAGE_LENGTH as u64 {
+            return Err(NetworkError::OversizedMessage);
+        }
+
+        let mut inputs = vec![];
+        for _ in 0..input_count {
+            inputs.push(TxIn::decode(reader)?);
+        }
+
+        let outputs = Vec::<TxOut>::decode(reader)?;
+
+        if with_witness {
+            for input in inputs.iter_mut() {
+                input.witness = Vec::<Vec<u8>>::decode(reader)?;
+            }
+
+            // A transaction with the marker but no witness data has another valid serialization, so it's rejected.
+            if inputs.iter().all(|input| input.witness.is_empty()) {
+                return Err(NetworkError::InvalidValue);
+            }
+        }
+
+        let lock_time = u32::decode(reader)?;
+
+        Ok(Transaction::new(version, inputs, outputs, lock_time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hash_from_hex;
+    use hex::FromHex;
+
+    #[test]
+    fn segwit_transaction_roundtrip() {
+        // The BIP143 native P2WPKH example transaction, after being signed.
+        let bytes = Vec::from_hex("01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000").unwrap();
+
+        let tx = Transaction::decode(&mut &bytes[..]).unwrap();
+        assert_eq!(tx.inputs.len(), 2);
+        assert!(tx.inputs[0].witness.is_empty());
+        assert_eq!(tx.inputs[1].witness.len(), 2);
+        assert_eq!(serialize(&tx), bytes);
+        assert_eq!(tx.txid(), hash_from_hex("e8151a2af31c368a35053ddd4bdb285a8595c769a3ad83e0fa02314a602d4609"));
+    }
+}
\ No newline at end of file
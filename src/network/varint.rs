@@ -2,6 +2,7 @@ use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Read, Write};
 
 use network::NetworkError;
+use network::encode::{Decodable, Encodable};
 
 #[derive(Clone, Copy, Debug)]
 pub struct VarInt(u64);
@@ -14,17 +15,10 @@ impl VarInt {
     pub fn value(&self) -> u64 {
         self.0
     }
+}
 
-    pub fn length(&self) -> usize {
-        match self.0 {
-            0 ... 0xFC => 1,
-            0xFD ... 0xFFFF => 3,
-            0x1_0000 ... 0xFFFF_FFFF => 5,
-            _ => 9,
-        }
-    }
-
-    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+impl Encodable for VarInt {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
         match self.0 {
             0 ... 0xFC => {
                 writer.write_u8(self.0 as u8)?;
@@ -46,7 +40,18 @@ impl VarInt {
         Ok(())
     }
 
-    pub fn deserialize<R: Read>(reader: &mut R) -> Result<VarInt, NetworkError> {
+    fn encoded_length(&self) -> usize {
+        match self.0 {
+            0 ... 0xFC => 1,
+            0xFD ... 0xFFFF => 3,
+            0x1_0000 ... 0xFFFF_FFFF => 5,
+            _ => 9,
+        }
+    }
+}
+
+impl Decodable for VarInt {
+    fn decode<R: Read>(reader: &mut R) -> Result<VarInt, NetworkError> {
         let length_type = reader.read_u8()?;
         let value;
 
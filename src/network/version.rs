@@ -1,20 +1,75 @@
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Read, Write};
+use std::net::SocketAddr;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use network::NetworkError;
+use network::encode::{Decodable, Encodable};
 use network::networkaddress::NetworkAddress;
-use network::varstring::VarString;
+use network::services::ServiceFlags;
+
+// Version we speak. When talking to a peer, the version actually used is the lowest of this and the peer's version.
+pub const PROTOCOL_VERSION: i32 = 70016;
+// Anything older than this doesn't know about `getheaders`, which we depend on.
+pub const MIN_PEER_PROTOCOL_VERSION: i32 = 31800;
+
+pub const BIP0031_VERSION: i32 = 60000;
+pub const SENDHEADERS_VERSION: i32 = 70012;
+pub const FEEFILTER_VERSION: i32 = 70013;
+pub const SHORT_IDS_BLOCKS_VERSION: i32 = 70014;
+pub const WTXID_RELAY_VERSION: i32 = 70016;
+
+// Protocol features available when talking at a given (negotiated) version.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ProtocolFeatures {
+    // BIP31: pings carry a nonce and are answered with a pong.
+    pub pong: bool,
+    // BIP130.
+    pub sendheaders: bool,
+    // BIP133.
+    pub feefilter: bool,
+    // BIP152.
+    pub compact_blocks: bool,
+    // BIP339.
+    pub wtxidrelay: bool,
+}
+
+impl ProtocolFeatures {
+    pub fn for_version(version: i32) -> ProtocolFeatures {
+        ProtocolFeatures {
+            pong: version > BIP0031_VERSION,
+            sendheaders: version >= SENDHEADERS_VERSION,
+            feefilter: version >= FEEFILTER_VERSION,
+            compact_blocks: version >= SHORT_IDS_BLOCKS_VERSION,
+            wtxidrelay: version >= WTXID_RELAY_VERSION,
+        }
+    }
+}
+
+// BIP14 limits the user agent to 256 bytes.
+pub const MAX_USER_AGENT_LENGTH: usize = 256;
+
+// Builds a BIP14 user agent, e.g. "/Kaliko:0.1.0(comment)/".
+pub fn format_user_agent(name: &str, version: &str, comments: &[String]) -> String {
+    if comments.is_empty() {
+        format!("/{}:{}/", name, version)
+    } else {
+        format!("/{}:{}({})/", name, version, comments.join("; "))
+    }
+}
+
+pub fn default_user_agent() -> String {
+    format_user_agent("Kaliko", env!("CARGO_PKG_VERSION"), &[])
+}
 
 #[derive(Clone, Debug)]
 pub struct VersionPayload {
     version: i32,
-    services: u64,
+    services: ServiceFlags,
     timestamp: i64,
     addr_recv: NetworkAddress,
     addr_from: NetworkAddress,
     nonce: u64,
-    user_agent: VarString,
+    user_agent: String,
     start_height: i32,
     relay: bool,
 }
@@ -24,39 +79,152 @@ impl VersionPayload {
         self.version
     }
 
+    pub fn services(&self) -> ServiceFlags {
+        self.services
+    }
+
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub fn addr_recv(&self) -> &NetworkAddress {
+        &self.addr_recv
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+
     pub fn start_height(&self) -> i32 {
         self.start_height
     }
-    
-    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
-        writer.write_i32::<LittleEndian>(self.version)?;
-        writer.write_u64::<LittleEndian>(self.services)?;
-        writer.write_i64::<LittleEndian>(self.timestamp)?;
 
-        self.addr_recv.serialize_no_time(writer)?;
-        self.addr_from.serialize_no_time(writer)?;
+    pub fn relay(&self) -> bool {
+        self.relay
+    }
 
-        writer.write_u64::<LittleEndian>(self.nonce)?;
+    pub fn new(nonce: u64) -> VersionPayload {
+        VersionPayloadBuilder::new(nonce).build()
+    }
+}
 
-        self.user_agent.serialize(writer)?;
+pub struct VersionPayloadBuilder {
+    payload: VersionPayload,
+}
 
-        writer.write_i32::<LittleEndian>(self.start_height)?;
-        writer.write_u8(self.relay as u8)?;
+impl VersionPayloadBuilder {
+    pub fn new(nonce: u64) -> VersionPayloadBuilder {
+        let time = SystemTime::now();
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        VersionPayloadBuilder {
+            payload: VersionPayload {
+                version: PROTOCOL_VERSION,
+                services: ServiceFlags::NONE,
+                timestamp: since_epoch as i64,
+                addr_recv: NetworkAddress::new(),
+                addr_from: NetworkAddress::new(),
+                nonce,
+                user_agent: String::new(),
+                start_height: 0,
+                relay: false,
+            },
+        }
+    }
+
+    pub fn services(mut self, services: ServiceFlags) -> VersionPayloadBuilder {
+        self.payload.services = services;
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: i64) -> VersionPayloadBuilder {
+        self.payload.timestamp = timestamp;
+        self
+    }
+
+    // Address of the peer we're sending the version to, along with the services we believe it has.
+    pub fn receiver(mut self, addr: SocketAddr, services: ServiceFlags) -> VersionPayloadBuilder {
+        self.payload.addr_recv = NetworkAddress::from_socket_addr(addr, services);
+        self
+    }
+
+    pub fn sender(mut self, addr: SocketAddr, services: ServiceFlags) -> VersionPayloadBuilder {
+        self.payload.addr_from = NetworkAddress::from_socket_addr(addr, services);
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> VersionPayloadBuilder {
+        let mut user_agent = user_agent.to_string();
+
+        // Truncating on a char boundary so we always send valid UTF-8.
+        while user_agent.len() > MAX_USER_AGENT_LENGTH {
+            user_agent.pop();
+        }
+
+        self.payload.user_agent = user_agent;
+        self
+    }
+
+    pub fn start_height(mut self, start_height: i32) -> VersionPayloadBuilder {
+        self.payload.start_height = start_height;
+        self
+    }
+
+    pub fn relay(mut self, relay: bool) -> VersionPayloadBuilder {
+        self.payload.relay = relay;
+        self
+    }
+
+    pub fn build(self) -> VersionPayload {
+        self.payload
+    }
+}
+
+impl Encodable for VersionPayload {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), NetworkError> {
+        self.version.encode(writer)?;
+        self.services.encode(writer)?;
+        self.timestamp.encode(writer)?;
+
+        self.addr_recv.encode_without_time(writer)?;
+        self.addr_from.encode_without_time(writer)?;
+
+        self.nonce.encode(writer)?;
+
+        self.user_agent.encode(writer)?;
+
+        self.start_height.encode(writer)?;
+        self.relay.encode(writer)?;
 
         Ok(())
     }
+}
+
+impl Decodable for VersionPayload {
+    fn decode<R: Read>(reader: &mut R) -> Result<VersionPayload, NetworkError> {
+        let version = i32::decode(reader)?;
+        let services = ServiceFlags::decode(reader)?;
+        let timestamp = i64::decode(reader)?;
+
+        let addr_recv = NetworkAddress::decode_without_time(reader)?;
+        let addr_from = NetworkAddress::decode_without_time(reader)?;
+        let nonce = u64::decode(reader)?;
+        let user_agent = String::decode(reader)?;
+
+        if user_agent.len() > MAX_USER_AGENT_LENGTH {
+            return Err(NetworkError::OversizedMessage);
+        }
 
-    pub fn deserialize<R: Read>(reader: &mut R) -> Result<VersionPayload, NetworkError> {
-        let version = reader.read_i32::<LittleEndian>()?;
-        let services = reader.read_u64::<LittleEndian>()?;
-        let timestamp = reader.read_i64::<LittleEndian>()?;
+        let start_height = i32::decode(reader)?;
 
-        let addr_recv = NetworkAddress::deserialize_no_time(reader)?;
-        let addr_from = NetworkAddress::deserialize_no_time(reader)?;
-        let nonce = reader.read_u64::<LittleEndian>()?;
-        let user_agent = VarString::deserialize(reader)?;
-        let start_height = reader.read_i32::<LittleEndian>()?;
-        let relay = reader.read_u8()? == 1;
+        // The relay flag is optional (older peers don't send it), and when missing it means the peer wants transactions relayed.
+        let mut relay = [1u8];
+        reader.read(&mut relay)?;
+        let relay = relay[0] != 0;
 
         Ok(VersionPayload{
             version,
@@ -70,28 +238,60 @@ impl VersionPayload {
             relay,
         })
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use network::encode::serialize;
 
-    pub fn length() -> usize {
-        86
+    #[test]
+    fn length_accounts_for_user_agent() {
+        let version = VersionPayload::new(0);
+        assert_eq!(version.encoded_length(), 86);
+
+        let version = VersionPayloadBuilder::new(0).user_agent("/Satoshi:0.16.0/").build();
+        assert_eq!(version.encoded_length(), 86 + 16);
+        assert_eq!(serialize(&version).len(), version.encoded_length());
     }
 
-    pub fn new(nonce: u64) -> VersionPayload {
-        let time = SystemTime::now();
-        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    #[test]
+    fn builder_fills_in_fields() {
+        let peer: SocketAddr = "203.0.113.5:8333".parse().unwrap();
+        let comments = vec![String::from("testing")];
+        let version = VersionPayloadBuilder::new(42)
+            .services(ServiceFlags::WITNESS)
+            .receiver(peer, ServiceFlags::NETWORK)
+            .user_agent(&format_user_agent("Kaliko", "0.1.0", &comments))
+            .start_height(1000)
+            .relay(true)
+            .build();
 
-        let recipient = NetworkAddress::new();
-        let origin = NetworkAddress::new();
+        let decoded = VersionPayload::decode(&mut &serialize(&version)[..]).unwrap();
+        assert_eq!(decoded.services(), ServiceFlags::WITNESS);
+        assert_eq!(decoded.addr_recv().socket_addr(), peer);
+        assert_eq!(decoded.addr_recv().services(), ServiceFlags::NETWORK);
+        assert_eq!(decoded.user_agent(), "/Kaliko:0.1.0(testing)/");
+        assert_eq!(decoded.start_height(), 1000);
+        assert_eq!(decoded.nonce(), 42);
+        assert!(decoded.relay());
+    }
 
-        VersionPayload {
-            version: 70015,
-            services: 0,
-            timestamp: since_epoch as i64,
-            addr_recv: recipient,
-            addr_from: origin,
-            nonce,
-            user_agent: VarString::new(),
-            start_height: 0,
-            relay: false,
-        }
+    #[test]
+    fn missing_relay_flag_means_relay() {
+        let version = VersionPayload::new(0);
+        let mut bytes = serialize(&version);
+        bytes.pop();
+
+        assert!(VersionPayload::decode(&mut &bytes[..]).unwrap().relay);
+    }
+
+    #[test]
+    fn features_follow_version() {
+        let features = ProtocolFeatures::for_version(70015);
+        assert!(features.sendheaders && features.feefilter && features.compact_blocks);
+        assert!(!features.wtxidrelay);
+
+        assert_eq!(ProtocolFeatures::for_version(MIN_PEER_PROTOCOL_VERSION), ProtocolFeatures::default());
     }
 }
\ No newline at end of file
@@ -0,0 +1,156 @@
+use network::{MAX_ADDR_COUNT, NetworkAddress};
+use rand;
+use rand::Rng;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Addresses a peer can send us are limited by a token bucket, refilled at this rate per second. This keeps a single peer from flooding our address manager.
+const ADDR_TOKENS_PER_SECOND: f64 = 0.1;
+const MAX_ADDR_TOKENS: f64 = MAX_ADDR_COUNT as f64;
+// Addresses queued for a peer are sent after a random delay averaging this many seconds, so it's harder to tell where an address came from.
+const AVG_ADDR_SEND_INTERVAL: f64 = 30.0;
+// How many addresses we remember having exchanged with a peer, to avoid sending them back.
+const MAX_KNOWN_ADDRESSES: usize = 5000;
+
+// Random delay with an exponential distribution averaging `average` seconds, so the times of events form a Poisson process.
+pub fn poisson_delay(average: f64) -> Duration {
+    let uniform = rand::thread_rng().gen_range(f64::MIN_POSITIVE, 1.0);
+    Duration::from_millis((-uniform.ln() * average * 1000.0) as u64)
+}
+
+// Address relay state of a single peer.
+pub struct AddrRelay {
+    tokens: f64,
+    tokens_updated: Instant,
+    to_send: Vec<NetworkAddress>,
+    known: HashSet<SocketAddr>,
+    next_send: Instant,
+    pub sent_getaddr: bool,
+    pub answered_getaddr: bool,
+}
+
+impl AddrRelay {
+    pub fn new() -> AddrRelay {
+        AddrRelay {
+            // Enough for the peer to announce its own address.
+            tokens: 1.0,
+            tokens_updated: Instant::now(),
+            to_send: vec![],
+            known: HashSet::new(),
+            next_send: Instant::now() + poisson_delay(AVG_ADDR_SEND_INTERVAL),
+            sent_getaddr: false,
+            answered_getaddr: false,
+        }
+    }
+
+    // Lets the peer send `count` more addresses, e.g. because we asked it for addresses.
+    pub fn add_tokens(&mut self, count: usize) {
+        self.tokens += count as f64;
+    }
+
+    // Returns whether the peer still had a token for processing one more address.
+    pub fn take_token(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.tokens_updated);
+        let elapsed_seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens_updated = now;
+
+        if self.tokens < MAX_ADDR_TOKENS {
+            self.tokens = (self.tokens + elapsed_seconds * ADDR_TOKENS_PER_SECOND).min(MAX_ADDR_TOKENS);
+        }
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+
+    // Remembers that the peer knows about `addr`, so we don't send it back.
+    pub fn add_known(&mut self, addr: SocketAddr) {
+        if self.known.len() >= MAX_KNOWN_ADDRESSES {
+            self.known.clear();
+        }
+
+        self.known.insert(addr);
+    }
+
+    // Queues `addr` to be sent to the peer, unless it already knows it. Once the queue is full, a random queued address gets replaced.
+    pub fn push(&mut self, addr: NetworkAddress) {
+        let socket_addr = addr.socket_addr();
+        if self.known.contains(&socket_addr) {
+            return;
+        }
+
+        self.add_known(socket_addr);
+
+        if self.to_send.len() >= MAX_ADDR_COUNT {
+            let index = rand::thread_rng().gen_range(0, self.to_send.len());
+            self.to_send[index] = addr;
+        } else {
+            self.to_send.push(addr);
+        }
+    }
+
+    // Returns the queued addresses once it's time to send them.
+    pub fn flush(&mut self, now: Instant) -> Option<Vec<NetworkAddress>> {
+        if now < self.next_send {
+            return None;
+        }
+
+        self.next_send = now + poisson_delay(AVG_ADDR_SEND_INTERVAL);
+
+        if self.to_send.is_empty() {
+            return None;
+        }
+
+        Some(self.to_send.drain(..).collect())
+    }
+}
+
+impl Default for AddrRelay {
+    fn default() -> AddrRelay {
+        AddrRelay::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use network::ServiceFlags;
+
+    #[test]
+    fn token_bucket_limits_addresses() {
+        let mut relay = AddrRelay::new();
+        let start = relay.tokens_updated;
+
+        assert!(relay.take_token(start));
+        assert!(!relay.take_token(start));
+
+        // One token every ten seconds.
+        assert!(relay.take_token(start + Duration::from_secs(10)));
+        assert!(!relay.take_token(start + Duration::from_secs(10)));
+
+        relay.add_tokens(2);
+        assert!(relay.take_token(start + Duration::from_secs(10)));
+        assert!(relay.take_token(start + Duration::from_secs(10)));
+        assert!(!relay.take_token(start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn known_addresses_are_not_sent_back() {
+        let mut relay = AddrRelay::new();
+        let known: SocketAddr = "1.2.3.4:8333".parse().unwrap();
+        let unknown: SocketAddr = "5.6.7.8:8333".parse().unwrap();
+
+        relay.add_known(known);
+        relay.push(NetworkAddress::from_socket_addr(known, ServiceFlags::NETWORK));
+        relay.push(NetworkAddress::from_socket_addr(unknown, ServiceFlags::NETWORK));
+        relay.push(NetworkAddress::from_socket_addr(unknown, ServiceFlags::NETWORK));
+
+        let sent = relay.flush(Instant::now() + Duration::from_secs(3600)).unwrap();
+        assert_eq!(sent.iter().map(|addr| addr.socket_addr()).collect::<Vec<SocketAddr>>(), vec![unknown]);
+        assert!(relay.flush(Instant::now() + Duration::from_secs(7200)).is_none());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,478 @@
+use byteorder::{ByteOrder, LittleEndian};
+use hex;
+use hex::FromHex;
+use network::ServiceFlags;
+use peer::netgroup::netgroup;
+use rand;
+use rand::Rng;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use toml;
+use util::{sha256d, write_file_atomically};
+
+// Sizes of the tables. Addresses are spread over buckets depending on their netgroup, so a single operator can only fill a few buckets.
+const NEW_BUCKET_COUNT: usize = 1024;
+const TRIED_BUCKET_COUNT: usize = 256;
+const BUCKET_SIZE: usize = 64;
+// How many buckets addresses from a single source group can end up in.
+const NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 64;
+// How many buckets addresses from a single group can end up in.
+const TRIED_BUCKETS_PER_GROUP: u64 = 8;
+
+// After this long without hearing about an address, it isn't worth keeping.
+const HORIZON: u64 = 30 * 24 * 60 * 60;
+// Addresses never connected to are given up on after this many attempts.
+const RETRIES: u32 = 3;
+// Addresses which did work are given up on after this many failures over at least `MIN_FAIL_TIME`.
+const MAX_FAILURES: u32 = 10;
+const MIN_FAIL_TIME: u64 = 7 * 24 * 60 * 60;
+// Connected peers only have their last seen time updated this often.
+const CONNECTED_UPDATE_INTERVAL: u64 = 20 * 60;
+
+#[derive(Clone, Debug)]
+pub struct AddressInfo {
+    pub addr: SocketAddr,
+    pub services: ServiceFlags,
+    // Who told us about this address.
+    pub source: IpAddr,
+    pub last_seen: u64,
+    pub last_try: u64,
+    pub last_success: u64,
+    // Attempts since the last successful connection.
+    pub attempts: u32,
+    pub in_tried: bool,
+}
+
+impl AddressInfo {
+    // Addresses not worth keeping around when there's something better to put in their place.
+    fn is_terrible(&self, now: u64) -> bool {
+        // Never remove an address we just tried.
+        if self.last_try != 0 && now - self.last_try.min(now) < 60 {
+            return false;
+        }
+
+        // Coming from the future.
+        if self.last_seen > now.saturating_add(10 * 60) {
+            return true;
+        }
+
+        if self.last_seen == 0 || now.saturating_sub(self.last_seen) > HORIZON {
+            return true;
+        }
+
+        if self.last_success == 0 && self.attempts >= RETRIES {
+            return true;
+        }
+
+        if now - self.last_success.min(now) > MIN_FAIL_TIME && self.attempts >= MAX_FAILURES {
+            return true;
+        }
+
+        false
+    }
+
+    // Relative chance of picking this address when selecting one to connect to.
+    fn chance(&self, now: u64) -> f64 {
+        let mut chance = 1.0;
+
+        // Deprioritize very recent attempts.
+        if now - self.last_try.min(now) < 10 * 60 {
+            chance *= 0.01;
+        }
+
+        // Deprioritize 66% after each failed attempt, but at most 1/28th.
+        chance * 0.66f64.powi(self.attempts.min(8) as i32)
+    }
+}
+
+// How addresses are kept on disk.
+#[derive(Deserialize, Serialize)]
+struct SavedAddress {
+    addr: String,
+    services: u64,
+    source: String,
+    last_seen: u64,
+    last_try: u64,
+    last_success: u64,
+    attempts: u32,
+    tried: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SavedAddressManager {
+    key: String,
+    addresses: Vec<SavedAddress>,
+}
+
+// Keeps the addresses of peers we know about, in the same spirit as bitcoind's addrman. Addresses start in the "new" table, and move to the "tried" table once we successfully connect to them.
+pub struct AddressManager {
+    // Secret used to decide the buckets, so others can't predict where their addresses will go.
+    key: [u8; 32],
+    addresses: HashMap<SocketAddr, AddressInfo>,
+    // (bucket, position) -> address.
+    new_table: HashMap<(usize, usize), SocketAddr>,
+    tried_table: HashMap<(usize, usize), SocketAddr>,
+}
+
+impl AddressManager {
+    pub fn new() -> AddressManager {
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+
+        AddressManager::with_key(key)
+    }
+
+    fn with_key(key: [u8; 32]) -> AddressManager {
+        AddressManager {
+            key,
+            addresses: HashMap::new(),
+            new_table: HashMap::new(),
+            tried_table: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    pub fn new_count(&self) -> usize {
+        self.new_table.len()
+    }
+
+    pub fn tried_count(&self) -> usize {
+        self.tried_table.len()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&AddressInfo> {
+        self.addresses.get(addr)
+    }
+
+    fn hash(&self, data: &[&[u8]]) -> u64 {
+        let mut bytes = self.key.to_vec();
+        for item in data {
+            bytes.extend_from_slice(item);
+        }
+
+        LittleEndian::read_u64(&sha256d(&bytes)[..8])
+    }
+
+    fn addr_bytes(addr: &SocketAddr) -> Vec<u8> {
+        addr.to_string().into_bytes()
+    }
+
+    fn new_position(&self, addr: &SocketAddr, source: &IpAddr) -> (usize, usize) {
+        let group = netgroup(&addr.ip());
+        let source_group = netgroup(source);
+
+        let bucket_in_source = self.hash(&[&group, &source_group]) % NEW_BUCKETS_PER_SOURCE_GROUP;
+        let mut bucket_bytes = [0u8; 8];
+        LittleEndian::write_u64(&mut bucket_bytes, bucket_in_source);
+        let bucket = (self.hash(&[&source_group, &bucket_bytes]) % NEW_BUCKET_COUNT as u64) as usize;
+
+        (bucket, self.position(b"N", bucket, addr))
+    }
+
+    fn tried_position(&self, addr: &SocketAddr) -> (usize, usize) {
+        let group = netgroup(&addr.ip());
+
+        let bucket_in_group = self.hash(&[&AddressManager::addr_bytes(addr)]) % TRIED_BUCKETS_PER_GROUP;
+        let mut bucket_bytes = [0u8; 8];
+        LittleEndian::write_u64(&mut bucket_bytes, bucket_in_group);
+        let bucket = (self.hash(&[&group, &bucket_bytes]) % TRIED_BUCKET_COUNT as u64) as usize;
+
+        (bucket, self.position(b"T", bucket, addr))
+    }
+
+    fn position(&self, table: &[u8], bucket: usize, addr: &SocketAddr) -> usize {
+        let mut bucket_bytes = [0u8; 8];
+        LittleEndian::write_u64(&mut bucket_bytes, bucket as u64);
+
+        (self.hash(&[table, &bucket_bytes, &AddressManager::addr_bytes(addr)]) % BUCKET_SIZE as u64) as usize
+    }
+
+    // Adds an address we heard about from `source`. Returns whether it was new to us.
+    pub fn add(&mut self, addr: SocketAddr, services: ServiceFlags, source: IpAddr, last_seen: u64, now: u64) -> bool {
+        // Don't believe times in the future.
+        let last_seen = last_seen.min(now);
+
+        if let Some(info) = self.addresses.get_mut(&addr) {
+            if last_seen > info.last_seen {
+                info.last_seen = last_seen;
+            }
+            info.services.insert(services);
+            return false;
+        }
+
+        let position = self.new_position(&addr, &source);
+
+        if let Some(existing) = self.new_table.get(&position).cloned() {
+            // Only replace what's there if it's not worth keeping.
+            if !self.addresses[&existing].is_terrible(now) {
+                return false;
+            }
+
+            self.addresses.remove(&existing);
+        }
+
+        self.new_table.insert(position, addr);
+        self.addresses.insert(addr, AddressInfo {
+            addr,
+            services,
+            source,
+            last_seen,
+            last_try: 0,
+            last_success: 0,
+            attempts: 0,
+            in_tried: false,
+        });
+
+        true
+    }
+
+    // We're about to try connecting to `addr`.
+    pub fn attempt(&mut self, addr: &SocketAddr, now: u64) {
+        if let Some(info) = self.addresses.get_mut(addr) {
+            info.last_try = now;
+            info.attempts += 1;
+        }
+    }
+
+    // We successfully connected to `addr`, so it goes to the tried table.
+    pub fn good(&mut self, addr: &SocketAddr, now: u64) {
+        let source = {
+            let info = match self.addresses.get_mut(addr) {
+                Some(info) => info,
+                None => return,
+            };
+
+            info.last_seen = now;
+            info.last_try = now;
+            info.last_success = now;
+            info.attempts = 0;
+
+            if info.in_tried {
+                return;
+            }
+
+            info.in_tried = true;
+            info.source
+        };
+
+        let new_position = self.new_position(addr, &source);
+        if self.new_table.get(&new_position) == Some(addr) {
+            self.new_table.remove(&new_position);
+        }
+
+        let tried_position = self.tried_position(addr);
+
+        // Whatever is in the way goes back to the new table, so it's not lost.
+        if let Some(evicted) = self.tried_table.insert(tried_position, *addr) {
+            let evicted_source = {
+                let info = self.addresses.get_mut(&evicted).expect("Addresses in the tables are always known");
+                info.in_tried = false;
+                info.source
+            };
+
+            let position = self.new_position(&evicted, &evicted_source);
+            if let Some(replaced) = self.new_table.insert(position, evicted) {
+                self.addresses.remove(&replaced);
+            }
+        }
+    }
+
+    // We're connected to `addr`, so it's still around.
+    pub fn connected(&mut self, addr: &SocketAddr, now: u64) {
+        if let Some(info) = self.addresses.get_mut(addr) {
+            if now - info.last_seen.min(now) > CONNECTED_UPDATE_INTERVAL {
+                info.last_seen = now;
+            }
+        }
+    }
+
+    // Picks an address to connect to. Tried and new addresses are equally likely to be picked unless `new_only` is set, and within each table, addresses that failed or were tried recently are less likely to be picked.
+    pub fn select(&self, new_only: bool, now: u64) -> Option<AddressInfo> {
+        if self.addresses.is_empty() || (new_only && self.new_table.is_empty()) {
+            return None;
+        }
+
+        let mut rng = rand::thread_rng();
+        let use_tried = !new_only && !self.tried_table.is_empty() && (self.new_table.is_empty() || rng.gen::<bool>());
+        let table = if use_tried { &self.tried_table } else { &self.new_table };
+
+        let mut chance_factor = 1.0;
+        loop {
+            let index = rng.gen_range(0, table.len());
+            let addr = table.values().nth(index).expect("Index is always within the table");
+            let info = &self.addresses[addr];
+
+            if rng.gen::<f64>() < chance_factor * info.chance(now) {
+                return Some(info.clone());
+            }
+
+            chance_factor *= 1.2;
+        }
+    }
+
+    // Returns up to `max_count` random addresses, skipping the ones not worth sharing.
+    pub fn addresses(&self, max_count: usize, now: u64) -> Vec<AddressInfo> {
+        let mut result = self.addresses.values().filter(|info| !info.is_terrible(now)).cloned().collect::<Vec<AddressInfo>>();
+        rand::thread_rng().shuffle(&mut result);
+        result.truncate(max_count);
+        result
+    }
+
+    pub fn load(path: &Path) -> Result<AddressManager, String> {
+        let mut contents = String::new();
+        File::open(path).and_then(|mut file| file.read_to_string(&mut contents)).map_err(|e| e.to_string())?;
+
+        let saved: SavedAddressManager = toml::from_str(&contents).map_err(|e| e.to_string())?;
+        let key = Vec::from_hex(&saved.key).map_err(|e| e.to_string())?;
+        if key.len() != 32 {
+            return Err(String::from("Invalid key"));
+        }
+
+        let mut result = AddressManager::with_key([0; 32]);
+        result.key.copy_from_slice(&key);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        // Tried addresses go first, so they're not pushed out of the new table by addresses that were only heard of.
+        let mut saved_addresses = saved.addresses;
+        saved_addresses.sort_by_key(|saved| !saved.tried);
+
+        for saved in saved_addresses {
+            let (addr, source) = match (saved.addr.parse::<SocketAddr>(), saved.source.parse::<IpAddr>()) {
+                (Ok(addr), Ok(source)) => (addr, source),
+                _ => continue,
+            };
+
+            result.add(addr, ServiceFlags::from_bits(saved.services), source, saved.last_seen, now);
+
+            if saved.tried {
+                result.good(&addr, saved.last_success);
+            }
+
+            if let Some(info) = result.addresses.get_mut(&addr) {
+                // A clock that went backwards since saving shouldn't leave addresses seen in the future.
+                info.last_seen = saved.last_seen.min(now);
+                info.last_try = saved.last_try;
+                info.last_success = saved.last_success;
+                info.attempts = saved.attempts;
+            }
+        }
+
+        Ok(result)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let saved = SavedAddressManager {
+            key: hex::encode(self.key),
+            addresses: self.addresses.values().map(|info| SavedAddress {
+                addr: info.addr.to_string(),
+                services: info.services.bits(),
+                source: info.source.to_string(),
+                last_seen: info.last_seen,
+                last_try: info.last_try,
+                last_success: info.last_success,
+                attempts: info.attempts,
+                tried: info.in_tried,
+            }).collect(),
+        };
+
+        let contents = toml::to_string(&saved).map_err(|e| e.to_string())?;
+        write_file_atomically(path, contents.as_bytes())
+    }
+}
+
+impl Default for AddressManager {
+    fn default() -> AddressManager {
+        AddressManager::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::save_then_load;
+
+    const NOW: u64 = 1_600_000_000;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn good_addresses_move_to_tried() {
+        let mut manager = AddressManager::new();
+        let source: IpAddr = "8.8.8.8".parse().unwrap();
+
+        assert!(manager.add(addr("1.2.3.4:8333"), ServiceFlags::NETWORK, source, NOW, NOW));
+        assert!(!manager.add(addr("1.2.3.4:8333"), ServiceFlags::WITNESS, source, NOW, NOW));
+        assert!(manager.add(addr("5.6.7.8:8333"), ServiceFlags::NETWORK, source, NOW, NOW));
+        assert_eq!((manager.new_count(), manager.tried_count()), (2, 0));
+        assert_eq!(manager.get(&addr("1.2.3.4:8333")).unwrap().services, ServiceFlags::NETWORK | ServiceFlags::WITNESS);
+
+        manager.attempt(&addr("1.2.3.4:8333"), NOW);
+        manager.good(&addr("1.2.3.4:8333"), NOW);
+        assert_eq!((manager.new_count(), manager.tried_count()), (1, 1));
+        assert_eq!(manager.get(&addr("1.2.3.4:8333")).unwrap().attempts, 0);
+
+        // Both tables have something, so either address can be picked.
+        for _ in 0..10 {
+            let selected = manager.select(false, NOW).unwrap().addr;
+            assert!(selected == addr("1.2.3.4:8333") || selected == addr("5.6.7.8:8333"));
+        }
+
+        assert_eq!(manager.select(true, NOW).unwrap().addr, addr("5.6.7.8:8333"));
+    }
+
+    #[test]
+    fn terrible_addresses_are_replaced() {
+        let info = AddressInfo {
+            addr: addr("1.2.3.4:8333"),
+            services: ServiceFlags::NONE,
+            source: "8.8.8.8".parse().unwrap(),
+            last_seen: NOW - 60,
+            last_try: NOW - 3600,
+            last_success: 0,
+            attempts: RETRIES,
+            in_tried: false,
+        };
+        assert!(info.is_terrible(NOW));
+
+        let old = AddressInfo { attempts: 0, last_seen: NOW - HORIZON - 1, ..info.clone() };
+        assert!(old.is_terrible(NOW));
+
+        let fine = AddressInfo { attempts: 1, ..info.clone() };
+        assert!(!fine.is_terrible(NOW));
+
+        // Seen a bit in the future, which is still allowed.
+        let ahead = AddressInfo { attempts: 0, last_seen: NOW + 60, ..info };
+        assert!(!ahead.is_terrible(NOW));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut manager = AddressManager::new();
+        let source: IpAddr = "8.8.8.8".parse().unwrap();
+        manager.add(addr("1.2.3.4:8333"), ServiceFlags::NETWORK, source, NOW, NOW);
+        manager.add(addr("[2a01:4f8::1]:8333"), ServiceFlags::NETWORK, source, NOW, NOW);
+        manager.good(&addr("1.2.3.4:8333"), NOW);
+
+        let loaded = save_then_load("peers", |path| manager.save(path), AddressManager::load);
+
+        assert_eq!(loaded.key, manager.key);
+        assert_eq!((loaded.new_count(), loaded.tried_count()), (1, 1));
+        let info = loaded.get(&addr("1.2.3.4:8333")).unwrap();
+        assert!(info.in_tried);
+        assert_eq!(info.last_success, NOW);
+        assert_eq!(info.services, ServiceFlags::NETWORK);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,55 @@
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::Path;
+use toml;
+use util::write_file_atomically;
+
+// Outbound peers we were connected to at shutdown, to connect to again at startup. This makes it harder for an attacker to take over all of our connections just by getting us to restart.
+pub const MAX_ANCHORS: usize = 2;
+
+#[derive(Deserialize, Serialize)]
+struct SavedAnchors {
+    anchors: Vec<String>,
+}
+
+// Reads the anchors and removes the file, so that if any of them makes us crash we don't keep connecting to them on every restart.
+pub fn read_anchors(path: &Path) -> Vec<SocketAddr> {
+    let mut contents = String::new();
+    if File::open(path).and_then(|mut file| file.read_to_string(&mut contents)).is_err() {
+        return vec![];
+    }
+
+    let _ = fs::remove_file(path);
+
+    match toml::from_str::<SavedAnchors>(&contents) {
+        Ok(saved) => saved.anchors.iter().filter_map(|anchor| anchor.parse().ok()).take(MAX_ANCHORS).collect(),
+        Err(_) => vec![],
+    }
+}
+
+pub fn write_anchors(path: &Path, anchors: &[SocketAddr]) -> Result<(), String> {
+    let saved = SavedAnchors {
+        anchors: anchors.iter().take(MAX_ANCHORS).map(|anchor| anchor.to_string()).collect(),
+    };
+
+    let contents = toml::to_string(&saved).map_err(|e| e.to_string())?;
+    write_file_atomically(path, contents.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::temp_path;
+
+    #[test]
+    fn anchors_are_only_read_once() {
+        let path = temp_path("anchors");
+        let anchors: Vec<SocketAddr> = vec!["1.2.3.4:8333".parse().unwrap(), "[2a01:4f8::1]:18333".parse().unwrap(), "5.6.7.8:8333".parse().unwrap()];
+
+        write_anchors(&path, &anchors).unwrap();
+        assert_eq!(read_anchors(&path), anchors[..MAX_ANCHORS].to_vec());
+        assert!(read_anchors(&path).is_empty());
+    }
+}
\ No newline at end of file
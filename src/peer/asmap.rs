@@ -0,0 +1,423 @@
+use peer::netgroup::netgroup;
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::Path;
+
+// Marks a group as being an AS number, so it never collides with a netgroup.
+const NET_ASN: u8 = 128;
+
+// Values in the asmap are encoded as a class followed by a number of bits that depends on the class. These are the bit sizes of each class, after a minimum value.
+const TYPE_BIT_SIZES: [u8; 3] = [0, 0, 1];
+const ASN_BIT_SIZES: [u8; 10] = [15, 16, 17, 18, 19, 20, 21, 22, 23, 24];
+const MATCH_BIT_SIZES: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+const JUMP_BIT_SIZES: [u8; 26] = [5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Instruction {
+    // Stop and return an ASN.
+    Return,
+    // Skip ahead by some number of bits if the next bit of the IP is 1.
+    Jump,
+    // Compare the next bits of the IP, returning the default ASN if they don't match.
+    Match,
+    // Set the ASN returned when a match fails.
+    Default,
+}
+
+// Maps IPs to the autonomous system announcing them, in the binary format used by Bitcoin Core's `-asmap` (the files from Core's contrib/asmap tool). Peers in the same AS are likely to be run by the same operator even if they're spread over many netgroups.
+pub struct Asmap {
+    // A program walking the bits of an IP, read bit by bit starting from the least significant bit of each byte.
+    data: Vec<u8>,
+}
+
+fn ip_bits(ip: &IpAddr) -> u128 {
+    let v6 = match *ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    };
+
+    u128::from_be_bytes(v6.octets())
+}
+
+// Reads the bits of the asmap program.
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn end(&self) -> usize {
+        self.data.len() * 8
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        if self.position >= self.end() {
+            return None;
+        }
+
+        let bit = self.data[self.position / 8] >> (self.position % 8) & 1 == 1;
+        self.position += 1;
+        Some(bit)
+    }
+
+    // Each 1 bit moves the value to the next class, until a 0 bit says which class the value is in. The last class has no 0 bit.
+    fn read_value(&mut self, min_value: u32, bit_sizes: &[u8]) -> Option<u32> {
+        let mut value = min_value;
+
+        for (i, &bit_size) in bit_sizes.iter().enumerate() {
+            let next_class = i + 1 != bit_sizes.len() && self.read_bit()?;
+            if next_class {
+                value += 1 << bit_size;
+                continue;
+            }
+
+            for b in 0..bit_size {
+                if self.read_bit()? {
+                    value += 1 << (bit_size - 1 - b);
+                }
+            }
+
+            return Some(value);
+        }
+
+        None
+    }
+
+    fn read_instruction(&mut self) -> Option<Instruction> {
+        match self.read_value(0, &TYPE_BIT_SIZES)? {
+            0 => Some(Instruction::Return),
+            1 => Some(Instruction::Jump),
+            2 => Some(Instruction::Match),
+            _ => Some(Instruction::Default),
+        }
+    }
+
+    fn read_asn(&mut self) -> Option<u32> {
+        self.read_value(1, &ASN_BIT_SIZES)
+    }
+
+    // The bits to match, after a leading 1 bit marking how many there are. Returns them and their count.
+    fn read_match(&mut self) -> Option<(u32, u32)> {
+        let value = self.read_value(2, &MATCH_BIT_SIZES)?;
+        let length = 31 - value.leading_zeros();
+        Some((value & !(1 << length), length))
+    }
+
+    fn read_jump(&mut self) -> Option<u32> {
+        self.read_value(17, &JUMP_BIT_SIZES)
+    }
+}
+
+// Makes sure the asmap can't make lookups fail in the middle, the same way Core's SanityCheckAsmap does.
+fn sanity_check(data: &[u8]) -> bool {
+    let mut reader = Reader { data, position: 0 };
+    let mut bits_left = 128;
+    // Where the jumps we haven't reached yet go to, and the bits left at that point.
+    let mut jumps: Vec<(usize, u32)> = vec![];
+    let mut previous = Instruction::Jump;
+    let mut had_incomplete_match = false;
+
+    while reader.position != reader.end() {
+        // Jumping into the middle of the previous instruction.
+        if jumps.last().is_some_and(|&(target, _)| reader.position >= target) {
+            return false;
+        }
+
+        let instruction = match reader.read_instruction() {
+            Some(instruction) => instruction,
+            None => return false,
+        };
+
+        match instruction {
+            Instruction::Return => {
+                // A DEFAULT right before a RETURN does nothing.
+                if previous == Instruction::Default || reader.read_asn().is_none() {
+                    return false;
+                }
+
+                match jumps.pop() {
+                    // The end of the program, which can only be followed by zero bits up to the next byte.
+                    None => {
+                        if reader.end() - reader.position > 7 {
+                            return false;
+                        }
+
+                        while let Some(bit) = reader.read_bit() {
+                            if bit {
+                                return false;
+                            }
+                        }
+
+                        return true;
+                    },
+                    // Goes on as if the last jump was taken, which must land right here or the code in between is unreachable.
+                    Some((target, bits)) => {
+                        if reader.position != target {
+                            return false;
+                        }
+
+                        bits_left = bits;
+                        previous = Instruction::Jump;
+                    },
+                }
+            },
+            Instruction::Jump => {
+                let jump = match reader.read_jump() {
+                    Some(jump) => jump as usize,
+                    None => return false,
+                };
+
+                if jump > reader.end() - reader.position || bits_left == 0 {
+                    return false;
+                }
+
+                bits_left -= 1;
+                let target = reader.position + jump;
+
+                // Jumps can't cross each other.
+                if jumps.last().is_some_and(|&(last_target, _)| target >= last_target) {
+                    return false;
+                }
+
+                jumps.push((target, bits_left));
+                previous = Instruction::Jump;
+            },
+            Instruction::Match => {
+                let length = match reader.read_match() {
+                    Some((_, length)) => length,
+                    None => return false,
+                };
+
+                // Within a sequence of matches at most one can match less than 8 bits.
+                if previous != Instruction::Match {
+                    had_incomplete_match = false;
+                }
+
+                if (length < 8 && had_incomplete_match) || length > bits_left {
+                    return false;
+                }
+
+                had_incomplete_match = length < 8;
+                bits_left -= length;
+                previous = Instruction::Match;
+            },
+            Instruction::Default => {
+                if previous == Instruction::Default || reader.read_asn().is_none() {
+                    return false;
+                }
+
+                previous = Instruction::Default;
+            },
+        }
+    }
+
+    // Ran out of data without a RETURN.
+    false
+}
+
+impl Asmap {
+    pub fn parse(data: Vec<u8>) -> Result<Asmap, String> {
+        if !sanity_check(&data) {
+            return Err(String::from("Invalid asmap"));
+        }
+
+        Ok(Asmap {
+            data,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Asmap, String> {
+        let mut data = vec![];
+        File::open(path).and_then(|mut file| file.read_to_end(&mut data)).map_err(|e| e.to_string())?;
+
+        Asmap::parse(data)
+    }
+
+    // Size of the asmap in bytes.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn asn(&self, ip: &IpAddr) -> Option<u32> {
+        let bits = ip_bits(ip);
+        // Bits of the IP not used yet, starting from the most significant one.
+        let mut bits_left = 128;
+        let mut next_bit = || {
+            bits_left -= 1;
+            bits >> bits_left & 1 == 1
+        };
+
+        let mut reader = Reader { data: &self.data, position: 0 };
+        let mut default_asn = 0;
+
+        // The asmap was checked when it was parsed, so every instruction can be read and never uses more than 128 bits.
+        while reader.position != reader.end() {
+            match reader.read_instruction()? {
+                Instruction::Return => {
+                    default_asn = reader.read_asn()?;
+                    break;
+                },
+                Instruction::Jump => {
+                    let jump = reader.read_jump()? as usize;
+                    if next_bit() {
+                        reader.position += jump;
+                    }
+                },
+                Instruction::Match => {
+                    let (value, length) = reader.read_match()?;
+                    if (0..length).any(|i| next_bit() != (value >> (length - 1 - i) & 1 == 1)) {
+                        break;
+                    }
+                },
+                Instruction::Default => {
+                    default_asn = reader.read_asn()?;
+                },
+            }
+        }
+
+        // ASN 0 means the IP isn't announced by anyone.
+        if default_asn == 0 {
+            None
+        } else {
+            Some(default_asn)
+        }
+    }
+
+    // The AS of the address when it's known, otherwise its netgroup.
+    pub fn group(&self, ip: &IpAddr) -> Vec<u8> {
+        match self.asn(ip) {
+            Some(asn) => {
+                let mut group = vec![NET_ASN];
+                group.extend_from_slice(&asn.to_be_bytes());
+                group
+            },
+            None => netgroup(ip),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes asmap programs, the inverse of Reader.
+    struct Writer {
+        bits: Vec<bool>,
+    }
+
+    impl Writer {
+        fn write_value(&mut self, min_value: u32, bit_sizes: &[u8], value: u32) {
+            let mut value = value - min_value;
+
+            for (i, &bit_size) in bit_sizes.iter().enumerate() {
+                let last = i + 1 == bit_sizes.len();
+                if !last && value >= 1 << bit_size {
+                    self.bits.push(true);
+                    value -= 1 << bit_size;
+                    continue;
+                }
+
+                if !last {
+                    self.bits.push(false);
+                }
+
+                for b in 0..bit_size {
+                    self.bits.push(value >> (bit_size - 1 - b) & 1 == 1);
+                }
+
+                return;
+            }
+        }
+
+        fn write_return(&mut self, asn: u32) {
+            self.write_value(0, &TYPE_BIT_SIZES, 0);
+            self.write_value(1, &ASN_BIT_SIZES, asn);
+        }
+
+        fn write_default(&mut self, asn: u32) {
+            self.write_value(0, &TYPE_BIT_SIZES, 3);
+            self.write_value(1, &ASN_BIT_SIZES, asn);
+        }
+
+        // Matches the given bits, 8 at a time.
+        fn write_matches(&mut self, bits: &[bool]) {
+            for chunk in bits.chunks(8) {
+                self.write_value(0, &TYPE_BIT_SIZES, 2);
+                let value = chunk.iter().fold(1, |value, &bit| value << 1 | bit as u32);
+                self.write_value(2, &MATCH_BIT_SIZES, value);
+            }
+        }
+
+        // Jumps over `skipped` if the next bit is 1.
+        fn write_jump(&mut self, skipped: Writer) {
+            self.write_value(0, &TYPE_BIT_SIZES, 1);
+            self.write_value(17, &JUMP_BIT_SIZES, skipped.bits.len() as u32);
+            self.bits.extend(skipped.bits);
+        }
+
+        fn into_bytes(self) -> Vec<u8> {
+            let mut bytes = vec![0u8; self.bits.len().div_ceil(8)];
+            for (i, bit) in self.bits.iter().enumerate() {
+                bytes[i / 8] |= (*bit as u8) << (i % 8);
+            }
+            bytes
+        }
+    }
+
+    fn prefix_bits(ip: &str, skip: usize, length: usize) -> Vec<bool> {
+        let bits = ip_bits(&ip.parse().unwrap());
+        (skip..length).map(|i| bits >> (127 - i) & 1 == 1).collect()
+    }
+
+    // 8.0.0.0/8 is AS3356, 8.8.8.0/24 is AS15169 and so is 2001:4860::/32. IPv4-mapped addresses and 2001:4860:: both start with two zero bits, and differ on the third.
+    fn test_asmap() -> Vec<u8> {
+        let mut ipv4 = Writer { bits: vec![] };
+        ipv4.write_matches(&prefix_bits("8.0.0.0", 3, 104));
+        ipv4.write_default(3356);
+        ipv4.write_matches(&prefix_bits("8.8.8.0", 104, 120));
+        ipv4.write_return(15169);
+
+        let mut asmap = Writer { bits: vec![] };
+        asmap.write_matches(&[false, false]);
+        asmap.write_jump(ipv4);
+        asmap.write_matches(&prefix_bits("2001:4860::", 3, 32));
+        asmap.write_return(15169);
+        asmap.into_bytes()
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let asmap = Asmap::parse(test_asmap()).unwrap();
+
+        assert_eq!(asmap.asn(&"8.8.8.8".parse().unwrap()), Some(15169));
+        assert_eq!(asmap.asn(&"8.8.4.4".parse().unwrap()), Some(3356));
+        assert_eq!(asmap.asn(&"::ffff:8.8.8.8".parse().unwrap()), Some(15169));
+        assert_eq!(asmap.asn(&"2001:4860:4860::8888".parse().unwrap()), Some(15169));
+        assert_eq!(asmap.asn(&"9.9.9.9".parse().unwrap()), None);
+        assert_eq!(asmap.asn(&"2001:db8::1".parse().unwrap()), None);
+
+        // Addresses in the same AS share a group even when their netgroups differ.
+        assert_eq!(asmap.group(&"8.8.8.8".parse().unwrap()), asmap.group(&"2001:4860::1".parse().unwrap()));
+        assert_eq!(asmap.group(&"9.9.9.9".parse().unwrap()), netgroup(&"9.9.9.9".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_asmaps() {
+        let valid = test_asmap();
+
+        assert!(Asmap::parse(vec![]).is_err());
+        // Cut short, and with garbage after the end.
+        assert!(Asmap::parse(valid[..valid.len() - 1].to_vec()).is_err());
+        let mut padded = valid.clone();
+        padded.push(0);
+        assert!(Asmap::parse(padded).is_err());
+        let mut garbage = valid;
+        garbage.push(0xFF);
+        assert!(Asmap::parse(garbage).is_err());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::Path;
+use toml;
+use util::write_file_atomically;
+
+// Misbehavior score at which a peer gets disconnected and banned.
+pub const BAN_THRESHOLD: u32 = 100;
+// How long a ban lasts unless configured otherwise.
+pub const DEFAULT_BAN_DURATION: u64 = 24 * 60 * 60;
+
+#[derive(Deserialize, Serialize)]
+struct SavedBan {
+    ip: String,
+    until: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SavedBanList {
+    bans: Vec<SavedBan>,
+}
+
+// IPs we refuse to connect to, each until some point in time.
+pub struct BanList {
+    bans: HashMap<IpAddr, u64>,
+}
+
+impl BanList {
+    pub fn new() -> BanList {
+        BanList {
+            bans: HashMap::new(),
+        }
+    }
+
+    // Bans `ip` until `until`. A longer ban already in place is kept.
+    pub fn ban(&mut self, ip: IpAddr, until: u64) {
+        let entry = self.bans.entry(ip).or_insert(until);
+        if *entry < until {
+            *entry = until;
+        }
+    }
+
+    // Returns whether the ip was banned.
+    pub fn unban(&mut self, ip: &IpAddr) -> bool {
+        self.bans.remove(ip).is_some()
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr, now: u64) -> bool {
+        match self.bans.get(ip) {
+            Some(&until) => until > now,
+            None => false,
+        }
+    }
+
+    // Forgets bans that already ended.
+    pub fn sweep(&mut self, now: u64) {
+        self.bans.retain(|_, until| *until > now);
+    }
+
+    // Current bans and when they end, soonest first.
+    pub fn banned(&self, now: u64) -> Vec<(IpAddr, u64)> {
+        let mut result = self.bans.iter().filter(|&(_, &until)| until > now).map(|(ip, until)| (*ip, *until)).collect::<Vec<(IpAddr, u64)>>();
+        result.sort_by_key(|&(ip, until)| (until, ip));
+        result
+    }
+
+    pub fn load(path: &Path) -> Result<BanList, String> {
+        let mut contents = String::new();
+        File::open(path).and_then(|mut file| file.read_to_string(&mut contents)).map_err(|e| e.to_string())?;
+
+        let saved: SavedBanList = toml::from_str(&contents).map_err(|e| e.to_string())?;
+        let mut result = BanList::new();
+
+        for ban in saved.bans {
+            let ip = ban.ip.parse::<IpAddr>().map_err(|e| e.to_string())?;
+            result.ban(ip, ban.until);
+        }
+
+        Ok(result)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let saved = SavedBanList {
+            bans: self.bans.iter().map(|(ip, until)| SavedBan { ip: ip.to_string(), until: *until }).collect(),
+        };
+
+        let contents = toml::to_string(&saved).map_err(|e| e.to_string())?;
+        write_file_atomically(path, contents.as_bytes())
+    }
+}
+
+impl Default for BanList {
+    fn default() -> BanList {
+        BanList::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::save_then_load;
+
+    #[test]
+    fn bans_expire() {
+        let mut ban_list = BanList::new();
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+
+        ban_list.ban(ip, 200);
+        ban_list.ban(ip, 100);
+        assert!(ban_list.is_banned(&ip, 150));
+        assert!(!ban_list.is_banned(&ip, 200));
+        assert!(!ban_list.is_banned(&"1.2.3.5".parse().unwrap(), 150));
+
+        ban_list.sweep(250);
+        assert!(ban_list.banned(0).is_empty());
+
+        ban_list.ban(ip, 300);
+        assert!(ban_list.unban(&ip));
+        assert!(!ban_list.unban(&ip));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut ban_list = BanList::new();
+        ban_list.ban("1.2.3.4".parse().unwrap(), 200);
+        ban_list.ban("2a01:4f8::1".parse().unwrap(), 100);
+
+        let loaded = save_then_load("banlist", |path| ban_list.save(path), BanList::load);
+
+        assert_eq!(loaded.banned(0), vec![("2a01:4f8::1".parse().unwrap(), 100), ("1.2.3.4".parse().unwrap(), 200)]);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,213 @@
+use hex;
+use network::block::Block;
+use network::transaction::{OutPoint, Transaction};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Our transactions are announced again after this many seconds, doubling after each time up to the maximum, until they confirm.
+const INITIAL_REBROADCAST_DELAY: u64 = 60;
+const MAX_REBROADCAST_DELAY: u64 = 30 * 60;
+
+// Transaction ids are shown reversed, like block hashes.
+pub fn id_to_hex(id: &[u8; 32]) -> String {
+    hex::encode(id.iter().rev().cloned().collect::<Vec<u8>>())
+}
+
+// A transaction of ours and how far it got through the network.
+pub struct OwnTransaction {
+    pub tx: Transaction,
+    pub txid: [u8; 32],
+    pub wtxid: [u8; 32],
+    // Peers which asked us for the transaction, and the ones which announced it back to us.
+    pub requested_by: HashSet<SocketAddr>,
+    pub announced_by: HashSet<SocketAddr>,
+    pub broadcasts: u32,
+    next_broadcast: Instant,
+}
+
+impl OwnTransaction {
+    // Whether `peer` is known to have the transaction already.
+    pub fn reached(&self, peer: &SocketAddr) -> bool {
+        self.requested_by.contains(peer) || self.announced_by.contains(peer)
+    }
+}
+
+// Keeps announcing our own transactions until they confirm or something conflicting does.
+pub struct TransactionBroadcaster {
+    transactions: HashMap<[u8; 32], OwnTransaction>,
+    // Wtxids of the transactions, since peers may use either id.
+    txids_by_wtxid: HashMap<[u8; 32], [u8; 32]>,
+}
+
+impl TransactionBroadcaster {
+    pub fn new() -> TransactionBroadcaster {
+        TransactionBroadcaster {
+            transactions: HashMap::new(),
+            txids_by_wtxid: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    // Starts broadcasting `tx`, with the first announcement due at `now`. Returns false if it was already being broadcast.
+    pub fn add(&mut self, tx: Transaction, now: Instant) -> bool {
+        let txid = tx.txid();
+        if self.transactions.contains_key(&txid) {
+            return false;
+        }
+
+        let wtxid = tx.wtxid();
+        self.txids_by_wtxid.insert(wtxid, txid);
+        self.transactions.insert(txid, OwnTransaction {
+            tx,
+            txid,
+            wtxid,
+            requested_by: HashSet::new(),
+            announced_by: HashSet::new(),
+            broadcasts: 0,
+            next_broadcast: now,
+        });
+
+        true
+    }
+
+    // Looks a transaction up by either its txid or its wtxid.
+    pub fn get(&self, id: &[u8; 32]) -> Option<&OwnTransaction> {
+        let txid = self.txids_by_wtxid.get(id).unwrap_or(id);
+        self.transactions.get(txid)
+    }
+
+    fn get_mut(&mut self, id: &[u8; 32]) -> Option<&mut OwnTransaction> {
+        let txid = *self.txids_by_wtxid.get(id).unwrap_or(id);
+        self.transactions.get_mut(&txid)
+    }
+
+    // Records that `peer` asked for the transaction with the given id, and returns it if it's ours.
+    pub fn record_request(&mut self, peer: SocketAddr, id: &[u8; 32]) -> Option<&Transaction> {
+        let own = self.get_mut(id)?;
+        own.requested_by.insert(peer);
+        Some(&own.tx)
+    }
+
+    // Records that `peer` announced the transaction with the given id to us, if it's ours.
+    pub fn record_announcement(&mut self, peer: SocketAddr, id: &[u8; 32]) {
+        if let Some(own) = self.get_mut(id) {
+            own.announced_by.insert(peer);
+        }
+    }
+
+    // Returns the txids of the transactions due to be announced again, and schedules their next announcement.
+    pub fn due(&mut self, now: Instant) -> Vec<[u8; 32]> {
+        let mut due = vec![];
+
+        for own in self.transactions.values_mut().filter(|own| own.next_broadcast <= now) {
+            let delay = INITIAL_REBROADCAST_DELAY.saturating_mul(1 << own.broadcasts.min(16)).min(MAX_REBROADCAST_DELAY);
+            own.broadcasts += 1;
+            own.next_broadcast = now + Duration::from_secs(delay);
+            due.push(own.txid);
+        }
+
+        due
+    }
+
+    // Stops broadcasting the transactions `block` confirms and the ones it makes invalid by spending their inputs. Returns them, in that order.
+    pub fn block_connected(&mut self, block: &Block) -> (Vec<OwnTransaction>, Vec<OwnTransaction>) {
+        let txids = block.transactions.iter().map(|tx| tx.txid()).collect::<HashSet<[u8; 32]>>();
+        let spent = block.transactions.iter().flat_map(|tx| tx.inputs.iter().map(|input| input.previous_output)).collect::<HashSet<OutPoint>>();
+
+        let confirmed = self.transactions.keys().filter(|txid| txids.contains(*txid)).cloned().collect::<Vec<[u8; 32]>>();
+        let conflicted = self.transactions.values()
+            .filter(|own| !txids.contains(&own.txid) && own.tx.inputs.iter().any(|input| spent.contains(&input.previous_output)))
+            .map(|own| own.txid)
+            .collect::<Vec<[u8; 32]>>();
+
+        (self.remove(&confirmed), self.remove(&conflicted))
+    }
+
+    // Stops broadcasting the given transactions, returning the ones that were ours.
+    pub fn remove(&mut self, txids: &[[u8; 32]]) -> Vec<OwnTransaction> {
+        txids.iter().filter_map(|txid| {
+            let own = self.transactions.remove(txid)?;
+            self.txids_by_wtxid.remove(&own.wtxid);
+            Some(own)
+        }).collect()
+    }
+}
+
+impl Default for TransactionBroadcaster {
+    fn default() -> TransactionBroadcaster {
+        TransactionBroadcaster::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::Network;
+    use network::transaction::{TxIn, TxOut};
+
+    fn transaction(id: u8) -> Transaction {
+        Transaction::new(2, vec![TxIn::new(OutPoint::new([id; 32], 0), vec![], 0xFFFFFFFD)], vec![TxOut::new(1000, vec![0x51])], 0)
+    }
+
+    #[test]
+    fn rebroadcasts_with_backoff() {
+        let mut broadcaster = TransactionBroadcaster::new();
+        let start = Instant::now();
+        let tx = transaction(1);
+        let txid = tx.txid();
+
+        assert!(broadcaster.add(tx.clone(), start));
+        assert!(!broadcaster.add(tx, start));
+
+        assert_eq!(broadcaster.due(start), vec![txid]);
+        assert!(broadcaster.due(start + Duration::from_secs(59)).is_empty());
+        assert_eq!(broadcaster.due(start + Duration::from_secs(60)), vec![txid]);
+        assert!(broadcaster.due(start + Duration::from_secs(179)).is_empty());
+        assert_eq!(broadcaster.due(start + Duration::from_secs(180)), vec![txid]);
+    }
+
+    #[test]
+    fn tracks_requests_and_blocks() {
+        let mut broadcaster = TransactionBroadcaster::new();
+        let peer: SocketAddr = "1.2.3.4:8333".parse().unwrap();
+        let confirmed = transaction(1);
+        let conflicted = transaction(2);
+        let pending = transaction(3);
+
+        for tx in [&confirmed, &conflicted, &pending] {
+            broadcaster.add(tx.clone(), Instant::now());
+        }
+
+        assert!(broadcaster.record_request(peer, &[0; 32]).is_none());
+        assert_eq!(broadcaster.record_request(peer, &confirmed.wtxid()).map(|tx| tx.txid()), Some(confirmed.txid()));
+        broadcaster.record_announcement(peer, &pending.txid());
+        assert!(broadcaster.get(&confirmed.txid()).unwrap().reached(&peer));
+        assert!(broadcaster.get(&pending.wtxid()).unwrap().reached(&peer));
+        assert!(!broadcaster.get(&conflicted.txid()).unwrap().reached(&peer));
+
+        // Something else spending the same coin as one of ours.
+        let mut double_spend = conflicted.clone();
+        double_spend.outputs[0].value = 500;
+
+        let block = Block::new(Network::Regtest.params().genesis, vec![confirmed.clone(), double_spend]);
+        let (done, failed) = broadcaster.block_connected(&block);
+
+        assert_eq!(done.iter().map(|own| own.txid).collect::<Vec<[u8; 32]>>(), vec![confirmed.txid()]);
+        assert_eq!(failed.iter().map(|own| own.txid).collect::<Vec<[u8; 32]>>(), vec![conflicted.txid()]);
+        assert_eq!(broadcaster.len(), 1);
+        assert!(broadcaster.get(&confirmed.wtxid()).is_none());
+
+        // Dropped by the mempool, along with something that was never ours.
+        let removed = broadcaster.remove(&[pending.txid(), [0; 32]]);
+        assert_eq!(removed.iter().map(|own| own.txid).collect::<Vec<[u8; 32]>>(), vec![pending.txid()]);
+        assert!(broadcaster.is_empty());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,228 @@
+use byteorder::{BigEndian, ByteOrder};
+use network::ServiceFlags;
+use rand;
+use rand::Rng;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+// Standard query with recursion desired.
+const QUERY_FLAGS: u16 = 0x0100;
+
+#[derive(Debug)]
+pub enum DnsError {
+    InvalidName,
+    Io(io::ErrorKind),
+    Malformed,
+    // The server answered with a non-zero response code.
+    ServerFailure(u8),
+}
+
+impl From<io::Error> for DnsError {
+    fn from(error: io::Error) -> DnsError {
+        DnsError::Io(error.kind())
+    }
+}
+
+// Seeds supporting it only return nodes with the requested services when asked through a subdomain such as `x9.seed.example.com` (for NETWORK and WITNESS).
+pub fn seed_hostname(seed: &str, services: ServiceFlags) -> String {
+    if services == ServiceFlags::NONE {
+        return seed.to_string();
+    }
+
+    format!("x{:x}.{}", services.bits(), seed)
+}
+
+fn build_query(id: u16, hostname: &str, record_type: u16) -> Result<Vec<u8>, DnsError> {
+    let mut query = vec![0u8; 12];
+    BigEndian::write_u16(&mut query[0..2], id);
+    BigEndian::write_u16(&mut query[2..4], QUERY_FLAGS);
+    // One question.
+    BigEndian::write_u16(&mut query[4..6], 1);
+
+    for label in hostname.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsError::InvalidName);
+        }
+
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+
+    let mut tail = [0u8; 4];
+    BigEndian::write_u16(&mut tail[0..2], record_type);
+    BigEndian::write_u16(&mut tail[2..4], CLASS_IN);
+    query.extend_from_slice(&tail);
+
+    Ok(query)
+}
+
+// Returns the position right after the name starting at `position`. Names may end in a pointer to another name, in which case they end right after the pointer.
+fn skip_name(packet: &[u8], mut position: usize) -> Result<usize, DnsError> {
+    loop {
+        let length = *packet.get(position).ok_or(DnsError::Malformed)? as usize;
+
+        match length {
+            0 => return Ok(position + 1),
+            _ if length & 0xC0 == 0xC0 => return Ok(position + 2),
+            _ => position += 1 + length,
+        }
+    }
+}
+
+fn read_u16(packet: &[u8], position: usize) -> Result<u16, DnsError> {
+    packet.get(position..position + 2).map(BigEndian::read_u16).ok_or(DnsError::Malformed)
+}
+
+fn parse_response(packet: &[u8], id: u16) -> Result<Vec<IpAddr>, DnsError> {
+    if packet.len() < 12 || read_u16(packet, 0)? != id {
+        return Err(DnsError::Malformed);
+    }
+
+    let response_code = packet[3] & 0x0F;
+    if response_code != 0 {
+        return Err(DnsError::ServerFailure(response_code));
+    }
+
+    let question_count = read_u16(packet, 4)?;
+    let answer_count = read_u16(packet, 6)?;
+
+    let mut position = 12;
+    for _ in 0..question_count {
+        // Name, type and class.
+        position = skip_name(packet, position)? + 4;
+    }
+
+    let mut result = vec![];
+    for _ in 0..answer_count {
+        position = skip_name(packet, position)?;
+
+        let record_type = read_u16(packet, position)?;
+        let record_class = read_u16(packet, position + 2)?;
+        // Skipping the TTL.
+        let data_length = read_u16(packet, position + 8)? as usize;
+        position += 10;
+
+        let data = packet.get(position..position + data_length).ok_or(DnsError::Malformed)?;
+        position += data_length;
+
+        // Seeds may also answer with e.g. CNAMEs, which are of no use to us.
+        match (record_type, record_class, data_length) {
+            (TYPE_A, CLASS_IN, 4) => result.push(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+            (TYPE_AAAA, CLASS_IN, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(data);
+                result.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            },
+            _ => (),
+        }
+    }
+
+    Ok(result)
+}
+
+// Asks `server` for the IPv4 and IPv6 addresses of `hostname` over UDP.
+pub fn query(server: SocketAddr, hostname: &str, timeout: Duration) -> Result<Vec<IpAddr>, DnsError> {
+    let bind_addr: SocketAddr = if server.is_ipv4() { "0.0.0.0:0".parse().unwrap() } else { "[::]:0".parse().unwrap() };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(server)?;
+
+    let mut result = vec![];
+
+    for &record_type in [TYPE_A, TYPE_AAAA].iter() {
+        let id = rand::thread_rng().gen::<u16>();
+        socket.send(&build_query(id, hostname, record_type)?)?;
+
+        let mut buffer = [0u8; 512];
+        // Answers to something else (e.g. a late answer to an earlier query) are skipped.
+        loop {
+            let length = socket.recv(&mut buffer)?;
+
+            match parse_response(&buffer[..length], id) {
+                Err(DnsError::Malformed) => continue,
+                Err(e) => return Err(e),
+                Ok(mut addresses) => {
+                    result.append(&mut addresses);
+                    break;
+                },
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+// Looks up the addresses of `hostname` through the operating system's resolver.
+pub fn query_system(hostname: &str) -> Result<Vec<IpAddr>, DnsError> {
+    let addrs = (hostname, 0).to_socket_addrs()?;
+    Ok(addrs.map(|addr| addr.ip()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Answers every query with a single record pointing back to the question's name, like a real server would.
+    fn run_stub_server(socket: UdpSocket, queries: usize) {
+        for _ in 0..queries {
+            let mut buffer = [0u8; 512];
+            let (length, from) = socket.recv_from(&mut buffer).unwrap();
+            let question = &buffer[..length];
+
+            let record_type = BigEndian::read_u16(&question[length - 4..length - 2]);
+            let data = match record_type {
+                TYPE_A => vec![1, 2, 3, 4],
+                _ => "2a01:4f8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec(),
+            };
+
+            let mut response = question.to_vec();
+            // Response flag, and one answer.
+            response[2] |= 0x80;
+            response[7] = 1;
+            // Pointer to the name in the question, type, class, TTL and data.
+            response.extend_from_slice(&[0xC0, 12]);
+            response.extend_from_slice(&question[length - 4..]);
+            response.extend_from_slice(&[0, 0, 0, 60, 0, data.len() as u8]);
+            response.extend_from_slice(&data);
+
+            socket.send_to(&response, from).unwrap();
+        }
+    }
+
+    #[test]
+    fn query_stub_server() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.local_addr().unwrap();
+        let handle = thread::spawn(move || run_stub_server(socket, 2));
+
+        let hostname = seed_hostname("seed.example.com", ServiceFlags::NETWORK | ServiceFlags::WITNESS);
+        assert_eq!(hostname, "x9.seed.example.com");
+
+        let addresses = query(server, &hostname, Duration::from_secs(5)).unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(addresses, vec!["1.2.3.4".parse::<IpAddr>().unwrap(), "2a01:4f8::1".parse().unwrap()]);
+    }
+
+    #[test]
+    fn server_failures() {
+        let query = build_query(7, "seed.example.com", TYPE_A).unwrap();
+
+        let mut response = query.clone();
+        // NXDOMAIN.
+        response[3] |= 3;
+        match parse_response(&response, 7) {
+            Err(DnsError::ServerFailure(3)) => (),
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        assert!(parse_response(&query, 8).is_err());
+        assert!(build_query(7, "seed..example.com", TYPE_A).is_err());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,68 @@
+use rand;
+use rand::Rng;
+
+// Fee rates are in satoshis per 1000 bytes. Transactions paying less than this aren't relayed unless configured otherwise.
+pub const DEFAULT_MIN_RELAY_FEE: u64 = 1000;
+// Bounds of the fee rates a fee filter gets rounded to, with each one 10% above the previous.
+const MIN_FEE_FILTER: u64 = DEFAULT_MIN_RELAY_FEE / 2;
+const MAX_FEE_FILTER: u64 = 10_000_000;
+const FEE_FILTER_SPACING: f64 = 1.1;
+
+// Rounds fee filters to a fixed set of values, sometimes down by one step, so our exact minimum fee (and through it, what's in our mempool) can't be used to fingerprint us. See BIP133.
+pub struct FeeFilterRounder {
+    // Sorted.
+    fee_set: Vec<u64>,
+}
+
+impl FeeFilterRounder {
+    pub fn new() -> FeeFilterRounder {
+        let mut fee_set = vec![0];
+        let mut fee = MIN_FEE_FILTER as f64;
+
+        while fee <= MAX_FEE_FILTER as f64 {
+            fee_set.push(fee as u64);
+            fee *= FEE_FILTER_SPACING;
+        }
+
+        FeeFilterRounder {
+            fee_set,
+        }
+    }
+
+    pub fn round(&self, min_fee: u64) -> u64 {
+        let mut index = self.fee_set.iter().position(|&fee| fee >= min_fee).unwrap_or(self.fee_set.len());
+
+        // Rounding down two times out of three, and always when above the highest value.
+        if index == self.fee_set.len() || (index > 0 && rand::thread_rng().gen_range(0, 3) != 0) {
+            index -= 1;
+        }
+
+        self.fee_set[index]
+    }
+}
+
+impl Default for FeeFilterRounder {
+    fn default() -> FeeFilterRounder {
+        FeeFilterRounder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rounds_to_a_nearby_value() {
+        let rounder = FeeFilterRounder::new();
+
+        assert_eq!(rounder.round(0), 0);
+        assert_eq!(rounder.round(u64::MAX), *rounder.fee_set.last().unwrap());
+
+        for _ in 0..100 {
+            let rounded = rounder.round(DEFAULT_MIN_RELAY_FEE);
+            assert!(rounder.fee_set.contains(&rounded));
+            assert!(rounded as f64 >= DEFAULT_MIN_RELAY_FEE as f64 / FEE_FILTER_SPACING / FEE_FILTER_SPACING);
+            assert!(rounded as f64 <= DEFAULT_MIN_RELAY_FEE as f64 * FEE_FILTER_SPACING);
+        }
+    }
+}
\ No newline at end of file
@@ -1,12 +1,54 @@
+use network::ServiceFlags;
+use network::version;
+use peer::fee_filter::DEFAULT_MIN_RELAY_FEE;
 use std::fs::File;
 use std::io::Read;
+use util::AdjustedTime;
 
+pub mod addr_relay;
+pub mod address_manager;
+pub mod anchors;
+pub mod asmap;
+pub mod ban_list;
+pub mod broadcast;
+pub mod dns_seed;
+pub mod fee_filter;
+pub mod netgroup;
 pub mod peer_connection;
+pub mod peer_info;
 pub mod peer_management;
 
+pub use self::address_manager::AddressManager;
+pub use self::asmap::Asmap;
+pub use self::ban_list::BanList;
 pub use self::peer_connection::PeerConnection;
+pub use self::peer_info::PeerInfo;
 pub use self::peer_management::PeerManager;
 
+// How we present ourselves to peers.
+#[derive(Clone, Debug)]
+pub struct PeerSettings {
+    pub user_agent: String,
+    pub services: ServiceFlags,
+    pub relay: bool,
+    // Transactions paying less than this, in satoshis per 1000 bytes, aren't relayed.
+    pub min_relay_fee: u64,
+    // Shared with everything else that needs the network time.
+    pub adjusted_time: AdjustedTime,
+}
+
+impl PeerSettings {
+    pub fn new() -> PeerSettings {
+        PeerSettings {
+            user_agent: version::default_user_agent(),
+            services: ServiceFlags::NONE,
+            relay: false,
+            min_relay_fee: DEFAULT_MIN_RELAY_FEE,
+            adjusted_time: AdjustedTime::new(),
+        }
+    }
+}
+
 pub fn read_peer_list(peer_list_location: &str) -> Vec<String> {
     let mut peer_list = File::open(peer_list_location).unwrap();
     let mut peers = String::new();
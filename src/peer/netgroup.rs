@@ -0,0 +1,150 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// First byte of a netgroup, so groups of different kinds of addresses never collide.
+const NET_UNROUTABLE: u8 = 0;
+const NET_IPV4: u8 = 1;
+const NET_IPV6: u8 = 2;
+const NET_LOCAL: u8 = 255;
+
+// IPv6 addresses that are really IPv4 ones (IPv4-mapped, 6to4 and Teredo) are looked at as the IPv4 address they carry.
+fn embedded_ipv4(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    let octets = ip.octets();
+
+    if octets[..12] == [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF] {
+        return Some(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]));
+    }
+
+    // 6to4, 2002::/16.
+    if octets[0] == 0x20 && octets[1] == 0x02 {
+        return Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5]));
+    }
+
+    // Teredo, 2001:0000::/32, with the client address obfuscated.
+    if octets[..4] == [0x20, 0x01, 0, 0] {
+        return Some(Ipv4Addr::new(octets[12] ^ 0xFF, octets[13] ^ 0xFF, octets[14] ^ 0xFF, octets[15] ^ 0xFF));
+    }
+
+    None
+}
+
+fn is_local(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(v4) => v4.is_loopback() || v4.octets()[0] == 0,
+        IpAddr::V6(v6) => match embedded_ipv4(&v6) {
+            Some(v4) => is_local(&IpAddr::V4(v4)),
+            None => v6.is_loopback(),
+        },
+    }
+}
+
+fn is_routable_ipv4(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_documentation()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || octets[0] == 0
+        // RFC6598, shared address space.
+        || (octets[0] == 100 && octets[1] & 0xC0 == 64)
+        // RFC2544, benchmarking.
+        || (octets[0] == 198 && octets[1] & 0xFE == 18)
+        // Reserved.
+        || octets[0] >= 240)
+}
+
+// Whether the address can be reached from the internet, which is what every address we learn from peers should be.
+pub fn is_routable(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ref v4) => is_routable_ipv4(v4),
+        IpAddr::V6(ref v6) => {
+            if let Some(v4) = embedded_ipv4(v6) {
+                return is_routable_ipv4(&v4);
+            }
+
+            let segments = v6.segments();
+
+            !(v6.is_unspecified()
+                || v6.is_loopback()
+                || v6.is_multicast()
+                // RFC4193, unique local addresses.
+                || segments[0] & 0xFE00 == 0xFC00
+                // RFC4862, link local addresses.
+                || segments[0] & 0xFFC0 == 0xFE80
+                // RFC3849, documentation.
+                || (segments[0] == 0x2001 && segments[1] == 0x0DB8))
+        },
+    }
+}
+
+// Group of addresses likely to be controlled by the same operator: the /16 of IPv4 addresses and the /32 of IPv6 ones. Peers in the same group aren't much more useful than a single one.
+pub fn netgroup(ip: &IpAddr) -> Vec<u8> {
+    if is_local(ip) {
+        return vec![NET_LOCAL];
+    }
+
+    if !is_routable(ip) {
+        return vec![NET_UNROUTABLE];
+    }
+
+    match *ip {
+        IpAddr::V4(v4) => vec![NET_IPV4, v4.octets()[0], v4.octets()[1]],
+        IpAddr::V6(v6) => {
+            if let Some(v4) = embedded_ipv4(&v6) {
+                return netgroup(&IpAddr::V4(v4));
+            }
+
+            let octets = v6.octets();
+            let mut group = vec![NET_IPV6];
+            group.extend_from_slice(&octets[..4]);
+
+            // Hurricane Electric hands out /36s for free, so those are grouped by /36.
+            if octets[..4] == [0x20, 0x01, 0x04, 0x70] {
+                group.push(octets[4] & 0xF0);
+            }
+
+            group
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_by_prefix() {
+        let first: IpAddr = "8.8.4.4".parse().unwrap();
+        let second: IpAddr = "8.8.200.1".parse().unwrap();
+        let mapped: IpAddr = "::ffff:8.8.1.1".parse().unwrap();
+        let six_to_four: IpAddr = "2002:808:101::1".parse().unwrap();
+        let other: IpAddr = "8.9.0.1".parse().unwrap();
+
+        assert_eq!(netgroup(&first), vec![NET_IPV4, 8, 8]);
+        assert_eq!(netgroup(&first), netgroup(&second));
+        assert_eq!(netgroup(&first), netgroup(&mapped));
+        assert_eq!(netgroup(&first), netgroup(&six_to_four));
+        assert_ne!(netgroup(&first), netgroup(&other));
+
+        let v6: IpAddr = "2a01:4f8:1:2::1".parse().unwrap();
+        let same_v6: IpAddr = "2a01:4f8:ffff::1".parse().unwrap();
+        assert_eq!(netgroup(&v6), netgroup(&same_v6));
+        assert_eq!(netgroup(&v6), vec![NET_IPV6, 0x2a, 0x01, 0x04, 0xf8]);
+    }
+
+    #[test]
+    fn unroutable_addresses() {
+        for ip in ["10.1.2.3", "192.168.0.1", "100.64.0.1", "203.0.113.7", "fd00::1", "fe80::1", "2001:db8::1"].iter() {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert!(!is_routable(&ip), "{} should not be routable", ip);
+            assert_eq!(netgroup(&ip), vec![NET_UNROUTABLE]);
+        }
+
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        assert_eq!(netgroup(&localhost), vec![NET_LOCAL]);
+        assert!(is_routable(&"1.2.3.4".parse().unwrap()));
+    }
+}
\ No newline at end of file
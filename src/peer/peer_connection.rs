@@ -1,27 +1,84 @@
 use ::KalikoControlMessage;
-use bitcoin;
+use bitcoin::ChainParams;
 use byteorder::{ByteOrder, LittleEndian};
-use network::{Command, Message, NetworkError};
+use network::{Command, MAX_PROTOCOL_MESSAGE_LENGTH, Message, NetworkError, ServiceFlags};
+use network::addr::AddrPayload;
 use network::blocks::GetBlocksOrHeadersPayload;
 use network::cmpct::SendCmpctPayload;
-use network::version::VersionPayload;
+use network::headers::HeadersPayload;
+use network::inv::{InvPayload, InventoryType, InventoryVector};
+use network::version::{MIN_PEER_PROTOCOL_VERSION, PROTOCOL_VERSION, ProtocolFeatures, VersionPayloadBuilder};
+use peer::{PeerInfo, PeerSettings};
+use peer::addr_relay::poisson_delay;
+use peer::fee_filter::FeeFilterRounder;
+use peer::peer_info::TrafficStats;
+use util::adjusted_time::local_time;
 use rand;
 use rand::Rng;
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::io::Read;
-use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::net::{IpAddr, Ipv6Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
-use std::{thread, time};
+use std::{cmp, thread, time};
+use std::time::Instant;
+
+// How often we ping peers to measure how responsive they are.
+const PING_INTERVAL: u64 = 2 * 60;
+// Peers which don't answer a ping or don't send anything for this long are disconnected.
+const TIMEOUT_INTERVAL: u64 = 20 * 60;
+// Our fee filter is sent again, if it changed, after a random delay averaging this many seconds.
+const AVG_FEE_FILTER_INTERVAL: f64 = 10.0 * 60.0;
+// Big changes to our fee filter are sent within this many seconds.
+const MAX_FEE_FILTER_CHANGE_DELAY: u64 = 5 * 60;
+// How many transactions we remember the peer knowing about, so we don't announce them back.
+const MAX_KNOWN_TRANSACTIONS: usize = 50_000;
+// At most this many transactions are requested from the peer at once.
+const MAX_TRANSACTIONS_IN_FLIGHT: usize = 5000;
+// Transactions the peer didn't send us within this many seconds of asking are given up on.
+const TRANSACTION_REQUEST_TIMEOUT: u64 = 60;
 
 pub struct PeerConnection {
-    network: bitcoin::Network,
+    params: ChainParams,
     stream: TcpStream,
     peer_addr: SocketAddr,
     protocol_version: i32,
+    features: ProtocolFeatures,
+    // The peer's fee filter.
     fee_filter: u64,
+    // Whether the peer wants transactions announced to it.
+    peer_relay: bool,
+    // Whether the peer wants new blocks announced with headers instead of inv (BIP130).
+    peer_prefers_headers: bool,
+    // Whether transactions are announced and requested by wtxid instead of txid with the peer (BIP339).
+    wtxid_relay: bool,
+    // Transactions (by the id used with the peer) the peer knows about, and the ones we asked it for.
+    known_transactions: HashSet<[u8; 32]>,
+    requested_transactions: HashMap<[u8; 32], Instant>,
+    // Our minimum fee rate, and what we last told the peer about it.
+    min_fee: u64,
+    fee_filter_rounder: FeeFilterRounder,
+    fee_filter_sent: Option<u64>,
+    next_fee_filter_send: Instant,
     peer_starting_height: i32,
+    peer_services: ServiceFlags,
+    peer_user_agent: String,
+    // Our address as seen by the peer.
+    addr_seen_by_peer: Option<SocketAddr>,
+    // How many seconds the peer's clock is ahead of ours.
+    peer_time_offset: i64,
+    settings: PeerSettings,
+    our_height: i32,
     message_buffer: Vec<u8>,
+    // Blocks we asked for, so we can tell when the peer sends one we didn't.
+    requested_blocks: HashSet<[u8; 32]>,
+    // Nonce of the ping we're waiting an answer for, and when it was sent.
+    ping_in_flight: Option<(u64, Instant)>,
+    last_ping_sent: Option<Instant>,
+    last_message_received: Instant,
+    sent: TrafficStats,
+    received: TrafficStats,
     outgoing_control_sender: Sender<KalikoControlMessage>,
     incoming_message_sender: Sender<KalikoControlMessage>,
     incoming_message_receiver: Receiver<KalikoControlMessage>,
@@ -42,31 +99,53 @@ impl Hash for PeerConnection {
 }
 
 impl PeerConnection {
-    pub fn new(network: bitcoin::Network, stream: TcpStream, outgoing_control_sender: Sender<KalikoControlMessage>) -> PeerConnection {
+    pub fn new(params: ChainParams, stream: TcpStream, settings: PeerSettings, our_height: i32, outgoing_control_sender: Sender<KalikoControlMessage>) -> PeerConnection {
         let (incoming_message_sender, incoming_message_receiver) = mpsc::channel();
         let peer_addr = stream.peer_addr().unwrap();
 
         PeerConnection {
-            network,
+            params,
             stream,
             peer_addr,
             protocol_version: 0,
+            features: ProtocolFeatures::default(),
             fee_filter: 0,
+            peer_relay: false,
+            peer_prefers_headers: false,
+            wtxid_relay: false,
+            known_transactions: HashSet::new(),
+            requested_transactions: HashMap::new(),
+            min_fee: settings.min_relay_fee,
+            fee_filter_rounder: FeeFilterRounder::new(),
+            fee_filter_sent: None,
+            next_fee_filter_send: Instant::now(),
             peer_starting_height: 0,
+            peer_services: ServiceFlags::NONE,
+            peer_user_agent: String::new(),
+            addr_seen_by_peer: None,
+            peer_time_offset: 0,
+            settings,
+            our_height,
             // TODO: possibly make this size configurable.
             message_buffer: Vec::with_capacity(4096),
+            requested_blocks: HashSet::new(),
+            ping_in_flight: None,
+            last_ping_sent: None,
+            last_message_received: Instant::now(),
+            sent: TrafficStats::new(),
+            received: TrafficStats::new(),
             outgoing_control_sender,
             incoming_message_sender,
             incoming_message_receiver,
         }
     }
 
-    pub fn connect(network: bitcoin::Network, peer: SocketAddr, outgoing_control_sender: Sender<KalikoControlMessage>) -> Result<PeerConnection, ()> {
+    pub fn connect(params: ChainParams, peer: SocketAddr, settings: PeerSettings, our_height: i32, timeout: time::Duration, outgoing_control_sender: Sender<KalikoControlMessage>) -> Result<PeerConnection, ()> {
         debug!("[{}] Attempting connection", peer);
 
-        if let Ok(connection) = TcpStream::connect(peer) {
+        if let Ok(connection) = TcpStream::connect_timeout(&peer, timeout) {
             debug!("[{}] Connection established", peer);
-            Ok(PeerConnection::new(network, connection, outgoing_control_sender))
+            Ok(PeerConnection::new(params, connection, settings, our_height, outgoing_control_sender))
         } else {
             outgoing_control_sender.send(KalikoControlMessage::PeerUnavailable(peer)).unwrap();
             Err(())
@@ -81,6 +160,42 @@ impl PeerConnection {
         self.peer_addr
     }
 
+    // Services the peer advertised in its version message.
+    pub fn services(&self) -> ServiceFlags {
+        self.peer_services
+    }
+
+    pub fn user_agent(&self) -> &str {
+        &self.peer_user_agent
+    }
+
+    // What the connection knows about the peer. The peer manager fills in the rest.
+    fn peer_info(&self) -> PeerInfo {
+        let mut info = PeerInfo::new(self.peer_addr());
+        info.services = self.peer_services;
+        info.user_agent = self.peer_user_agent.clone();
+        info.version = self.protocol_version;
+        info.starting_height = self.peer_starting_height;
+        info.current_height = self.peer_starting_height;
+        info.fee_filter = self.fee_filter;
+        info.wtxid_relay = self.wtxid_relay;
+        info.sent = self.sent.clone();
+        info.received = self.received.clone();
+        info
+    }
+
+    // Lets the peer manager decide whether the peer deserves to be banned.
+    fn misbehaving(&self, score: u32, reason: &str) {
+        info!("[{}] Misbehaving by {}: {}", self.peer_addr(), score, reason);
+        self.outgoing_control_sender.send(KalikoControlMessage::Misbehaving(self.peer_addr(), score, reason.to_string())).unwrap();
+    }
+
+    fn send_command(&mut self, command: Command) -> Result<(), NetworkError> {
+        self.sent.record(&command, 24 + command.length());
+        let msg = Message::new(self.params.magic, command);
+        msg.serialize(&mut self.stream)
+    }
+
     fn get_checked_message(&mut self) -> Result<Message, NetworkError> {
         // TODO: don't ignore error here.
         self.stream.read_to_end(&mut self.message_buffer);
@@ -90,8 +205,15 @@ impl PeerConnection {
             return Err(NetworkError::NotEnoughData);
         }
 
+        let payload_length = LittleEndian::read_u32(&self.message_buffer[16..20]) as usize;
+
+        // Bail out before buffering the payload of a message we would never accept.
+        if payload_length > MAX_PROTOCOL_MESSAGE_LENGTH {
+            return Err(NetworkError::OversizedMessage);
+        }
+
         // Check if we have a complete message in the buffer.
-        let message_length = 24 + LittleEndian::read_u32(&self.message_buffer[16..20]) as usize;
+        let message_length = 24 + payload_length;
 
         if self.message_buffer.len() < message_length {
             // We haven't yet received the full payload.
@@ -99,65 +221,94 @@ impl PeerConnection {
         }
 
         let full_message_bytes = self.message_buffer.drain(0..message_length).collect::<Vec<u8>>();
-        let msg = Message::deserialize(&mut &full_message_bytes[..]).unwrap();
-        if msg.network != self.network {
-            // TODO: shutdown this connection or do something else.
+        let msg = Message::deserialize(&mut &full_message_bytes[..])?;
+        self.received.record(&msg.command, message_length);
+        if msg.magic != self.params.magic {
             return Err(NetworkError::WrongNetwork)
         }
 
         Ok(msg)
     }
 
-    fn version_handshake(&mut self) -> Result<bool, NetworkError> {
-        let version = VersionPayload::new(rand::thread_rng().next_u64());
-        let cmd = Command::Version(version);
-        let msg = Message::new(bitcoin::Network::Testnet3, cmd);
-        msg.serialize(&mut self.stream)?;
-
-        let result_msg = Message::deserialize(&mut self.stream)?;
-        match result_msg.command {
-            Command::Version(p) => {
-                self.protocol_version = p.version();
-                self.peer_starting_height = p.start_height();
-            },
-            _ => panic!("Expected version command"),
-        }
-
-        let result_msg = Message::deserialize(&mut self.stream)?;
-        match result_msg.command {
-            Command::Verack => {},
-            _ => panic!("Expected verack command"),
+    fn version_handshake(&mut self) -> Result<(), NetworkError> {
+        let version = VersionPayloadBuilder::new(rand::thread_rng().next_u64())
+            .services(self.settings.services)
+            .timestamp(self.settings.adjusted_time.now())
+            .receiver(self.peer_addr, ServiceFlags::NONE)
+            .sender(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0), self.settings.services)
+            .user_agent(&self.settings.user_agent)
+            .start_height(self.our_height)
+            .relay(self.settings.relay)
+            .build();
+        self.send_command(Command::Version(version))?;
+
+        let mut received_version = false;
+        let mut received_verack = false;
+
+        // The peer's version and verack may come in any order, and other feature negotiation messages (e.g. wtxidrelay, sendaddrv2) may show up between them.
+        while !(received_version && received_verack) {
+            let result_msg = Message::deserialize(&mut self.stream)?;
+            if result_msg.magic != self.params.magic {
+                return Err(NetworkError::WrongNetwork);
+            }
+
+            self.received.record(&result_msg.command, 24 + result_msg.command.length());
+
+            match result_msg.command {
+                Command::Version(ref p) if !received_version => {
+                    if p.version() < MIN_PEER_PROTOCOL_VERSION {
+                        return Err(NetworkError::ObsoleteProtocolVersion(p.version()));
+                    }
+
+                    self.protocol_version = cmp::min(PROTOCOL_VERSION, p.version());
+                    self.features = ProtocolFeatures::for_version(self.protocol_version);
+                    self.peer_starting_height = p.start_height();
+                    self.peer_services = p.services();
+                    self.peer_user_agent = p.user_agent().to_string();
+                    self.peer_relay = p.relay();
+                    self.addr_seen_by_peer = Some(p.addr_recv().socket_addr());
+                    self.peer_time_offset = p.timestamp() - local_time();
+                    received_version = true;
+
+                    debug!("[{}] Peer's version is {} ({}) with services {}, negotiated version {} with features {:?}", self.peer_addr(), p.version(), p.user_agent(), p.services(), self.protocol_version, self.features);
+
+                    // Feature negotiation has to happen before our verack.
+                    if self.features.wtxidrelay {
+                        self.send_command(Command::WtxidRelay)?;
+                    }
+
+                    // Acknowledge the peer's version.
+                    self.send_command(Command::Verack)?;
+                },
+                Command::Verack if !received_verack => {
+                    received_verack = true;
+                },
+                // Only counts when both sides sent it.
+                Command::WtxidRelay if received_version && !received_verack => {
+                    self.wtxid_relay = self.features.wtxidrelay;
+                },
+                Command::Unknown { .. } if received_version => {
+                    debug!("[{}] Ignoring {} during version handshake", self.peer_addr(), result_msg.command.name());
+                },
+                ref command => {
+                    return Err(NetworkError::UnexpectedMessage(command.name().to_string()));
+                },
+            }
         }
 
-        // Send our verack as well.
-        let msg = Message::new(bitcoin::Network::Testnet3, Command::Verack);
-        msg.serialize(&mut self.stream)?;
-
-        // TODO: remove this and instead make it support other versions.
-        if self.protocol_version != 70015 {
-            info!("[{}] Because our peer's version is not 70015, we're ending the connection with them", self.peer_addr());
-            Ok(false)
-        } else {
-            Ok(true)
-        }
+        Ok(())
     }
 
     // To be used for sending certain meta commands to parameterize the communication between two peers only.
     fn send_parameter_messages(&mut self) {
-        let msg = Message::new(bitcoin::Network::Testnet3, Command::SendHeaders);
-        msg.serialize(&mut self.stream).unwrap();
-
-        let cmpct = SendCmpctPayload::new();
-        let msg = Message::new(bitcoin::Network::Testnet3, Command::SendCmpct(cmpct));
-        msg.serialize(&mut self.stream).unwrap();
-
-        let ping_nonce = rand::thread_rng().next_u64();
-        let msg = Message::new(bitcoin::Network::Testnet3, Command::Ping(ping_nonce));
-        msg.serialize(&mut self.stream).unwrap();
+        if self.features.sendheaders {
+            self.send_command(Command::SendHeaders).unwrap();
+        }
 
-        let filter = 0x03e8;
-        let msg = Message::new(bitcoin::Network::Testnet3, Command::Feefilter(filter));
-        msg.serialize(&mut self.stream).unwrap();
+        if self.features.compact_blocks {
+            let cmpct = SendCmpctPayload::new();
+            self.send_command(Command::SendCmpct(cmpct)).unwrap();
+        }
     }
 
     fn handle_network_message(&mut self, msg: Message) {
@@ -167,11 +318,17 @@ impl PeerConnection {
         // If it's something we can reply without sending to the receiver, do it here.
         match msg.command {
             Command::Ping(nonce) => {
-                let pong = Message::new(bitcoin::Network::Testnet3, Command::Pong(nonce));
-                pong.serialize(&mut self.stream).unwrap();
+                self.send_command(Command::Pong(nonce)).unwrap();
                 return;
             },
             Command::Pong(nonce) => {
+                match self.ping_in_flight {
+                    Some((expected, sent)) if expected == nonce => {
+                        self.ping_in_flight = None;
+                        self.outgoing_control_sender.send(KalikoControlMessage::PeerPingTime(self.peer_addr(), sent.elapsed())).unwrap();
+                    },
+                    _ => debug!("[{}] Ignoring pong with unexpected nonce {}", self.peer_addr(), nonce),
+                }
                 return;
             },
             Command::Feefilter(fee_filter) => {
@@ -183,9 +340,30 @@ impl PeerConnection {
                 return;
             },
             Command::SendHeaders => {
-                // TODO: Set headers parameters here.
+                self.peer_prefers_headers = true;
                 return;
             },
+            Command::WtxidRelay => {
+                debug!("[{}] Ignoring wtxidrelay after the version handshake", self.peer_addr());
+                return;
+            },
+            Command::Inv(ref p) => {
+                self.request_announced_transactions(p);
+            },
+            Command::Tx(ref tx) => {
+                let id = if self.wtxid_relay { tx.wtxid() } else { tx.txid() };
+                self.requested_transactions.remove(&id);
+                self.add_known_transaction(id);
+            },
+            Command::Block(ref block) => {
+                let mut hash = [0u8; 32];
+                hash.copy_from_slice(&block.hash());
+
+                if !self.requested_blocks.remove(&hash) {
+                    self.misbehaving(10, "unsolicited block");
+                    return;
+                }
+            },
             _ => (),
         }
 
@@ -197,36 +375,203 @@ impl PeerConnection {
 
         match msg {
             KalikoControlMessage::RequestHeaders(locator) => {
-                let msg = Message::new(bitcoin::Network::Testnet3, Command::GetHeaders(GetBlocksOrHeadersPayload::new(locator)));
-                debug!("Getheaders message: {:?}", msg);
-                msg.serialize(&mut self.stream).unwrap();
+                let command = Command::GetHeaders(GetBlocksOrHeadersPayload::new(locator));
+                debug!("Getheaders message: {:?}", command);
+                self.send_command(command).unwrap();
+            },
+            KalikoControlMessage::RequestPeerInfo(reply) => {
+                let _ = reply.send(self.peer_info());
+            },
+            KalikoControlMessage::AnnounceHeaders(_, headers) => {
+                if self.peer_prefers_headers {
+                    self.send_command(Command::Headers(HeadersPayload { headers })).unwrap();
+                } else if let Some(tip) = headers.last() {
+                    let mut hash = [0u8; 32];
+                    hash.copy_from_slice(&tip.hash());
+                    let inventory = vec![InventoryVector::new(InventoryType::Block, hash)];
+                    self.send_command(Command::Inv(InvPayload::new(inventory))).unwrap();
+                }
+            },
+            KalikoControlMessage::MinFeeUpdated(min_fee) => {
+                self.update_min_fee(min_fee);
+            },
+            KalikoControlMessage::AnnounceTransaction(txid, wtxid, fee_rate) => {
+                let (inventory_type, id) = if self.wtxid_relay { (InventoryType::Wtx, wtxid) } else { (InventoryType::Tx, txid) };
+
+                // The peer doesn't want to hear about transactions it would never accept, or already knows about.
+                let wanted = self.peer_relay && fee_rate >= self.fee_filter && !self.known_transactions.contains(&id);
+
+                if wanted {
+                    self.add_known_transaction(id);
+                    let inventory = vec![InventoryVector::new(inventory_type, id)];
+                    self.send_command(Command::Inv(InvPayload::new(inventory))).unwrap();
+                }
+            },
+            KalikoControlMessage::AnnounceOwnTransaction(txid, wtxid) => {
+                let (inventory_type, id) = if self.wtxid_relay { (InventoryType::Wtx, wtxid) } else { (InventoryType::Tx, txid) };
+
+                if self.peer_relay {
+                    self.add_known_transaction(id);
+                    let inventory = vec![InventoryVector::new(inventory_type, id)];
+                    self.send_command(Command::Inv(InvPayload::new(inventory))).unwrap();
+                }
+            },
+            KalikoControlMessage::SendTransaction(tx) => {
+                self.send_command(Command::Tx(tx)).unwrap();
+            },
+            KalikoControlMessage::SendNotFound(inventory) => {
+                self.send_command(Command::NotFound(InvPayload::new(inventory))).unwrap();
+            },
+            KalikoControlMessage::RequestAddresses => {
+                self.send_command(Command::GetAddr).unwrap();
+            },
+            KalikoControlMessage::SendAddresses(addresses) => {
+                self.send_command(Command::Addr(AddrPayload::new(addresses))).unwrap();
+            },
+            KalikoControlMessage::RequestBlocks(hashes) => {
+                let inventory = hashes.iter().map(|hash| {
+                    let mut block_hash = [0u8; 32];
+                    block_hash.copy_from_slice(hash);
+                    self.requested_blocks.insert(block_hash);
+                    InventoryVector::new(InventoryType::Block, block_hash)
+                }).collect();
+
+                self.send_command(Command::GetData(InvPayload::new(inventory))).unwrap();
             },
             _ => (),
         }
     }
 
-    pub fn handle_connection(&mut self) {
-        match self.version_handshake() {
-            Ok(false) | Err(_) => {
-                self.outgoing_control_sender.send(KalikoControlMessage::PeerUnavailable(self.peer_addr())).unwrap();
-                return;
+    fn add_known_transaction(&mut self, id: [u8; 32]) {
+        if self.known_transactions.len() >= MAX_KNOWN_TRANSACTIONS {
+            self.known_transactions.clear();
+        }
+
+        self.known_transactions.insert(id);
+    }
+
+    // Asks the peer for the transactions it announced that we didn't ask anyone for yet.
+    fn request_announced_transactions(&mut self, inv: &InvPayload) {
+        // Announcements using the other kind of id than the one negotiated are ignored, as BIP339 says.
+        let expected_type = if self.wtxid_relay { InventoryType::Wtx } else { InventoryType::Tx };
+        let timeout = time::Duration::from_secs(TRANSACTION_REQUEST_TIMEOUT);
+        self.requested_transactions.retain(|_, requested| requested.elapsed() < timeout);
+
+        let mut inventory = vec![];
+        for item in inv.inventory().iter().filter(|item| item.object_type() == expected_type) {
+            let id = item.hash();
+            self.add_known_transaction(id);
+
+            if !self.settings.relay || self.requested_transactions.contains_key(&id) || self.requested_transactions.len() >= MAX_TRANSACTIONS_IN_FLIGHT {
+                continue;
+            }
+
+            self.requested_transactions.insert(id, Instant::now());
+            // Transactions requested by txid are asked for with their witness data, which a wtxid already covers.
+            inventory.push(if self.wtxid_relay { InventoryVector::new(InventoryType::Wtx, id) } else { InventoryVector::with_witness(InventoryType::Tx, id) });
+        }
+
+        if !inventory.is_empty() {
+            let _ = self.send_command(Command::GetData(InvPayload::new(inventory)));
+        }
+    }
+
+    fn update_min_fee(&mut self, min_fee: u64) {
+        self.min_fee = min_fee.max(self.settings.min_relay_fee);
+
+        // Big changes shouldn't wait too long, otherwise the peer keeps sending us what we'll reject, or keeps holding back what we'd now accept.
+        if let Some(sent) = self.fee_filter_sent {
+            let big_change = self.min_fee * 4 < sent * 3 || self.min_fee * 3 > sent * 4;
+            let soonest = Instant::now() + time::Duration::from_secs(MAX_FEE_FILTER_CHANGE_DELAY);
+
+            if big_change && self.next_fee_filter_send > soonest {
+                self.next_fee_filter_send = Instant::now() + time::Duration::from_secs(rand::thread_rng().gen_range(0, MAX_FEE_FILTER_CHANGE_DELAY));
+            }
+        }
+    }
+
+    // Tells the peer our fee filter when it's time to and it changed since we last did.
+    fn send_fee_filter_if_needed(&mut self) {
+        // Peers not relaying transactions to us have no use for it.
+        if !self.features.feefilter || !self.settings.relay || Instant::now() < self.next_fee_filter_send {
+            return;
+        }
+
+        self.next_fee_filter_send = Instant::now() + poisson_delay(AVG_FEE_FILTER_INTERVAL);
+
+        let fee_filter = self.fee_filter_rounder.round(self.min_fee);
+        if self.fee_filter_sent == Some(fee_filter) {
+            return;
+        }
+
+        if self.send_command(Command::Feefilter(fee_filter)).is_ok() {
+            self.fee_filter_sent = Some(fee_filter);
+        }
+    }
+
+    // Sends a ping when it's time to, and returns whether the peer is still responsive.
+    fn check_ping_and_timeouts(&mut self) -> bool {
+        let timeout = time::Duration::from_secs(TIMEOUT_INTERVAL);
+
+        if self.last_message_received.elapsed() > timeout {
+            info!("[{}] Peer hasn't sent anything in too long", self.peer_addr());
+            return false;
+        }
+
+        match self.ping_in_flight {
+            Some((_, sent)) => {
+                if sent.elapsed() > timeout {
+                    info!("[{}] Peer didn't answer our ping", self.peer_addr());
+                    return false;
+                }
+            },
+            None => {
+                let ping_due = match self.last_ping_sent {
+                    Some(sent) => sent.elapsed() >= time::Duration::from_secs(PING_INTERVAL),
+                    None => true,
+                };
+
+                if ping_due {
+                    let nonce = rand::thread_rng().next_u64();
+                    let now = Instant::now();
+
+                    if self.send_command(Command::Ping(nonce)).is_err() {
+                        return false;
+                    }
+
+                    self.ping_in_flight = Some((nonce, now));
+                    self.last_ping_sent = Some(now);
+                }
             },
-            _ => (),
         }
 
-        info!("[{}] Version handshake complete! Remote's version is {}", self.peer_addr(), self.protocol_version);
+        true
+    }
+
+    pub fn handle_connection(&mut self) {
+        if let Err(e) = self.version_handshake() {
+            info!("[{}] Version handshake failed, ending the connection: {:?}", self.peer_addr(), e);
+            let _ = self.stream.shutdown(Shutdown::Both);
+            self.outgoing_control_sender.send(KalikoControlMessage::PeerUnavailable(self.peer_addr())).unwrap();
+            return;
+        }
+
+        info!("[{}] Version handshake complete! Negotiated version is {}", self.peer_addr(), self.protocol_version);
         self.outgoing_control_sender.send(KalikoControlMessage::PeerConnectionEstablished(self.peer_addr(), self.incoming_channel())).unwrap();
         self.outgoing_control_sender.send(KalikoControlMessage::PeerAnnouncedHeight(self.peer_addr(), self.peer_starting_height)).unwrap();
+        if let Some(addr) = self.addr_seen_by_peer {
+            self.outgoing_control_sender.send(KalikoControlMessage::ExternalAddressSeen(self.peer_addr(), addr)).unwrap();
+        }
+        self.outgoing_control_sender.send(KalikoControlMessage::PeerTimeOffset(self.peer_addr(), self.peer_time_offset)).unwrap();
 
-        // self.send_parameter_messages();
-        // println!("Finished sending all parameter messages!");
+        self.send_parameter_messages();
 
-        // let msg = Message::new(bitcoin::Network::Testnet3, Command::GetBlocks(GetBlocksOrHeadersPayload::new()));
+        // let msg = Message::new(self.params.magic, Command::GetBlocks(GetBlocksOrHeadersPayload::new()));
         // msg.serialize(&mut self.stream).unwrap();
         // println!("Sent getblocks command");
 
         // Sending fake getheaders message for first 4 blocks of the testnet3 blockchain.
-        // let msg = Message::new(bitcoin::Network::Testnet3, Command::GetHeaders(GetBlocksOrHeadersPayload::new()));
+        // let msg = Message::new(self.params.magic, Command::GetHeaders(GetBlocksOrHeadersPayload::new()));
         // msg.serialize(&mut self.stream).unwrap();
         // println!("Sent getblocks command");
 
@@ -237,23 +582,46 @@ impl PeerConnection {
         loop {
             match self.get_checked_message() {
                 Err(NetworkError::NotEnoughData) => (),
-                Err(NetworkError::InvalidCommand(name)) => {
-                    debug!("[{}] Received invalid command: {}", self.peer_addr(), name);
-                },
                 Err(NetworkError::PeerClosedConnection) => {
                     debug!("[{}] Peer has closed connection to us, breaking out of loop", self.peer_addr());
                     break;
                 },
-                Err(p) => {
-                    debug!("[{}] Got the following error: {:?}", self.peer_addr(), p);
-                    panic!("Got unexpected error");
-                }
+                // After these, we can't tell where the next message starts anymore.
+                Err(NetworkError::OversizedMessage) => {
+                    self.misbehaving(100, "oversized message");
+                    let _ = self.stream.shutdown(Shutdown::Both);
+                    break;
+                },
+                Err(NetworkError::WrongNetwork) => {
+                    self.misbehaving(100, "message for another network");
+                    let _ = self.stream.shutdown(Shutdown::Both);
+                    break;
+                },
+                Err(NetworkError::InvalidChecksum) => {
+                    self.misbehaving(10, "bad checksum");
+                },
+                Err(e) => {
+                    self.misbehaving(20, &format!("malformed message ({:?})", e));
+                },
                 Ok(msg) => {
+                    self.last_message_received = Instant::now();
                     self.handle_network_message(msg);
                 },
             };
 
+            if !self.check_ping_and_timeouts() {
+                let _ = self.stream.shutdown(Shutdown::Both);
+                break;
+            }
+
+            self.send_fee_filter_if_needed();
+
             match self.incoming_message_receiver.try_recv() {
+                Ok(KalikoControlMessage::Disconnect) => {
+                    debug!("[{}] Asked to disconnect", self.peer_addr());
+                    let _ = self.stream.shutdown(Shutdown::Both);
+                    break;
+                },
                 Ok(msg) => self.handle_control_message(msg),
                 _ => (),
             };
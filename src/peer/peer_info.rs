@@ -0,0 +1,138 @@
+use network::{Command, ServiceFlags};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+// Unknown commands are named by the peer, so they're all counted under this name to keep a peer from growing the stats without limit.
+pub const OTHER_COMMANDS: &str = "*other*";
+
+// Messages exchanged with a peer for a single command, in one direction.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MessageStats {
+    pub count: u64,
+    // Including the message header.
+    pub bytes: u64,
+}
+
+// Messages exchanged with a peer in one direction, by command.
+#[derive(Clone, Debug, Default)]
+pub struct TrafficStats {
+    per_command: HashMap<String, MessageStats>,
+}
+
+impl TrafficStats {
+    pub fn new() -> TrafficStats {
+        TrafficStats {
+            per_command: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, command: &Command, bytes: usize) {
+        match *command {
+            Command::Unknown { .. } => self.record_name(OTHER_COMMANDS, bytes),
+            _ => self.record_name(command.name(), bytes),
+        }
+    }
+
+    fn record_name(&mut self, command: &str, bytes: usize) {
+        let stats = self.per_command.entry(command.to_string()).or_default();
+        stats.count += 1;
+        stats.bytes += bytes as u64;
+    }
+
+    pub fn get(&self, command: &str) -> MessageStats {
+        self.per_command.get(command).cloned().unwrap_or_default()
+    }
+
+    pub fn total(&self) -> MessageStats {
+        self.per_command.values().fold(MessageStats::default(), |total, stats| MessageStats {
+            count: total.count + stats.count,
+            bytes: total.bytes + stats.bytes,
+        })
+    }
+
+    // Stats of every command exchanged, sorted by command.
+    pub fn commands(&self) -> Vec<(String, MessageStats)> {
+        let mut result = self.per_command.iter().map(|(command, stats)| (command.clone(), *stats)).collect::<Vec<(String, MessageStats)>>();
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        result
+    }
+}
+
+// Snapshot of what we know about a connected peer.
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    pub services: ServiceFlags,
+    pub user_agent: String,
+    // Protocol version negotiated with the peer.
+    pub version: i32,
+    // Height the peer announced in its version message.
+    pub starting_height: i32,
+    // Height of the best header the peer gave us that's in our chain, or its starting height if higher.
+    pub current_height: i32,
+    pub inbound: bool,
+    pub connected_time: Duration,
+    pub ping: Option<Duration>,
+    pub min_ping: Option<Duration>,
+    // Minimum fee rate, in satoshis per 1000 bytes, of transactions the peer wants to hear about.
+    pub fee_filter: u64,
+    // Whether transactions are announced by wtxid instead of txid.
+    pub wtxid_relay: bool,
+    pub sent: TrafficStats,
+    pub received: TrafficStats,
+}
+
+impl PeerInfo {
+    pub fn new(addr: SocketAddr) -> PeerInfo {
+        PeerInfo {
+            addr,
+            services: ServiceFlags::NONE,
+            user_agent: String::new(),
+            version: 0,
+            starting_height: 0,
+            current_height: 0,
+            inbound: false,
+            connected_time: Duration::from_secs(0),
+            ping: None,
+            min_ping: None,
+            fee_filter: 0,
+            wtxid_relay: false,
+            sent: TrafficStats::new(),
+            received: TrafficStats::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traffic_is_counted_per_command() {
+        let mut stats = TrafficStats::new();
+        stats.record_name("ping", 32);
+        stats.record_name("headers", 1000);
+        stats.record_name("ping", 32);
+
+        assert_eq!(stats.get("ping"), MessageStats { count: 2, bytes: 64 });
+        assert_eq!(stats.get("inv"), MessageStats::default());
+        assert_eq!(stats.total(), MessageStats { count: 3, bytes: 1064 });
+        assert_eq!(stats.commands().iter().map(|(command, _)| command.as_str()).collect::<Vec<&str>>(), vec!["headers", "ping"]);
+    }
+
+    #[test]
+    fn unknown_commands_share_one_entry() {
+        let mut stats = TrafficStats::new();
+        for i in 0..100 {
+            let mut name = [0; 12];
+            name[..4].copy_from_slice(b"junk");
+            name[4] = i;
+            stats.record(&Command::Unknown { name, payload: vec![] }, 24);
+        }
+        stats.record(&Command::Verack, 24);
+
+        assert_eq!(stats.get(OTHER_COMMANDS), MessageStats { count: 100, bytes: 2400 });
+        assert_eq!(stats.commands().len(), 2);
+    }
+}
\ No newline at end of file
@@ -1,20 +1,138 @@
 use ::KalikoControlMessage;
-use bitcoin;
-use network::Command;
-use network::Message;
-use peer::PeerConnection;
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::net::SocketAddr;
+use bitcoin::ChainParams;
+use network::{Command, MAX_ADDR_COUNT, Message, NetworkAddress, ServiceFlags};
+use network::inv::{InventoryType, InventoryVector};
+use peer::{AddressManager, Asmap, BanList, PeerConnection, PeerInfo, PeerSettings};
+use peer::addr_relay::{AddrRelay, poisson_delay};
+use peer::anchors::{MAX_ANCHORS, read_anchors, write_anchors};
+use peer::ban_list::{BAN_THRESHOLD, DEFAULT_BAN_DURATION};
+use peer::broadcast::{TransactionBroadcaster, id_to_hex};
+use peer::dns_seed;
+use peer::netgroup::{is_routable, netgroup};
+use rand;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
 use std::time;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// How often the address manager is written to disk.
+const ADDRESS_SAVE_INTERVAL: u64 = 15 * 60;
+// How many addresses we look at before giving up on filling a connection slot for now.
+const MAX_SELECT_TRIES: usize = 100;
+// How often we make a short lived connection to an address we never connected to, to find out whether it's worth keeping.
+const FEELER_INTERVAL: u64 = 2 * 60;
+// How long we wait before connecting again to an address that failed, doubling after each failure up to the maximum.
+const BASE_RETRY_DELAY: u64 = 30;
+const MAX_RETRY_DELAY: u64 = 60 * 60;
+// Used unless configured otherwise.
+const DEFAULT_CONNECT_TIMEOUT: u64 = 5;
+// How long to wait for a DNS seed to answer.
+const DNS_SEED_TIMEOUT: u64 = 10;
+// Used unless configured otherwise.
+const DEFAULT_MAX_INBOUND_PEERS: usize = 32;
+// Only addr messages this small are relayed, since bigger ones are usually answers to getaddr.
+const MAX_RELAYED_ADDR_MESSAGE: usize = 10;
+// How many peers each relayed address goes to.
+const ADDR_RELAY_PEERS: usize = 2;
+// Only addresses seen this recently are relayed.
+const ADDR_RELAY_MAX_AGE: u64 = 10 * 60;
+// At most this share of our addresses goes into an answer to getaddr, so a peer can't learn all of them.
+const GETADDR_MAX_SHARE: f64 = 0.23;
+// We advertise our own address to each peer about this often.
+const AVG_SELF_ADVERTISEMENT_INTERVAL: f64 = 24.0 * 60.0 * 60.0;
+// How long we wait for peers to report their stats.
+const PEER_INFO_TIMEOUT: u64 = 5;
+// How often we check whether our tip is stale.
+const STALE_CHECK_INTERVAL: u64 = 10 * 60;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+// Delay before retrying an address after `failures` failed attempts in a row. `jitter` goes from 0 to 1 and takes off up to half of the delay, so addresses failing together don't all get retried together.
+fn retry_delay(failures: u32, jitter: f64) -> time::Duration {
+    let delay = BASE_RETRY_DELAY.saturating_mul(1 << failures.saturating_sub(1).min(16)).min(MAX_RETRY_DELAY);
+    time::Duration::from_millis((delay as f64 * 1000.0 * (1.0 - jitter / 2.0)) as u64)
+}
+
+// What we keep track of for each peer we're connected to.
+struct ActivePeer {
+    channel: Sender<KalikoControlMessage>,
+    // Whether the peer connected to us.
+    inbound: bool,
+    connected_at: Instant,
+    // Last time the peer sent us headers, which is how it tells us about new blocks.
+    last_headers: Option<Instant>,
+    min_ping: Option<time::Duration>,
+    last_ping: Option<time::Duration>,
+    // Best height we know the peer to have.
+    best_height: i32,
+    addr_relay: AddrRelay,
+    next_self_advertisement: Instant,
+}
+
+impl ActivePeer {
+    fn new(channel: Sender<KalikoControlMessage>, inbound: bool) -> ActivePeer {
+        ActivePeer {
+            channel,
+            inbound,
+            connected_at: Instant::now(),
+            last_headers: None,
+            min_ping: None,
+            last_ping: None,
+            best_height: 0,
+            addr_relay: AddrRelay::new(),
+            // Outbound peers learn about us right away.
+            next_self_advertisement: if inbound { Instant::now() + poisson_delay(AVG_SELF_ADVERTISEMENT_INTERVAL) } else { Instant::now() },
+        }
+    }
+}
 
 pub struct PeerManager {
-    network: bitcoin::Network,
+    params: ChainParams,
+    settings: PeerSettings,
+    best_height: i32,
     max_active_peers: usize,
-    max_potential_peers: usize,
-    potential_peers: VecDeque<SocketAddr>,
-    active_peers: HashMap<SocketAddr, Sender<KalikoControlMessage>>,
+    address_manager: AddressManager,
+    addresses_location: PathBuf,
+    last_address_save: Instant,
+    asmap: Option<Asmap>,
+    anchors_location: PathBuf,
+    // Anchors from the last run we still have to connect to.
+    pending_anchors: Vec<SocketAddr>,
+    next_feeler: Instant,
+    feelers: HashSet<SocketAddr>,
+    ban_list: BanList,
+    ban_list_location: PathBuf,
+    ban_duration: u64,
+    misbehavior_scores: HashMap<SocketAddr, u32>,
+    // Consecutive failures of each address we couldn't connect to, and when it may be tried again.
+    backoff: HashMap<SocketAddr, (u32, Instant)>,
+    // Addresses from the peer list, which we go back to whenever we're left without peers.
+    seeds: Vec<SocketAddr>,
+    connect_timeout: time::Duration,
+    // DNS server to query seeds through, instead of the system resolver.
+    dns_server: Option<SocketAddr>,
+    // Port we accept connections on, when we do.
+    listen_port: Option<u16>,
+    max_inbound_peers: usize,
+    // Connections accepted by the listener, waiting for us to start them.
+    pending_inbound: Arc<Mutex<HashMap<SocketAddr, TcpStream>>>,
+    inbound_connecting: HashSet<SocketAddr>,
+    // How many peers saw us at each IP, which tells us which address to advertise.
+    external_address_votes: HashMap<IpAddr, usize>,
+    // Our own transactions, which we keep announcing until they confirm.
+    broadcaster: TransactionBroadcaster,
+    running: bool,
+    // When our best height last went up.
+    last_tip_update: Instant,
+    next_stale_check: Instant,
+    active_peers: HashMap<SocketAddr, ActivePeer>,
     connecting_peers: HashSet<SocketAddr>,
     incoming_control_sender: Sender<KalikoControlMessage>,
     incoming_control_receiver: Receiver<KalikoControlMessage>,
@@ -22,14 +140,57 @@ pub struct PeerManager {
 }
 
 impl PeerManager {
-    pub fn new(network: bitcoin::Network, max_active_peers: usize, max_potential_peers: usize, outgoing_control_sender: Sender<KalikoControlMessage>) -> PeerManager {
+    pub fn new(params: ChainParams, settings: PeerSettings, data_dir: &Path, max_active_peers: usize, outgoing_control_sender: Sender<KalikoControlMessage>) -> PeerManager {
         let (incoming_control_sender, incoming_control_receiver) = channel();
 
+        let addresses_location = data_dir.join("peers.toml");
+        let address_manager = match AddressManager::load(&addresses_location) {
+            Ok(manager) => {
+                info!("Loaded {} peer addresses", manager.len());
+                manager
+            },
+            Err(e) => {
+                info!("Starting with no known peer addresses: {}", e);
+                AddressManager::new()
+            },
+        };
+
+        let anchors_location = data_dir.join("anchors.toml");
+        let pending_anchors = read_anchors(&anchors_location);
+
+        let ban_list_location = data_dir.join("banlist.toml");
+        let ban_list = BanList::load(&ban_list_location).unwrap_or_default();
+
         PeerManager {
-            network,
+            params,
+            settings,
+            best_height: 0,
             max_active_peers,
-            max_potential_peers,
-            potential_peers: VecDeque::with_capacity(max_potential_peers),
+            address_manager,
+            addresses_location,
+            last_address_save: Instant::now(),
+            asmap: None,
+            anchors_location,
+            pending_anchors,
+            next_feeler: Instant::now() + time::Duration::from_secs(FEELER_INTERVAL),
+            feelers: HashSet::new(),
+            ban_list,
+            ban_list_location,
+            ban_duration: DEFAULT_BAN_DURATION,
+            misbehavior_scores: HashMap::new(),
+            backoff: HashMap::new(),
+            seeds: vec![],
+            connect_timeout: time::Duration::from_secs(DEFAULT_CONNECT_TIMEOUT),
+            dns_server: None,
+            listen_port: None,
+            max_inbound_peers: DEFAULT_MAX_INBOUND_PEERS,
+            pending_inbound: Arc::new(Mutex::new(HashMap::new())),
+            inbound_connecting: HashSet::new(),
+            external_address_votes: HashMap::new(),
+            broadcaster: TransactionBroadcaster::new(),
+            running: true,
+            last_tip_update: Instant::now(),
+            next_stale_check: Instant::now() + time::Duration::from_secs(STALE_CHECK_INTERVAL),
             active_peers: HashMap::new(),
             connecting_peers: HashSet::new(),
             incoming_control_sender,
@@ -38,105 +199,384 @@ impl PeerManager {
         }
     }
 
+    // Groups outbound peers by the AS announcing their addresses instead of by netgroup.
+    pub fn set_asmap(&mut self, asmap: Asmap) {
+        self.asmap = Some(asmap);
+    }
+
+    // How long, in seconds, misbehaving peers stay banned.
+    pub fn set_ban_duration(&mut self, ban_duration: u64) {
+        self.ban_duration = ban_duration;
+    }
+
+    pub fn set_connect_timeout(&mut self, connect_timeout: time::Duration) {
+        self.connect_timeout = connect_timeout;
+    }
+
+    pub fn set_dns_server(&mut self, dns_server: SocketAddr) {
+        self.dns_server = Some(dns_server);
+    }
+
+    // Accepts connections from other peers on `port`.
+    pub fn set_listen_port(&mut self, port: u16) {
+        self.listen_port = Some(port);
+    }
+
+    pub fn set_max_inbound_peers(&mut self, max_inbound_peers: usize) {
+        self.max_inbound_peers = max_inbound_peers;
+    }
+
     pub fn control_sender(&self) -> Sender<KalikoControlMessage> {
         self.incoming_control_sender.clone()
     }
 
     fn handle_control_message(&mut self, msg: KalikoControlMessage) {
         match msg {
-            KalikoControlMessage::NetworkMessage(_, Message {command: Command::Addr(p), ..}) => {
-                // Add all addresses as potential peers to connect to.
-                for addr in p.addr_list {
-                    let socket_addr = addr.socket_addr();
+            KalikoControlMessage::NetworkMessage(peer, Message {command: Command::Addr(p), ..}) => {
+                self.handle_addr(peer, p.addr_list);
+            },
+            KalikoControlMessage::NetworkMessage(peer, Message {command: Command::GetAddr, ..}) => {
+                self.handle_getaddr(peer);
+            },
+            KalikoControlMessage::SeedAddresses(addrs) => {
+                // Seeds are trusted as given, since they may well be local nodes on test networks.
+                let now = now();
+
+                for addr in addrs.iter() {
+                    self.address_manager.add(*addr, ServiceFlags::NONE, addr.ip(), now, now);
+                }
+
+                self.seeds = addrs;
+            },
+            KalikoControlMessage::DnsSeedAddresses(addrs) => {
+                // Everything from the same seed shares a source, so a single seed can't fill the whole new table.
+                let now = now();
+                let source = match addrs.first() {
+                    Some(addr) => addr.ip(),
+                    None => return,
+                };
 
-                    if !self.potential_peers.contains(&socket_addr) && !self.active_peers.contains_key(&socket_addr) && !self.connecting_peers.contains(&socket_addr) {
-                        self.potential_peers.push_back(socket_addr);
+                for addr in addrs.iter() {
+                    if is_routable(&addr.ip()) {
+                        self.address_manager.add(*addr, ServiceFlags::NETWORK | ServiceFlags::WITNESS, source, now, now);
                     }
                 }
             },
             KalikoControlMessage::NetworkMessage(peer, Message {command: Command::Headers(p), ..}) => {
+                if let Some(active_peer) = self.active_peers.get_mut(&peer) {
+                    if !p.headers.is_empty() {
+                        active_peer.last_headers = Some(Instant::now());
+                    }
+                }
+
                 self.outgoing_control_sender.send(KalikoControlMessage::NewHeadersAvailable(peer, p.headers)).unwrap();
             },
-            KalikoControlMessage::StartPeerConnection(peer) => {
-                if self.active_peers.contains_key(&peer) || self.connecting_peers.contains(&peer){
-                    return;
-                }
+            KalikoControlMessage::NetworkMessage(peer, Message {command: Command::Inv(p), ..}) => {
+                let blocks = p.inventory().iter().filter(|inv| inv.object_type() == InventoryType::Block).map(|inv| inv.hash().to_vec()).collect::<Vec<Vec<u8>>>();
 
-                if self.active_peers.len() + self.connecting_peers.len() >= self.max_active_peers {
-                    // Add as a potential peer if we're already full. If any peer drops out, we can try potential peers.
-                    if !self.potential_peers.contains(&peer) {
-                        // Kick oldest entry if our potential peer queue is already full.
-                        if self.potential_peers.len() >= self.max_potential_peers {
-                            self.potential_peers.pop_front();
-                        }
+                if !blocks.is_empty() && !self.feelers.contains(&peer) {
+                    self.outgoing_control_sender.send(KalikoControlMessage::BlocksAnnounced(peer, blocks)).unwrap();
+                }
 
-                        self.potential_peers.push_back(peer);
-                    }
+                for inv in p.inventory().iter().filter(|inv| inv.object_type() == InventoryType::Tx || inv.object_type() == InventoryType::Wtx) {
+                    self.broadcaster.record_announcement(peer, &inv.hash());
+                }
+            },
+            KalikoControlMessage::NetworkMessage(peer, Message {command: Command::GetData(p), ..}) => {
+                self.serve_transactions(peer, p.inventory());
+            },
+            KalikoControlMessage::NetworkMessage(peer, Message {command: Command::Tx(tx), ..}) => {
+                self.outgoing_control_sender.send(KalikoControlMessage::NewTransactionAvailable(peer, tx)).unwrap();
+            },
+            KalikoControlMessage::NetworkMessage(peer, Message {command: Command::Block(b), ..}) => {
+                self.outgoing_control_sender.send(KalikoControlMessage::NewBlockAvailable(peer, b)).unwrap();
+            },
+            KalikoControlMessage::StartPeerConnection(peer) => {
+                if self.active_peers.contains_key(&peer) || self.connecting_peers.contains(&peer) || self.is_banned(&peer) || self.is_backing_off(&peer) {
                     return;
                 }
 
-                // If the peer we received is in `self.potential_peers`, but we have the space to connect to more peers, we can just try to connect now and remove the peer from `self.potential_peers`.
-                if self.potential_peers.contains(&peer) {
-                    // This is just an operation to remove `peer` from `self.potential_peers`.
-                    self.potential_peers.retain(|p| p != &peer);
+                if self.outbound_count() >= self.max_active_peers {
+                    return;
                 }
 
+                self.address_manager.attempt(&peer, now());
                 self.connecting_peers.insert(peer);
                 self.try_start_connection(peer);
             },
+            KalikoControlMessage::InboundConnection(p) => {
+                self.start_inbound_connection(p);
+            },
             KalikoControlMessage::PeerUnavailable(p) => {
+                if self.inbound_connecting.remove(&p) {
+                    return;
+                }
+
                 if self.connecting_peers.contains(&p) {
                     self.connecting_peers.remove(&p);
                 }
+                self.feelers.remove(&p);
+
+                let failures = self.backoff.get(&p).map_or(0, |&(failures, _)| failures) + 1;
+                let delay = retry_delay(failures, rand::thread_rng().gen::<f64>());
+                debug!("[{}] Connection failed {} times in a row, retrying in {:?}", p, failures, delay);
+                self.backoff.insert(p, (failures, Instant::now() + delay));
             },
             KalikoControlMessage::PeerConnectionDestroyed(p) => {
                 self.active_peers.remove(&p);
+                self.misbehavior_scores.remove(&p);
+                self.feelers.remove(&p);
             },
             KalikoControlMessage::PeerConnectionEstablished(p, chan) => {
+                let inbound = self.inbound_connecting.remove(&p);
+
+                // The addresses of inbound peers are only where they connected from, not where they can be reached.
+                if !inbound {
+                    self.address_manager.good(&p, now());
+                    self.backoff.remove(&p);
+                }
+
+                if self.feelers.contains(&p) {
+                    // The feeler did its job, which was only finding out whether the address works.
+                    debug!("[{}] Feeler connection succeeded", p);
+                    let _ = chan.send(KalikoControlMessage::Disconnect);
+                    return;
+                }
+
                 if self.connecting_peers.contains(&p) {
                     self.connecting_peers.remove(&p);
                 }
 
-                self.active_peers.insert(p, chan);
+                let mut active_peer = ActivePeer::new(chan, inbound);
+
+                // Outbound peers are asked for addresses once. Inbound ones aren't, since that's how an attacker connecting to us would learn which addresses we know.
+                if !inbound {
+                    active_peer.addr_relay.sent_getaddr = true;
+                    active_peer.addr_relay.add_tokens(MAX_ADDR_COUNT);
+                    let _ = active_peer.channel.send(KalikoControlMessage::RequestAddresses);
+                }
+
+                self.active_peers.insert(p, active_peer);
             },
+            KalikoControlMessage::ExternalAddressSeen(peer, addr) => {
+                // Inbound peers could all claim the same address for us, so only outbound ones get a vote.
+                let outbound = self.active_peers.get(&peer).is_some_and(|active_peer| !active_peer.inbound);
+
+                if outbound && is_routable(&addr.ip()) {
+                    *self.external_address_votes.entry(addr.ip()).or_insert(0) += 1;
+                }
+            },
+            KalikoControlMessage::PeerTimeOffset(peer, offset) => {
+                // We pick our outbound peers, while anyone can connect to us and skew our clock.
+                let outbound = self.active_peers.get(&peer).is_some_and(|active_peer| !active_peer.inbound);
+
+                if outbound {
+                    self.settings.adjusted_time.add_sample(peer.ip(), offset);
+                }
+            },
+            KalikoControlMessage::PeerPingTime(peer, ping) => {
+                if let Some(active_peer) = self.active_peers.get_mut(&peer) {
+                    debug!("[{}] Ping time is {:?}", peer, ping);
+                    active_peer.last_ping = Some(ping);
+                    active_peer.min_ping = Some(active_peer.min_ping.map_or(ping, |min_ping| min_ping.min(ping)));
+                }
+            },
+            KalikoControlMessage::PeerAnnouncedHeight(peer, _) if self.feelers.contains(&peer) => (),
             KalikoControlMessage::PeerAnnouncedHeight(peer, height) => {
+                if let Some(active_peer) = self.active_peers.get_mut(&peer) {
+                    active_peer.best_height = active_peer.best_height.max(height);
+                }
+
                 self.outgoing_control_sender.send(KalikoControlMessage::PeerAnnouncedHeight(peer, height)).unwrap();
             },
+            KalikoControlMessage::ChainHeightUpdated(height) => {
+                if height > self.best_height {
+                    self.last_tip_update = Instant::now();
+                }
+
+                // Used when introducing ourselves to new peers.
+                self.best_height = height;
+            },
             KalikoControlMessage::RequestHeadersFromPeer(peer, locator) => {
                 match self.active_peers.get(&peer) {
                     None => (),
-                    Some(chan) => {
-                        chan.send(KalikoControlMessage::RequestHeaders(locator)).unwrap();
+                    Some(active_peer) => {
+                        active_peer.channel.send(KalikoControlMessage::RequestHeaders(locator)).unwrap();
                     },
                 }
             },
+            KalikoControlMessage::RequestBlocksFromPeer(peer, hashes) => {
+                if let Some(active_peer) = self.active_peers.get(&peer) {
+                    active_peer.channel.send(KalikoControlMessage::RequestBlocks(hashes)).unwrap();
+                }
+            },
+            KalikoControlMessage::Misbehaving(peer, score, reason) => {
+                let total = {
+                    let total = self.misbehavior_scores.entry(peer).or_insert(0);
+                    *total += score;
+                    *total
+                };
+
+                debug!("[{}] Misbehavior score is now {} after {}", peer, total, reason);
+
+                if total >= BAN_THRESHOLD {
+                    info!("[{}] Banning peer for misbehaving: {}", peer, reason);
+                    let duration = self.ban_duration;
+                    self.ban(peer.ip(), duration);
+                }
+            },
+            KalikoControlMessage::Ban(ip, duration) => {
+                let duration = if duration == 0 { self.ban_duration } else { duration };
+                self.ban(ip, duration);
+            },
+            KalikoControlMessage::Unban(ip) => {
+                let was_banned = self.ban_list.unban(&ip);
+                if was_banned {
+                    self.save_ban_list();
+                }
+            },
+            KalikoControlMessage::PeerBestHeight(peer, height) => {
+                if let Some(active_peer) = self.active_peers.get_mut(&peer) {
+                    active_peer.best_height = active_peer.best_height.max(height);
+                }
+            },
+            KalikoControlMessage::MinFeeUpdated(min_fee) => {
+                for active_peer in self.active_peers.values() {
+                    let _ = active_peer.channel.send(KalikoControlMessage::MinFeeUpdated(min_fee));
+                }
+            },
+            KalikoControlMessage::AnnounceTransaction(txid, wtxid, fee_rate) => {
+                for active_peer in self.active_peers.values() {
+                    let _ = active_peer.channel.send(KalikoControlMessage::AnnounceTransaction(txid, wtxid, fee_rate));
+                }
+            },
+            KalikoControlMessage::ServeTransactions(peer, transactions, missing) => {
+                if let Some(active_peer) = self.active_peers.get(&peer) {
+                    for tx in transactions {
+                        let _ = active_peer.channel.send(KalikoControlMessage::SendTransaction(tx));
+                    }
+
+                    // Otherwise the peer would keep waiting for them.
+                    if !missing.is_empty() {
+                        let _ = active_peer.channel.send(KalikoControlMessage::SendNotFound(missing));
+                    }
+                }
+            },
+            KalikoControlMessage::BroadcastTransaction(tx) => {
+                let txid = tx.txid();
+                if self.broadcaster.add(tx, Instant::now()) {
+                    info!("Broadcasting transaction {}", id_to_hex(&txid));
+                }
+            },
+            KalikoControlMessage::BlockConnected(_, block) => {
+                let (confirmed, conflicted) = self.broadcaster.block_connected(&block);
+
+                for own in confirmed {
+                    info!("Transaction {} confirmed after {} broadcasts, requested by {} peers and announced back by {}", id_to_hex(&own.txid), own.broadcasts, own.requested_by.len(), own.announced_by.len());
+                }
+                for own in conflicted {
+                    warn!("Transaction {} conflicts with block {}, no longer broadcasting it", id_to_hex(&own.txid), block.header);
+                }
+            },
+            KalikoControlMessage::TransactionsRemoved(txids) => {
+                for own in self.broadcaster.remove(&txids) {
+                    warn!("Transaction {} was dropped from the mempool, no longer broadcasting it", id_to_hex(&own.txid));
+                }
+            },
+            KalikoControlMessage::AnnounceHeaders(source, headers) => {
+                for (addr, active_peer) in self.active_peers.iter() {
+                    if *addr != source {
+                        let _ = active_peer.channel.send(KalikoControlMessage::AnnounceHeaders(source, headers.clone()));
+                    }
+                }
+            },
+            KalikoControlMessage::GetPeerInfo(reply) => {
+                self.collect_peer_info(reply);
+            },
+            KalikoControlMessage::ListBanned(reply) => {
+                let _ = reply.send(self.ban_list.banned(now()));
+            },
+            KalikoControlMessage::Shutdown => {
+                info!("Shutting down the peer manager");
+                self.running = false;
+                self.save_addresses();
+                self.save_ban_list();
+
+                // The peers we've been connected to for the longest are the ones least likely to be an attacker's.
+                let mut peers = self.active_peers.iter().filter(|&(_, active_peer)| !active_peer.inbound).map(|(addr, active_peer)| (active_peer.connected_at, *addr)).collect::<Vec<(Instant, SocketAddr)>>();
+                peers.sort();
+                let anchors = peers.into_iter().map(|(_, addr)| addr).take(MAX_ANCHORS).collect::<Vec<SocketAddr>>();
+                if let Err(e) = write_anchors(&self.anchors_location, &anchors) {
+                    warn!("Couldn't save anchors: {}", e);
+                }
+
+                for active_peer in self.active_peers.values() {
+                    let _ = active_peer.channel.send(KalikoControlMessage::Disconnect);
+                }
+
+                self.outgoing_control_sender.send(KalikoControlMessage::ShutdownComplete).unwrap();
+            },
             _ => (),
         }
     }
 
+    // Asks the network's DNS seeds for addresses in the background.
+    fn query_dns_seeds(&self) {
+        let seeds = self.params.dns_seeds;
+        let port = self.params.default_port;
+        let dns_server = self.dns_server;
+        let control_sender = self.incoming_control_sender.clone();
+
+        thread::spawn(move || {
+            for seed in seeds {
+                let lookup = |hostname: &str| {
+                    match dns_server {
+                        Some(server) => dns_seed::query(server, hostname, time::Duration::from_secs(DNS_SEED_TIMEOUT)),
+                        None => dns_seed::query_system(hostname),
+                    }
+                };
+
+                // Not every seed can filter by services, in which case we settle for whatever it has.
+                let filtered = dns_seed::seed_hostname(seed, ServiceFlags::NETWORK | ServiceFlags::WITNESS);
+                let ips = match lookup(&filtered) {
+                    Ok(ref ips) if !ips.is_empty() => ips.clone(),
+                    _ => lookup(seed).unwrap_or_default(),
+                };
+
+                info!("Got {} addresses from DNS seed {}", ips.len(), seed);
+
+                let addrs = ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect::<Vec<SocketAddr>>();
+                if control_sender.send(KalikoControlMessage::DnsSeedAddresses(addrs)).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
     pub fn start(mut self) {
+        if self.address_manager.is_empty() {
+            self.query_dns_seeds();
+        }
+
+        if let Some(port) = self.listen_port {
+            self.start_listener(port);
+        }
+
         thread::spawn(move || {
-            loop {
+            while self.running {
                 match self.incoming_control_receiver.try_recv() {
                     Ok(msg) => {
                         debug!("Got control message: {:?}", msg);
                         self.handle_control_message(msg);
                     },
                     _ => {
-                        // Check if we're at full capacity of connected peers. If not, try to connect to some more from potential peers.
                         // Notice that we only do this if we have no other message to receive from the channel. When we have too many messages to handle, the last priority is connecting to more peers (which will cause us to receive even more messages to handle).
-                        // Also notice that if we take the current code as-is out of this place, we will have a logic bug because we don't take into account the amount of `StartPeerConnection` messages when calculating `num_new_peers`. This will cause us to remove a lot of peers from `self.potential_peers` only to have them put back into `self.potential_peers` when the messages are processed.
-                        let mut num_new_peers = self.max_active_peers - (self.active_peers.len() + self.connecting_peers.len());
-                        num_new_peers = ::std::cmp::min(self.potential_peers.len(), num_new_peers);
-                        if num_new_peers > 0 {
-                            info!("We're not at capacity of connected peers, so trying to connect to some more");
-                            let peers_to_try = self.potential_peers.drain(..num_new_peers).collect::<Vec<SocketAddr>>();
-
-                            for peer in peers_to_try {
-                                trace!("Trying to connect to {}", peer);
-                                self.incoming_control_sender.send(KalikoControlMessage::StartPeerConnection(peer)).unwrap();
-                            }
-                        }
+                        self.connect_to_more_peers();
+                        self.start_feeler_if_needed();
+                        self.send_queued_addresses();
+                        self.rebroadcast_own_transactions();
+                        self.evict_peer_if_tip_is_stale();
+                        self.save_addresses_if_needed();
                     },
                 }
 
@@ -145,13 +585,455 @@ impl PeerManager {
         });
     }
 
+    // Group used to keep outbound peers diverse. Addresses that aren't routable are usually local nodes we were told to use, so each one is its own group.
+    fn group(&self, addr: &SocketAddr) -> Vec<u8> {
+        if !is_routable(&addr.ip()) {
+            return addr.to_string().into_bytes();
+        }
+
+        match self.asmap {
+            Some(ref asmap) => asmap.group(&addr.ip()),
+            None => netgroup(&addr.ip()),
+        }
+    }
+
+    fn start_connection(&mut self, addr: SocketAddr, now: u64) {
+        trace!("Trying to connect to {}", addr);
+        self.address_manager.attempt(&addr, now);
+        self.connecting_peers.insert(addr);
+        self.try_start_connection(addr);
+    }
+
+    // Fills free connection slots with addresses picked by the address manager, never using two peers from the same group.
+    fn connect_to_more_peers(&mut self) {
+        let now = now();
+
+        // Without any peers, we can't learn about new addresses, so we fall back on the seeds.
+        if self.active_peers.is_empty() && self.connecting_peers.is_empty() {
+            for seed in self.seeds.clone() {
+                if self.outbound_count() >= self.max_active_peers {
+                    break;
+                }
+
+                if !self.is_banned(&seed) && !self.is_backing_off(&seed) {
+                    self.start_connection(seed, now);
+                }
+            }
+        }
+
+        // Anchors go first, so we end up connected to the same peers as before the restart.
+        while self.outbound_count() < self.max_active_peers {
+            let anchor = match self.pending_anchors.pop() {
+                Some(anchor) => anchor,
+                None => break,
+            };
+
+            if !self.active_peers.contains_key(&anchor) && !self.connecting_peers.contains(&anchor) && !self.is_banned(&anchor) && !self.is_backing_off(&anchor) {
+                info!("Connecting to anchor {}", anchor);
+                self.start_connection(anchor, now);
+            }
+        }
+
+        let outbound_peers = self.active_peers.iter().filter(|&(_, active_peer)| !active_peer.inbound).map(|(addr, _)| addr);
+        let mut used_groups = outbound_peers.chain(self.connecting_peers.iter()).map(|addr| self.group(addr)).collect::<HashSet<Vec<u8>>>();
+        let mut tries = 0;
+
+        while self.outbound_count() < self.max_active_peers && tries < MAX_SELECT_TRIES {
+            tries += 1;
+
+            let info = match self.address_manager.select(false, now) {
+                Some(info) => info,
+                None => return,
+            };
+
+            if self.active_peers.contains_key(&info.addr) || self.connecting_peers.contains(&info.addr) || self.feelers.contains(&info.addr) || self.is_banned(&info.addr) || self.is_backing_off(&info.addr) {
+                continue;
+            }
+
+            let group = self.group(&info.addr);
+            if used_groups.contains(&group) {
+                continue;
+            }
+
+            // Only go back to addresses tried recently once we've looked at plenty of others.
+            if now - info.last_try.min(now) < 10 * 60 && tries < 30 {
+                continue;
+            }
+
+            used_groups.insert(group);
+            self.start_connection(info.addr, now);
+        }
+    }
+
+    // Feelers test addresses we only heard about, so the tried table keeps growing with addresses known to work. They're only made once all slots are filled, since filling those is more important.
+    fn start_feeler_if_needed(&mut self) {
+        if Instant::now() < self.next_feeler {
+            return;
+        }
+
+        self.next_feeler = Instant::now() + time::Duration::from_secs(FEELER_INTERVAL);
+
+        if self.outbound_count() < self.max_active_peers {
+            return;
+        }
+
+        let now = now();
+        let info = match self.address_manager.select(true, now) {
+            Some(info) => info,
+            None => return,
+        };
+
+        if self.active_peers.contains_key(&info.addr) || self.connecting_peers.contains(&info.addr) || self.feelers.contains(&info.addr) || self.is_banned(&info.addr) || self.is_backing_off(&info.addr) {
+            return;
+        }
+
+        debug!("[{}] Making a feeler connection", info.addr);
+        self.address_manager.attempt(&info.addr, now);
+        self.feelers.insert(info.addr);
+        self.try_start_connection(info.addr);
+    }
+
+    // When we haven't heard of a new block in a while, some of our peers may be keeping blocks from us. If all slots are taken, we make room for a new peer by dropping the one that went the longest without sending us headers.
+    fn evict_peer_if_tip_is_stale(&mut self) {
+        if Instant::now() < self.next_stale_check {
+            return;
+        }
+
+        self.next_stale_check = Instant::now() + time::Duration::from_secs(STALE_CHECK_INTERVAL);
+
+        let stale_tip_age = time::Duration::from_secs(3 * self.params.pow_target_spacing as u64);
+        if self.last_tip_update.elapsed() < stale_tip_age || self.outbound_count() < self.max_active_peers {
+            return;
+        }
+
+        // Peers that never sent headers go first, then the ones whose last headers are the oldest. Between those, the most recently connected goes first.
+        let worst_peer = self.active_peers.iter()
+            .filter(|&(_, active_peer)| !active_peer.inbound)
+            .min_by_key(|&(_, active_peer)| (active_peer.last_headers, ::std::cmp::Reverse(active_peer.connected_at)))
+            .map(|(addr, active_peer)| (*addr, active_peer.channel.clone()));
+
+        if let Some((addr, channel)) = worst_peer {
+            info!("[{}] Our tip is stale, disconnecting peer to try another one", addr);
+            let _ = channel.send(KalikoControlMessage::Disconnect);
+        }
+    }
+
+    fn save_addresses_if_needed(&mut self) {
+        if self.last_address_save.elapsed() < time::Duration::from_secs(ADDRESS_SAVE_INTERVAL) {
+            return;
+        }
+
+        self.save_addresses();
+    }
+
+    fn save_addresses(&mut self) {
+        self.last_address_save = Instant::now();
+
+        if let Err(e) = self.address_manager.save(&self.addresses_location) {
+            warn!("Couldn't save peer addresses: {}", e);
+        }
+    }
+
+    // Outbound peers we're connected or connecting to. Inbound peers and feelers have their own limits.
+    fn outbound_count(&self) -> usize {
+        self.active_peers.values().filter(|active_peer| !active_peer.inbound).count() + self.connecting_peers.len()
+    }
+
+    // The address other peers can reach us at, if we're listening and know our IP.
+    fn external_address(&self) -> Option<SocketAddr> {
+        let port = self.listen_port?;
+        let (ip, _) = self.external_address_votes.iter().max_by_key(|&(ip, votes)| (*votes, *ip))?;
+
+        Some(SocketAddr::new(*ip, port))
+    }
+
+    fn handle_addr(&mut self, peer: SocketAddr, addr_list: Vec<NetworkAddress>) {
+        let now = now();
+        let relay = addr_list.len() <= MAX_RELAYED_ADDR_MESSAGE;
+        let mut to_relay = vec![];
+        let mut dropped = 0;
+
+        {
+            let active_peer = match self.active_peers.get_mut(&peer) {
+                Some(active_peer) => active_peer,
+                None => return,
+            };
+
+            let instant_now = Instant::now();
+            for addr in addr_list {
+                if !active_peer.addr_relay.take_token(instant_now) {
+                    dropped += 1;
+                    continue;
+                }
+
+                let socket_addr = addr.socket_addr();
+                active_peer.addr_relay.add_known(socket_addr);
+
+                if !is_routable(&socket_addr.ip()) {
+                    continue;
+                }
+
+                self.address_manager.add(socket_addr, addr.services(), peer.ip(), addr.time() as u64, now);
+
+                if relay && (addr.time() as u64) + ADDR_RELAY_MAX_AGE > now {
+                    to_relay.push(addr);
+                }
+            }
+        }
+
+        if dropped > 0 {
+            debug!("[{}] Dropped {} addresses over the rate limit", peer, dropped);
+        }
+
+        for addr in to_relay {
+            self.relay_address(peer, addr);
+        }
+    }
+
+    // Passes a fresh address on to a few random peers, other than the one it came from.
+    fn relay_address(&mut self, from: SocketAddr, addr: NetworkAddress) {
+        let mut candidates = self.active_peers.keys().filter(|&peer| *peer != from).cloned().collect::<Vec<SocketAddr>>();
+        rand::thread_rng().shuffle(&mut candidates);
+
+        for peer in candidates.into_iter().take(ADDR_RELAY_PEERS) {
+            if let Some(active_peer) = self.active_peers.get_mut(&peer) {
+                active_peer.addr_relay.push(addr.clone());
+            }
+        }
+    }
+
+    fn handle_getaddr(&mut self, peer: SocketAddr) {
+        let now = now();
+        let max_count = ((self.address_manager.len() as f64 * GETADDR_MAX_SHARE) as usize).min(MAX_ADDR_COUNT);
+        let addresses = self.address_manager.addresses(max_count, now);
+
+        let active_peer = match self.active_peers.get_mut(&peer) {
+            Some(active_peer) => active_peer,
+            None => return,
+        };
+
+        // Answering outbound peers would let them fingerprint us, and answering more than once lets anyone scrape our whole address manager.
+        if !active_peer.inbound || active_peer.addr_relay.answered_getaddr {
+            debug!("[{}] Ignoring getaddr", peer);
+            return;
+        }
+
+        active_peer.addr_relay.answered_getaddr = true;
+
+        for info in addresses {
+            let mut addr = NetworkAddress::from_socket_addr(info.addr, info.services);
+            addr.set_time(info.last_seen as u32);
+            active_peer.addr_relay.push(addr);
+        }
+    }
+
+    // Sends the addresses queued for each peer whose timer ran out, along with our own address from time to time.
+    fn send_queued_addresses(&mut self) {
+        let instant_now = Instant::now();
+        let external_address = self.external_address().map(|addr| {
+            let mut addr = NetworkAddress::from_socket_addr(addr, self.settings.services);
+            addr.set_time(now() as u32);
+            addr
+        });
+
+        for active_peer in self.active_peers.values_mut() {
+            if let Some(ref addr) = external_address {
+                if instant_now >= active_peer.next_self_advertisement {
+                    active_peer.next_self_advertisement = instant_now + poisson_delay(AVG_SELF_ADVERTISEMENT_INTERVAL);
+                    active_peer.addr_relay.push(addr.clone());
+                }
+            }
+
+            if let Some(addresses) = active_peer.addr_relay.flush(instant_now) {
+                let _ = active_peer.channel.send(KalikoControlMessage::SendAddresses(addresses));
+            }
+        }
+    }
+
+    // Sends the peer those of our own transactions it asked for, and leaves the rest to the mempool.
+    fn serve_transactions(&mut self, peer: SocketAddr, inventory: &[InventoryVector]) {
+        let channel = match self.active_peers.get(&peer) {
+            Some(active_peer) => active_peer.channel.clone(),
+            None => return,
+        };
+
+        let mut others = vec![];
+        for inv in inventory.iter().filter(|inv| inv.object_type() == InventoryType::Tx || inv.object_type() == InventoryType::Wtx) {
+            match self.broadcaster.record_request(peer, &inv.hash()) {
+                Some(tx) => {
+                    let _ = channel.send(KalikoControlMessage::SendTransaction(tx.clone()));
+                },
+                None => others.push(inv.clone()),
+            }
+        }
+
+        if !others.is_empty() {
+            self.outgoing_control_sender.send(KalikoControlMessage::TransactionsRequested(peer, others)).unwrap();
+        }
+    }
+
+    // Announces our own transactions that are due again to the peers we don't know to have them.
+    fn rebroadcast_own_transactions(&mut self) {
+        for txid in self.broadcaster.due(Instant::now()) {
+            let own = match self.broadcaster.get(&txid) {
+                Some(own) => own,
+                None => continue,
+            };
+
+            for (addr, active_peer) in self.active_peers.iter() {
+                if !own.reached(addr) && !self.feelers.contains(addr) {
+                    let _ = active_peer.channel.send(KalikoControlMessage::AnnounceOwnTransaction(own.txid, own.wtxid));
+                }
+            }
+        }
+    }
+
+    fn start_inbound_connection(&mut self, addr: SocketAddr) {
+        let stream = match self.pending_inbound.lock().unwrap().remove(&addr) {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        let inbound_count = self.active_peers.values().filter(|active_peer| active_peer.inbound).count() + self.inbound_connecting.len();
+        if self.is_banned(&addr) || inbound_count >= self.max_inbound_peers || self.active_peers.contains_key(&addr) {
+            debug!("[{}] Refusing inbound connection", addr);
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+
+        debug!("[{}] Accepted inbound connection", addr);
+        self.inbound_connecting.insert(addr);
+
+        let control_sender = self.incoming_control_sender.clone();
+        let params = self.params.clone();
+        let settings = self.settings.clone();
+        let best_height = self.best_height;
+
+        thread::spawn(move || {
+            PeerConnection::new(params, stream, settings, best_height, control_sender).handle_connection();
+        });
+    }
+
+    // Accepts connections in the background, handing them over to the peer manager.
+    fn start_listener(&self, port: u16) {
+        let listener = match TcpListener::bind(("0.0.0.0", port)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Couldn't listen on port {}: {}", port, e);
+                return;
+            },
+        };
+
+        info!("Listening for connections on port {}", port);
+        let pending_inbound = self.pending_inbound.clone();
+        let control_sender = self.incoming_control_sender.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+
+                let addr = match stream.peer_addr() {
+                    Ok(addr) => addr,
+                    Err(_) => continue,
+                };
+
+                pending_inbound.lock().unwrap().insert(addr, stream);
+                if control_sender.send(KalikoControlMessage::InboundConnection(addr)).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    // Asks every peer connection for its stats and adds what we know about the peer, without holding up the peer manager while waiting for the answers.
+    fn collect_peer_info(&self, reply: Sender<Vec<PeerInfo>>) {
+        let (info_sender, info_receiver) = channel();
+        let mut partial_info = HashMap::new();
+
+        for (addr, active_peer) in self.active_peers.iter() {
+            if active_peer.channel.send(KalikoControlMessage::RequestPeerInfo(info_sender.clone())).is_err() {
+                continue;
+            }
+
+            let mut info = PeerInfo::new(*addr);
+            info.current_height = active_peer.best_height;
+            info.inbound = active_peer.inbound;
+            info.connected_time = active_peer.connected_at.elapsed();
+            info.ping = active_peer.last_ping;
+            info.min_ping = active_peer.min_ping;
+            partial_info.insert(*addr, info);
+        }
+
+        thread::spawn(move || {
+            let deadline = Instant::now() + time::Duration::from_secs(PEER_INFO_TIMEOUT);
+            let mut result = vec![];
+
+            // Peers disconnecting meanwhile never answer.
+            while result.len() < partial_info.len() {
+                let mut info: PeerInfo = match info_receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(info) => info,
+                    Err(_) => break,
+                };
+
+                if let Some(ours) = partial_info.get(&info.addr) {
+                    info.current_height = info.current_height.max(ours.current_height);
+                    info.inbound = ours.inbound;
+                    info.connected_time = ours.connected_time;
+                    info.ping = ours.ping;
+                    info.min_ping = ours.min_ping;
+                    result.push(info);
+                }
+            }
+
+            result.sort_by_key(|info| info.addr);
+            let _ = reply.send(result);
+        });
+    }
+
+    fn is_backing_off(&self, addr: &SocketAddr) -> bool {
+        match self.backoff.get(addr) {
+            Some(&(_, retry_at)) => Instant::now() < retry_at,
+            None => false,
+        }
+    }
+
+    fn is_banned(&self, addr: &SocketAddr) -> bool {
+        self.ban_list.is_banned(&addr.ip(), now())
+    }
+
+    // Bans `ip` for `duration` seconds and drops every connection we have to it.
+    fn ban(&mut self, ip: IpAddr, duration: u64) {
+        self.ban_list.ban(ip, now().saturating_add(duration));
+        self.save_ban_list();
+
+        for (addr, active_peer) in self.active_peers.iter() {
+            if addr.ip() == ip {
+                let _ = active_peer.channel.send(KalikoControlMessage::Disconnect);
+            }
+        }
+    }
+
+    fn save_ban_list(&mut self) {
+        self.ban_list.sweep(now());
+
+        if let Err(e) = self.ban_list.save(&self.ban_list_location) {
+            warn!("Couldn't save the ban list: {}", e);
+        }
+    }
+
     fn try_start_connection(&mut self, addr: SocketAddr) {
         let control_sender = self.incoming_control_sender.clone();
-        let network = self.network;
+        let params = self.params.clone();
+        let settings = self.settings.clone();
+        let best_height = self.best_height;
+        let connect_timeout = self.connect_timeout;
 
         // Attempt the connection in a separate thread - this avoids blocking the peer manager from dealing with other messages.
         thread::spawn(move || {
-            match PeerConnection::connect(network, addr, control_sender) {
+            match PeerConnection::connect(params, addr, settings, best_height, connect_timeout, control_sender) {
                 Ok(mut connection) => {
                     connection.handle_connection();
                 },
@@ -159,4 +1041,17 @@ impl PeerManager {
             }
         });
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_backs_off() {
+        assert_eq!(retry_delay(1, 0.0), time::Duration::from_secs(BASE_RETRY_DELAY));
+        assert_eq!(retry_delay(2, 0.0), time::Duration::from_secs(2 * BASE_RETRY_DELAY));
+        assert_eq!(retry_delay(3, 1.0), time::Duration::from_secs(2 * BASE_RETRY_DELAY));
+        assert_eq!(retry_delay(100, 0.0), time::Duration::from_secs(MAX_RETRY_DELAY));
+    }
 }
\ No newline at end of file
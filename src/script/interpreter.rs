@@ -0,0 +1,738 @@
+use ripemd160::Ripemd160;
+use secp256k1::{ContextFlag, Message, PublicKey, Secp256k1, Signature};
+use sha2::Digest;
+
+use network::transaction::{Transaction, LOCKTIME_THRESHOLD, SEQUENCE_FINAL, SEQUENCE_LOCKTIME_DISABLE_FLAG, SEQUENCE_LOCKTIME_MASK, SEQUENCE_LOCKTIME_TYPE_FLAG};
+use script::*;
+use script::opcodes::*;
+use util::{hash160, sha1, sha256, sha256d};
+
+// Verification flags. Besides the ones used when validating blocks, only MINIMALDATA and DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM are supported.
+pub const VERIFY_P2SH: u32 = 1 << 0;
+pub const VERIFY_DERSIG: u32 = 1 << 2;
+pub const VERIFY_NULLDUMMY: u32 = 1 << 4;
+// Pushes and numbers must use as few bytes as possible.
+pub const VERIFY_MINIMALDATA: u32 = 1 << 6;
+// BIP65 and BIP112, which turn OP_NOP2 and OP_NOP3 into OP_CHECKLOCKTIMEVERIFY and OP_CHECKSEQUENCEVERIFY.
+pub const VERIFY_CHECKLOCKTIMEVERIFY: u32 = 1 << 9;
+pub const VERIFY_CHECKSEQUENCEVERIFY: u32 = 1 << 10;
+pub const VERIFY_WITNESS: u32 = 1 << 11;
+// Witness versions we don't know the rules of (including taproot) fail instead of passing.
+pub const VERIFY_DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM: u32 = 1 << 12;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SigVersion {
+    Base,
+    WitnessV0,
+}
+
+// Checks signatures for a given input of a transaction.
+pub struct SignatureChecker<'a> {
+    tx: &'a Transaction,
+    input_index: usize,
+    // Value of the output being spent, which is signed by witness v0 signatures.
+    amount: i64,
+    secp: Secp256k1,
+}
+
+impl<'a> SignatureChecker<'a> {
+    pub fn new(tx: &'a Transaction, input_index: usize, amount: i64) -> SignatureChecker<'a> {
+        SignatureChecker {
+            tx,
+            input_index,
+            amount,
+            secp: Secp256k1::with_caps(ContextFlag::VerifyOnly),
+        }
+    }
+
+    // `signature` includes the sighash type as its last byte.
+    pub fn check_signature(&self, signature: &[u8], pubkey: &[u8], script_code: &[u8], sigversion: SigVersion) -> bool {
+        if signature.is_empty() {
+            return false;
+        }
+
+        let pubkey = match PublicKey::from_slice(&self.secp, pubkey) {
+            Ok(pubkey) => pubkey,
+            Err(_) => return false,
+        };
+
+        let (der, sighash_type) = signature.split_at(signature.len() - 1);
+        let mut signature = match Signature::from_der_lax(&self.secp, der) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        // High S values are valid in consensus, but libsecp256k1 only accepts low S ones.
+        signature.normalize_s(&self.secp);
+
+        let sighash_type = sighash_type[0] as u32;
+        let hash = match sigversion {
+            SigVersion::Base => legacy_sighash(self.tx, self.input_index, script_code, sighash_type),
+            SigVersion::WitnessV0 => witness_v0_sighash(self.tx, self.input_index, script_code, self.amount, sighash_type),
+        };
+
+        let message = Message::from_slice(&hash).expect("Hashes are always 32 bytes long");
+        self.secp.verify(&message, &signature, &pubkey).is_ok()
+    }
+
+    // BIP65: the transaction's lock time is of the same kind as `lock_time` and at least as late.
+    pub fn check_lock_time(&self, lock_time: i64) -> bool {
+        let tx_lock_time = self.tx.lock_time as i64;
+        let threshold = LOCKTIME_THRESHOLD as i64;
+
+        if (tx_lock_time < threshold) != (lock_time < threshold) || lock_time > tx_lock_time {
+            return false;
+        }
+
+        // Otherwise the lock time could be bypassed by making every input final.
+        self.tx.inputs[self.input_index].sequence != SEQUENCE_FINAL
+    }
+
+    // BIP112: the input's relative lock time is of the same kind as `sequence` and at least as long.
+    pub fn check_sequence(&self, sequence: i64) -> bool {
+        let tx_sequence = self.tx.inputs[self.input_index].sequence;
+
+        if self.tx.version < 2 || tx_sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return false;
+        }
+
+        let mask = (SEQUENCE_LOCKTIME_TYPE_FLAG | SEQUENCE_LOCKTIME_MASK) as i64;
+        let tx_sequence = (tx_sequence as i64) & mask;
+        let sequence = sequence & mask;
+        let type_flag = SEQUENCE_LOCKTIME_TYPE_FLAG as i64;
+
+        (tx_sequence < type_flag) == (sequence < type_flag) && sequence <= tx_sequence
+    }
+}
+
+// BIP66 strict DER encoding, with the sighash type at the end.
+fn is_valid_signature_encoding(sig: &[u8]) -> bool {
+    if sig.len() < 9 || sig.len() > 73 {
+        return false;
+    }
+
+    if sig[0] != 0x30 || sig[1] as usize != sig.len() - 3 {
+        return false;
+    }
+
+    let r_length = sig[3] as usize;
+    if 5 + r_length >= sig.len() {
+        return false;
+    }
+
+    let s_length = sig[5 + r_length] as usize;
+    if r_length + s_length + 7 != sig.len() {
+        return false;
+    }
+
+    // R must be a positive integer without unneeded padding.
+    if sig[2] != 0x02 || r_length == 0 || sig[4] & 0x80 != 0 {
+        return false;
+    }
+
+    if r_length > 1 && sig[4] == 0 && sig[5] & 0x80 == 0 {
+        return false;
+    }
+
+    // Same for S.
+    if sig[r_length + 4] != 0x02 || s_length == 0 || sig[r_length + 6] & 0x80 != 0 {
+        return false;
+    }
+
+    if s_length > 1 && sig[r_length + 6] == 0 && sig[r_length + 7] & 0x80 == 0 {
+        return false;
+    }
+
+    true
+}
+
+fn check_signature_encoding(sig: &[u8], flags: u32) -> Result<(), ScriptError> {
+    // Empty signatures are always allowed, so a signature check can fail without failing the whole script.
+    if !sig.is_empty() && flags & VERIFY_DERSIG != 0 && !is_valid_signature_encoding(sig) {
+        return Err(ScriptError::SigDer);
+    }
+
+    Ok(())
+}
+
+// Removes all pushes of `data` from `script`, which legacy signatures need since a signature can't sign itself.
+fn find_and_delete(script: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut pattern = vec![];
+    push_data(&mut pattern, data);
+
+    let mut result = vec![];
+    let mut position = 0;
+
+    while position < script.len() {
+        if script[position..].starts_with(&pattern) {
+            position += pattern.len();
+            continue;
+        }
+
+        let mut iter = Instructions { script, position };
+        match iter.next() {
+            Some(Ok(_)) => {
+                result.extend_from_slice(&script[position..iter.position]);
+                position = iter.position;
+            },
+            _ => {
+                result.extend_from_slice(&script[position..]);
+                break;
+            },
+        }
+    }
+
+    result
+}
+
+// Element `depth` positions from the top of the stack, where 1 is the top.
+fn top(stack: &[Vec<u8>], depth: usize) -> Result<&Vec<u8>, ScriptError> {
+    if depth == 0 || depth > stack.len() {
+        return Err(ScriptError::InvalidStackOperation);
+    }
+
+    Ok(&stack[stack.len() - depth])
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, ScriptError> {
+    stack.pop().ok_or(ScriptError::InvalidStackOperation)
+}
+
+fn push_bool(stack: &mut Vec<Vec<u8>>, value: bool) {
+    stack.push(if value { vec![1] } else { vec![] });
+}
+
+pub fn eval_script(stack: &mut Vec<Vec<u8>>, script: &[u8], flags: u32, checker: &SignatureChecker, sigversion: SigVersion) -> Result<(), ScriptError> {
+    if script.len() > MAX_SCRIPT_SIZE {
+        return Err(ScriptError::ScriptSize);
+    }
+
+    let mut alt_stack: Vec<Vec<u8>> = vec![];
+    // One entry for each IF we're in, telling whether its current branch is executed.
+    let mut conditions: Vec<bool> = vec![];
+    let mut op_count = 0;
+    // Signatures only sign the script after the last executed OP_CODESEPARATOR.
+    let mut code_start = 0;
+    let require_minimal = flags & VERIFY_MINIMALDATA != 0;
+
+    let mut position = 0;
+    let mut iter = instructions(script);
+    while let Some(instruction) = iter.next() {
+        let executing = conditions.iter().all(|c| *c);
+        // The opcode of this instruction, since pushes don't keep it.
+        let opcode = script[position];
+        position = iter.position;
+
+        let op = match instruction? {
+            Instruction::Push(data) => {
+                if data.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                    return Err(ScriptError::PushSize);
+                }
+
+                if executing {
+                    if require_minimal && !is_minimal_push(opcode, data) {
+                        return Err(ScriptError::MinimalData);
+                    }
+
+                    stack.push(data.to_vec());
+
+                    if stack.len() + alt_stack.len() > MAX_STACK_SIZE {
+                        return Err(ScriptError::StackSize);
+                    }
+                }
+
+                continue;
+            },
+            Instruction::Op(op) => op,
+        };
+
+        if op > OP_16 {
+            op_count += 1;
+            if op_count > MAX_OPS_PER_SCRIPT {
+                return Err(ScriptError::OpCount);
+            }
+        }
+
+        match op {
+            OP_CAT | OP_SUBSTR | OP_LEFT | OP_RIGHT | OP_INVERT | OP_AND | OP_OR | OP_XOR | OP_2MUL | OP_2DIV | OP_MUL | OP_DIV | OP_MOD | OP_LSHIFT | OP_RSHIFT => {
+                // Disabled opcodes fail the script even in branches that aren't executed.
+                return Err(ScriptError::DisabledOpcode(op));
+            },
+            _ => (),
+        }
+
+        if !executing && !(OP_IF..=OP_ENDIF).contains(&op) {
+            continue;
+        }
+
+        match op {
+            OP_1NEGATE | OP_1..=OP_16 => {
+                let value = if op == OP_1NEGATE { -1 } else { (op - OP_1 + 1) as i64 };
+                stack.push(encode_number(value));
+            },
+            OP_NOP | OP_NOP1 | OP_NOP4..=OP_NOP10 => (),
+            // Without their flags, they act as OP_NOP2 and OP_NOP3. Lock times are 5 bytes long, so they can go beyond 2^31.
+            OP_CHECKLOCKTIMEVERIFY if flags & VERIFY_CHECKLOCKTIMEVERIFY != 0 => {
+                let lock_time = decode_number(top(stack, 1)?, 5, require_minimal)?;
+
+                if lock_time < 0 {
+                    return Err(ScriptError::NegativeLockTime);
+                }
+
+                if !checker.check_lock_time(lock_time) {
+                    return Err(ScriptError::UnsatisfiedLockTime);
+                }
+            },
+            OP_CHECKSEQUENCEVERIFY if flags & VERIFY_CHECKSEQUENCEVERIFY != 0 => {
+                let sequence = decode_number(top(stack, 1)?, 5, require_minimal)?;
+
+                if sequence < 0 {
+                    return Err(ScriptError::NegativeLockTime);
+                }
+
+                // Values with the disable flag set always pass, so future soft forks can give them a meaning.
+                if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG as i64 == 0 && !checker.check_sequence(sequence) {
+                    return Err(ScriptError::UnsatisfiedLockTime);
+                }
+            },
+            OP_CHECKLOCKTIMEVERIFY | OP_CHECKSEQUENCEVERIFY => (),
+            OP_IF | OP_NOTIF => {
+                let mut value = false;
+
+                if executing {
+                    let top = stack.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                    value = cast_to_bool(&top);
+
+                    if op == OP_NOTIF {
+                        value = !value;
+                    }
+                }
+
+                conditions.push(value);
+            },
+            OP_ELSE => {
+                let last = conditions.last_mut().ok_or(ScriptError::UnbalancedConditional)?;
+                *last = !*last;
+            },
+            OP_ENDIF => {
+                conditions.pop().ok_or(ScriptError::UnbalancedConditional)?;
+            },
+            OP_VERIFY => {
+                if !cast_to_bool(&pop(stack)?) {
+                    return Err(ScriptError::Verify);
+                }
+            },
+            OP_RETURN => return Err(ScriptError::OpReturn),
+            OP_TOALTSTACK => {
+                let value = pop(stack)?;
+                alt_stack.push(value);
+            },
+            OP_FROMALTSTACK => {
+                let value = alt_stack.pop().ok_or(ScriptError::InvalidAltStackOperation)?;
+                stack.push(value);
+            },
+            OP_2DROP => {
+                pop(stack)?;
+                pop(stack)?;
+            },
+            OP_2DUP => {
+                let first = top(stack, 2)?.clone();
+                let second = top(stack, 1)?.clone();
+                stack.push(first);
+                stack.push(second);
+            },
+            OP_3DUP => {
+                // Each copy moves the next element to the same depth.
+                for _ in 0..3 {
+                    let value = top(stack, 3)?.clone();
+                    stack.push(value);
+                }
+            },
+            OP_2OVER => {
+                for _ in 0..2 {
+                    let value = top(stack, 4)?.clone();
+                    stack.push(value);
+                }
+            },
+            OP_2ROT => {
+                top(stack, 6)?;
+                let length = stack.len();
+                let moved = stack.drain(length - 6..length - 4).collect::<Vec<Vec<u8>>>();
+                stack.extend(moved);
+            },
+            OP_2SWAP => {
+                top(stack, 4)?;
+                let length = stack.len();
+                stack.swap(length - 4, length - 2);
+                stack.swap(length - 3, length - 1);
+            },
+            OP_IFDUP => {
+                let value = top(stack, 1)?.clone();
+                if cast_to_bool(&value) {
+                    stack.push(value);
+                }
+            },
+            OP_DEPTH => {
+                let depth = encode_number(stack.len() as i64);
+                stack.push(depth);
+            },
+            OP_DROP => {
+                pop(stack)?;
+            },
+            OP_DUP => {
+                let value = top(stack, 1)?.clone();
+                stack.push(value);
+            },
+            OP_NIP => {
+                let value = pop(stack)?;
+                pop(stack)?;
+                stack.push(value);
+            },
+            OP_OVER => {
+                let value = top(stack, 2)?.clone();
+                stack.push(value);
+            },
+            OP_PICK | OP_ROLL => {
+                let depth = decode_number(top(stack, 1)?, 4, require_minimal)?;
+                pop(stack)?;
+
+                if depth < 0 || depth as usize >= stack.len() {
+                    return Err(ScriptError::InvalidStackOperation);
+                }
+
+                let index = stack.len() - 1 - depth as usize;
+                let value = if op == OP_ROLL { stack.remove(index) } else { stack[index].clone() };
+                stack.push(value);
+            },
+            OP_ROT => {
+                top(stack, 3)?;
+                let length = stack.len();
+                stack.swap(length - 3, length - 2);
+                stack.swap(length - 2, length - 1);
+            },
+            OP_SWAP => {
+                top(stack, 2)?;
+                let length = stack.len();
+                stack.swap(length - 1, length - 2);
+            },
+            OP_TUCK => {
+                let value = top(stack, 1)?.clone();
+                top(stack, 2)?;
+                let length = stack.len();
+                stack.insert(length - 2, value);
+            },
+            OP_SIZE => {
+                let size = encode_number(top(stack, 1)?.len() as i64);
+                stack.push(size);
+            },
+            OP_EQUAL | OP_EQUALVERIFY => {
+                let first = pop(stack)?;
+                let second = pop(stack)?;
+                let equal = first == second;
+
+                if op == OP_EQUALVERIFY {
+                    if !equal {
+                        return Err(ScriptError::EqualVerify);
+                    }
+                } else {
+                    push_bool(stack, equal);
+                }
+            },
+            OP_1ADD | OP_1SUB | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL => {
+                let value = decode_number(top(stack, 1)?, 4, require_minimal)?;
+                pop(stack)?;
+
+                let result = match op {
+                    OP_1ADD => value + 1,
+                    OP_1SUB => value - 1,
+                    OP_NEGATE => -value,
+                    OP_ABS => value.abs(),
+                    OP_NOT => (value == 0) as i64,
+                    _ => (value != 0) as i64,
+                };
+                stack.push(encode_number(result));
+            },
+            OP_ADD | OP_SUB | OP_BOOLAND | OP_BOOLOR | OP_NUMEQUAL | OP_NUMEQUALVERIFY | OP_NUMNOTEQUAL | OP_LESSTHAN | OP_GREATERTHAN | OP_LESSTHANOREQUAL | OP_GREATERTHANOREQUAL | OP_MIN | OP_MAX => {
+                let first = decode_number(top(stack, 2)?, 4, require_minimal)?;
+                let second = decode_number(top(stack, 1)?, 4, require_minimal)?;
+                pop(stack)?;
+                pop(stack)?;
+
+                let result = match op {
+                    OP_ADD => first + second,
+                    OP_SUB => first - second,
+                    OP_BOOLAND => (first != 0 && second != 0) as i64,
+                    OP_BOOLOR => (first != 0 || second != 0) as i64,
+                    OP_NUMEQUAL | OP_NUMEQUALVERIFY => (first == second) as i64,
+                    OP_NUMNOTEQUAL => (first != second) as i64,
+                    OP_LESSTHAN => (first < second) as i64,
+                    OP_GREATERTHAN => (first > second) as i64,
+                    OP_LESSTHANOREQUAL => (first <= second) as i64,
+                    OP_GREATERTHANOREQUAL => (first >= second) as i64,
+                    OP_MIN => first.min(second),
+                    _ => first.max(second),
+                };
+
+                if op == OP_NUMEQUALVERIFY {
+                    if result == 0 {
+                        return Err(ScriptError::NumEqualVerify);
+                    }
+                } else {
+                    stack.push(encode_number(result));
+                }
+            },
+            OP_WITHIN => {
+                let value = decode_number(top(stack, 3)?, 4, require_minimal)?;
+                let min = decode_number(top(stack, 2)?, 4, require_minimal)?;
+                let max = decode_number(top(stack, 1)?, 4, require_minimal)?;
+                for _ in 0..3 {
+                    pop(stack)?;
+                }
+
+                push_bool(stack, min <= value && value < max);
+            },
+            OP_RIPEMD160 | OP_SHA1 | OP_SHA256 | OP_HASH160 | OP_HASH256 => {
+                let value = pop(stack)?;
+                let hash = match op {
+                    OP_RIPEMD160 => Ripemd160::digest(&value).to_vec(),
+                    OP_SHA1 => sha1(&value).to_vec(),
+                    OP_SHA256 => sha256(&value).to_vec(),
+                    OP_HASH160 => hash160(&value).to_vec(),
+                    _ => sha256d(&value).to_vec(),
+                };
+                stack.push(hash);
+            },
+            OP_CODESEPARATOR => {
+                code_start = iter.position;
+            },
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                let pubkey = pop(stack)?;
+                let signature = pop(stack)?;
+
+                let mut script_code = script[code_start..].to_vec();
+                if sigversion == SigVersion::Base {
+                    script_code = find_and_delete(&script_code, &signature);
+                }
+
+                check_signature_encoding(&signature, flags)?;
+                let success = checker.check_signature(&signature, &pubkey, &script_code, sigversion);
+
+                if op == OP_CHECKSIGVERIFY {
+                    if !success {
+                        return Err(ScriptError::Verify);
+                    }
+                } else {
+                    push_bool(stack, success);
+                }
+            },
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                let mut i = 1;
+                let mut key_count = decode_number(top(stack, i)?, 4, require_minimal)?;
+                if key_count < 0 || key_count > MAX_PUBKEYS_PER_MULTISIG as i64 {
+                    return Err(ScriptError::PubkeyCount);
+                }
+
+                op_count += key_count as usize;
+                if op_count > MAX_OPS_PER_SCRIPT {
+                    return Err(ScriptError::OpCount);
+                }
+
+                i += 1;
+                let mut key_position = i;
+                i += key_count as usize;
+
+                let mut sig_count = decode_number(top(stack, i)?, 4, require_minimal)?;
+                if sig_count < 0 || sig_count > key_count {
+                    return Err(ScriptError::SigCount);
+                }
+
+                i += 1;
+                let mut sig_position = i;
+                i += sig_count as usize;
+                // Makes sure the extra element popped because of the off-by-one bug is there.
+                top(stack, i)?;
+
+                let mut script_code = script[code_start..].to_vec();
+                if sigversion == SigVersion::Base {
+                    for k in 0..sig_count as usize {
+                        script_code = find_and_delete(&script_code, top(stack, sig_position + k)?);
+                    }
+                }
+
+                // Signatures must be in the same order as their public keys.
+                let mut success = true;
+                while success && sig_count > 0 {
+                    let signature = top(stack, sig_position)?;
+                    let pubkey = top(stack, key_position)?;
+
+                    check_signature_encoding(signature, flags)?;
+                    if checker.check_signature(signature, pubkey, &script_code, sigversion) {
+                        sig_position += 1;
+                        sig_count -= 1;
+                    }
+
+                    key_position += 1;
+                    key_count -= 1;
+
+                    if sig_count > key_count {
+                        success = false;
+                    }
+                }
+
+                for _ in 0..i - 1 {
+                    pop(stack)?;
+                }
+
+                // The extra element must be empty (BIP147).
+                let dummy = pop(stack)?;
+                if flags & VERIFY_NULLDUMMY != 0 && !dummy.is_empty() {
+                    return Err(ScriptError::NullDummy);
+                }
+
+                if op == OP_CHECKMULTISIGVERIFY {
+                    if !success {
+                        return Err(ScriptError::Verify);
+                    }
+                } else {
+                    push_bool(stack, success);
+                }
+            },
+            // OP_RESERVED, OP_VER, OP_VERIF, OP_VERNOTIF, OP_RESERVED1, OP_RESERVED2 and everything after OP_NOP10.
+            _ => {
+                return Err(ScriptError::BadOpcode);
+            },
+        }
+
+        if stack.len() + alt_stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackSize);
+        }
+    }
+
+    if !conditions.is_empty() {
+        return Err(ScriptError::UnbalancedConditional);
+    }
+
+    Ok(())
+}
+
+fn verify_witness_program(witness: &[Vec<u8>], version: u8, program: &[u8], flags: u32, checker: &SignatureChecker) -> Result<(), ScriptError> {
+    // Witness versions other than 0 are left for future soft forks, so they're valid for now unless we're asked to be careful.
+    if version != 0 {
+        if flags & VERIFY_DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM != 0 {
+            return Err(ScriptError::DiscourageUpgradableWitnessProgram);
+        }
+
+        return Ok(());
+    }
+
+    let (mut stack, script) = match program.len() {
+        // P2WSH: the last witness item is the script, which must hash to the program.
+        32 => {
+            let (script, stack) = witness.split_last().ok_or(ScriptError::WitnessProgramWitnessEmpty)?;
+
+            if &sha256(script)[..] != program {
+                return Err(ScriptError::WitnessProgramMismatch);
+            }
+
+            (stack.to_vec(), script.clone())
+        },
+        // P2WPKH: the witness is a signature and a public key, checked as if it were P2PKH.
+        20 => {
+            if witness.len() != 2 {
+                return Err(ScriptError::WitnessProgramMismatch);
+            }
+
+            let mut script = vec![OP_DUP, OP_HASH160];
+            push_data(&mut script, program);
+            script.push(OP_EQUALVERIFY);
+            script.push(OP_CHECKSIG);
+
+            (witness.to_vec(), script)
+        },
+        _ => return Err(ScriptError::WitnessProgramWrongLength),
+    };
+
+    if stack.iter().any(|item| item.len() > MAX_SCRIPT_ELEMENT_SIZE) {
+        return Err(ScriptError::PushSize);
+    }
+
+    eval_script(&mut stack, &script, flags, checker, SigVersion::WitnessV0)?;
+
+    // Witness scripts must leave exactly one true element on the stack.
+    if stack.len() != 1 {
+        return Err(ScriptError::CleanStack);
+    }
+
+    if !cast_to_bool(&stack[0]) {
+        return Err(ScriptError::EvalFalse);
+    }
+
+    Ok(())
+}
+
+// Checks that `script_sig` and `witness` satisfy `script_pubkey`.
+pub fn verify_script(script_sig: &[u8], script_pubkey: &[u8], witness: &[Vec<u8>], flags: u32, checker: &SignatureChecker) -> Result<(), ScriptError> {
+    let mut stack = vec![];
+    eval_script(&mut stack, script_sig, flags, checker, SigVersion::Base)?;
+
+    // P2SH needs the stack as left by the scriptSig.
+    let stack_copy = stack.clone();
+
+    eval_script(&mut stack, script_pubkey, flags, checker, SigVersion::Base)?;
+
+    if stack.is_empty() || !cast_to_bool(&stack[stack.len() - 1]) {
+        return Err(ScriptError::EvalFalse);
+    }
+
+    let mut had_witness = false;
+
+    if flags & VERIFY_WITNESS != 0 {
+        if let Some((version, program)) = witness_program(script_pubkey) {
+            had_witness = true;
+
+            // Native witness programs can't have a scriptSig, or it could be changed without invalidating the signatures.
+            if !script_sig.is_empty() {
+                return Err(ScriptError::WitnessMalleated);
+            }
+
+            verify_witness_program(witness, version, program, flags, checker)?;
+            stack.truncate(1);
+        }
+    }
+
+    if flags & VERIFY_P2SH != 0 && is_p2sh(script_pubkey) {
+        if !is_push_only(script_sig) {
+            return Err(ScriptError::SigPushOnly);
+        }
+
+        stack = stack_copy;
+        let redeem_script = pop(&mut stack)?;
+
+        eval_script(&mut stack, &redeem_script, flags, checker, SigVersion::Base)?;
+
+        if stack.is_empty() || !cast_to_bool(&stack[stack.len() - 1]) {
+            return Err(ScriptError::EvalFalse);
+        }
+
+        if flags & VERIFY_WITNESS != 0 {
+            if let Some((version, program)) = witness_program(&redeem_script) {
+                had_witness = true;
+
+                // The scriptSig must be only the push of the redeem script.
+                let mut expected_script_sig = vec![];
+                push_data(&mut expected_script_sig, &redeem_script);
+                if script_sig != &expected_script_sig[..] {
+                    return Err(ScriptError::WitnessMalleatedP2SH);
+                }
+
+                verify_witness_program(witness, version, program, flags, checker)?;
+                stack.truncate(1);
+            }
+        }
+    }
+
+    if flags & VERIFY_WITNESS != 0 && !had_witness && !witness.is_empty() {
+        return Err(ScriptError::WitnessUnexpected);
+    }
+
+    Ok(())
+}
\ No newline at end of file
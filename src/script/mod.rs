@@ -0,0 +1,260 @@
+pub use self::interpreter::{verify_script, SignatureChecker, VERIFY_CHECKLOCKTIMEVERIFY, VERIFY_CHECKSEQUENCEVERIFY, VERIFY_DERSIG, VERIFY_DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM, VERIFY_MINIMALDATA, VERIFY_NULLDUMMY, VERIFY_P2SH, VERIFY_WITNESS};
+pub use self::sighash::{legacy_sighash, witness_v0_sighash, SIGHASH_ALL, SIGHASH_ANYONECANPAY, SIGHASH_NONE, SIGHASH_SINGLE};
+
+pub mod interpreter;
+pub mod opcodes;
+pub mod sighash;
+
+#[cfg(test)]
+mod tests;
+
+use self::opcodes::*;
+
+pub const MAX_SCRIPT_SIZE: usize = 10000;
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+pub const MAX_OPS_PER_SCRIPT: usize = 201;
+pub const MAX_STACK_SIZE: usize = 1000;
+pub const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ScriptError {
+    BadOpcode,
+    CleanStack,
+    DisabledOpcode(u8),
+    DiscourageUpgradableWitnessProgram,
+    EqualVerify,
+    EvalFalse,
+    InvalidAltStackOperation,
+    InvalidNumber,
+    InvalidStackOperation,
+    MinimalData,
+    NegativeLockTime,
+    NullDummy,
+    NumEqualVerify,
+    OpCount,
+    OpReturn,
+    PubkeyCount,
+    PushSize,
+    ScriptSize,
+    SigCount,
+    SigDer,
+    SigPushOnly,
+    StackSize,
+    UnbalancedConditional,
+    UnsatisfiedLockTime,
+    Verify,
+    WitnessMalleated,
+    WitnessMalleatedP2SH,
+    WitnessProgramMismatch,
+    WitnessProgramWitnessEmpty,
+    WitnessProgramWrongLength,
+    WitnessUnexpected,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Instruction<'a> {
+    // Data pushed by OP_0 or any of the push opcodes.
+    Push(&'a [u8]),
+    Op(u8),
+}
+
+// Iterates over the instructions in a script. Returns an error once it finds a push going beyond the end of the script.
+pub struct Instructions<'a> {
+    script: &'a [u8],
+    position: usize,
+}
+
+pub fn instructions<'a>(script: &'a [u8]) -> Instructions<'a> {
+    Instructions {
+        script,
+        position: 0,
+    }
+}
+
+impl<'a> Instructions<'a> {
+    fn read_length(&mut self, size: usize) -> Result<usize, ScriptError> {
+        if self.position + size > self.script.len() {
+            return Err(ScriptError::BadOpcode);
+        }
+
+        let mut length = 0;
+        for (i, byte) in self.script[self.position..self.position + size].iter().enumerate() {
+            length |= (*byte as usize) << (8 * i);
+        }
+
+        self.position += size;
+        Ok(length)
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction<'a>, ScriptError>;
+
+    fn next(&mut self) -> Option<Result<Instruction<'a>, ScriptError>> {
+        if self.position >= self.script.len() {
+            return None;
+        }
+
+        let opcode = self.script[self.position];
+        self.position += 1;
+
+        let length = match opcode {
+            OP_0 => return Some(Ok(Instruction::Push(&[]))),
+            0x01..=0x4b => opcode as usize,
+            OP_PUSHDATA1 => match self.read_length(1) { Ok(l) => l, Err(e) => return Some(Err(e)) },
+            OP_PUSHDATA2 => match self.read_length(2) { Ok(l) => l, Err(e) => return Some(Err(e)) },
+            OP_PUSHDATA4 => match self.read_length(4) { Ok(l) => l, Err(e) => return Some(Err(e)) },
+            _ => return Some(Ok(Instruction::Op(opcode))),
+        };
+
+        if self.position + length > self.script.len() {
+            // Makes sure we don't return anything else after the error.
+            self.position = self.script.len();
+            return Some(Err(ScriptError::BadOpcode));
+        }
+
+        let data = &self.script[self.position..self.position + length];
+        self.position += length;
+
+        Some(Ok(Instruction::Push(data)))
+    }
+}
+
+// Appends the smallest push of `data` to `script`.
+pub fn push_data(script: &mut Vec<u8>, data: &[u8]) {
+    match data.len() {
+        0..=0x4b => script.push(data.len() as u8),
+        0x4c..=0xFF => {
+            script.push(OP_PUSHDATA1);
+            script.push(data.len() as u8);
+        },
+        0x100..=0xFFFF => {
+            script.push(OP_PUSHDATA2);
+            script.push(data.len() as u8);
+            script.push((data.len() >> 8) as u8);
+        },
+        _ => {
+            script.push(OP_PUSHDATA4);
+            for i in 0..4 {
+                script.push((data.len() >> (8 * i)) as u8);
+            }
+        },
+    }
+
+    script.extend_from_slice(data);
+}
+
+// Whether `opcode` is the smallest way to push `data`.
+pub fn is_minimal_push(opcode: u8, data: &[u8]) -> bool {
+    match data.len() {
+        0 => opcode == OP_0,
+        1 if data[0] >= 1 && data[0] <= 16 => opcode == OP_1 + data[0] - 1,
+        1 if data[0] == 0x81 => opcode == OP_1NEGATE,
+        1..=0x4b => opcode as usize == data.len(),
+        0x4c..=0xFF => opcode == OP_PUSHDATA1,
+        0x100..=0xFFFF => opcode == OP_PUSHDATA2,
+        _ => true,
+    }
+}
+
+pub fn is_push_only(script: &[u8]) -> bool {
+    instructions(script).all(|instruction| match instruction {
+        Ok(Instruction::Push(_)) => true,
+        Ok(Instruction::Op(op)) => op <= OP_16,
+        Err(_) => false,
+    })
+}
+
+// OP_HASH160 <20 bytes> OP_EQUAL.
+pub fn is_p2sh(script: &[u8]) -> bool {
+    script.len() == 23 && script[0] == OP_HASH160 && script[1] == 0x14 && script[22] == OP_EQUAL
+}
+
+// BIP141: a version opcode followed by a single push of 2 to 40 bytes. Returns the version and the program.
+pub fn witness_program(script: &[u8]) -> Option<(u8, &[u8])> {
+    if script.len() < 4 || script.len() > 42 {
+        return None;
+    }
+
+    let version = match script[0] {
+        OP_0 => 0,
+        OP_1..=OP_16 => script[0] - OP_1 + 1,
+        _ => return None,
+    };
+
+    if script[1] as usize + 2 != script.len() {
+        return None;
+    }
+
+    Some((version, &script[2..]))
+}
+
+// Numbers on the stack are little endian with a sign bit, and at most `max_size` bytes long. With `require_minimal`, they can't use more bytes than needed.
+pub fn decode_number(data: &[u8], max_size: usize, require_minimal: bool) -> Result<i64, ScriptError> {
+    if data.len() > max_size {
+        return Err(ScriptError::InvalidNumber);
+    }
+
+    if require_minimal {
+        if let Some(last) = data.last() {
+            // The last byte can only be 0x00 or 0x80 when the one before it needs its top bit for the value.
+            if last & 0x7F == 0 && (data.len() == 1 || data[data.len() - 2] & 0x80 == 0) {
+                return Err(ScriptError::MinimalData);
+            }
+        }
+    }
+
+    if data.is_empty() {
+        return Ok(0);
+    }
+
+    let mut result = 0i64;
+    for (i, byte) in data.iter().enumerate() {
+        result |= (*byte as i64) << (8 * i);
+    }
+
+    let last = data[data.len() - 1];
+    if last & 0x80 != 0 {
+        // Clearing the sign bit and negating.
+        return Ok(-(result & !(0x80i64 << (8 * (data.len() - 1)))));
+    }
+
+    Ok(result)
+}
+
+pub fn encode_number(value: i64) -> Vec<u8> {
+    if value == 0 {
+        return vec![];
+    }
+
+    let negative = value < 0;
+    let mut absolute = value.unsigned_abs();
+    let mut result = vec![];
+
+    while absolute > 0 {
+        result.push((absolute & 0xFF) as u8);
+        absolute >>= 8;
+    }
+
+    // If the most significant byte already uses the sign bit, we need another byte to hold the sign.
+    if result[result.len() - 1] & 0x80 != 0 {
+        result.push(if negative { 0x80 } else { 0 });
+    } else if negative {
+        let last = result.len() - 1;
+        result[last] |= 0x80;
+    }
+
+    result
+}
+
+// Anything other than (negative) zero is true.
+pub fn cast_to_bool(data: &[u8]) -> bool {
+    for (i, byte) in data.iter().enumerate() {
+        if *byte != 0 {
+            // Negative zero is still false.
+            return !(i == data.len() - 1 && *byte == 0x80);
+        }
+    }
+
+    false
+}
\ No newline at end of file
@@ -0,0 +1,132 @@
+use network::encode::{serialize, Encodable};
+use network::transaction::{Transaction, TxOut};
+use script::{instructions, Instruction};
+use script::opcodes::OP_CODESEPARATOR;
+use util::sha256d;
+
+pub const SIGHASH_ALL: u32 = 1;
+pub const SIGHASH_NONE: u32 = 2;
+pub const SIGHASH_SINGLE: u32 = 3;
+pub const SIGHASH_ANYONECANPAY: u32 = 0x80;
+
+// Hash signed when SIGHASH_SINGLE has no matching output. Kept for compatibility with the original implementation.
+const SIGHASH_ONE: [u8; 32] = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+fn append<T: Encodable>(data: &mut Vec<u8>, value: &T) {
+    value.encode(data).expect("Writing to a Vec never fails");
+}
+
+fn remove_codeseparators(script: &[u8]) -> Vec<u8> {
+    let mut result = vec![];
+    let mut iter = instructions(script);
+    let mut start = 0;
+
+    while let Some(instruction) = iter.next() {
+        let end = iter.position;
+
+        match instruction {
+            Ok(Instruction::Op(OP_CODESEPARATOR)) => (),
+            _ => result.extend_from_slice(&script[start..end]),
+        }
+
+        start = end;
+    }
+
+    result
+}
+
+// Signature hash of inputs that aren't spending a witness program.
+pub fn legacy_sighash(tx: &Transaction, input_index: usize, script_code: &[u8], sighash_type: u32) -> [u8; 32] {
+    let base_type = sighash_type & 0x1f;
+
+    if input_index >= tx.inputs.len() || (base_type == SIGHASH_SINGLE && input_index >= tx.outputs.len()) {
+        return SIGHASH_ONE;
+    }
+
+    let mut tx_copy = tx.clone();
+
+    for (i, input) in tx_copy.inputs.iter_mut().enumerate() {
+        input.witness.clear();
+
+        if i == input_index {
+            input.script_sig = remove_codeseparators(script_code);
+        } else {
+            input.script_sig.clear();
+
+            // The other inputs can be replaced when not all outputs are signed.
+            if base_type == SIGHASH_NONE || base_type == SIGHASH_SINGLE {
+                input.sequence = 0;
+            }
+        }
+    }
+
+    if base_type == SIGHASH_NONE {
+        tx_copy.outputs.clear();
+    } else if base_type == SIGHASH_SINGLE {
+        tx_copy.outputs.truncate(input_index + 1);
+        for output in tx_copy.outputs.iter_mut().take(input_index) {
+            *output = TxOut::new(-1, vec![]);
+        }
+    }
+
+    if sighash_type & SIGHASH_ANYONECANPAY != 0 {
+        tx_copy.inputs = vec![tx_copy.inputs.swap_remove(input_index)];
+    }
+
+    let mut data = serialize(&tx_copy);
+    append(&mut data, &sighash_type);
+
+    sha256d(&data)
+}
+
+// BIP143 signature hash, used by inputs spending version 0 witness programs.
+pub fn witness_v0_sighash(tx: &Transaction, input_index: usize, script_code: &[u8], amount: i64, sighash_type: u32) -> [u8; 32] {
+    let base_type = sighash_type & 0x1f;
+    let anyone_can_pay = sighash_type & SIGHASH_ANYONECANPAY != 0;
+
+    let mut hash_prevouts = [0u8; 32];
+    let mut hash_sequence = [0u8; 32];
+    let mut hash_outputs = [0u8; 32];
+
+    if !anyone_can_pay {
+        let mut data = vec![];
+        for input in tx.inputs.iter() {
+            append(&mut data, &input.previous_output);
+        }
+        hash_prevouts = sha256d(&data);
+    }
+
+    if !anyone_can_pay && base_type != SIGHASH_SINGLE && base_type != SIGHASH_NONE {
+        let mut data = vec![];
+        for input in tx.inputs.iter() {
+            append(&mut data, &input.sequence);
+        }
+        hash_sequence = sha256d(&data);
+    }
+
+    if base_type != SIGHASH_SINGLE && base_type != SIGHASH_NONE {
+        let mut data = vec![];
+        for output in tx.outputs.iter() {
+            append(&mut data, output);
+        }
+        hash_outputs = sha256d(&data);
+    } else if base_type == SIGHASH_SINGLE && input_index < tx.outputs.len() {
+        hash_outputs = sha256d(&serialize(&tx.outputs[input_index]));
+    }
+
+    let input = &tx.inputs[input_index];
+    let mut data = vec![];
+
+    append(&mut data, &tx.version);
+    append(&mut data, &hash_prevouts);
+    append(&mut data, &hash_sequence);
+    append(&mut data, &input.previous_output);
+    append(&mut data, &script_code.to_vec());
+    append(&mut data, &amount);
+    append(&mut data, &input.sequence);
+    append(&mut data, &hash_outputs);
+    append(&mut data, &tx.lock_time);
+    append(&mut data, &sighash_type);
+
+    sha256d(&data)
+}
\ No newline at end of file
@@ -0,0 +1,239 @@
+use hex::FromHex;
+use network::Decodable;
+use network::transaction::{OutPoint, Transaction, TxIn, TxOut};
+use rand::thread_rng;
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use super::*;
+use super::opcodes::*;
+use util::{hash160, sha256};
+
+const BLOCK_FLAGS: u32 = VERIFY_P2SH | VERIFY_WITNESS | VERIFY_DERSIG | VERIFY_NULLDUMMY;
+
+fn spending_tx() -> Transaction {
+    let input = TxIn::new(OutPoint::new([7; 32], 0), vec![], 0xFFFFFFFF);
+    Transaction::new(1, vec![input], vec![TxOut::new(1000, vec![OP_RETURN])], 0)
+}
+
+fn sign(secp: &Secp256k1, key: &SecretKey, hash: [u8; 32]) -> Vec<u8> {
+    let signature = secp.sign(&Message::from_slice(&hash).unwrap(), key).unwrap();
+    let mut result = signature.serialize_der(secp);
+    result.push(SIGHASH_ALL as u8);
+    result
+}
+
+// Runs a script without signatures, given in hex.
+fn run(script_sig: &str, script_pubkey: &str, flags: u32) -> Result<(), ScriptError> {
+    let tx = spending_tx();
+    let script_sig = Vec::from_hex(script_sig).unwrap();
+    let script_pubkey = Vec::from_hex(script_pubkey).unwrap();
+    verify_script(&script_sig, &script_pubkey, &[], flags, &SignatureChecker::new(&tx, 0, 0))
+}
+
+#[test]
+fn numbers_roundtrip() {
+    for value in [0i64, 1, -1, 127, 128, -128, 255, 256, 0x7FFFFFFF, -0x7FFFFFFF].iter() {
+        assert_eq!(decode_number(&encode_number(*value), 4, true), Ok(*value));
+    }
+
+    assert_eq!(encode_number(-1), vec![0x81]);
+    assert_eq!(encode_number(128), vec![0x80, 0x00]);
+    assert!(!cast_to_bool(&[0, 0, 0x80]));
+    assert!(cast_to_bool(&[0, 1]));
+
+    // Extra zero bytes are only accepted when minimal encoding isn't required.
+    assert_eq!(decode_number(&[1, 0], 4, false), Ok(1));
+    assert_eq!(decode_number(&[1, 0], 4, true), Err(ScriptError::MinimalData));
+    assert_eq!(decode_number(&[0x80], 4, true), Err(ScriptError::MinimalData));
+    assert_eq!(decode_number(&[0xFF, 0x80], 4, true), Ok(-0xFF));
+    assert_eq!(decode_number(&[0, 0, 0, 0, 1], 4, false), Err(ScriptError::InvalidNumber));
+}
+
+#[test]
+fn arithmetic_opcodes() {
+    // Cases from Core's script_tests.json, as scriptSig and scriptPubKey.
+    let valid = [
+        ("5151", "935287"),   // 1 1 ADD 2 EQUAL
+        ("5352", "945187"),   // 3 2 SUB 1 EQUAL
+        ("51", "8b5287"),     // 1 1ADD 2 EQUAL
+        ("51", "8c0087"),     // 1 1SUB 0 EQUAL
+        ("55", "8f018587"),   // 5 NEGATE -5 EQUAL
+        ("4f", "905187"),     // -1 ABS 1 EQUAL
+        ("00", "91"),         // 0 NOT
+        ("52", "925187"),     // 2 0NOTEQUAL 1 EQUAL
+        ("5100", "9b"),       // 1 0 BOOLOR
+        ("5100", "9a91"),     // 1 0 BOOLAND NOT
+        ("5353", "9c"),       // 3 3 NUMEQUAL
+        ("5353", "9d51"),     // 3 3 NUMEQUALVERIFY 1
+        ("5152", "9e"),       // 1 2 NUMNOTEQUAL
+        ("5253", "9f"),       // 2 3 LESSTHAN
+        ("5352", "a0"),       // 3 2 GREATERTHAN
+        ("5252", "a1"),       // 2 2 LESSTHANOREQUAL
+        ("5252", "a2"),       // 2 2 GREATERTHANOREQUAL
+        ("5352", "a35287"),   // 3 2 MIN 2 EQUAL
+        ("5352", "a45387"),   // 3 2 MAX 3 EQUAL
+        ("000051", "a5"),     // 0 0 1 WITHIN
+        ("510051", "a591"),   // 1 0 1 WITHIN NOT
+        ("020100", "8b5287"), // 0x02 0x0100 1ADD 2 EQUAL, fine without MINIMALDATA
+        ("00", "63896851"),   // 0 IF RESERVED1 ENDIF 1
+    ];
+
+    for &(script_sig, script_pubkey) in valid.iter() {
+        assert_eq!(run(script_sig, script_pubkey, BLOCK_FLAGS), Ok(()), "{} {}", script_sig, script_pubkey);
+    }
+
+    assert_eq!(run("5152", "9d51", BLOCK_FLAGS), Err(ScriptError::NumEqualVerify));
+    assert_eq!(run("050000000001", "8b", BLOCK_FLAGS), Err(ScriptError::InvalidNumber));
+    assert_eq!(run("51", "89", BLOCK_FLAGS), Err(ScriptError::BadOpcode));
+    assert_eq!(run("020100", "8b5287", BLOCK_FLAGS | VERIFY_MINIMALDATA), Err(ScriptError::MinimalData));
+    // A one byte push of 1 instead of OP_1.
+    assert_eq!(run("0101", "5187", BLOCK_FLAGS), Ok(()));
+    assert_eq!(run("0101", "5187", BLOCK_FLAGS | VERIFY_MINIMALDATA), Err(ScriptError::MinimalData));
+}
+
+#[test]
+fn stack_opcodes() {
+    // Each scriptPubKey checks the resulting stack from the top with EQUALVERIFY.
+    let valid = [
+        ("515253", "6f538852885188538852885187"),       // 3DUP
+        ("51525354", "70528851885488538852885187"),     // 2OVER
+        ("515253545556", "71528851885688558854885387"), // 2ROT
+        ("51525354", "725288518854885387"),             // 2SWAP
+        ("515253", "52795188538852885187"),             // 2 PICK
+        ("515253", "527a518853885287"),                 // 2 ROLL
+        ("515253", "7b518853885287"),                   // ROT
+        ("5152", "7d528851885287"),                     // TUCK
+    ];
+
+    for &(script_sig, script_pubkey) in valid.iter() {
+        assert_eq!(run(script_sig, script_pubkey, BLOCK_FLAGS), Ok(()), "{} {}", script_sig, script_pubkey);
+    }
+
+    assert_eq!(run("51", "517a", BLOCK_FLAGS), Err(ScriptError::InvalidStackOperation));
+    assert_eq!(run("51", "4f79", BLOCK_FLAGS), Err(ScriptError::InvalidStackOperation));
+    assert_eq!(run("5152", "6f", BLOCK_FLAGS), Err(ScriptError::InvalidStackOperation));
+}
+
+#[test]
+fn sha1_opcode() {
+    let long = format!("4c64{}", "61".repeat(100));
+
+    assert_eq!(run("00", "a714da39a3ee5e6b4b0d3255bfef95601890afd8070987", BLOCK_FLAGS), Ok(()));
+    assert_eq!(run("03616263", "a714a9993e364706816aba3e25717850c26c9cd0d89d87", BLOCK_FLAGS), Ok(()));
+    assert_eq!(run(&long, "a7147f9000257a4918d7072655ea468540cdcbd42e0c87", BLOCK_FLAGS), Ok(()));
+}
+
+#[test]
+fn lock_time_opcodes() {
+    let input = TxIn::new(OutPoint::new([7; 32], 0), vec![], 0);
+    let tx = Transaction::new(2, vec![input], vec![TxOut::new(1000, vec![OP_RETURN])], 100);
+    let checker = SignatureChecker::new(&tx, 0, 0);
+    let flags = BLOCK_FLAGS | VERIFY_CHECKLOCKTIMEVERIFY | VERIFY_CHECKSEQUENCEVERIFY;
+    let check = |script_pubkey: &str, flags: u32| verify_script(&[], &Vec::from_hex(script_pubkey).unwrap(), &[], flags, &checker);
+
+    // 100 CHECKLOCKTIMEVERIFY, then 101, a timestamp and -1.
+    assert_eq!(check("0164b1", flags), Ok(()));
+    assert_eq!(check("0165b1", flags), Err(ScriptError::UnsatisfiedLockTime));
+    assert_eq!(check("040065cd1db1", flags), Err(ScriptError::UnsatisfiedLockTime));
+    assert_eq!(check("4fb1", flags), Err(ScriptError::NegativeLockTime));
+    assert_eq!(check("0165b1", BLOCK_FLAGS), Ok(()));
+
+    // 0 CHECKSEQUENCEVERIFY DROP 1, then with 1, and with the disable flag set.
+    assert_eq!(check("00b27551", flags), Ok(()));
+    assert_eq!(check("51b27551", flags), Err(ScriptError::UnsatisfiedLockTime));
+    assert_eq!(check("050000008000b27551", flags), Ok(()));
+
+    // A final input would let the lock time be ignored.
+    let final_input = TxIn::new(OutPoint::new([7; 32], 0), vec![], 0xFFFFFFFF);
+    let final_tx = Transaction::new(2, vec![final_input], vec![TxOut::new(1000, vec![OP_RETURN])], 100);
+    let script_pubkey = Vec::from_hex("0164b1").unwrap();
+    assert_eq!(verify_script(&[], &script_pubkey, &[], flags, &SignatureChecker::new(&final_tx, 0, 0)), Err(ScriptError::UnsatisfiedLockTime));
+}
+
+#[test]
+fn upgradable_witness_programs() {
+    let tx = spending_tx();
+    let checker = SignatureChecker::new(&tx, 0, 0);
+    // A taproot output, spent with a witness we don't know how to check.
+    let script_pubkey = Vec::from_hex(format!("5120{}", "11".repeat(32))).unwrap();
+    let witness = vec![vec![1]];
+
+    assert_eq!(verify_script(&[], &script_pubkey, &witness, BLOCK_FLAGS, &checker), Ok(()));
+    assert_eq!(verify_script(&[], &script_pubkey, &witness, BLOCK_FLAGS | VERIFY_DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM, &checker), Err(ScriptError::DiscourageUpgradableWitnessProgram));
+}
+
+#[test]
+fn bip143_native_p2wpkh_example() {
+    // Input 0 spends a P2PK output and input 1 a P2WPKH one, so this covers both kinds of signature hashes.
+    let bytes = Vec::from_hex("01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000").unwrap();
+    let tx = Transaction::decode(&mut &bytes[..]).unwrap();
+
+    let p2pk = Vec::from_hex("2103c9f4836b9a4f77fc0d81f7bcb01b7f1b35916864b9476c241ce9fc198bd25432ac").unwrap();
+    let checker = SignatureChecker::new(&tx, 0, 625000000);
+    assert_eq!(verify_script(&tx.inputs[0].script_sig, &p2pk, &tx.inputs[0].witness, BLOCK_FLAGS, &checker), Ok(()));
+
+    let p2wpkh = Vec::from_hex("00141d0f172a0ecb48aee1be1f2687d2963ae33f71a1").unwrap();
+    let checker = SignatureChecker::new(&tx, 1, 600000000);
+    assert_eq!(verify_script(&tx.inputs[1].script_sig, &p2wpkh, &tx.inputs[1].witness, BLOCK_FLAGS, &checker), Ok(()));
+
+    // The amount is signed, so a different one must fail.
+    let checker = SignatureChecker::new(&tx, 1, 600000001);
+    assert_eq!(verify_script(&tx.inputs[1].script_sig, &p2wpkh, &tx.inputs[1].witness, BLOCK_FLAGS, &checker), Err(ScriptError::EvalFalse));
+}
+
+#[test]
+fn p2pkh_signature() {
+    let secp = Secp256k1::new();
+    let (secret_key, public_key) = secp.generate_keypair(&mut thread_rng()).unwrap();
+    let pubkey = public_key.serialize().to_vec();
+
+    let mut script_pubkey = vec![OP_DUP, OP_HASH160];
+    push_data(&mut script_pubkey, &hash160(&pubkey));
+    script_pubkey.push(OP_EQUALVERIFY);
+    script_pubkey.push(OP_CHECKSIG);
+
+    let mut tx = spending_tx();
+    let signature = sign(&secp, &secret_key, legacy_sighash(&tx, 0, &script_pubkey, SIGHASH_ALL));
+    let mut script_sig = vec![];
+    push_data(&mut script_sig, &signature);
+    push_data(&mut script_sig, &pubkey);
+    tx.inputs[0].script_sig = script_sig.clone();
+
+    assert_eq!(verify_script(&script_sig, &script_pubkey, &[], BLOCK_FLAGS, &SignatureChecker::new(&tx, 0, 0)), Ok(()));
+
+    // Changing what was signed invalidates the signature.
+    tx.outputs[0].value = 999;
+    assert_eq!(verify_script(&script_sig, &script_pubkey, &[], BLOCK_FLAGS, &SignatureChecker::new(&tx, 0, 0)), Err(ScriptError::EvalFalse));
+}
+
+#[test]
+fn multisig_inside_p2wsh() {
+    let secp = Secp256k1::new();
+    let keys = (0..3).map(|_| secp.generate_keypair(&mut thread_rng()).unwrap()).collect::<Vec<(SecretKey, PublicKey)>>();
+
+    // 2-of-3 multisig.
+    let mut witness_script = vec![OP_1 + 1];
+    for (_, public_key) in keys.iter() {
+        push_data(&mut witness_script, &public_key.serialize());
+    }
+    witness_script.push(OP_1 + 2);
+    witness_script.push(OP_CHECKMULTISIG);
+
+    let mut script_pubkey = vec![OP_0];
+    push_data(&mut script_pubkey, &sha256(&witness_script));
+
+    let tx = spending_tx();
+    let hash = witness_v0_sighash(&tx, 0, &witness_script, 5000, SIGHASH_ALL);
+    let first = sign(&secp, &keys[0].0, hash);
+    let third = sign(&secp, &keys[2].0, hash);
+    let checker = SignatureChecker::new(&tx, 0, 5000);
+
+    let witness = vec![vec![], first.clone(), third.clone(), witness_script.clone()];
+    assert_eq!(verify_script(&[], &script_pubkey, &witness, BLOCK_FLAGS, &checker), Ok(()));
+
+    // Signatures out of order don't match their keys.
+    let witness = vec![vec![], third.clone(), first.clone(), witness_script.clone()];
+    assert_eq!(verify_script(&[], &script_pubkey, &witness, BLOCK_FLAGS, &checker), Err(ScriptError::EvalFalse));
+
+    let witness = vec![vec![1], first, third, witness_script];
+    assert_eq!(verify_script(&[], &script_pubkey, &witness, BLOCK_FLAGS, &checker), Err(ScriptError::NullDummy));
+}
\ No newline at end of file
@@ -1,16 +1,56 @@
 use ::KalikoControlMessage;
+use bip325;
+use bitcoin::ChainParams;
+use network::block::Block;
+use network::MAX_HEADERS_COUNT;
 use network::headers::BlockHeader;
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use util::AdjustedTime;
+
+// Headers more than this many seconds ahead of the network time are rejected.
+const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60;
+// A peer sending this many headers messages in a row that don't connect to our chain is penalized.
+const MAX_UNCONNECTING_HEADERS: u32 = 10;
+// New tips are announced with at most this many headers. Bigger changes only announce the tip.
+const MAX_BLOCKS_TO_ANNOUNCE: usize = 8;
+// Outside of signet, only this many blocks at the tip are downloaded, which is enough for the mempool to learn about confirmations.
+const MAX_TIP_BLOCKS_TO_DOWNLOAD: usize = 16;
+// Signet headers are dropped if their blocks stop arriving for this many seconds.
+const PENDING_HEADERS_TIMEOUT: u64 = 10 * 60;
+
+fn header_hash(header: &BlockHeader) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&header.hash());
+    hash
+}
 
 pub struct BlockHeaderStorage {
+    params: ChainParams,
     storage_file: File,
     chain: Vec<BlockHeader>,
+    // Height of every header in `chain`, so looking one up doesn't hash the whole chain.
+    heights: HashMap<[u8; 32], usize>,
     splits: Vec<Vec<BlockHeader>>,
+    // Blocks found to be invalid. Headers building on them are never accepted again.
+    invalid_blocks: HashSet<Vec<u8>>,
+    // How many headers messages in a row from each peer didn't connect to our chain.
+    unconnecting_headers: HashMap<SocketAddr, u32>,
+    // Signet headers only prove a block was mined, not that it was signed, so they wait here until their block is checked. They build on a header of `chain`, which they replace once they're longer.
+    pending_headers: VecDeque<BlockHeader>,
+    pending_hashes: HashSet<[u8; 32]>,
+    // Checked blocks of pending headers, waiting for the blocks before them.
+    pending_blocks: HashMap<[u8; 32], Block>,
+    // When the pending headers last made progress.
+    pending_since: Instant,
+    adjusted_time: AdjustedTime,
 
     header_request_time: Option<Instant>,
     incoming_control_sender: Sender<KalikoControlMessage>,
@@ -19,32 +59,81 @@ pub struct BlockHeaderStorage {
 }
 
 impl BlockHeaderStorage {
-    pub fn new(storage_location: &str, outgoing_control_sender: Sender<KalikoControlMessage>) -> BlockHeaderStorage {
-        let storage_file = OpenOptions::new().read(true).append(true).create(true).open(storage_location).unwrap();
+    // `data_dir` is the directory holding the data of the network described by `params`.
+    pub fn new(params: &ChainParams, data_dir: &Path, adjusted_time: AdjustedTime, outgoing_control_sender: Sender<KalikoControlMessage>) -> Result<BlockHeaderStorage, String> {
+        fs::create_dir_all(data_dir).map_err(|e| format!("Couldn't create data directory {}: {}", data_dir.display(), e))?;
+        let storage_file = OpenOptions::new().read(true).append(true).create(true).open(data_dir.join("headers")).map_err(|e| format!("Couldn't open the headers file in {}: {}", data_dir.display(), e))?;
 
         // TODO: read storage_file and build the blockchain again.
-        let latest_header = BlockHeader::new_genesis();
-        let chain = vec![latest_header];
+        let latest_header = params.genesis;
 
         let (incoming_control_sender, incoming_control_receiver) = channel();
 
-        BlockHeaderStorage {
+        let mut storage = BlockHeaderStorage {
+            params: params.clone(),
             storage_file,
-            chain,
+            chain: vec![],
+            heights: HashMap::new(),
             splits: vec![],
+            invalid_blocks: HashSet::new(),
+            unconnecting_headers: HashMap::new(),
+            pending_headers: VecDeque::new(),
+            pending_hashes: HashSet::new(),
+            pending_blocks: HashMap::new(),
+            pending_since: Instant::now(),
+            adjusted_time,
 
             header_request_time: None,
             incoming_control_sender,
             incoming_control_receiver,
             outgoing_control_sender,
-        }
+        };
+        storage.extend_chain(vec![latest_header]);
+
+        Ok(storage)
     }
 
     pub fn incoming_sender(&self) -> Sender<KalikoControlMessage> {
         self.incoming_control_sender.clone()
     }
 
-    fn build_headers(&mut self, mut headers: Vec<BlockHeader>) {
+    fn height_of(&self, hash: &[u8]) -> Option<usize> {
+        if hash.len() != 32 {
+            return None;
+        }
+
+        let mut key = [0u8; 32];
+        key.copy_from_slice(hash);
+        self.heights.get(&key).cloned()
+    }
+
+    fn extend_chain(&mut self, headers: Vec<BlockHeader>) {
+        for header in headers {
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(&header.hash());
+            self.heights.insert(hash, self.chain.len());
+            self.chain.push(header);
+        }
+    }
+
+    // Removes the headers from `height` onwards and returns them.
+    fn truncate_chain(&mut self, height: usize) -> Vec<BlockHeader> {
+        let removed = self.chain.split_off(height);
+        for header in removed.iter() {
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(&header.hash());
+            self.heights.remove(&hash);
+        }
+
+        removed
+    }
+
+    // Reports a peer which sent us something invalid.
+    fn misbehaving(&self, peer: SocketAddr, score: u32, reason: &str) {
+        self.outgoing_control_sender.send(KalikoControlMessage::Misbehaving(peer, score, reason.to_string())).unwrap();
+    }
+
+    fn build_headers(&mut self, peer: SocketAddr, mut headers: Vec<BlockHeader>) {
         // The logic in build_headers assumes that all headers are part of the same chain, so we must ensure that before doing any work.
         let (chained_headers, _) = headers.iter().fold((true, Vec::<u8>::new()), |acc, header| {
             match acc.0 {
@@ -68,7 +157,29 @@ impl BlockHeaderStorage {
 
         if !chained_headers {
             info!("We received headers to build that are not all connected!");
-            // TODO: return error?
+            self.misbehaving(peer, 20, "non-continuous headers");
+            return;
+        }
+
+        if headers.iter().any(|h| self.invalid_blocks.contains(&h.hash()) || self.invalid_blocks.contains(h.prev_block.as_slice())) {
+            info!("We received headers building on an invalid block, ignoring them");
+            self.misbehaving(peer, 100, "headers building on an invalid block");
+            return;
+        }
+
+        // These may become valid later, so the peer isn't to blame for them.
+        let max_timestamp = self.adjusted_time.now() + MAX_FUTURE_BLOCK_TIME;
+        if let Some(position) = headers.iter().position(|h| h.timestamp() as i64 > max_timestamp) {
+            info!("We received header {} with a timestamp too far in the future, ignoring it and the ones after it", headers[position]);
+            headers.truncate(position);
+
+            if headers.is_empty() {
+                return;
+            }
+        }
+
+        if self.params.signet_challenge.is_some() {
+            self.add_pending_headers(peer, headers);
             return;
         }
 
@@ -78,24 +189,16 @@ impl BlockHeaderStorage {
         }
 
         // Find in our chain where is the block referenced by the current header's `prev_block`.
-        let common_base_height = {
-            let first_header = &headers[0];
-            let prev_block_hash = first_header.prev_block;
-
-            let prev_header = self.chain.iter().rev().enumerate().find(|(_, h)| h.hash() == prev_block_hash);
-            if let None = prev_header {
-                // If the block is never found, we just ignore the current headers.
-                // TODO: instead of ignoring the current headers, send a getheaders command to the peer who sent us these headers - we may be on the wrong branch.
-                return;
-            }
-
-            prev_header.unwrap().0
+        let common_base_height = match self.height_of(&headers[0].prev_block) {
+            Some(height) => self.chain.len() - 1 - height,
+            // Headers not connecting to our chain are handled before getting here.
+            None => return,
         };
 
         // If the first header builds upon the chain that we have, we can just accept those headers. However, if they are a split in the chain, we need to switch to that split if the received headers form a bigger chain. Otherwise, we need to track the split and only switch when we find the biggest split.
         if common_base_height == 0 {
             // Just add the current header to the chain.
-            self.chain.append(&mut headers);
+            self.extend_chain(headers);
         } else {
             if headers.len() < common_base_height {
                 // We still have the bigger chain.
@@ -106,11 +209,11 @@ impl BlockHeaderStorage {
 
             if headers.len() > common_base_height {
                 // Remove the smaller branch, and start adding the headers from the bigger chain.
-                self.chain.truncate(common_chain_size);
-                self.chain.append(&mut headers);
+                self.truncate_chain(common_chain_size);
+                self.extend_chain(headers);
             } else {
                 // Keep the split and start tracking it.
-                let first_split = self.chain.split_off(common_chain_size);
+                let first_split = self.truncate_chain(common_chain_size);
                 self.splits.push(first_split);
                 self.splits.push(headers);
                 return;
@@ -118,10 +221,270 @@ impl BlockHeaderStorage {
         }
     }
 
+    fn is_pending(&self, hash: &[u8]) -> bool {
+        if hash.len() != 32 {
+            return false;
+        }
+
+        let mut key = [0u8; 32];
+        key.copy_from_slice(hash);
+        self.pending_hashes.contains(&key)
+    }
+
+    // Keeps signet headers aside until their blocks are checked, if they would give us a longer chain than both ours and the pending one.
+    fn add_pending_headers(&mut self, peer: SocketAddr, headers: Vec<BlockHeader>) {
+        let prev_block = headers[0].prev_block;
+        let kept = if !self.pending_hashes.contains(&prev_block) {
+            0
+        } else {
+            match self.pending_headers.iter().rposition(|header| header_hash(header) == prev_block) {
+                Some(position) => position + 1,
+                None => return,
+            }
+        };
+
+        let pending_base = self.pending_headers.front().and_then(|header| self.height_of(&header.prev_block));
+        let base = match kept {
+            0 => self.height_of(&prev_block),
+            _ => pending_base,
+        };
+        let base = match base {
+            Some(height) => height,
+            // Headers not connecting to our chain are handled before getting here.
+            None => return,
+        };
+
+        let best_height = match pending_base {
+            Some(height) => (self.chain.len() - 1).max(height + self.pending_headers.len()),
+            None => self.chain.len() - 1,
+        };
+        if base + kept + headers.len() <= best_height {
+            debug!("[{}] Pending headers starting at {} don't make a longer chain, ignoring them", peer, headers[0]);
+            return;
+        }
+
+        if self.pending_headers.is_empty() {
+            self.pending_since = Instant::now();
+        }
+        self.drop_pending_headers(kept);
+
+        let hashes = headers.iter().map(|h| h.hash()).collect::<Vec<Vec<u8>>>();
+        for header in headers {
+            self.pending_hashes.insert(header_hash(&header));
+            self.pending_headers.push_back(header);
+        }
+
+        self.outgoing_control_sender.send(KalikoControlMessage::RequestBlocksFromPeer(peer, hashes)).unwrap();
+    }
+
+    // Forgets the pending headers from `position` onwards, along with their blocks.
+    fn drop_pending_headers(&mut self, position: usize) {
+        for header in self.pending_headers.split_off(position) {
+            let hash = header_hash(&header);
+            self.pending_hashes.remove(&hash);
+            self.pending_blocks.remove(&hash);
+        }
+    }
+
+    // Moves the pending headers whose blocks were checked into the chain, once that makes it longer.
+    fn connect_pending_blocks(&mut self, peer: SocketAddr) {
+        let fork_height = match self.pending_headers.front().and_then(|header| self.height_of(&header.prev_block)) {
+            Some(height) => height,
+            None => return,
+        };
+        let ready = self.pending_headers.iter().take_while(|header| self.pending_blocks.contains_key(&header_hash(header))).count();
+        if fork_height + ready < self.chain.len() {
+            return;
+        }
+
+        let previous_tip = self.chain[self.chain.len() - 1].hash();
+        self.truncate_chain(fork_height + 1);
+        let mut blocks = vec![];
+        for header in self.pending_headers.drain(..ready).collect::<Vec<BlockHeader>>() {
+            let hash = header_hash(&header);
+            self.pending_hashes.remove(&hash);
+            blocks.extend(self.pending_blocks.remove(&hash));
+            self.extend_chain(vec![header]);
+        }
+
+        self.outgoing_control_sender.send(KalikoControlMessage::ChainHeightUpdated(self.chain.len() as i32 - 1)).unwrap();
+        for (offset, block) in blocks.into_iter().enumerate() {
+            self.outgoing_control_sender.send(KalikoControlMessage::BlockConnected((fork_height + 1 + offset) as i32, block)).unwrap();
+        }
+
+        self.announce_tip(peer, &previous_tip);
+    }
+
+    // Headers either answer our getheaders or announce new blocks, which we handle the same way.
+    fn handle_headers(&mut self, peer: SocketAddr, mut headers: Vec<BlockHeader>) {
+        // A full message means the peer likely has more headers for us.
+        let full_batch = headers.len() == MAX_HEADERS_COUNT;
+
+        // Announcements often include blocks we already know about.
+        let mut known_height = None;
+        while let Some(hash) = headers.first().map(header_hash) {
+            match self.height_of(&hash) {
+                Some(height) => known_height = Some(height),
+                None if self.pending_hashes.contains(&hash) => (),
+                None => break,
+            }
+            headers.remove(0);
+        }
+
+        if let Some(height) = known_height {
+            self.outgoing_control_sender.send(KalikoControlMessage::PeerBestHeight(peer, height as i32)).unwrap();
+        }
+
+        // The peer has nothing we don't know about.
+        if headers.is_empty() {
+            return;
+        }
+
+        // Without this, anyone could make up headers, and their blocks would reach the mempool as confirmed.
+        if let Some(header) = headers.iter().find(|header| !header.check_proof_of_work(self.params.pow_limit_bits)) {
+            info!("[{}] Header {} doesn't have the proof of work it claims, ignoring it and the ones with it", peer, header);
+            self.misbehaving(peer, 100, "header without proof of work");
+            return;
+        }
+
+        if self.height_of(&headers[0].prev_block).is_none() && !self.pending_hashes.contains(&headers[0].prev_block) {
+            // Likely an announcement of a block whose parent we don't have, so we ask for what we're missing.
+            let count = {
+                let count = self.unconnecting_headers.entry(peer).or_insert(0);
+                *count += 1;
+                *count
+            };
+
+            debug!("[{}] Headers starting at {} don't connect to our chain", peer, headers[0]);
+            if count % MAX_UNCONNECTING_HEADERS == 0 {
+                self.misbehaving(peer, 20, "too many headers not connecting to our chain");
+            }
+
+            self.outgoing_control_sender.send(KalikoControlMessage::RequestHeadersFromPeer(peer, self.block_locator())).unwrap();
+            return;
+        }
+
+        self.unconnecting_headers.remove(&peer);
+
+        debug!("Building headers...");
+        let previous_height = self.chain.len();
+        let previous_tip = self.chain[self.chain.len() - 1].hash();
+        let received = headers.iter().map(|h| h.hash()).collect::<Vec<Vec<u8>>>();
+        self.build_headers(peer, headers);
+
+        // The peer has at least every header it sent us that we ended up with.
+        if let Some(height) = received.last().and_then(|hash| self.height_of(hash)) {
+            self.outgoing_control_sender.send(KalikoControlMessage::PeerBestHeight(peer, height as i32)).unwrap();
+        }
+        if self.chain.len() != previous_height {
+            self.outgoing_control_sender.send(KalikoControlMessage::ChainHeightUpdated(self.chain.len() as i32 - 1)).unwrap();
+        }
+
+        // Only checked headers make it into the chain, so the new tip is safe to relay.
+        if self.chain[self.chain.len() - 1].hash() != previous_tip {
+            self.announce_tip(peer, &previous_tip);
+
+            // The mempool learns about confirmations from these blocks.
+            let blocks = self.blocks_to_download(received, full_batch);
+            if !blocks.is_empty() {
+                self.outgoing_control_sender.send(KalikoControlMessage::RequestBlocksFromPeer(peer, blocks)).unwrap();
+            }
+        }
+
+        debug!("New chain:");
+        for item in self.chain.iter() {
+            debug!("  {}", item);
+        }
+
+        if full_batch {
+            self.outgoing_control_sender.send(KalikoControlMessage::RequestHeadersFromPeer(peer, self.block_locator())).unwrap();
+        }
+    }
+
+    fn handle_block_announcements(&mut self, peer: SocketAddr, hashes: Vec<Vec<u8>>) {
+        let mut unknown = false;
+
+        for hash in hashes.iter() {
+            match self.height_of(hash) {
+                Some(height) => self.outgoing_control_sender.send(KalikoControlMessage::PeerBestHeight(peer, height as i32)).unwrap(),
+                None => unknown |= !self.invalid_blocks.contains(hash) && !self.is_pending(hash),
+            }
+        }
+
+        // The headers of the new blocks tell us where they fit, so we ask for them instead of the blocks.
+        if unknown {
+            debug!("[{}] Peer announced blocks we don't know about, requesting headers", peer);
+            self.outgoing_control_sender.send(KalikoControlMessage::RequestHeadersFromPeer(peer, self.block_locator())).unwrap();
+        }
+    }
+
+    // Lets other peers know about our new tip, which we learned about from `source`.
+    fn announce_tip(&self, source: SocketAddr, previous_tip: &[u8]) {
+        let new_headers = match self.height_of(previous_tip) {
+            Some(position) if self.chain.len() - position - 1 <= MAX_BLOCKS_TO_ANNOUNCE => self.chain[position + 1..].to_vec(),
+            _ => vec![self.chain[self.chain.len() - 1]],
+        };
+
+        self.outgoing_control_sender.send(KalikoControlMessage::AnnounceHeaders(source, new_headers)).unwrap();
+    }
+
+    // Only the blocks at the tip are needed, once we're done syncing headers. Signet blocks are requested along with their pending headers.
+    fn blocks_to_download(&self, mut received: Vec<Vec<u8>>, full_batch: bool) -> Vec<Vec<u8>> {
+        if full_batch {
+            return vec![];
+        }
+
+        let skipped = received.len().saturating_sub(MAX_TIP_BLOCKS_TO_DOWNLOAD);
+        received.split_off(skipped)
+    }
+
+    fn check_block(&mut self, peer: SocketAddr, block: Block) {
+        let hash = header_hash(&block.header);
+        let pending = self.pending_hashes.contains(&hash);
+
+        let height = match self.height_of(&hash) {
+            Some(height) if self.params.signet_challenge.is_none() => Some(height),
+            // Signet blocks are checked while their header is pending, and connected along with it.
+            _ if pending => None,
+            // Not (or no longer) part of our chain, so there's nothing to check.
+            _ => return,
+        };
+
+        // A block not matching its header tells nothing about the header itself.
+        if !block.check_merkle_root() {
+            info!("Block {} doesn't match its header, ignoring it", block.header);
+            return;
+        }
+
+        if let Some(height) = height {
+            self.outgoing_control_sender.send(KalikoControlMessage::BlockConnected(height as i32, block)).unwrap();
+            return;
+        }
+
+        if let Err(e) = bip325::check_block_solution(&block, &self.params) {
+            warn!("Block {} has an invalid signet solution ({:?}), dropping its header and the ones after it", block.header, e);
+
+            self.misbehaving(peer, 100, "invalid signet block solution");
+            self.invalid_blocks.insert(hash.to_vec());
+            let position = self.pending_headers.iter().position(|header| header_hash(header) == hash).unwrap_or(self.pending_headers.len());
+            self.drop_pending_headers(position);
+            return;
+        }
+
+        self.pending_blocks.insert(hash, block);
+        self.pending_since = Instant::now();
+        self.connect_pending_blocks(peer);
+    }
+
     fn block_locator(&self) -> Vec<Vec<u8>> {
         // TODO: what to do when we have a split?
         let mut result = vec![];
 
+        // Peers shouldn't send us the pending headers again.
+        if let Some(header) = self.pending_headers.back() {
+            result.push(header.hash());
+        }
+
         result.append(&mut self.chain.iter().rev().take(10).map(|b| b.hash()).collect::<Vec<Vec<u8>>>());
 
         let mut chain_iter = self.chain.iter().rev().skip(10);
@@ -142,7 +505,16 @@ impl BlockHeaderStorage {
     pub fn start(mut self) {
         thread::spawn(move || {
             loop {
-                let msg = self.incoming_control_receiver.recv().unwrap();
+                if !self.pending_headers.is_empty() && self.pending_since.elapsed() > Duration::from_secs(PENDING_HEADERS_TIMEOUT) {
+                    info!("The blocks of {} pending headers didn't arrive in time, dropping them", self.pending_headers.len());
+                    self.drop_pending_headers(0);
+                }
+
+                let msg = match self.incoming_control_receiver.recv_timeout(Duration::from_secs(1)) {
+                    Ok(msg) => msg,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
 
                 debug!("Got control message: {:?}", msg);
                 match msg {
@@ -156,15 +528,13 @@ impl BlockHeaderStorage {
                         self.outgoing_control_sender.send(KalikoControlMessage::RequestHeadersFromPeer(peer, self.block_locator())).unwrap();
                     },
                     KalikoControlMessage::NewHeadersAvailable(peer, headers) => {
-                        debug!("Building headers...");
-                        self.build_headers(headers);
-                        debug!("New chain:");
-                        for item in self.chain.iter() {
-                            debug!("  {}", item);
-                        }
-
-                        // Send message requesting more headers just in case that peer has more headers for us.
-                        self.outgoing_control_sender.send(KalikoControlMessage::RequestHeadersFromPeer(peer, self.block_locator())).unwrap();
+                        self.handle_headers(peer, headers);
+                    },
+                    KalikoControlMessage::BlocksAnnounced(peer, hashes) => {
+                        self.handle_block_announcements(peer, hashes);
+                    },
+                    KalikoControlMessage::NewBlockAvailable(peer, block) => {
+                        self.check_block(peer, block);
                     },
                     _ => continue,
                 }
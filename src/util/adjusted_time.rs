@@ -0,0 +1,131 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Only this many peers get to take part in the network time, so a flood of connections can't keep moving it.
+const MAX_TIME_SAMPLES: usize = 200;
+// The network time isn't trusted before this many peers agree on it.
+const MIN_TIME_SAMPLES: usize = 5;
+// Our clock is never adjusted by more than this many seconds. Peers being this far off is more likely an attack than our clock being wrong.
+const MAX_TIME_ADJUSTMENT: i64 = 70 * 60;
+// We warn about our clock when peers think it's off by more than this many seconds.
+const CLOCK_WARNING_THRESHOLD: i64 = 5 * 60;
+
+pub fn local_time() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+#[derive(Debug)]
+struct TimeSamples {
+    // Peers whose offset we already have, so a single peer reconnecting can't count more than once.
+    peers: HashSet<IpAddr>,
+    offsets: Vec<i64>,
+    offset: i64,
+    warned: bool,
+}
+
+// Our clock adjusted by the median of how far off it is from our peers' clocks. Clones share the same samples.
+#[derive(Clone, Debug)]
+pub struct AdjustedTime {
+    samples: Arc<Mutex<TimeSamples>>,
+}
+
+impl AdjustedTime {
+    pub fn new() -> AdjustedTime {
+        AdjustedTime {
+            samples: Arc::new(Mutex::new(TimeSamples {
+                peers: HashSet::new(),
+                // Our own clock counts too.
+                offsets: vec![0],
+                offset: 0,
+                warned: false,
+            })),
+        }
+    }
+
+    // Records that the clock of the peer at `ip` is `offset` seconds ahead of ours.
+    pub fn add_sample(&self, ip: IpAddr, offset: i64) {
+        let mut samples = self.samples.lock().unwrap();
+
+        if samples.offsets.len() >= MAX_TIME_SAMPLES || !samples.peers.insert(ip) {
+            return;
+        }
+
+        samples.offsets.push(offset);
+
+        // With an even number of samples there's no single median, so we wait for the next one.
+        if samples.offsets.len() < MIN_TIME_SAMPLES || samples.offsets.len().is_multiple_of(2) {
+            return;
+        }
+
+        let mut sorted = samples.offsets.clone();
+        sorted.sort();
+        let median = sorted[sorted.len() / 2];
+
+        samples.offset = if median.abs() <= MAX_TIME_ADJUSTMENT { median } else { 0 };
+        debug!("Network time offset is now {}s from {} samples", samples.offset, sorted.len());
+
+        if median.abs() > CLOCK_WARNING_THRESHOLD && !samples.warned {
+            samples.warned = true;
+            warn!("Our clock is {}s off from the median of our peers' clocks, please check that your computer's date and time are correct", -median);
+        }
+    }
+
+    // Seconds to add to our clock to get the network time.
+    pub fn offset(&self) -> i64 {
+        self.samples.lock().unwrap().offset
+    }
+
+    pub fn now(&self) -> i64 {
+        local_time() + self.offset()
+    }
+}
+
+impl Default for AdjustedTime {
+    fn default() -> AdjustedTime {
+        AdjustedTime::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([1, 2, 3, last])
+    }
+
+    #[test]
+    fn median_of_peer_offsets() {
+        let adjusted_time = AdjustedTime::new();
+
+        for (i, &offset) in [10, 20, 30, 40].iter().enumerate() {
+            adjusted_time.add_sample(ip(i as u8), offset);
+        }
+        // 0, 10, 20, 30 and 40.
+        assert_eq!(adjusted_time.offset(), 20);
+
+        // The same peer only counts once.
+        adjusted_time.add_sample(ip(0), 50);
+        adjusted_time.add_sample(ip(0), 50);
+        assert_eq!(adjusted_time.offset(), 20);
+
+        // Waiting for an odd number of samples.
+        adjusted_time.add_sample(ip(4), 50);
+        assert_eq!(adjusted_time.offset(), 20);
+        adjusted_time.add_sample(ip(5), 60);
+        assert_eq!(adjusted_time.offset(), 30);
+    }
+
+    #[test]
+    fn large_offsets_are_ignored() {
+        let adjusted_time = AdjustedTime::new();
+
+        for i in 0..4 {
+            adjusted_time.add_sample(ip(i), 2 * MAX_TIME_ADJUSTMENT);
+        }
+
+        assert_eq!(adjusted_time.offset(), 0);
+    }
+}
\ No newline at end of file
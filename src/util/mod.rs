@@ -0,0 +1,102 @@
+pub mod adjusted_time;
+
+pub use self::adjusted_time::AdjustedTime;
+
+use ripemd160::Ripemd160;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut result = [0u8; 32];
+    result.copy_from_slice(&Sha256::digest(data));
+    result
+}
+
+// Double SHA256, used for block and transaction hashes.
+pub fn sha256d(data: &[u8]) -> [u8; 32] {
+    sha256(&sha256(data))
+}
+
+// RIPEMD160 of the SHA256, used for public key and script hashes.
+pub fn hash160(data: &[u8]) -> [u8; 20] {
+    let mut result = [0u8; 20];
+    result.copy_from_slice(&Ripemd160::digest(&Sha256::digest(data)));
+    result
+}
+
+// Writes to a temporary file first and then moves it over `path`, so a crash in the middle doesn't lose what was saved before.
+pub fn write_file_atomically(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let temp_path = path.with_extension("tmp");
+    File::create(&temp_path).and_then(|mut file| file.write_all(contents)).map_err(|e| e.to_string())?;
+    fs::rename(&temp_path, path).map_err(|e| e.to_string())
+}
+
+// A path in the temporary directory that no other test will use.
+#[cfg(test)]
+pub fn temp_path(name: &str) -> ::std::path::PathBuf {
+    use rand::Rng;
+    ::std::env::temp_dir().join(format!("kaliko_{}_{}.toml", name, ::rand::thread_rng().next_u64()))
+}
+
+// Saves something to a temporary file and loads it back, so tests only have to check what came out.
+#[cfg(test)]
+pub fn save_then_load<T, S, L>(name: &str, save: S, load: L) -> T
+    where S: FnOnce(&Path) -> Result<(), String>, L: FnOnce(&Path) -> Result<T, String> {
+    let path = temp_path(name);
+    save(&path).unwrap();
+    let loaded = load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    loaded
+}
+
+// Only OP_SHA1 needs SHA1, so it's implemented here instead of pulling another crate in.
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    // Padding with a 1 bit, zeros and the length in bits, up to a multiple of 64 bytes.
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([chunk[4 * i], chunk[4 * i + 1], chunk[4 * i + 2], chunk[4 * i + 3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (value, added) in h.iter_mut().zip([a, b, c, d, e].iter()) {
+            *value = value.wrapping_add(*added);
+        }
+    }
+
+    let mut result = [0u8; 20];
+    for (i, value) in h.iter().enumerate() {
+        result[4 * i..4 * i + 4].copy_from_slice(&value.to_be_bytes());
+    }
+    result
+}
\ No newline at end of file